@@ -0,0 +1,95 @@
+//! The output callback's mixing, for 2, 4 and 8 stereo inputs at 64 and 256 frames, popped and
+//! mixed a sample at a time against whole slices through `mix`. Run it again with
+//! `--features simd` for the AVX paths.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use loopback_clone::mix;
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+
+const CHANNELS: usize = 2;
+const GAIN: f32 = 0.7;
+
+struct Inputs {
+    producers: Vec<HeapProducer<f32>>,
+    consumers: Vec<HeapConsumer<f32>>,
+    period: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl Inputs {
+    fn new(inputs: usize, frames: usize) -> Self {
+        let (producers, consumers) = (0..inputs)
+            .map(|_| HeapRb::new(frames * CHANNELS * 4).split())
+            .unzip();
+        Inputs {
+            producers,
+            consumers,
+            period: (0..frames * CHANNELS)
+                .map(|i| (i as f32 * 0.05).sin() * 0.5)
+                .collect(),
+            output: vec![0.0; frames * CHANNELS],
+        }
+    }
+
+    /// Queues a period on every input, as their callbacks would.
+    fn fill(&mut self) {
+        for producer in &mut self.producers {
+            producer.push_slice(&self.period);
+        }
+    }
+}
+
+fn per_sample(inputs: &mut Inputs) -> f32 {
+    inputs.fill();
+    let output = inputs.output.as_mut_slice();
+    output.iter_mut().for_each(|sample| *sample = 0.0);
+    for consumer in &mut inputs.consumers {
+        let wanted = output.len();
+        for (index, sample) in consumer.pop_iter().take(wanted).enumerate() {
+            output[index] += sample;
+        }
+    }
+    let mut peak = 0.0f32;
+    for sample in output.iter_mut() {
+        *sample *= GAIN;
+        peak = peak.max(sample.abs());
+    }
+    peak
+}
+
+fn slices(inputs: &mut Inputs) -> f32 {
+    inputs.fill();
+    let output = inputs.output.as_mut_slice();
+    output.fill(0.0);
+    for consumer in &mut inputs.consumers {
+        let (first, second) = consumer.as_slices();
+        let (start, end) = output.split_at_mut(first.len().min(output.len()));
+        mix::add(start, first);
+        mix::add(end, second);
+        let mixed = output.len().min(first.len() + second.len());
+        consumer.skip(mixed);
+    }
+    mix::scale(output, GAIN);
+    mix::peak(output)
+}
+
+fn mixing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mix");
+    for frames in [64, 256] {
+        for inputs in [2, 4, 8] {
+            let parameter = format!("{} inputs x {} frames", inputs, frames);
+            let mut state = Inputs::new(inputs, frames);
+            group.bench_function(BenchmarkId::new("per sample", &parameter), |b| {
+                b.iter(|| black_box(per_sample(&mut state)))
+            });
+            let mut state = Inputs::new(inputs, frames);
+            group.bench_function(BenchmarkId::new("slices", &parameter), |b| {
+                b.iter(|| black_box(slices(&mut state)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mixing);
+criterion_main!(benches);
@@ -0,0 +1,132 @@
+#ifndef LOOPBACK_H
+#define LOOPBACK_H
+
+/* Generated from src/ffi.rs by cbindgen, don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+
+
+#define LOOPBACK_OK 0
+
+/**
+ * A pointer argument was null.
+ */
+#define LOOPBACK_ERROR_NULL -1
+
+/**
+ * An argument was out of range or otherwise invalid.
+ */
+#define LOOPBACK_ERROR_INVALID -2
+
+/**
+ * Something failed inside the library.
+ */
+#define LOOPBACK_ERROR_FAILED -3
+
+/**
+ * The library panicked, which is a bug.
+ */
+#define LOOPBACK_ERROR_PANIC -4
+
+/**
+ * A device named in the configuration isn't there.
+ */
+#define LOOPBACK_ERROR_DEVICE_NOT_FOUND -5
+
+/**
+ * A device can't run the way the configuration needs it to.
+ */
+#define LOOPBACK_ERROR_UNSUPPORTED -6
+
+/**
+ * Another application has the output device to itself.
+ */
+#define LOOPBACK_ERROR_DEVICE_BUSY -7
+
+/**
+ * A running pipeline.
+ */
+typedef struct LoopbackHandle LoopbackHandle;
+
+/**
+ * Builds and starts a pipeline from a JSON configuration, returning null on failure. An input's
+ * `channel` counts from 1, as on the command line.
+ *
+ * # Safety
+ *
+ * `config_json` must be null or point to a NUL terminated string.
+ */
+struct LoopbackHandle *loopback_create(const char *config_json);
+
+/**
+ * Sets the level of the input at `index`, in the order the configuration listed them.
+ *
+ * # Safety
+ *
+ * `handle` must be null or a live handle from [`loopback_create`].
+ */
+int32_t loopback_set_gain(struct LoopbackHandle *handle, uint32_t index, float db);
+
+/**
+ * Mutes or unmutes the input at `index`.
+ *
+ * # Safety
+ *
+ * `handle` must be null or a live handle from [`loopback_create`].
+ */
+int32_t loopback_mute(struct LoopbackHandle *handle, uint32_t index, bool muted);
+
+/**
+ * Returns the pipeline's current state as JSON, or null on failure. The string must be freed
+ * with [`loopback_string_free`].
+ *
+ * The output peak is the loudest sample since the previous call, and the true peak the loudest
+ * point between samples too. Each input's clipped samples are those that have arrived at full
+ * scale in runs long enough to be clipping, since the pipeline was created.
+ *
+ * # Safety
+ *
+ * `handle` must be null or a live handle from [`loopback_create`].
+ */
+char *loopback_stats_json(struct LoopbackHandle *handle);
+
+/**
+ * Returns what the pipeline is doing as JSON, laid out as described in
+ * [`crate::describe`], or null on failure. The string must be freed with
+ * [`loopback_string_free`].
+ *
+ * # Safety
+ *
+ * `handle` must be null or a live handle from [`loopback_create`].
+ */
+char *loopback_describe_json(struct LoopbackHandle *handle);
+
+/**
+ * Frees a string returned by the library. Null is ignored.
+ *
+ * # Safety
+ *
+ * `string` must be null or a string returned by the library that hasn't been freed.
+ */
+void loopback_string_free(char *string);
+
+/**
+ * Describes the most recent failure on this thread, or returns null if nothing has failed. The
+ * string belongs to the library and stays valid until the next failure on this thread.
+ */
+const char *loopback_last_error(void);
+
+/**
+ * Stops the pipeline and frees the handle. Null is ignored.
+ *
+ * # Safety
+ *
+ * `handle` must be null or a live handle from [`loopback_create`], which can't be used again.
+ */
+void loopback_destroy(struct LoopbackHandle *handle);
+
+#endif  /* LOOPBACK_H */
@@ -0,0 +1,273 @@
+//! Inputs attached to a running pipeline, on top of the ones it was built with.
+//!
+//! The control thread hands the output callback each new input's ring buffer through a
+//! [`Attacher`], and the callback fades it in. Detaching fades the input out and hands the ring
+//! buffer back, so it's freed on the control thread rather than the audio thread.
+//!
+//! Each input also has a pan the control thread can change at any time, which only applies to
+//! stereo and which the callback ramps towards like the fades.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+
+use crate::latency::Adjustment;
+use crate::pipeline::{ms_to_frames, MixLayout};
+
+/// The most inputs that can be attached at once.
+pub const MAX_ATTACHED: usize = 16;
+/// How long an attached input takes to fade in or out.
+const FADE_MS: f32 = 20.0;
+/// How far either side of the middle [`auto_pan`] spreads the attached inputs, out of 1.
+pub const AUTO_PAN_WIDTH: f32 = 0.5;
+
+enum Request {
+    Attach {
+        id: u64,
+        consumer: HeapConsumer<f32>,
+        pan: Arc<AtomicU32>,
+    },
+    Detach {
+        id: u64,
+    },
+}
+
+/// The control thread's end.
+pub(crate) struct Attacher {
+    requests: HeapProducer<Request>,
+    retired: HeapConsumer<HeapConsumer<f32>>,
+}
+
+/// The output callback's end, which mixes the attached inputs in.
+pub(crate) struct AttachedMix {
+    requests: HeapConsumer<Request>,
+    retired: HeapProducer<HeapConsumer<f32>>,
+    /// Allocated up front with room for [`MAX_ATTACHED`].
+    inputs: Vec<Attached>,
+    fade_step: f32,
+}
+
+struct Attached {
+    id: u64,
+    consumer: HeapConsumer<f32>,
+    gain: f32,
+    leaving: bool,
+    /// Where the control thread wants the input, from -1 (left) to 1 (right), as f32 bits.
+    pan_target: Arc<AtomicU32>,
+    /// Where the ramp towards `pan_target` has got to.
+    pan: f32,
+}
+
+pub(crate) fn channel(sample_rate: u32) -> (Attacher, AttachedMix) {
+    // Both directions hold everything there can be at once, so neither ever fills up.
+    let (requests, request_consumer) = HeapRb::new(2 * MAX_ATTACHED).split();
+    let (retired_producer, retired) = HeapRb::new(2 * MAX_ATTACHED).split();
+    (
+        Attacher { requests, retired },
+        AttachedMix {
+            requests: request_consumer,
+            retired: retired_producer,
+            inputs: Vec::with_capacity(MAX_ATTACHED),
+            fade_step: 1.0 / ms_to_frames(FADE_MS, sample_rate).max(1) as f32,
+        },
+    )
+}
+
+impl Attacher {
+    /// Starts mixing in `consumer`, which is prefilled like the other inputs' ring buffers,
+    /// panned by `pan` from the start.
+    pub(crate) fn attach(&mut self, id: u64, consumer: HeapConsumer<f32>, pan: Arc<AtomicU32>) {
+        self.free_retired();
+        // The pipeline never attaches more than the callback has room for.
+        let _ = self.requests.push(Request::Attach { id, consumer, pan });
+    }
+
+    /// Fades the input out and stops mixing it in.
+    pub(crate) fn detach(&mut self, id: u64) {
+        self.free_retired();
+        let _ = self.requests.push(Request::Detach { id });
+    }
+
+    /// Frees the ring buffers of inputs that have finished fading out.
+    pub(crate) fn free_retired(&mut self) {
+        while self.retired.pop().is_some() {}
+    }
+}
+
+impl AttachedMix {
+    /// Adds every attached input to interleaved `data`, treating the inputs like the pipeline's
+    /// own as far as `adjustment` goes. Returns whether any input fell behind.
+    pub(crate) fn mix(
+        &mut self,
+        data: &mut [f32],
+        layout: &MixLayout,
+        adjustment: Adjustment,
+    ) -> bool {
+        while let Some(request) = self.requests.pop() {
+            match request {
+                // Inputs still fading out can leave no room, and growing would allocate.
+                Request::Attach { consumer, .. } if self.inputs.len() == self.inputs.capacity() => {
+                    let _ = self.retired.push(consumer);
+                }
+                Request::Attach { id, consumer, pan } => self.inputs.push(Attached {
+                    id,
+                    consumer,
+                    gain: 0.0,
+                    leaving: false,
+                    pan: f32::from_bits(pan.load(Ordering::Relaxed)),
+                    pan_target: pan,
+                }),
+                Request::Detach { id } => {
+                    if let Some(input) = self.inputs.iter_mut().find(|input| input.id == id) {
+                        input.leaving = true;
+                    }
+                }
+            }
+        }
+
+        let channels = layout.input_channels;
+        let frames = data.len() / layout.output_channels;
+        let mut fell_behind = false;
+        for input in &mut self.inputs {
+            match adjustment {
+                Adjustment::Wait => continue,
+                Adjustment::Discard(discard) => {
+                    input.consumer.skip(discard * channels);
+                }
+                Adjustment::Mix => {}
+            }
+            // An input on its way out has had its stream stopped, so it is expected to run dry.
+            if !input.leaving && input.consumer.len() < frames * channels {
+                fell_behind = true;
+            }
+
+            let target = if input.leaving { 0.0 } else { 1.0 };
+            let pan_target = f32::from_bits(input.pan_target.load(Ordering::Relaxed));
+            for frame in 0..frames {
+                if input.gain < target {
+                    input.gain = (input.gain + self.fade_step).min(target);
+                } else if input.gain > target {
+                    input.gain = (input.gain - self.fade_step).max(target);
+                }
+                if input.pan < pan_target {
+                    input.pan = (input.pan + self.fade_step).min(pan_target);
+                } else if input.pan > pan_target {
+                    input.pan = (input.pan - self.fade_step).max(pan_target);
+                }
+                for channel in 0..channels {
+                    let Some(sample) = input.consumer.pop() else {
+                        break;
+                    };
+                    if let Some(index) = layout.output_index(frame * channels + channel) {
+                        let pan_gain = if channels == 2 {
+                            balance(input.pan, channel)
+                        } else {
+                            1.0
+                        };
+                        data[index] += sample * input.gain * pan_gain;
+                    }
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < self.inputs.len() {
+            if self.inputs[i].leaving && self.inputs[i].gain == 0.0 {
+                let input = self.inputs.swap_remove(i);
+                // There's always room, but if there somehow weren't, freeing it here is better
+                // than keeping it forever.
+                let _ = self.retired.push(input.consumer);
+            } else {
+                i += 1;
+            }
+        }
+        fell_behind
+    }
+}
+
+/// Where `--auto-pan` puts each of `inputs`: spread evenly from left of centre to right of centre
+/// in the order they come, or in the middle if there's only one.
+pub fn auto_pan<'a>(inputs: impl IntoIterator<Item = &'a str>) -> Vec<(&'a str, f32)> {
+    let inputs = inputs.into_iter().collect::<Vec<_>>();
+    let last = inputs.len().saturating_sub(1);
+    inputs
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let position = if last == 0 {
+                0.0
+            } else {
+                -AUTO_PAN_WIDTH + 2.0 * AUTO_PAN_WIDTH * i as f32 / last as f32
+            };
+            (name, position)
+        })
+        .collect()
+}
+
+/// The gain of stereo `channel` at `pan`, which turns the other side down rather than this one up,
+/// so a centred input is left as it is.
+fn balance(pan: f32, channel: usize) -> f32 {
+    match channel {
+        0 => (1.0 - pan).min(1.0),
+        _ => (1.0 + pan).min(1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_pan_puts_a_lone_input_in_the_middle() {
+        assert_eq!(auto_pan(["USB Mic"]), [("USB Mic", 0.0)]);
+        assert_eq!(auto_pan([]), []);
+    }
+
+    #[test]
+    fn auto_pan_puts_two_inputs_either_side() {
+        assert_eq!(
+            auto_pan(["USB Mic", "Phone"]),
+            [("USB Mic", -AUTO_PAN_WIDTH), ("Phone", AUTO_PAN_WIDTH)]
+        );
+    }
+
+    #[test]
+    fn auto_pan_spreads_more_inputs_evenly_in_order() {
+        let positions = auto_pan(["A", "B", "C", "D", "E"]);
+        assert_eq!(
+            positions,
+            [
+                ("A", -0.5),
+                ("B", -0.25),
+                ("C", 0.0),
+                ("D", 0.25),
+                ("E", 0.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn auto_pan_moves_the_others_when_an_input_comes_or_goes() {
+        let mut inputs = vec!["USB Mic", "Phone"];
+        inputs.push("Guest");
+        assert_eq!(
+            auto_pan(inputs.iter().copied()),
+            [("USB Mic", -0.5), ("Phone", 0.0), ("Guest", 0.5)]
+        );
+        inputs.remove(0);
+        assert_eq!(
+            auto_pan(inputs.iter().copied()),
+            [("Phone", -0.5), ("Guest", 0.5)]
+        );
+        inputs.remove(1);
+        assert_eq!(auto_pan(inputs.iter().copied()), [("Phone", 0.0)]);
+    }
+
+    #[test]
+    fn balance_turns_the_other_side_down() {
+        assert_eq!((balance(0.0, 0), balance(0.0, 1)), (1.0, 1.0));
+        assert_eq!((balance(-0.5, 0), balance(-0.5, 1)), (1.0, 0.5));
+        assert_eq!((balance(1.0, 0), balance(1.0, 1)), (0.0, 1.0));
+    }
+}
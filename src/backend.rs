@@ -0,0 +1,153 @@
+//! The seam between the pipeline and the audio devices it runs on.
+//!
+//! The pipeline only ever talks to devices through these traits, which [`cpal_host`] implements
+//! for real hardware and [`fake`] implements with synthetic devices driven by a deterministic
+//! clock. [`null`] adds an output that isn't a device at all, and `opus_udp` one that streams
+//! the mix over the network.
+
+pub mod cpal_host;
+pub mod fake;
+pub mod null;
+#[cfg(feature = "opus")]
+pub mod opus_udp;
+
+use std::error::Error;
+use std::fmt;
+
+pub use cpal::{StreamConfig, StreamError};
+
+use crate::error::BoxError;
+use crate::negotiate::ConfigRange;
+
+/// Called with interleaved f32 samples captured by an input.
+pub type InputCallback = Box<dyn FnMut(&[f32]) + Send + 'static>;
+/// Called with an interleaved f32 buffer to fill for an output.
+pub type OutputCallback = Box<dyn FnMut(&mut [f32]) + Send + 'static>;
+/// Called when a stream fails after it has been built.
+pub type ErrorCallback = Box<dyn FnMut(StreamError) + Send + 'static>;
+
+/// A device as listed by [`DeviceProvider::devices`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub is_input: bool,
+    pub is_output: bool,
+    /// An identifier that survives renames, such as a CoreAudio UID, for backends that expose one.
+    pub stable_id: Option<String>,
+}
+
+/// What a backend says went wrong.
+#[derive(Debug)]
+pub enum BackendError {
+    /// There's no device with this name going the way it was looked for.
+    DeviceNotFound(String),
+    /// The device has gone away since it was found.
+    DeviceGone(String),
+    /// Anything else, saying what couldn't be done, and the backend's own error if it has one.
+    Failed {
+        what: String,
+        source: Option<BoxError>,
+    },
+}
+
+impl BackendError {
+    pub fn failed(what: impl Into<String>) -> Self {
+        BackendError::Failed {
+            what: what.into(),
+            source: None,
+        }
+    }
+
+    pub fn caused(what: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        BackendError::Failed {
+            what: what.into(),
+            source: Some(source.into()),
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::DeviceNotFound(name) => write!(f, "couldn't find device \"{}\"", name),
+            BackendError::DeviceGone(name) => {
+                write!(f, "device \"{}\" is no longer available", name)
+            }
+            BackendError::Failed { what, .. } => f.write_str(what),
+        }
+    }
+}
+
+impl Error for BackendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BackendError::Failed {
+                source: Some(source),
+                ..
+            } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// Finds devices by name.
+pub trait DeviceProvider {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError>;
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError>;
+    /// Every device currently available, inputs first.
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError>;
+}
+
+impl<P: DeviceProvider + ?Sized> DeviceProvider for Box<P> {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError> {
+        (**self).input_device(name)
+    }
+
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError> {
+        (**self).output_device(name)
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        (**self).devices()
+    }
+}
+
+/// A device that audio can be captured from.
+pub trait InputSource {
+    fn name(&self) -> &str;
+    fn default_config(&self) -> Result<StreamConfig, BackendError>;
+    /// Everything the device supports with f32 samples, which is only its default unless the
+    /// backend knows better.
+    fn supported_configs(&self) -> Result<Vec<ConfigRange>, BackendError> {
+        Ok(vec![ConfigRange::exactly(&self.default_config()?)])
+    }
+    fn build_input_stream(
+        &self,
+        config: &StreamConfig,
+        on_data: InputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError>;
+}
+
+/// A device that audio can be played to.
+pub trait OutputSink {
+    fn name(&self) -> &str;
+    fn default_config(&self) -> Result<StreamConfig, BackendError>;
+    /// Everything the device supports with f32 samples, which is only its default unless the
+    /// backend knows better.
+    fn supported_configs(&self) -> Result<Vec<ConfigRange>, BackendError> {
+        Ok(vec![ConfigRange::exactly(&self.default_config()?)])
+    }
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        on_data: OutputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError>;
+}
+
+/// A built stream, which delivers callbacks only while playing and stops for good once dropped.
+pub trait Stream {
+    fn play(&self) -> Result<(), BackendError>;
+    fn pause(&self) -> Result<(), BackendError>;
+}
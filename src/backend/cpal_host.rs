@@ -0,0 +1,346 @@
+//! Real devices, through whichever host cpal picks by default.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use crate::error::BoxError;
+use crate::negotiate::ConfigRange;
+
+use super::{
+    BackendError, DeviceInfo, DeviceProvider, ErrorCallback, InputCallback, InputSource,
+    OutputCallback, OutputSink, Stream, StreamConfig,
+};
+
+pub struct CpalProvider {
+    host: cpal::Host,
+}
+
+impl CpalProvider {
+    pub fn new() -> Self {
+        CpalProvider {
+            host: cpal::default_host(),
+        }
+    }
+}
+
+impl Default for CpalProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceProvider for CpalProvider {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError> {
+        let devices = self.host.input_devices().map_err(failed(LISTING))?;
+        let device = find_device(devices, name)?;
+        Ok(Box::new(CpalDevice {
+            device,
+            name: name.to_owned(),
+        }))
+    }
+
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError> {
+        let devices = self.host.output_devices().map_err(failed(LISTING))?;
+        let device = find_device(devices, name)?;
+        Ok(Box::new(CpalDevice {
+            device,
+            name: name.to_owned(),
+        }))
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        let ids = stable_ids::by_name();
+        let mut devices: Vec<DeviceInfo> = Vec::new();
+        for (is_input, list) in [
+            (
+                true,
+                self.host
+                    .input_devices()
+                    .map_err(failed(LISTING))?
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                false,
+                self.host
+                    .output_devices()
+                    .map_err(failed(LISTING))?
+                    .collect(),
+            ),
+        ] {
+            for device in list {
+                let Ok(name) = device.name() else { continue };
+                match devices.iter_mut().find(|info| info.name == name) {
+                    Some(info) => info.is_output |= !is_input,
+                    None => devices.push(DeviceInfo {
+                        stable_id: ids
+                            .iter()
+                            .find(|(device, _)| *device == name)
+                            .map(|(_, id)| id.clone()),
+                        name,
+                        is_input,
+                        is_output: !is_input,
+                    }),
+                }
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/// The identifiers the platform keeps for its devices, by name, since cpal only exposes names.
+#[cfg(target_os = "macos")]
+mod stable_ids {
+    use std::ffi::{c_char, c_void};
+    use std::{mem, ptr};
+
+    type AudioObjectId = u32;
+    type CFStringRef = *const c_void;
+
+    #[repr(C)]
+    struct PropertyAddress {
+        selector: u32,
+        scope: u32,
+        element: u32,
+    }
+
+    const SYSTEM_OBJECT: AudioObjectId = 1;
+    const SCOPE_GLOBAL: u32 = u32::from_be_bytes(*b"glob");
+    const ELEMENT_MAIN: u32 = 0;
+    const HARDWARE_DEVICES: u32 = u32::from_be_bytes(*b"dev#");
+    /// The name cpal goes by.
+    const DEVICE_NAME: u32 = u32::from_be_bytes(*b"lnam");
+    const DEVICE_UID: u32 = u32::from_be_bytes(*b"uid ");
+    const UTF8: u32 = 0x0800_0100;
+
+    #[link(name = "CoreAudio", kind = "framework")]
+    extern "C" {
+        fn AudioObjectGetPropertyDataSize(
+            object: AudioObjectId,
+            address: *const PropertyAddress,
+            qualifier_size: u32,
+            qualifier: *const c_void,
+            size: *mut u32,
+        ) -> i32;
+        fn AudioObjectGetPropertyData(
+            object: AudioObjectId,
+            address: *const PropertyAddress,
+            qualifier_size: u32,
+            qualifier: *const c_void,
+            size: *mut u32,
+            data: *mut c_void,
+        ) -> i32;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFStringGetLength(string: CFStringRef) -> isize;
+        fn CFStringGetMaximumSizeForEncoding(length: isize, encoding: u32) -> isize;
+        fn CFStringGetCString(
+            string: CFStringRef,
+            buffer: *mut c_char,
+            size: isize,
+            encoding: u32,
+        ) -> bool;
+        fn CFRelease(object: *const c_void);
+    }
+
+    fn address(selector: u32) -> PropertyAddress {
+        PropertyAddress {
+            selector,
+            scope: SCOPE_GLOBAL,
+            element: ELEMENT_MAIN,
+        }
+    }
+
+    /// Every device's CoreAudio UID, by name, leaving out any it won't say.
+    pub fn by_name() -> Vec<(String, String)> {
+        let devices = address(HARDWARE_DEVICES);
+        let mut size = 0;
+        // SAFETY: the address outlives the calls, and `ids` is as big as CoreAudio said to make it.
+        let ids = unsafe {
+            if AudioObjectGetPropertyDataSize(SYSTEM_OBJECT, &devices, 0, ptr::null(), &mut size)
+                != 0
+            {
+                return Vec::new();
+            }
+            let mut ids = vec![0 as AudioObjectId; size as usize / mem::size_of::<AudioObjectId>()];
+            if AudioObjectGetPropertyData(
+                SYSTEM_OBJECT,
+                &devices,
+                0,
+                ptr::null(),
+                &mut size,
+                ids.as_mut_ptr().cast(),
+            ) != 0
+            {
+                return Vec::new();
+            }
+            ids.truncate(size as usize / mem::size_of::<AudioObjectId>());
+            ids
+        };
+        ids.into_iter()
+            .filter_map(|id| Some((string(id, DEVICE_NAME)?, string(id, DEVICE_UID)?)))
+            .collect()
+    }
+
+    /// A device's string property, which CoreAudio hands over for the caller to release.
+    fn string(device: AudioObjectId, selector: u32) -> Option<String> {
+        let address = address(selector);
+        let mut value: CFStringRef = ptr::null();
+        let mut size = mem::size_of::<CFStringRef>() as u32;
+        // SAFETY: `value` has room for the one CFStringRef asked for, which is released once
+        // copied out of.
+        unsafe {
+            let status = AudioObjectGetPropertyData(
+                device,
+                &address,
+                0,
+                ptr::null(),
+                &mut size,
+                (&mut value as *mut CFStringRef).cast(),
+            );
+            if status != 0 || value.is_null() {
+                return None;
+            }
+            let capacity =
+                CFStringGetMaximumSizeForEncoding(CFStringGetLength(value), UTF8) as usize + 1;
+            let mut buffer = vec![0 as c_char; capacity];
+            let copied = CFStringGetCString(value, buffer.as_mut_ptr(), capacity as isize, UTF8);
+            CFRelease(value);
+            copied.then(|| {
+                std::ffi::CStr::from_ptr(buffer.as_ptr())
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        }
+    }
+}
+
+/// cpal only exposes names on the other platforms, not the IDs they keep.
+#[cfg(not(target_os = "macos"))]
+mod stable_ids {
+    pub fn by_name() -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+fn find_device(
+    mut devices: impl Iterator<Item = cpal::Device>,
+    wanted: &str,
+) -> Result<cpal::Device, BackendError> {
+    devices
+        .find(|device| device.name().map(|name| name == wanted).unwrap_or(false))
+        .ok_or_else(|| BackendError::DeviceNotFound(wanted.to_owned()))
+}
+
+const LISTING: &str = "couldn't list the devices";
+
+/// Wraps one of cpal's errors, saying `what` couldn't be done.
+fn failed<E: Into<BoxError>>(what: &'static str) -> impl FnOnce(E) -> BackendError {
+    move |source| BackendError::caused(what, source)
+}
+
+fn f32_ranges(configs: impl Iterator<Item = cpal::SupportedStreamConfigRange>) -> Vec<ConfigRange> {
+    configs
+        .filter(|config| config.sample_format() == cpal::SampleFormat::F32)
+        .map(|config| ConfigRange {
+            channels: config.channels(),
+            min_sample_rate: config.min_sample_rate().0,
+            max_sample_rate: config.max_sample_rate().0,
+        })
+        .collect()
+}
+
+struct CpalDevice {
+    device: cpal::Device,
+    name: String,
+}
+
+impl InputSource for CpalDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn default_config(&self) -> Result<StreamConfig, BackendError> {
+        Ok(self
+            .device
+            .default_input_config()
+            .map_err(failed("couldn't get the default config"))?
+            .into())
+    }
+
+    fn supported_configs(&self) -> Result<Vec<ConfigRange>, BackendError> {
+        Ok(f32_ranges(
+            self.device
+                .supported_input_configs()
+                .map_err(failed("couldn't get the supported configs"))?,
+        ))
+    }
+
+    fn build_input_stream(
+        &self,
+        config: &StreamConfig,
+        mut on_data: InputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        let stream = self
+            .device
+            .build_input_stream(
+                config,
+                move |data: &[f32], _: &cpal::InputCallbackInfo| on_data(data),
+                on_error,
+            )
+            .map_err(failed("couldn't build the input stream"))?;
+        Ok(Box::new(CpalStream(stream)))
+    }
+}
+
+impl OutputSink for CpalDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn default_config(&self) -> Result<StreamConfig, BackendError> {
+        Ok(self
+            .device
+            .default_output_config()
+            .map_err(failed("couldn't get the default config"))?
+            .into())
+    }
+
+    fn supported_configs(&self) -> Result<Vec<ConfigRange>, BackendError> {
+        Ok(f32_ranges(
+            self.device
+                .supported_output_configs()
+                .map_err(failed("couldn't get the supported configs"))?,
+        ))
+    }
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        mut on_data: OutputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        let stream = self
+            .device
+            .build_output_stream(
+                config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| on_data(data),
+                on_error,
+            )
+            .map_err(failed("couldn't build the output stream"))?;
+        Ok(Box::new(CpalStream(stream)))
+    }
+}
+
+struct CpalStream(cpal::Stream);
+
+impl Stream for CpalStream {
+    fn play(&self) -> Result<(), BackendError> {
+        self.0.play().map_err(failed("couldn't start the stream"))
+    }
+
+    fn pause(&self) -> Result<(), BackendError> {
+        self.0.pause().map_err(failed("couldn't pause the stream"))
+    }
+}
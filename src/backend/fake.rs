@@ -0,0 +1,516 @@
+//! Synthetic devices for exercising the pipeline without any audio hardware.
+//!
+//! Nothing happens in the background: callbacks are only delivered when [`FakeProvider::advance`]
+//! is called, one period at a time. In each period every playing input delivers one buffer, in the
+//! order the inputs were added, and then every playing output is asked to fill one buffer, which
+//! is kept so it can be inspected with [`FakeProvider::take_output`].
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{
+    BackendError, DeviceInfo, DeviceProvider, ErrorCallback, InputCallback, InputSource,
+    OutputCallback, OutputSink, Stream, StreamConfig, StreamError,
+};
+
+/// The number of frames per period when a device's config doesn't fix one.
+pub const DEFAULT_PERIOD_FRAMES: u32 = 512;
+
+/// What a fake input captures.
+#[derive(Clone, Debug)]
+pub enum Signal {
+    Silence,
+    /// The same sine on every channel.
+    Sine {
+        frequency: f32,
+        amplitude: f32,
+    },
+    /// Interleaved samples, followed by silence once they run out.
+    Samples(Arc<[f32]>),
+}
+
+impl Signal {
+    fn sample(&self, frame: u64, channel: usize, config: &StreamConfig) -> f32 {
+        match self {
+            Signal::Silence => 0.0,
+            Signal::Sine {
+                frequency,
+                amplitude,
+            } => {
+                let t = frame as f64 / config.sample_rate.0 as f64;
+                amplitude * (t * *frequency as f64 * std::f64::consts::TAU).sin() as f32
+            }
+            Signal::Samples(samples) => {
+                let index = frame as usize * config.channels as usize + channel;
+                samples.get(index).copied().unwrap_or(0.0)
+            }
+        }
+    }
+}
+
+/// Where a fake device fails, for exercising how the pipeline copes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Asking for its config.
+    Query,
+    /// Building a stream on it.
+    Build,
+    /// Starting a stream on it.
+    Start,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Input,
+    Output,
+}
+
+struct Device {
+    name: String,
+    direction: Direction,
+    config: StreamConfig,
+    signal: Signal,
+    connected: bool,
+    /// Periods left for which the device delivers no callbacks.
+    stalled: u64,
+    /// How far into `signal` the device has got, in samples.
+    samples: u64,
+    /// The length in samples of each callback in a period, when it isn't one period's worth.
+    buffers: Option<Vec<usize>>,
+    captured: Vec<f32>,
+    /// Where the device fails, and what the backend says when it does.
+    failure: Option<(Failure, String)>,
+    stable_id: Option<String>,
+}
+
+impl Device {
+    fn check(&self, at: Failure) -> Result<(), BackendError> {
+        match &self.failure {
+            Some((failure, message)) if *failure == at => Err(BackendError::failed(message)),
+            _ => Ok(()),
+        }
+    }
+}
+
+enum Callback {
+    Input(InputCallback),
+    Output(OutputCallback),
+}
+
+struct Slot {
+    id: u64,
+    device: usize,
+    playing: bool,
+    on_data: Callback,
+    on_error: ErrorCallback,
+}
+
+#[derive(Default)]
+struct State {
+    devices: Vec<Device>,
+    streams: Vec<Slot>,
+    next_stream: u64,
+    periods: u64,
+}
+
+/// A set of synthetic devices sharing one deterministic clock.
+///
+/// Clones share the same devices, so one clone can be handed to the pipeline while another drives
+/// the clock.
+#[derive(Clone, Default)]
+pub struct FakeProvider {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_input(&self, name: &str, config: StreamConfig, signal: Signal) -> &Self {
+        self.add_device(name, Direction::Input, config, signal)
+    }
+
+    pub fn add_output(&self, name: &str, config: StreamConfig) -> &Self {
+        self.add_device(name, Direction::Output, config, Signal::Silence)
+    }
+
+    fn add_device(
+        &self,
+        name: &str,
+        direction: Direction,
+        config: StreamConfig,
+        signal: Signal,
+    ) -> &Self {
+        self.lock().devices.push(Device {
+            name: name.to_owned(),
+            direction,
+            config,
+            signal,
+            connected: true,
+            stalled: 0,
+            samples: 0,
+            buffers: None,
+            captured: Vec::new(),
+            failure: None,
+            stable_id: None,
+        });
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A panicking callback shouldn't make every later assertion about the devices panic too.
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Delivers `periods` periods of callbacks.
+    pub fn advance(&self, periods: u64) {
+        let mut state = self.lock();
+        for _ in 0..periods {
+            let State {
+                devices, streams, ..
+            } = &mut *state;
+
+            // Streams are built in whatever order the pipeline likes, but inputs always deliver
+            // before outputs so a period behaves like one round trip through the ring buffers.
+            for direction in [Direction::Input, Direction::Output] {
+                for (index, device) in devices.iter_mut().enumerate() {
+                    if device.direction != direction || !device.connected {
+                        continue;
+                    }
+                    if device.stalled > 0 {
+                        device.stalled -= 1;
+                        continue;
+                    }
+
+                    let channels = device.config.channels as usize;
+                    let lengths = match &device.buffers {
+                        Some(lengths) => lengths.clone(),
+                        None => vec![period_frames(&device.config) as usize * channels],
+                    };
+                    let mut buffer = vec![0.0; lengths.iter().sum()];
+                    let mut delivered = false;
+                    for slot in streams
+                        .iter_mut()
+                        .filter(|slot| slot.device == index && slot.playing)
+                    {
+                        delivered = true;
+                        match &mut slot.on_data {
+                            Callback::Input(on_data) => {
+                                for (i, sample) in buffer.iter_mut().enumerate() {
+                                    let position = device.samples + i as u64;
+                                    *sample = device.signal.sample(
+                                        position / channels as u64,
+                                        position as usize % channels,
+                                        &device.config,
+                                    );
+                                }
+                                let mut start = 0;
+                                for &length in &lengths {
+                                    on_data(&buffer[start..start + length]);
+                                    start += length;
+                                }
+                            }
+                            Callback::Output(on_data) => {
+                                buffer.iter_mut().for_each(|sample| *sample = 0.0);
+                                let mut start = 0;
+                                for &length in &lengths {
+                                    on_data(&mut buffer[start..start + length]);
+                                    start += length;
+                                }
+                                device.captured.extend_from_slice(&buffer);
+                            }
+                        }
+                    }
+                    if delivered {
+                        device.samples += buffer.len() as u64;
+                    }
+                }
+            }
+
+            state.periods += 1;
+        }
+    }
+
+    /// The number of periods delivered so far.
+    pub fn periods(&self) -> u64 {
+        self.lock().periods
+    }
+
+    /// Has the device deliver a callback of each of `lengths` samples every period, instead of one
+    /// of a period's frames, as backends with odd buffer sizes do. The lengths can be empty, bigger
+    /// than the config fixes, or not whole frames, and the device carries on mid-frame after one
+    /// that isn't.
+    pub fn set_buffers(&self, name: &str, lengths: &[usize]) {
+        if let Some(device) = self.lock().devices.iter_mut().find(|d| d.name == name) {
+            device.buffers = Some(lengths.to_vec());
+        }
+    }
+
+    /// Skips the device's callbacks for the next `periods` periods, as if it had stalled.
+    pub fn stall(&self, name: &str, periods: u64) {
+        if let Some(device) = self.lock().devices.iter_mut().find(|d| d.name == name) {
+            device.stalled = periods;
+        }
+    }
+
+    /// Unplugs the device: its streams report `DeviceNotAvailable` and never deliver again, and it
+    /// can't be found until it is reconnected.
+    pub fn disconnect(&self, name: &str) {
+        let mut state = self.lock();
+        let State {
+            devices, streams, ..
+        } = &mut *state;
+        for (index, device) in devices.iter_mut().enumerate() {
+            if device.name != name {
+                continue;
+            }
+            device.connected = false;
+            for slot in streams.iter_mut().filter(|slot| slot.device == index) {
+                slot.playing = false;
+                (slot.on_error)(StreamError::DeviceNotAvailable);
+            }
+            // Whatever was built before the device went away stays dead.
+            streams.retain(|slot| slot.device != index);
+        }
+    }
+
+    /// Has the device fail `at` that from now on, with `message` as what the backend said.
+    pub fn fail(&self, name: &str, at: Failure, message: &str) {
+        if let Some(device) = self.lock().devices.iter_mut().find(|d| d.name == name) {
+            device.failure = Some((at, message.to_owned()));
+        }
+    }
+
+    /// Gives the device an identifier that survives renames, as backends like CoreAudio do.
+    pub fn set_stable_id(&self, name: &str, id: &str) {
+        for device in self.lock().devices.iter_mut().filter(|d| d.name == name) {
+            device.stable_id = Some(id.to_owned());
+        }
+    }
+
+    /// Renames the device, keeping its stable ID, as a firmware update or a change of language
+    /// can.
+    pub fn rename(&self, name: &str, new_name: &str) {
+        for device in self.lock().devices.iter_mut().filter(|d| d.name == name) {
+            device.name = new_name.to_owned();
+        }
+    }
+
+    /// Plugs a disconnected device back in, so new streams can be built on it.
+    pub fn reconnect(&self, name: &str) {
+        if let Some(device) = self.lock().devices.iter_mut().find(|d| d.name == name) {
+            device.connected = true;
+        }
+    }
+
+    /// The streams built on the device that are playing, by an id no later stream reuses.
+    pub fn playing_streams(&self, name: &str) -> Vec<u64> {
+        let state = self.lock();
+        state
+            .streams
+            .iter()
+            .filter(|slot| slot.playing && state.devices[slot.device].name == name)
+            .map(|slot| slot.id)
+            .collect()
+    }
+
+    /// Takes everything the output has played so far.
+    pub fn take_output(&self, name: &str) -> Vec<f32> {
+        self.lock()
+            .devices
+            .iter_mut()
+            .find(|device| device.name == name && device.direction == Direction::Output)
+            .map(|device| std::mem::take(&mut device.captured))
+            .unwrap_or_default()
+    }
+
+    fn find(&self, name: &str, direction: Direction) -> Result<FakeDevice, BackendError> {
+        let state = self.lock();
+        let index = state
+            .devices
+            .iter()
+            .position(|device| {
+                device.name == name && device.direction == direction && device.connected
+            })
+            .ok_or_else(|| BackendError::DeviceNotFound(name.to_owned()))?;
+        Ok(FakeDevice {
+            provider: self.clone(),
+            index,
+            name: name.to_owned(),
+        })
+    }
+
+    fn build_stream(
+        &self,
+        index: usize,
+        config: &StreamConfig,
+        on_data: Callback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        let mut state = self.lock();
+        let device = &state.devices[index];
+        if !device.connected {
+            return Err(BackendError::DeviceGone(device.name.clone()));
+        }
+        device.check(Failure::Build)?;
+        if config.channels != device.config.channels
+            || config.sample_rate != device.config.sample_rate
+        {
+            return Err(BackendError::failed(format!(
+                "device \"{}\" doesn't support `{:?}`, only `{:?}`",
+                device.name, config, device.config
+            )));
+        }
+
+        let id = state.next_stream;
+        state.next_stream += 1;
+        state.streams.push(Slot {
+            id,
+            device: index,
+            playing: false,
+            on_data,
+            on_error,
+        });
+        Ok(Box::new(FakeStream {
+            provider: self.clone(),
+            id,
+        }))
+    }
+}
+
+fn period_frames(config: &StreamConfig) -> u32 {
+    match config.buffer_size {
+        cpal::BufferSize::Fixed(frames) => frames,
+        cpal::BufferSize::Default => DEFAULT_PERIOD_FRAMES,
+    }
+}
+
+impl DeviceProvider for FakeProvider {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError> {
+        Ok(Box::new(self.find(name, Direction::Input)?))
+    }
+
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError> {
+        Ok(Box::new(self.find(name, Direction::Output)?))
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        let state = self.lock();
+        let mut devices: Vec<DeviceInfo> = Vec::new();
+        for direction in [Direction::Input, Direction::Output] {
+            for device in &state.devices {
+                if device.direction != direction || !device.connected {
+                    continue;
+                }
+                let is_input = direction == Direction::Input;
+                match devices.iter_mut().find(|info| info.name == device.name) {
+                    Some(info) => info.is_output |= !is_input,
+                    None => devices.push(DeviceInfo {
+                        name: device.name.clone(),
+                        is_input,
+                        is_output: !is_input,
+                        stable_id: device.stable_id.clone(),
+                    }),
+                }
+            }
+        }
+        Ok(devices)
+    }
+}
+
+struct FakeDevice {
+    provider: FakeProvider,
+    index: usize,
+    name: String,
+}
+
+impl FakeDevice {
+    fn config(&self) -> Result<StreamConfig, BackendError> {
+        let state = self.provider.lock();
+        let device = &state.devices[self.index];
+        device.check(Failure::Query)?;
+        Ok(device.config.clone())
+    }
+}
+
+impl InputSource for FakeDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn default_config(&self) -> Result<StreamConfig, BackendError> {
+        self.config()
+    }
+
+    fn build_input_stream(
+        &self,
+        config: &StreamConfig,
+        on_data: InputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        self.provider
+            .build_stream(self.index, config, Callback::Input(on_data), on_error)
+    }
+}
+
+impl OutputSink for FakeDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn default_config(&self) -> Result<StreamConfig, BackendError> {
+        self.config()
+    }
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        on_data: OutputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        self.provider
+            .build_stream(self.index, config, Callback::Output(on_data), on_error)
+    }
+}
+
+struct FakeStream {
+    provider: FakeProvider,
+    id: u64,
+}
+
+impl FakeStream {
+    fn set_playing(&self, playing: bool) -> Result<(), BackendError> {
+        let mut state = self.provider.lock();
+        let State {
+            devices, streams, ..
+        } = &mut *state;
+        let slot = streams
+            .iter_mut()
+            .find(|slot| slot.id == self.id)
+            .ok_or_else(|| BackendError::failed("the stream's device is no longer available"))?;
+        if playing {
+            devices[slot.device].check(Failure::Start)?;
+        }
+        slot.playing = playing;
+        Ok(())
+    }
+}
+
+impl Stream for FakeStream {
+    fn play(&self) -> Result<(), BackendError> {
+        self.set_playing(true)
+    }
+
+    fn pause(&self) -> Result<(), BackendError> {
+        self.set_playing(false)
+    }
+}
+
+impl Drop for FakeStream {
+    fn drop(&mut self) {
+        self.provider
+            .lock()
+            .streams
+            .retain(|slot| slot.id != self.id);
+    }
+}
@@ -0,0 +1,258 @@
+//! An output that plays to nowhere, asking for audio on a software clock instead of a device's.
+//!
+//! This runs the pipeline headless, for processing and recording without any output device.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::{
+    BackendError, DeviceInfo, DeviceProvider, ErrorCallback, InputSource, OutputCallback,
+    OutputSink, Stream, StreamConfig,
+};
+use crate::negotiate::ConfigRange;
+
+/// The output name that picks the null sink.
+pub const NAME: &str = "null";
+
+/// The number of frames per callback when the config doesn't fix one.
+const DEFAULT_PERIOD_FRAMES: u32 = 512;
+/// How far behind the clock can fall, as after the machine sleeps, before it starts over from now
+/// rather than catching up all at once.
+const MAX_BEHIND: Duration = Duration::from_secs(1);
+
+/// Looks devices up in the wrapped provider, except for an output called [`NAME`], which is a
+/// [`NullSink`].
+pub struct WithNullOutput<P>(pub P);
+
+impl<P: DeviceProvider> DeviceProvider for WithNullOutput<P> {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError> {
+        self.0.input_device(name)
+    }
+
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError> {
+        if name == NAME {
+            return Ok(Box::new(NullSink));
+        }
+        self.0.output_device(name)
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        self.0.devices()
+    }
+}
+
+/// Supports any channel count and rate, and throws away whatever it's given.
+pub struct NullSink;
+
+impl OutputSink for NullSink {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn default_config(&self) -> Result<StreamConfig, BackendError> {
+        Ok(StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Default,
+        })
+    }
+
+    fn supported_configs(&self) -> Result<Vec<ConfigRange>, BackendError> {
+        Ok((1..=64)
+            .map(|channels| ConfigRange {
+                channels,
+                min_sample_rate: 8_000,
+                max_sample_rate: 384_000,
+            })
+            .collect())
+    }
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        mut on_data: OutputCallback,
+        _on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        let frames = match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => frames,
+            cpal::BufferSize::Default => DEFAULT_PERIOD_FRAMES,
+        };
+        let rate = config.sample_rate.0 as f64;
+        let period = Duration::from_secs_f64(frames as f64 / rate);
+        let mut buffer = vec![0.0; frames as usize * config.channels as usize];
+
+        let playing = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("null output".to_owned())
+            .spawn({
+                let playing = Arc::clone(&playing);
+                let stop = Arc::clone(&stop);
+                move || {
+                    // Every deadline comes from when the clock started, so sleeping late never
+                    // adds up into drift.
+                    let mut started = None;
+                    let mut periods = 0u64;
+                    while !stop.load(Ordering::Relaxed) {
+                        if !playing.load(Ordering::Relaxed) {
+                            started = None;
+                            std::thread::sleep(period);
+                            continue;
+                        }
+                        let now = Instant::now();
+                        let start = *started.get_or_insert_with(|| {
+                            periods = 0;
+                            now
+                        });
+                        let deadline = start
+                            + Duration::from_secs_f64((periods * frames as u64) as f64 / rate);
+                        if deadline > now {
+                            std::thread::sleep(deadline - now);
+                        } else if now - deadline > MAX_BEHIND {
+                            started = None;
+                            continue;
+                        }
+                        on_data(&mut buffer);
+                        periods += 1;
+                    }
+                }
+            })
+            .map_err(|source| {
+                BackendError::caused("couldn't start the null output's thread", source)
+            })?;
+        Ok(Box::new(NullStream {
+            playing,
+            stop,
+            thread: Some(thread),
+        }))
+    }
+}
+
+struct NullStream {
+    playing: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Stream for NullStream {
+    fn play(&self) -> Result<(), BackendError> {
+        self.playing.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<(), BackendError> {
+        self.playing.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for NullStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::FakeProvider;
+    use std::sync::atomic::AtomicU64;
+
+    /// Plays a null stream of `frames` frames per callback at `rate` for `duration`, returning
+    /// how many callbacks there were and how many samples each one was given.
+    fn play_for(frames: u32, rate: u32, duration: Duration, stall_every: u64) -> (u64, Vec<usize>) {
+        let calls = Arc::new(AtomicU64::new(0));
+        let lengths = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(rate),
+            buffer_size: cpal::BufferSize::Fixed(frames),
+        };
+        let stream = NullSink
+            .build_output_stream(
+                &config,
+                Box::new({
+                    let calls = Arc::clone(&calls);
+                    let lengths = Arc::clone(&lengths);
+                    move |buffer: &mut [f32]| {
+                        let call = calls.fetch_add(1, Ordering::Relaxed);
+                        lengths.lock().unwrap().push(buffer.len());
+                        if stall_every > 0 && call % stall_every == stall_every - 1 {
+                            std::thread::sleep(Duration::from_millis(15));
+                        }
+                    }
+                }),
+                Box::new(|_| {}),
+            )
+            .unwrap();
+        stream.play().unwrap();
+        std::thread::sleep(duration);
+        drop(stream);
+        let lengths = lengths.lock().unwrap().clone();
+        (calls.load(Ordering::Relaxed), lengths)
+    }
+
+    #[test]
+    fn asks_for_audio_at_the_configured_rate() {
+        // 100 periods a second, for a second, plus the one asked for right away.
+        let (calls, lengths) = play_for(480, 48_000, Duration::from_secs(1), 0);
+        assert!((95..=102).contains(&calls), "{} callbacks", calls);
+        assert!(lengths.iter().all(|&length| length == 960));
+    }
+
+    #[test]
+    fn catches_up_after_a_slow_callback_instead_of_drifting() {
+        // Every tenth callback takes longer than a period, which sleeping a period at a time
+        // would never make up.
+        let (calls, _) = play_for(480, 48_000, Duration::from_secs(1), 10);
+        assert!((95..=102).contains(&calls), "{} callbacks", calls);
+    }
+
+    #[test]
+    fn asks_for_nothing_until_played_or_once_paused() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let config = StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(48),
+        };
+        let stream = NullSink
+            .build_output_stream(
+                &config,
+                Box::new({
+                    let calls = Arc::clone(&calls);
+                    move |_: &mut [f32]| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                    }
+                }),
+                Box::new(|_| {}),
+            )
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        stream.play().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        stream.pause().unwrap();
+        // Lets a callback already under way finish.
+        std::thread::sleep(Duration::from_millis(10));
+        let paused = calls.load(Ordering::Relaxed);
+        assert!(paused > 0);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(calls.load(Ordering::Relaxed), paused);
+    }
+
+    #[test]
+    fn only_takes_the_output_called_null() {
+        let provider = WithNullOutput(FakeProvider::new());
+        assert_eq!(provider.output_device(NAME).unwrap().name(), NAME);
+        assert!(provider.output_device("Speakers").is_err());
+    }
+}
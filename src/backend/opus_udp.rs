@@ -0,0 +1,539 @@
+//! An output that streams the mix over UDP as Opus, for sending it to a remote co-host at a
+//! fraction of the bandwidth raw samples would take.
+//!
+//! It runs on the same software clock as the [null sink](super::null), whose thread only copies
+//! what was mixed into a ring buffer. A thread of its own then resamples that to 48 kHz if Opus
+//! can't take the pipeline's rate, cuts it into 20 ms frames, encodes them and sends each one as
+//! soon as it's encoded, so the packets go out at the pace the audio is mixed and the encoder
+//! never runs on the clock's thread.
+//!
+//! Each packet is a big-endian `u32` sequence number, counting up from 0, followed by one Opus
+//! packet.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use ringbuf::HeapRb;
+
+use super::{
+    null::NullSink, BackendError, DeviceInfo, DeviceProvider, ErrorCallback, InputSource,
+    OutputCallback, OutputSink, Stream, StreamConfig,
+};
+use crate::error::{self, parse_error, ParseError};
+use crate::negotiate::ConfigRange;
+use crate::pipeline::ms_to_frames;
+use crate::resample::{self, Quality, Resampler};
+
+/// What output names that pick an Opus stream start with.
+pub const PREFIX: &str = "opus-udp:";
+
+const DEFAULT_BITRATE: u32 = 64_000;
+const FRAME_MS: u32 = 20;
+/// The rates Opus encodes at. Anything else is resampled to 48 kHz first.
+const OPUS_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+/// The 44.1 kHz family, which can be resampled to 48 kHz.
+const RESAMPLED_RATES: [u32; 4] = [22_050, 44_100, 88_200, 176_400];
+/// How much mixed audio can wait for the network thread. Whole callbacks are dropped beyond that.
+const QUEUE_MS: f32 = 500.0;
+/// The most an Opus packet can take, as libopus recommends allowing for.
+const MAX_PACKET_BYTES: usize = 4_000;
+/// How long the network thread waits for more audio when there's none.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Where an Opus stream goes, and how many bits a second it takes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpusTarget {
+    /// A host and port, like `example.com:5004`.
+    pub address: String,
+    pub bitrate: u32,
+}
+
+impl OpusTarget {
+    /// Parses what follows [`PREFIX`], like `example.com:5004` or `10.0.0.2:5004:bitrate=96k`.
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let (address, bitrate) = match value.rsplit_once(':') {
+            Some((address, option)) if option.starts_with("bitrate=") => {
+                (address, parse_bitrate(&option["bitrate=".len()..])?)
+            }
+            _ => (value, DEFAULT_BITRATE),
+        };
+        let valid = address
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            return Err(parse_error!(
+                "expected a host and port like `example.com:5004`, optionally followed by \
+                 `:bitrate=64k`, got `{}`",
+                value
+            ));
+        }
+        Ok(OpusTarget {
+            address: address.to_owned(),
+            bitrate,
+        })
+    }
+}
+
+/// Parses a bitrate like `64k` or `64000`, within what Opus can do.
+fn parse_bitrate(value: &str) -> Result<u32, ParseError> {
+    let bitrate = match value.strip_suffix('k') {
+        Some(kilobits) => kilobits
+            .parse::<u32>()
+            .ok()
+            .and_then(|k| k.checked_mul(1_000)),
+        None => value.parse().ok(),
+    }
+    .ok_or_else(|| parse_error!("expected a bitrate like `64k`, got `{}`", value))?;
+    if !(6_000..=510_000).contains(&bitrate) {
+        return Err(parse_error!(
+            "Opus takes bitrates from 6k to 510k, not {}k",
+            bitrate / 1_000
+        ));
+    }
+    Ok(bitrate)
+}
+
+/// Looks devices up in the wrapped provider, except for outputs named with [`PREFIX`], which
+/// are [`OpusSink`]s.
+pub struct WithOpusOutput<P>(pub P);
+
+impl<P: DeviceProvider> DeviceProvider for WithOpusOutput<P> {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError> {
+        self.0.input_device(name)
+    }
+
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError> {
+        match name.strip_prefix(PREFIX) {
+            Some(target) => Ok(Box::new(OpusSink {
+                name: name.to_owned(),
+                target: OpusTarget::parse(target).map_err(|err| {
+                    BackendError::caused(format!("\"{}\" isn't an Opus output", name), err)
+                })?,
+            })),
+            None => self.0.output_device(name),
+        }
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        self.0.devices()
+    }
+}
+
+/// Mono or stereo at any rate Opus takes or that can be resampled to 48 kHz.
+pub struct OpusSink {
+    name: String,
+    target: OpusTarget,
+}
+
+impl OutputSink for OpusSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn default_config(&self) -> Result<StreamConfig, BackendError> {
+        Ok(StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Default,
+        })
+    }
+
+    fn supported_configs(&self) -> Result<Vec<ConfigRange>, BackendError> {
+        Ok([1, 2]
+            .into_iter()
+            .flat_map(|channels| {
+                OPUS_RATES
+                    .into_iter()
+                    .chain(RESAMPLED_RATES)
+                    .map(move |rate| ConfigRange {
+                        channels,
+                        min_sample_rate: rate,
+                        max_sample_rate: rate,
+                    })
+            })
+            .collect())
+    }
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        mut on_data: OutputCallback,
+        on_error: ErrorCallback,
+    ) -> Result<Box<dyn Stream>, BackendError> {
+        let mut packetizer = Packetizer::new(
+            config.channels as usize,
+            config.sample_rate.0,
+            self.target.bitrate,
+        )?;
+        let socket = connect(&self.target.address)?;
+
+        let queue_samples = ms_to_frames(QUEUE_MS, config.sample_rate.0) * config.channels as usize;
+        let (mut producer, mut consumer) = HeapRb::<f32>::new(queue_samples).split();
+        let clock = NullSink.build_output_stream(
+            config,
+            Box::new(move |data: &mut [f32]| {
+                on_data(data);
+                // Only whole callbacks, so the network thread never sees part of a frame.
+                if producer.free_len() >= data.len() {
+                    producer.push_slice(data);
+                }
+            }),
+            on_error,
+        )?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let address = self.target.address.clone();
+        let thread = std::thread::Builder::new()
+            .name("opus output".to_owned())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || {
+                    let mut samples = vec![0.0; queue_samples];
+                    let mut warned = false;
+                    while !stop.load(Ordering::Relaxed) {
+                        let popped = consumer.pop_slice(&mut samples);
+                        if popped == 0 {
+                            std::thread::sleep(POLL_INTERVAL);
+                            continue;
+                        }
+                        let sent = packetizer.push(&samples[..popped], |packet| {
+                            // Nothing listening yet is no reason to stop sending.
+                            if let Err(err) = socket.send(packet) {
+                                if !warned {
+                                    log::warn!("couldn't send Opus to {}: {}", address, err);
+                                    warned = true;
+                                }
+                            }
+                        });
+                        if let Err(err) = sent {
+                            log::error!(
+                                "stopped streaming Opus to {}: {}",
+                                address,
+                                error::with_causes(&err)
+                            );
+                            break;
+                        }
+                    }
+                }
+            })
+            .map_err(|source| BackendError::caused("couldn't start the Opus thread", source))?;
+        Ok(Box::new(OpusStream {
+            clock: Some(clock),
+            stop,
+            thread: Some(thread),
+        }))
+    }
+}
+
+/// A UDP socket that sends to `address`.
+fn connect(address: &str) -> Result<UdpSocket, BackendError> {
+    let remote = address
+        .to_socket_addrs()
+        .map_err(|source| BackendError::caused(format!("couldn't resolve {}", address), source))?
+        .next()
+        .ok_or_else(|| BackendError::failed(format!("{} resolves to no addresses", address)))?;
+    let local: SocketAddr = match remote {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(local)
+        .map_err(|source| BackendError::caused("couldn't open a UDP socket", source))?;
+    socket
+        .connect(remote)
+        .map_err(|source| BackendError::caused(format!("couldn't send to {}", address), source))?;
+    Ok(socket)
+}
+
+struct OpusStream {
+    clock: Option<Box<dyn Stream>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Stream for OpusStream {
+    fn play(&self) -> Result<(), BackendError> {
+        self.clock.as_ref().unwrap().play()
+    }
+
+    fn pause(&self) -> Result<(), BackendError> {
+        self.clock.as_ref().unwrap().pause()
+    }
+}
+
+impl Drop for OpusStream {
+    fn drop(&mut self) {
+        // The clock goes first, so nothing more is queued while the network thread finishes.
+        self.clock = None;
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Turns interleaved audio into numbered Opus packets, resampling it to 48 kHz first if Opus
+/// can't take its rate.
+pub struct Packetizer {
+    channels: usize,
+    resampler: Option<Resampler>,
+    resampled: Vec<f32>,
+    encoder: opus::Encoder,
+    rate: u32,
+    /// The frame being filled, which is encoded once it holds 20 ms.
+    frame: Vec<f32>,
+    frame_samples: usize,
+    /// The sequence number, followed by room for the packet.
+    packet: Vec<u8>,
+    sequence: u32,
+}
+
+impl Packetizer {
+    pub fn new(channels: usize, sample_rate: u32, bitrate: u32) -> Result<Self, BackendError> {
+        let opus_channels = match channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => {
+                return Err(BackendError::failed(format!(
+                    "Opus streams are mono or stereo, not {} channels",
+                    channels
+                )))
+            }
+        };
+        let (rate, resampler) = if OPUS_RATES.contains(&sample_rate) {
+            (sample_rate, None)
+        } else if resample::supported(sample_rate, 48_000) {
+            let resampler = Resampler::new(sample_rate, 48_000, channels, Quality::default());
+            (48_000, resampler)
+        } else {
+            return Err(BackendError::failed(format!(
+                "Opus can't take {} Hz, and it can't be resampled to 48 kHz",
+                sample_rate
+            )));
+        };
+        let mut encoder = opus::Encoder::new(rate, opus_channels, opus::Application::Audio)
+            .map_err(|source| BackendError::caused("couldn't create the Opus encoder", source))?;
+        encoder
+            .set_bitrate(opus::Bitrate::Bits(bitrate as i32))
+            .map_err(|source| BackendError::caused("couldn't set the Opus bitrate", source))?;
+        let frame_samples = (rate * FRAME_MS / 1_000) as usize * channels;
+        Ok(Packetizer {
+            channels,
+            resampler,
+            resampled: Vec::new(),
+            encoder,
+            rate,
+            frame: Vec::with_capacity(frame_samples),
+            frame_samples,
+            packet: vec![0; 4 + MAX_PACKET_BYTES],
+            sequence: 0,
+        })
+    }
+
+    /// The rate the audio is encoded at, which a decoder of it should run at too.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Takes interleaved `samples`, handing every packet they complete to `send`.
+    pub fn push(
+        &mut self,
+        samples: &[f32],
+        mut send: impl FnMut(&[u8]),
+    ) -> Result<(), BackendError> {
+        let samples = match &mut self.resampler {
+            Some(resampler) => {
+                let frames = samples.len() / self.channels;
+                self.resampled
+                    .resize(resampler.max_output_frames(frames) * self.channels, 0.0);
+                let written = resampler.process(samples, &mut self.resampled);
+                &self.resampled[..written]
+            }
+            None => samples,
+        };
+        let mut rest = samples;
+        while !rest.is_empty() {
+            let (taken, after) =
+                rest.split_at((self.frame_samples - self.frame.len()).min(rest.len()));
+            self.frame.extend_from_slice(taken);
+            rest = after;
+            if self.frame.len() < self.frame_samples {
+                break;
+            }
+            self.packet[..4].copy_from_slice(&self.sequence.to_be_bytes());
+            let length = self
+                .encoder
+                .encode_float(&self.frame, &mut self.packet[4..])
+                .map_err(|source| BackendError::caused("couldn't encode Opus", source))?;
+            send(&self.packet[..4 + length]);
+            self.sequence = self.sequence.wrapping_add(1);
+            self.frame.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correlation::best_alignment;
+    use std::time::Instant;
+
+    /// A stereo mix of two tones, a different one on each side, at `rate`.
+    fn tones(frames: usize, rate: u32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let t = frame as f32 / rate as f32;
+                [
+                    (t * 440.0 * std::f32::consts::TAU).sin() * 0.4,
+                    (t * 660.0 * std::f32::consts::TAU).sin() * 0.4,
+                ]
+            })
+            .collect()
+    }
+
+    /// Decodes `packets` at `rate`, checking they're numbered in order from 0.
+    fn decode(packets: &[Vec<u8>], rate: u32) -> Vec<f32> {
+        let mut decoder = opus::Decoder::new(rate, opus::Channels::Stereo).unwrap();
+        let mut decoded = Vec::new();
+        let mut frame = vec![0.0; (rate * FRAME_MS / 1_000) as usize * 2];
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(
+                u32::from_be_bytes(packet[..4].try_into().unwrap()),
+                i as u32
+            );
+            let frames = decoder
+                .decode_float(&packet[4..], &mut frame, false)
+                .unwrap();
+            decoded.extend_from_slice(&frame[..frames * 2]);
+        }
+        decoded
+    }
+
+    /// How closely the left channel of `decoded` follows that of `original` at their best
+    /// alignment over half a second, from 0.1 s in to leave the encoder's start alone.
+    fn correlation(original: &[f32], decoded: &[f32], rate: u32) -> f32 {
+        let left = |samples: &[f32]| samples.iter().step_by(2).copied().collect::<Vec<_>>();
+        let (original, decoded) = (left(original), left(decoded));
+        let start = rate as usize / 10;
+        let length = rate as usize / 2;
+        let (_, correlation) = best_alignment(
+            &original[start..start + length],
+            &decoded[start..start + length],
+            rate as usize / 100,
+        );
+        correlation
+    }
+
+    #[test]
+    fn parses_targets_with_or_without_a_bitrate() {
+        assert_eq!(
+            OpusTarget::parse("example.com:5004").unwrap(),
+            OpusTarget {
+                address: "example.com:5004".to_owned(),
+                bitrate: 64_000
+            }
+        );
+        assert_eq!(
+            OpusTarget::parse("10.0.0.2:5004:bitrate=96k").unwrap(),
+            OpusTarget {
+                address: "10.0.0.2:5004".to_owned(),
+                bitrate: 96_000
+            }
+        );
+        assert_eq!(
+            OpusTarget::parse("[::1]:5004:bitrate=32000")
+                .unwrap()
+                .address,
+            "[::1]:5004"
+        );
+        assert!(OpusTarget::parse("example.com").is_err());
+        assert!(OpusTarget::parse("example.com:port").is_err());
+        assert!(OpusTarget::parse(":5004").is_err());
+        assert!(OpusTarget::parse("example.com:5004:bitrate=fast").is_err());
+        assert!(OpusTarget::parse("example.com:5004:bitrate=1k").is_err());
+    }
+
+    #[test]
+    fn survives_encoding_in_numbered_20_ms_packets() {
+        let original = tones(48_000, 48_000);
+        let mut packetizer = Packetizer::new(2, 48_000, 96_000).unwrap();
+        let mut packets = Vec::new();
+        // In callbacks that don't line up with the frames.
+        for chunk in original.chunks(2 * 441) {
+            packetizer
+                .push(chunk, |packet| packets.push(packet.to_vec()))
+                .unwrap();
+        }
+        assert_eq!(packets.len(), 50);
+        let decoded = decode(&packets, 48_000);
+        assert_eq!(decoded.len(), original.len());
+        let correlation = correlation(&original, &decoded, 48_000);
+        assert!(correlation > 0.9, "{}", correlation);
+    }
+
+    #[test]
+    fn resamples_rates_opus_cant_take_to_48_khz() {
+        let original = tones(44_100, 44_100);
+        let mut packetizer = Packetizer::new(2, 44_100, 96_000).unwrap();
+        assert_eq!(packetizer.rate(), 48_000);
+        let mut packets = Vec::new();
+        packetizer
+            .push(&original, |packet| packets.push(packet.to_vec()))
+            .unwrap();
+        let decoded = decode(&packets, 48_000);
+        // Compared against the same tones at the rate they were resampled to.
+        let correlation = correlation(&tones(48_000, 48_000), &decoded, 48_000);
+        assert!(correlation > 0.9, "{}", correlation);
+
+        assert!(Packetizer::new(2, 32_000, 64_000).is_err());
+        assert!(Packetizer::new(6, 48_000, 64_000).is_err());
+    }
+
+    #[test]
+    fn streams_the_mix_to_a_socket_on_its_own_clock() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let provider = WithOpusOutput(crate::backend::fake::FakeProvider::new());
+        let sink = provider
+            .output_device(&format!("{}{}", PREFIX, receiver.local_addr().unwrap()))
+            .unwrap();
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(480),
+        };
+        let mut frame = 0;
+        let stream = sink
+            .build_output_stream(
+                &config,
+                Box::new(move |data: &mut [f32]| {
+                    let tones = tones(frame + data.len() / 2, 48_000);
+                    data.copy_from_slice(&tones[frame * 2..]);
+                    frame += data.len() / 2;
+                }),
+                Box::new(|_| {}),
+            )
+            .unwrap();
+        stream.play().unwrap();
+
+        let started = Instant::now();
+        let mut packets = Vec::new();
+        let mut buffer = [0; 4 + MAX_PACKET_BYTES];
+        while packets.len() < 40 && started.elapsed() < Duration::from_secs(5) {
+            let length = receiver.recv(&mut buffer).unwrap();
+            packets.push(buffer[..length].to_vec());
+        }
+        drop(stream);
+        assert_eq!(packets.len(), 40);
+        let decoded = decode(&packets, 48_000);
+        let correlation = correlation(&tones(decoded.len() / 2, 48_000), &decoded, 48_000);
+        assert!(correlation > 0.9, "{}", correlation);
+    }
+}
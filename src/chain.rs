@@ -0,0 +1,502 @@
+//! The per-input processing chain, made of stages that run in a fixed order.
+//!
+//! The canonical order is DC block → gate → denoise → EQ → compressor → gain/pan → reverb
+//! send. Only some of those stages exist so far, and [`StageKind`] lists them in that order. A
+//! chain is boxed up before any audio runs, and processing through it never allocates.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::backend::StreamConfig;
+use crate::mix;
+use crate::pipeline::{ms_to_frames, OverrideGain};
+
+/// How long an override gain takes to fade between its old and new value.
+pub const OVERRIDE_FADE_MS: f32 = 50.0;
+
+/// The kinds of stage, in the order they must appear in a chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StageKind {
+    Denoise,
+    Gain,
+    Reverb,
+}
+
+impl fmt::Display for StageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StageKind::Denoise => "denoise",
+            StageKind::Gain => "gain",
+            StageKind::Reverb => "reverb",
+        })
+    }
+}
+
+/// Stages out of the canonical order: `later` comes before `earlier` in the chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OrderError {
+    pub earlier: StageKind,
+    pub later: StageKind,
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the {} stage must come before the {} stage",
+            self.earlier, self.later
+        )
+    }
+}
+
+impl Error for OrderError {}
+
+/// One step of an input's processing.
+pub trait ProcessStage: Send {
+    fn kind(&self) -> StageKind;
+
+    /// Processes interleaved `frames` with `channels` channels in place.
+    fn process(&mut self, frames: &mut [f32], channels: usize);
+
+    /// Processes `planes` in place, which hold `frames` frames of the first channel, then as many
+    /// of the second, and so on. The result must be exactly what [`ProcessStage::process`] would
+    /// give for the same audio interleaved.
+    fn process_planar(&mut self, planes: &mut [f32], frames: usize);
+
+    /// The delay, in frames, that the stage adds to its input.
+    fn latency_frames(&self) -> usize {
+        0
+    }
+
+    /// Forgets everything about the audio processed so far. Only done while no stream is running
+    /// the stage, so it may allocate.
+    fn reset(&mut self);
+
+    /// The bytes the stage allocated when it was built, for `--print-memory`.
+    fn memory_bytes(&self) -> usize {
+        0
+    }
+
+    /// A short description for `--print-chain`.
+    fn describe(&self) -> String {
+        self.kind().to_string()
+    }
+}
+
+/// The stages an input runs through, in canonical order.
+pub struct InputChain {
+    channels: usize,
+    stages: Vec<Box<dyn ProcessStage>>,
+    /// Room for the audio deinterleaved, if the chain runs planar.
+    planes: Option<Vec<f32>>,
+}
+
+impl InputChain {
+    /// Fails if the stages aren't in the canonical order.
+    pub fn new(channels: usize, stages: Vec<Box<dyn ProcessStage>>) -> Result<Self, OrderError> {
+        for pair in stages.windows(2) {
+            if pair[0].kind() > pair[1].kind() {
+                return Err(OrderError {
+                    earlier: pair[1].kind(),
+                    later: pair[0].kind(),
+                });
+            }
+        }
+        Ok(InputChain {
+            channels,
+            stages,
+            planes: None,
+        })
+    }
+
+    /// Runs the stages on planar audio, deinterleaving up to `max_frames` at a time on the way in
+    /// and interleaving again on the way out.
+    pub fn planar(mut self, max_frames: usize) -> Self {
+        self.planes = Some(vec![0.0; max_frames.max(1) * self.channels]);
+        self
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn process(&mut self, frames: &mut [f32]) {
+        let Some(planes) = &mut self.planes else {
+            for stage in &mut self.stages {
+                stage.process(frames, self.channels);
+            }
+            return;
+        };
+        if self.stages.is_empty() {
+            return;
+        }
+
+        for chunk in frames.chunks_mut(planes.len()) {
+            let len = chunk.len() / self.channels;
+            let planes = &mut planes[..chunk.len()];
+            deinterleave(chunk, planes, self.channels);
+            for stage in &mut self.stages {
+                stage.process_planar(planes, len);
+            }
+            interleave(planes, chunk, self.channels);
+        }
+    }
+
+    /// Processes `frames` like [`InputChain::process`], handing `tap` the audio as it is on the
+    /// way into the first stage of kind `before` or later.
+    pub fn process_tapped(
+        &mut self,
+        frames: &mut [f32],
+        before: StageKind,
+        tap: &mut dyn FnMut(&[f32]),
+    ) {
+        let split = self.split(before);
+        let Some(planes) = &mut self.planes else {
+            let (first, rest) = self.stages.split_at_mut(split);
+            for stage in first {
+                stage.process(frames, self.channels);
+            }
+            tap(frames);
+            for stage in rest {
+                stage.process(frames, self.channels);
+            }
+            return;
+        };
+
+        for chunk in frames.chunks_mut(planes.len()) {
+            let len = chunk.len() / self.channels;
+            let planes = &mut planes[..chunk.len()];
+            deinterleave(chunk, planes, self.channels);
+            let (first, rest) = self.stages.split_at_mut(split);
+            for stage in first {
+                stage.process_planar(planes, len);
+            }
+            // The chunk gets overwritten on the way out anyway.
+            interleave(planes, chunk, self.channels);
+            tap(chunk);
+            for stage in rest {
+                stage.process_planar(planes, len);
+            }
+            interleave(planes, chunk, self.channels);
+        }
+    }
+
+    /// Where the first stage of kind `kind` or later is, or the end.
+    fn split(&self, kind: StageKind) -> usize {
+        self.stages
+            .iter()
+            .position(|stage| stage.kind() >= kind)
+            .unwrap_or(self.stages.len())
+    }
+
+    /// The delay, in frames, that the whole chain adds to the input.
+    pub fn latency_frames(&self) -> usize {
+        self.stages.iter().map(|stage| stage.latency_frames()).sum()
+    }
+
+    /// The delay, in frames, that the stages before the first of kind `kind` or later add.
+    pub fn latency_frames_before(&self, kind: StageKind) -> usize {
+        self.stages[..self.split(kind)]
+            .iter()
+            .map(|stage| stage.latency_frames())
+            .sum()
+    }
+
+    /// The bytes the stages and the room for planar audio take up.
+    pub fn memory_bytes(&self) -> usize {
+        let planes = self.planes.as_ref().map_or(0, Vec::len) * std::mem::size_of::<f32>();
+        planes
+            + self
+                .stages
+                .iter()
+                .map(|stage| stage.memory_bytes())
+                .sum::<usize>()
+    }
+
+    /// Resets every stage, which has to happen whenever the input's stream is rebuilt so that no
+    /// state from the old stream leaks into the new one. The pipeline does so before every stream
+    /// it builds on the chain.
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    /// A one line description of every stage, for `--print-chain`.
+    pub fn describe(&self) -> String {
+        if self.stages.is_empty() {
+            return "(empty)".to_owned();
+        }
+        self.stages
+            .iter()
+            .map(|stage| match stage.latency_frames() {
+                0 => stage.describe(),
+                latency => format!("{} ({} frames)", stage.describe(), latency),
+            })
+            .collect::<Vec<_>>()
+            .join(" → ")
+    }
+}
+
+fn deinterleave(frames: &[f32], planes: &mut [f32], channels: usize) {
+    let len = frames.len() / channels;
+    for (frame, samples) in frames.chunks_exact(channels).enumerate() {
+        for (channel, &sample) in samples.iter().enumerate() {
+            planes[channel * len + frame] = sample;
+        }
+    }
+}
+
+fn interleave(planes: &[f32], frames: &mut [f32], channels: usize) {
+    let len = frames.len() / channels;
+    for (frame, samples) in frames.chunks_exact_mut(channels).enumerate() {
+        for (channel, sample) in samples.iter_mut().enumerate() {
+            *sample = planes[channel * len + frame];
+        }
+    }
+}
+
+/// Polarity and a gain the control thread can fade at runtime.
+pub struct GainStage {
+    polarity: f32,
+    override_gain: Arc<OverrideGain>,
+    /// Where the fade towards the override gain's target has got to.
+    gain: f32,
+    /// How much `gain` moves per frame while fading.
+    fade_step: f32,
+}
+
+impl GainStage {
+    pub fn new(config: &StreamConfig, invert: bool) -> Self {
+        GainStage {
+            polarity: if invert { -1.0 } else { 1.0 },
+            override_gain: Arc::new(OverrideGain::new()),
+            gain: 1.0,
+            fade_step: 1.0 / ms_to_frames(OVERRIDE_FADE_MS, config.sample_rate.0).max(1) as f32,
+        }
+    }
+
+    /// A handle the control thread can use to fade this input in and out.
+    pub fn override_gain(&self) -> Arc<OverrideGain> {
+        Arc::clone(&self.override_gain)
+    }
+}
+
+impl ProcessStage for GainStage {
+    fn kind(&self) -> StageKind {
+        StageKind::Gain
+    }
+
+    fn process(&mut self, frames: &mut [f32], channels: usize) {
+        let target = self.override_gain.target();
+        if self.polarity == 1.0 && self.gain == 1.0 && target == 1.0 {
+            return;
+        }
+        if self.gain == target {
+            mix::scale(frames, self.polarity * self.gain);
+            return;
+        }
+
+        for frame in frames.chunks_mut(channels) {
+            if self.gain < target {
+                self.gain = (self.gain + self.fade_step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - self.fade_step).max(target);
+            }
+            for sample in frame {
+                *sample *= self.polarity * self.gain;
+            }
+        }
+    }
+
+    fn process_planar(&mut self, planes: &mut [f32], frames: usize) {
+        let target = self.override_gain.target();
+        if frames == 0 || self.polarity == 1.0 && self.gain == 1.0 && target == 1.0 {
+            return;
+        }
+
+        if self.gain == target {
+            mix::scale(planes, self.polarity * self.gain);
+            return;
+        }
+
+        // Every channel fades along the same path, from the same starting point.
+        let start = self.gain;
+        for plane in planes.chunks_mut(frames) {
+            self.gain = start;
+            for sample in plane {
+                if self.gain < target {
+                    self.gain = (self.gain + self.fade_step).min(target);
+                } else if self.gain > target {
+                    self.gain = (self.gain - self.fade_step).max(target);
+                }
+                *sample *= self.polarity * self.gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // Jump straight to wherever the control thread wants the gain, there's nothing to fade
+        // from after a rebuild.
+        self.gain = self.override_gain.target();
+    }
+
+    fn describe(&self) -> String {
+        if self.polarity < 0.0 {
+            "gain (inverted)".to_owned()
+        } else {
+            "gain".to_owned()
+        }
+    }
+}
+
+/// Time a stage spends processing, shared between the audio callback and the stats printout.
+#[derive(Default)]
+pub struct CpuUsage {
+    busy_nanos: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl CpuUsage {
+    pub(crate) fn record(&self, busy_nanos: u64, samples: u64) {
+        self.busy_nanos.fetch_add(busy_nanos, Ordering::Relaxed);
+        self.samples.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    /// Returns and resets the time spent processing so far, together with the number of samples
+    /// processed in that time.
+    pub fn take(&self) -> (u64, u64) {
+        (
+            self.busy_nanos.swap(0, Ordering::Relaxed),
+            self.samples.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverb::{Reverb, ReverbSend};
+
+    const RATE: u32 = 48_000;
+
+    /// A chain ready to run, and the handles to change its gain and reverb with while it does.
+    fn chain(planar: bool) -> (InputChain, Arc<OverrideGain>, Arc<ReverbSend>) {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let gain = GainStage::new(&config, true);
+        let override_gain = gain.override_gain();
+        let send = Arc::new(ReverbSend::new(0.3));
+        send.set_enabled(true);
+        let stages: Vec<Box<dyn ProcessStage>> = vec![
+            #[cfg(feature = "denoise")]
+            Box::new(crate::denoise::Denoiser::new(2, 0.7)),
+            Box::new(gain),
+            Box::new(Reverb::new(2, RATE, Arc::clone(&send))),
+        ];
+        let mut chain = InputChain::new(2, stages).unwrap();
+        if planar {
+            chain = chain.planar(300);
+        }
+        chain.reset();
+        (chain, override_gain, send)
+    }
+
+    /// Runs `input` through a chain in uneven callbacks, fading the gain and muting part way,
+    /// and returns what came out and what was tapped before the gain.
+    fn run(planar: bool, input: &[f32]) -> (Vec<f32>, Vec<f32>) {
+        let (mut chain, gain, _send) = chain(planar);
+        let mut output = input.to_vec();
+        let mut tapped = Vec::new();
+        let mut callbacks = [97, 512, 301, 1, 640].iter().cycle();
+        let mut rest = &mut output[..];
+        let mut callback = 0;
+        while !rest.is_empty() {
+            match callback {
+                3 => gain.set(0.25),
+                7 => gain.set_muted(true),
+                9 => gain.set_muted(false),
+                _ => {}
+            }
+            let len = (callbacks.next().unwrap() * 2).min(rest.len());
+            let (frames, after) = rest.split_at_mut(len);
+            chain.process_tapped(frames, StageKind::Gain, &mut |frames| {
+                tapped.extend_from_slice(frames)
+            });
+            rest = after;
+            callback += 1;
+        }
+        (output, tapped)
+    }
+
+    #[test]
+    fn planar_and_interleaved_are_bit_identical() {
+        let input = (0..2 * RATE as usize / 2)
+            .map(|i| (i as f32 * 0.013).sin() * 0.6 + (i as f32 * 0.29).cos() * 0.2)
+            .collect::<Vec<_>>();
+        let (interleaved, interleaved_tap) = run(false, &input);
+        let (planar, planar_tap) = run(true, &input);
+        assert_ne!(interleaved, input);
+        assert!(interleaved
+            .iter()
+            .zip(&planar)
+            .all(|(a, b)| a.to_bits() == b.to_bits()));
+        assert!(interleaved_tap
+            .iter()
+            .zip(&planar_tap)
+            .all(|(a, b)| a.to_bits() == b.to_bits()));
+        assert_eq!(interleaved_tap.len(), input.len());
+        assert_eq!(planar_tap.len(), input.len());
+    }
+
+    #[test]
+    fn tapping_leaves_the_output_alone() {
+        let input = (0..4_000)
+            .map(|i| (i as f32 * 0.05).sin() * 0.5)
+            .collect::<Vec<_>>();
+        for planar in [false, true] {
+            let (mut plain, _gain, _send) = chain(planar);
+            let mut frames = input.clone();
+            plain.process(&mut frames);
+            let (mut tapped_chain, _gain, _send) = chain(planar);
+            let mut tapped = input.clone();
+            tapped_chain.process_tapped(&mut tapped, StageKind::Reverb, &mut |_| {});
+            assert_eq!(frames, tapped);
+        }
+    }
+
+    #[test]
+    fn refuses_stages_out_of_order() {
+        let send = Arc::new(ReverbSend::new(0.3));
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let stages: Vec<Box<dyn ProcessStage>> = vec![
+            Box::new(Reverb::new(2, RATE, send)),
+            Box::new(GainStage::new(&config, false)),
+        ];
+        let err = InputChain::new(2, stages).err().unwrap();
+        assert_eq!(
+            err,
+            OrderError {
+                earlier: StageKind::Gain,
+                later: StageKind::Reverb,
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "the gain stage must come before the reverb stage"
+        );
+    }
+}
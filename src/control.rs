@@ -0,0 +1,955 @@
+//! Commands that change the pipeline while it runs, and the state they drive.
+//!
+//! Commands arrive as lines of text (from stdin, for now) and are applied on the control thread,
+//! which only ever touches the audio side through the atomics in [`crate::pipeline`].
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use std::error::Error;
+use std::fmt;
+
+use crate::error::{parse_error, ParseError};
+use crate::pipeline::OverrideGain;
+use crate::reverb::ReverbSend;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    DuckHold(DuckHoldCommand),
+    Status,
+    /// Prints what the pipeline is doing as JSON.
+    Describe,
+    /// Marks the current position of the recordings with a label.
+    Marker(String),
+    /// Beeps on one output channel, counting from 1, or on every one in turn.
+    Identify(Option<usize>),
+    /// Saves the last of the output, or all of the replay buffer.
+    Clip(Option<Duration>),
+    /// Attaches an input device to the running pipeline.
+    AddInput(String),
+    /// Detaches an input added with `add-input` or `--auto-attach`.
+    RemoveInput(String),
+    /// Stops a configured input's stream, keeping everything else about it.
+    StopInput(String),
+    /// Starts a stopped input's stream again.
+    StartInput(String),
+    Compare(CompareCommand),
+    /// A media key, or a command standing in for one, which only does anything while armed.
+    Key(MediaKey),
+    /// Arms or disarms the media keys, or toggles them without a state.
+    Arm(Option<bool>),
+    Reverb(ReverbCommand),
+    Scene(SceneCommand),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SceneCommand {
+    /// Switches to a scene, over the default transition unless given another.
+    Switch {
+        name: String,
+        transition: Option<Duration>,
+    },
+    List,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReverbCommand {
+    On,
+    Off,
+    /// Sets how loud the reverb is, from 0 to 1.
+    Wet(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKey {
+    VolumeUp,
+    VolumeDown,
+    PlayPause,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuckHoldCommand {
+    On,
+    Off,
+    For(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompareCommand {
+    /// Alternates between two inputs, each playing for `period` at a time.
+    Start {
+        inputs: [String; 2],
+        period: Duration,
+    },
+    Stop,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        // Labels and device names are free text, so they don't go through the word splitting
+        // below.
+        if let Some(label) = free_text(line, "marker") {
+            if label.is_empty() {
+                return Err(parse_error!(
+                    "`marker` expects a label, like `marker \"funny moment\"`"
+                ));
+            }
+            return Ok(Command::Marker(label.to_owned()));
+        }
+        for (name, command) in [
+            ("add-input", Command::AddInput as fn(String) -> Command),
+            ("remove-input", Command::RemoveInput),
+        ] {
+            if let Some(device) = free_text(line, name) {
+                if device.is_empty() {
+                    return Err(parse_error!(
+                        "`{}` expects a device name, like `{} \"USB Microphone\"`",
+                        name,
+                        name
+                    ));
+                }
+                return Ok(command(device.to_owned()));
+            }
+        }
+        if let Some(rest) = after_word(line, "input") {
+            for (name, command) in [
+                ("stop", Command::StopInput as fn(String) -> Command),
+                ("start", Command::StartInput),
+            ] {
+                if let Some(input) = free_text(rest, name) {
+                    if input.is_empty() {
+                        return Err(parse_error!(
+                            "`input {}` expects an input's name, like `input {} \"Game Capture\"`",
+                            name,
+                            name
+                        ));
+                    }
+                    return Ok(command(input.to_owned()));
+                }
+            }
+            return Err(parse_error!(
+                "`input` expects `stop <name>` or `start <name>`"
+            ));
+        }
+        if let Some(rest) = after_word(line, "compare") {
+            return Ok(Command::Compare(match quoted_words(rest)?[..] {
+                ["stop"] => CompareCommand::Stop,
+                [a, b, period] => {
+                    let period = parse_duration(period)?;
+                    if period.is_zero() {
+                        return Err(parse_error!(
+                            "`compare` needs each input to play for longer than nothing at all"
+                        ));
+                    }
+                    if a == b {
+                        return Err(parse_error!(
+                            "`compare` needs two different inputs, but was given \"{}\" twice",
+                            a
+                        ));
+                    }
+                    CompareCommand::Start {
+                        inputs: [a.to_owned(), b.to_owned()],
+                        period,
+                    }
+                }
+                _ => {
+                    return Err(parse_error!(
+                        "`compare` expects two inputs and how long each plays, like \
+                     `compare Microphone \"Game Capture\" 2s`, or `compare stop`"
+                    ))
+                }
+            }));
+        }
+        if let Some(rest) = after_word(line, "scene") {
+            return Ok(Command::Scene(match quoted_words(rest)?[..] {
+                ["list"] => SceneCommand::List,
+                [name] => SceneCommand::Switch {
+                    name: name.to_owned(),
+                    transition: None,
+                },
+                [name, transition] => SceneCommand::Switch {
+                    name: name.to_owned(),
+                    transition: Some(parse_duration(transition)?),
+                },
+                _ => {
+                    return Err(parse_error!(
+                        "`scene` expects a scene's name and how long switching to it takes if not \
+                     the default, like `scene gameplay` or `scene chatting 2s`, or `scene list`"
+                    ))
+                }
+            }));
+        }
+
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some("duck-hold") => match words.next() {
+                Some("on") => Command::DuckHold(DuckHoldCommand::On),
+                Some("off") => Command::DuckHold(DuckHoldCommand::Off),
+                Some(duration) => {
+                    Command::DuckHold(DuckHoldCommand::For(parse_duration(duration)?))
+                }
+                None => {
+                    return Err(parse_error!(
+                        "`duck-hold` expects `on`, `off`, or a duration like `30s`"
+                    ))
+                }
+            },
+            Some("status") => Command::Status,
+            Some("describe") => Command::Describe,
+            Some("volume-up") => Command::Key(MediaKey::VolumeUp),
+            Some("volume-down") => Command::Key(MediaKey::VolumeDown),
+            Some("play-pause") => Command::Key(MediaKey::PlayPause),
+            Some("fx") => match (words.next(), words.next()) {
+                (Some("reverb"), Some("on")) => Command::Reverb(ReverbCommand::On),
+                (Some("reverb"), Some("off")) => Command::Reverb(ReverbCommand::Off),
+                (Some("reverb"), Some("wet")) => {
+                    let level = words.next().unwrap_or_default();
+                    Command::Reverb(ReverbCommand::Wet(
+                        level
+                            .parse()
+                            .ok()
+                            .filter(|wet| (0.0..=1.0).contains(wet))
+                            .ok_or_else(|| {
+                                parse_error!("expected a wet level from 0 to 1, got `{}`", level)
+                            })?,
+                    ))
+                }
+                _ => {
+                    return Err(parse_error!(
+                        "`fx` expects `reverb on`, `reverb off` or `reverb wet <level>`"
+                    ))
+                }
+            },
+            Some("arm") => match words.next() {
+                Some("on") => Command::Arm(Some(true)),
+                Some("off") => Command::Arm(Some(false)),
+                Some(other) => {
+                    return Err(parse_error!(
+                        "`arm` expects `on`, `off` or nothing, got `{}`",
+                        other
+                    ))
+                }
+                None => Command::Arm(None),
+            },
+            Some("identify") => match words.next() {
+                Some(channel) => Command::Identify(Some(
+                    channel
+                        .parse()
+                        .ok()
+                        .filter(|&channel| channel > 0)
+                        .ok_or_else(|| {
+                            parse_error!("expected an output channel like `3`, got `{}`", channel)
+                        })?,
+                )),
+                None => Command::Identify(None),
+            },
+            Some("clip") => Command::Clip(words.next().map(parse_duration).transpose()?),
+            Some(other) => return Err(parse_error!("unknown command `{}`", other)),
+            None => return Err(parse_error!("empty command")),
+        };
+        if let Some(extra) = words.next() {
+            return Err(parse_error!("unexpected `{}` after the command", extra));
+        }
+        Ok(command)
+    }
+}
+
+/// The rest of `line` if it starts with the word `command`, trimmed and with any quotes around
+/// it removed.
+fn free_text<'a>(line: &'a str, command: &str) -> Option<&'a str> {
+    let rest = after_word(line, command)?.trim();
+    Some(
+        rest.strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+            .unwrap_or(rest),
+    )
+}
+
+/// The rest of `line` if it starts with the word `command`.
+fn after_word<'a>(line: &'a str, command: &str) -> Option<&'a str> {
+    let rest = line.trim().strip_prefix(command)?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest)
+}
+
+/// Splits `text` into words, keeping anything in double quotes together as one word without the
+/// quotes.
+fn quoted_words(text: &str) -> Result<Vec<&str>, ParseError> {
+    let mut words = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted
+                    .find('"')
+                    .ok_or_else(|| parse_error!("unterminated quote in `{}`", text.trim()))?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len())),
+        };
+        words.push(word);
+        rest = after.trim_start();
+    }
+    Ok(words)
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters and `?` for any
+/// one character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    // Where to resume from when a mismatch comes after a `*`: the pattern just past it, and the
+    // next character of the name for it to swallow.
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n + 1));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((after_star, next)) => {
+                    p = after_star;
+                    n = next;
+                    backtrack = Some((after_star, next + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Parses durations like `500ms`, `30s` or `2m`, where a bare number means seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, ParseError> {
+    let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (value, 1.0)
+    };
+    let seconds = number
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .ok_or_else(|| parse_error!("expected a duration like `30s`, got `{}`", value))?;
+    Duration::try_from_secs_f64(seconds * scale)
+        .map_err(|_| parse_error!("`{}` is too long a duration", value))
+}
+
+/// A momentary kill switch that fades an input out until it is released, either explicitly or once
+/// a timer runs out.
+pub struct DuckHold {
+    input: String,
+    gain: Arc<OverrideGain>,
+    state: DuckHoldState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DuckHoldState {
+    Released,
+    Held { until: Option<Instant> },
+}
+
+impl DuckHold {
+    pub fn new(input: &str, gain: Arc<OverrideGain>) -> Self {
+        DuckHold {
+            input: input.to_owned(),
+            gain,
+            state: DuckHoldState::Released,
+        }
+    }
+
+    /// Applies a command. A new duration replaces whatever timer was running, and `on` holds
+    /// indefinitely even if a timer was running.
+    pub fn apply(&mut self, command: DuckHoldCommand, now: Instant) {
+        self.state = match command {
+            DuckHoldCommand::On => DuckHoldState::Held { until: None },
+            DuckHoldCommand::Off => DuckHoldState::Released,
+            // A hold too long to have an end is as good as one without.
+            DuckHoldCommand::For(duration) => DuckHoldState::Held {
+                until: now.checked_add(duration),
+            },
+        };
+        self.update_gain();
+    }
+
+    /// Moves the hold onto the gain of a rebuilt pipeline, keeping whatever state it was in.
+    pub fn attach(&mut self, gain: Arc<OverrideGain>) {
+        self.gain = gain;
+        self.update_gain();
+    }
+
+    /// Releases the hold once its timer has run out, returning whether it just was.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        if let DuckHoldState::Held { until: Some(until) } = self.state {
+            if now >= until {
+                self.state = DuckHoldState::Released;
+                self.update_gain();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn update_gain(&self) {
+        self.gain.set(match self.state {
+            DuckHoldState::Released => 1.0,
+            DuckHoldState::Held { .. } => 0.0,
+        });
+    }
+
+    pub fn status(&self, now: Instant) -> String {
+        match self.state {
+            DuckHoldState::Released => format!("duck-hold on \"{}\": off", self.input),
+            DuckHoldState::Held { until: None } => {
+                format!("duck-hold on \"{}\": on until released", self.input)
+            }
+            DuckHoldState::Held { until: Some(until) } => format!(
+                "duck-hold on \"{}\": on, {:.0}s remaining",
+                self.input,
+                until.saturating_duration_since(now).as_secs_f32().ceil()
+            ),
+        }
+    }
+}
+
+/// Alternates between two inputs for blind listening, with every other input muted, and puts every
+/// input's mute back how it was once stopped.
+///
+/// Switching only flips which inputs are muted, so the input callbacks fade across it.
+pub struct Comparison {
+    inputs: [String; 2],
+    period: Duration,
+    /// Every input's gain, along with whether it was muted before the comparison started.
+    saved: Vec<(String, Arc<OverrideGain>, bool)>,
+    /// Which of `inputs` is playing.
+    playing: usize,
+    next_switch: Instant,
+}
+
+impl Comparison {
+    /// Starts with the first input playing. `gains` are every input's, by name.
+    pub fn start(
+        inputs: [String; 2],
+        period: Duration,
+        gains: &[(String, Arc<OverrideGain>)],
+        now: Instant,
+    ) -> Result<Self, ControlError> {
+        for input in &inputs {
+            if !gains.iter().any(|(name, _)| name == input) {
+                return Err(ControlError::NoSuchInput(input.clone()));
+            }
+        }
+        let mut comparison = Comparison {
+            inputs,
+            period,
+            saved: Vec::new(),
+            playing: 0,
+            next_switch: now + period,
+        };
+        comparison.update(gains)?;
+        Ok(comparison)
+    }
+
+    /// Catches up with inputs that have come and gone: new ones are muted too, and ones that have
+    /// gone are forgotten. Fails, restoring every input, if one of the two being compared is gone.
+    pub fn update(&mut self, gains: &[(String, Arc<OverrideGain>)]) -> Result<(), ControlError> {
+        self.saved
+            .retain(|(name, _, _)| gains.iter().any(|(input, _)| input == name));
+        for (name, gain) in gains {
+            match self.saved.iter_mut().find(|(saved, _, _)| saved == name) {
+                // A rebuilt pipeline has new gains, which start out as they were before the
+                // comparison.
+                Some((_, saved, muted)) if !Arc::ptr_eq(saved, gain) => {
+                    gain.set_muted(*muted);
+                    *saved = Arc::clone(gain);
+                }
+                Some(_) => {}
+                None => self
+                    .saved
+                    .push((name.clone(), Arc::clone(gain), gain.muted())),
+            }
+        }
+        if let Some(gone) = self
+            .inputs
+            .iter()
+            .find(|input| !self.saved.iter().any(|(name, _, _)| name == *input))
+        {
+            let gone = gone.clone();
+            self.stop();
+            return Err(ControlError::InputGone(gone));
+        }
+        self.apply();
+        Ok(())
+    }
+
+    /// Switches to the other input once it's time, returning whether it just did.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        if now < self.next_switch {
+            return false;
+        }
+        self.playing = 1 - self.playing;
+        // Each switch is due a period after the last was due, so late ticks don't add up.
+        self.next_switch += self.period;
+        if self.next_switch <= now {
+            self.next_switch = now + self.period;
+        }
+        self.apply();
+        true
+    }
+
+    /// Puts every input's mute back how it was before the comparison started.
+    pub fn stop(&mut self) {
+        for (_, gain, muted) in &self.saved {
+            gain.set_muted(*muted);
+        }
+    }
+
+    fn apply(&self) {
+        for (name, gain, _) in &self.saved {
+            gain.set_muted(*name != self.inputs[self.playing]);
+        }
+    }
+
+    pub fn status(&self, now: Instant) -> String {
+        format!(
+            "compare: \"{}\" playing, \"{}\" next in {:.1}s",
+            self.inputs[self.playing],
+            self.inputs[1 - self.playing],
+            self.next_switch
+                .saturating_duration_since(now)
+                .as_secs_f32()
+        )
+    }
+}
+
+/// The level and mute of the whole mix, as driven by media keys: volume up and down nudge the
+/// level by [`MasterControl::STEP_DB`], and play/pause mutes and unmutes everything.
+///
+/// The keys are ignored until armed, so they only reach the mix while that's wanted.
+pub struct MasterControl {
+    gain: Arc<OverrideGain>,
+    armed: Arc<AtomicBool>,
+}
+
+impl MasterControl {
+    pub const STEP_DB: f32 = 1.0;
+    /// The range the level is kept in.
+    pub const MIN_DB: f32 = -60.0;
+    pub const MAX_DB: f32 = 12.0;
+
+    pub fn new(gain: Arc<OverrideGain>) -> Self {
+        MasterControl {
+            gain,
+            armed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Arms or disarms the keys, or toggles them without a state.
+    pub fn arm(&mut self, armed: Option<bool>) {
+        let armed = armed.unwrap_or(!self.armed());
+        self.armed.store(armed, Ordering::Relaxed);
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the keys are armed, for key hooks on other threads, which leave the keys to the
+    /// rest of the system while they aren't.
+    pub fn armed_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.armed)
+    }
+
+    /// Applies a key, returning whether it was armed to.
+    pub fn key(&mut self, key: MediaKey) -> bool {
+        if !self.armed() {
+            return false;
+        }
+        let level = self.gain.level_db();
+        match key {
+            MediaKey::VolumeUp => self
+                .gain
+                .set_level_db((level + Self::STEP_DB).min(Self::MAX_DB)),
+            MediaKey::VolumeDown => self
+                .gain
+                .set_level_db((level - Self::STEP_DB).max(Self::MIN_DB)),
+            MediaKey::PlayPause => self.gain.set_muted(!self.gain.muted()),
+        }
+        true
+    }
+
+    /// Moves the level and mute onto the master gain of a rebuilt pipeline.
+    pub fn attach(&mut self, gain: Arc<OverrideGain>) {
+        gain.set_level_db(self.gain.level_db());
+        gain.set_muted(self.gain.muted());
+        self.gain = gain;
+    }
+
+    pub fn status(&self) -> String {
+        format!(
+            "master: {:+.0} dB{}, keys {}",
+            self.gain.level_db(),
+            if self.gain.muted() { ", muted" } else { "" },
+            if self.armed() { "armed" } else { "not armed" }
+        )
+    }
+}
+
+/// Turns the inputs' reverb sends on and off and sets how loud they are, remembering both so a
+/// rebuilt pipeline's sends can be set up the same.
+pub struct ReverbControl {
+    sends: Vec<(String, Arc<ReverbSend>)>,
+    enabled: bool,
+    wet: f32,
+}
+
+impl ReverbControl {
+    pub fn new(sends: &[(String, Arc<ReverbSend>)], wet: f32) -> Self {
+        let mut control = ReverbControl {
+            sends: Vec::new(),
+            enabled: false,
+            wet,
+        };
+        control.attach(sends);
+        control
+    }
+
+    pub fn apply(&mut self, command: ReverbCommand) -> Result<(), ControlError> {
+        if self.sends.is_empty() {
+            return Err(ControlError::NoReverb);
+        }
+        match command {
+            ReverbCommand::On => self.enabled = true,
+            ReverbCommand::Off => self.enabled = false,
+            ReverbCommand::Wet(wet) => self.wet = wet,
+        }
+        for (_, send) in &self.sends {
+            send.set_enabled(self.enabled);
+            send.set_wet(self.wet);
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn wet(&self) -> f32 {
+        self.wet
+    }
+
+    /// Whether no input has a reverb.
+    pub fn is_empty(&self) -> bool {
+        self.sends.is_empty()
+    }
+
+    /// Moves the state onto the sends of a rebuilt pipeline.
+    pub fn attach(&mut self, sends: &[(String, Arc<ReverbSend>)]) {
+        for (_, send) in sends {
+            send.set_enabled(self.enabled);
+            send.set_wet(self.wet);
+        }
+        self.sends = sends.to_vec();
+    }
+
+    /// `None` when no input has a reverb.
+    pub fn status(&self) -> Option<String> {
+        let inputs = self
+            .sends
+            .iter()
+            .map(|(input, _)| format!("\"{}\"", input))
+            .collect::<Vec<_>>();
+        (!inputs.is_empty()).then(|| {
+            format!(
+                "reverb: {} at {:.0}% wet, on {}",
+                if self.enabled { "on" } else { "off" },
+                self.wet * 100.0,
+                inputs.join(", ")
+            )
+        })
+    }
+}
+
+/// Why a command couldn't be carried out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    /// There's no input by that name to compare.
+    NoSuchInput(String),
+    /// A comparison stopped because this input, one of the two, went away.
+    InputGone(String),
+    /// No input has a reverb to change.
+    NoReverb,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::NoSuchInput(input) => {
+                write!(f, "there's no input called \"{}\" to compare", input)
+            }
+            ControlError::InputGone(input) => {
+                write!(f, "stopped comparing, since \"{}\" has gone away", input)
+            }
+            ControlError::NoReverb => f.write_str("no input has a reverb: add one with `--reverb`"),
+        }
+    }
+}
+
+impl Error for ControlError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_device_names_against_globs() {
+        let cases = [
+            ("USB*", "USB Audio CODEC", true),
+            ("*Mic*", "Rode NT-USB Mic", true),
+            ("*Mic", "Mic Array", false),
+            ("AirPods?", "AirPods2", true),
+            ("AirPods?", "AirPods", false),
+            ("a*b*c", "abcbc", true),
+            ("a*c", "abcbcd", false),
+            // A `*` that first swallows too little has to be taken back.
+            ("*ab", "aab", true),
+            ("*a*b", "xaxxb", true),
+            ("**", "anything", true),
+            ("*", "", true),
+            ("", "", true),
+            ("", "Mic", false),
+            // Exact, case and all.
+            ("BlackHole 2ch", "BlackHole 2ch", true),
+            ("usb*", "USB Audio CODEC", false),
+            ("Mikrofon ?", "Mikrofon ü", true),
+        ];
+        for (pattern, name, matches) in cases {
+            assert_eq!(
+                glob_match(pattern, name),
+                matches,
+                "{:?} against {:?}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn parses_scene_commands() {
+        assert_eq!(
+            Command::parse("scene gameplay").unwrap(),
+            Command::Scene(SceneCommand::Switch {
+                name: "gameplay".to_owned(),
+                transition: None,
+            })
+        );
+        assert_eq!(
+            Command::parse("scene chatting 2s").unwrap(),
+            Command::Scene(SceneCommand::Switch {
+                name: "chatting".to_owned(),
+                transition: Some(Duration::from_secs(2)),
+            })
+        );
+        assert_eq!(
+            Command::parse("scene list").unwrap(),
+            Command::Scene(SceneCommand::List)
+        );
+        assert!(Command::parse("scene").is_err());
+        assert!(Command::parse("scene chatting soon").is_err());
+        assert!(Command::parse("scene a b c").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1_500));
+    }
+
+    #[test]
+    fn rejects_bad_and_overflowing_durations() {
+        for value in ["", "s", "-1s", "nan", "inf", "1e30s", "1e300m"] {
+            assert!(parse_duration(value).is_err(), "{:?} parsed", value);
+        }
+    }
+
+    #[test]
+    fn duck_hold_too_long_to_end_holds_indefinitely() {
+        let gain = Arc::new(OverrideGain::new());
+        let mut hold = DuckHold::new("Mic", Arc::clone(&gain));
+        let now = Instant::now();
+        hold.apply(DuckHoldCommand::For(Duration::MAX), now);
+        assert_eq!(hold.state, DuckHoldState::Held { until: None });
+        assert!(!hold.tick(now + Duration::from_secs(3_600)));
+        assert_eq!(gain.target(), 0.0);
+    }
+
+    #[test]
+    fn duck_hold_releases_once_its_timer_runs_out() {
+        let gain = Arc::new(OverrideGain::new());
+        let mut hold = DuckHold::new("Mic", Arc::clone(&gain));
+        let now = Instant::now();
+        hold.apply(DuckHoldCommand::For(Duration::from_secs(5)), now);
+        assert!(!hold.tick(now + Duration::from_secs(4)));
+        assert!(hold.tick(now + Duration::from_secs(5)));
+        assert_eq!(gain.target(), 1.0);
+    }
+
+    #[test]
+    fn master_keys_do_nothing_until_armed() {
+        let gain = Arc::new(OverrideGain::new());
+        let mut master = MasterControl::new(Arc::clone(&gain));
+        assert!(!master.key(MediaKey::VolumeUp));
+        assert!(!master.key(MediaKey::PlayPause));
+        assert_eq!((gain.level_db(), gain.muted()), (0.0, false));
+
+        master.arm(None);
+        assert!(master.armed() && master.armed_flag().load(Ordering::Relaxed));
+        assert!(master.key(MediaKey::VolumeUp));
+        assert_eq!(gain.level_db(), MasterControl::STEP_DB);
+        master.arm(None);
+        assert!(!master.armed());
+        master.arm(Some(true));
+        master.arm(Some(true));
+        assert!(master.armed());
+        master.arm(Some(false));
+        assert!(!master.key(MediaKey::VolumeDown));
+        assert_eq!(gain.level_db(), MasterControl::STEP_DB);
+    }
+
+    #[test]
+    fn master_keys_nudge_the_level_within_its_range_and_toggle_mute() {
+        let gain = Arc::new(OverrideGain::new());
+        let mut master = MasterControl::new(Arc::clone(&gain));
+        master.arm(Some(true));
+        master.key(MediaKey::VolumeDown);
+        master.key(MediaKey::VolumeDown);
+        assert_eq!(gain.level_db(), -2.0);
+        assert_eq!(master.status(), "master: -2 dB, keys armed");
+
+        for _ in 0..100 {
+            master.key(MediaKey::VolumeUp);
+        }
+        assert_eq!(gain.level_db(), MasterControl::MAX_DB);
+        for _ in 0..100 {
+            master.key(MediaKey::VolumeDown);
+        }
+        assert_eq!(gain.level_db(), MasterControl::MIN_DB);
+
+        master.key(MediaKey::PlayPause);
+        assert_eq!(gain.target(), 0.0);
+        assert_eq!(master.status(), "master: -60 dB, muted, keys armed");
+        master.key(MediaKey::PlayPause);
+        assert!(!gain.muted());
+    }
+
+    #[test]
+    fn master_level_and_mute_carry_over_to_a_rebuilt_pipeline() {
+        let mut master = MasterControl::new(Arc::new(OverrideGain::new()));
+        master.arm(Some(true));
+        master.key(MediaKey::VolumeUp);
+        master.key(MediaKey::PlayPause);
+        let rebuilt = Arc::new(OverrideGain::new());
+        master.attach(Arc::clone(&rebuilt));
+        assert_eq!((rebuilt.level_db(), rebuilt.muted()), (1.0, true));
+        assert!(master.key(MediaKey::VolumeUp));
+        assert_eq!(rebuilt.level_db(), 2.0);
+    }
+
+    fn gains(names: &[&str]) -> Vec<(String, Arc<OverrideGain>)> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), Arc::new(OverrideGain::new())))
+            .collect()
+    }
+
+    fn muted(gains: &[(String, Arc<OverrideGain>)]) -> Vec<bool> {
+        gains.iter().map(|(_, gain)| gain.muted()).collect()
+    }
+
+    fn compare_mic_and_game(
+        gains: &[(String, Arc<OverrideGain>)],
+        now: Instant,
+    ) -> Result<Comparison, ControlError> {
+        Comparison::start(
+            ["Mic".to_owned(), "Game".to_owned()],
+            Duration::from_secs(10),
+            gains,
+            now,
+        )
+    }
+
+    #[test]
+    fn comparison_alternates_between_its_inputs_muting_the_rest() {
+        let gains = gains(&["Mic", "Game", "Music"]);
+        let now = Instant::now();
+        let mut comparison = compare_mic_and_game(&gains, now).unwrap();
+        assert_eq!(muted(&gains), [false, true, true]);
+        assert!(!comparison.tick(now + Duration::from_secs(9)));
+        assert!(comparison.tick(now + Duration::from_secs(10)));
+        assert_eq!(muted(&gains), [true, false, true]);
+        assert!(comparison.tick(now + Duration::from_secs(20)));
+        assert_eq!(muted(&gains), [false, true, true]);
+    }
+
+    #[test]
+    fn comparison_restores_the_mutes_when_stopped_early() {
+        let gains = gains(&["Mic", "Game", "Music"]);
+        gains[2].1.set_muted(true);
+        let now = Instant::now();
+        let mut comparison = compare_mic_and_game(&gains, now).unwrap();
+        assert!(comparison.tick(now + Duration::from_secs(10)));
+        assert!(!comparison.tick(now + Duration::from_secs(15)));
+        comparison.stop();
+        assert_eq!(muted(&gains), [false, false, true]);
+    }
+
+    #[test]
+    fn comparison_restores_the_mutes_when_a_compared_input_goes_away() {
+        let mut gains = gains(&["Mic", "Game", "Music", "Chat"]);
+        gains[2].1.set_muted(true);
+        let now = Instant::now();
+        let mut comparison = compare_mic_and_game(&gains, now).unwrap();
+        assert_eq!(muted(&gains), [false, true, true, true]);
+
+        // Another input going away is only forgotten.
+        let chat = gains.pop().unwrap();
+        comparison.update(&gains).unwrap();
+        assert!(chat.1.muted());
+        assert_eq!(muted(&gains), [false, true, true]);
+
+        let game = gains.remove(1);
+        let err = comparison.update(&gains).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "stopped comparing, since \"Game\" has gone away"
+        );
+        assert_eq!(muted(&gains), [false, true]);
+        assert!(game.1.muted());
+    }
+
+    #[test]
+    fn comparison_refuses_an_input_there_isnt() {
+        let gains = gains(&["Mic", "Music"]);
+        let err = compare_mic_and_game(&gains, Instant::now()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "there's no input called \"Game\" to compare"
+        );
+        assert_eq!(muted(&gains), [false, false]);
+    }
+}
@@ -0,0 +1,106 @@
+//! Offline analysis of recorded blocks, which runs outside the audio callbacks.
+
+/// Averages interleaved frames down to a single channel.
+pub fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Finds where `template` occurs in `signal`, returning the offset into `signal` together with
+/// the normalised correlation there, which is close to 1 for a clean match.
+///
+/// The correlation at each offset is normalised by the energy of the stretch of `signal` it
+/// covers, so a loud but unrelated noise doesn't outscore a quiet copy of the template.
+pub fn find_template(template: &[f32], signal: &[f32]) -> Option<(usize, f32)> {
+    if template.is_empty() || signal.len() < template.len() {
+        return None;
+    }
+    let template_energy = template.iter().map(|sample| sample * sample).sum::<f32>();
+    if template_energy == 0.0 {
+        return None;
+    }
+
+    // The energy of `signal[offset..offset + template.len()]`, kept up to date as offset moves.
+    let mut window_energy = signal[..template.len()]
+        .iter()
+        .map(|sample| sample * sample)
+        .sum::<f32>();
+    let mut best: Option<(usize, f32)> = None;
+    for offset in 0..=signal.len() - template.len() {
+        if offset > 0 {
+            let left = signal[offset - 1];
+            let entered = signal[offset + template.len() - 1];
+            window_energy = (window_energy - left * left + entered * entered).max(0.0);
+        }
+        if window_energy <= f32::EPSILON {
+            continue;
+        }
+        let dot = template
+            .iter()
+            .zip(&signal[offset..])
+            .map(|(a, b)| a * b)
+            .sum::<f32>();
+        let correlation = dot / (template_energy * window_energy).sqrt();
+        if best.is_none_or(|(_, best)| correlation > best) {
+            best = Some((offset, correlation));
+        }
+    }
+    best
+}
+
+/// Finds the offset of `b` relative to `a`, within `max_lag` samples either way, at which the two
+/// signals correlate most strongly, regardless of sign.
+///
+/// Returns the offset (positive when `b` lags behind `a`) together with the normalised correlation
+/// at that offset, which is negative when the signals are out of phase.
+pub fn best_alignment(a: &[f32], b: &[f32], max_lag: usize) -> (isize, f32) {
+    let energy = |signal: &[f32]| signal.iter().map(|sample| sample * sample).sum::<f32>();
+    let norm = (energy(a) * energy(b)).sqrt();
+    if norm == 0.0 {
+        return (0, 0.0);
+    }
+
+    let max_lag = max_lag.min(a.len().min(b.len()).saturating_sub(1)) as isize;
+    (-max_lag..=max_lag)
+        .map(|lag| (lag, correlation_at(a, b, lag) / norm))
+        .max_by(|(_, x), (_, y)| x.abs().total_cmp(&y.abs()))
+        .unwrap_or((0, 0.0))
+}
+
+/// Like [`best_alignment`], but fast enough for lags of a good fraction of a second: the signals
+/// are first aligned at a fraction of the rate, and then to the sample around that.
+pub fn wide_alignment(a: &[f32], b: &[f32], max_lag: usize) -> (isize, f32) {
+    const DECIMATION: usize = 8;
+    let decimate = |signal: &[f32]| {
+        signal
+            .chunks_exact(DECIMATION)
+            .map(|chunk| chunk.iter().sum::<f32>() / DECIMATION as f32)
+            .collect::<Vec<_>>()
+    };
+    let (coarse, _) = best_alignment(&decimate(a), &decimate(b), max_lag / DECIMATION);
+
+    let energy = |signal: &[f32]| signal.iter().map(|sample| sample * sample).sum::<f32>();
+    let norm = (energy(a) * energy(b)).sqrt();
+    if norm == 0.0 {
+        return (0, 0.0);
+    }
+    let longest = a.len().min(b.len()).saturating_sub(1) as isize;
+    let centre = coarse * DECIMATION as isize;
+    let reach = DECIMATION as isize;
+    ((centre - reach).max(-longest)..=(centre + reach).min(longest))
+        .map(|lag| (lag, correlation_at(a, b, lag) / norm))
+        .max_by(|(_, x), (_, y)| x.abs().total_cmp(&y.abs()))
+        .unwrap_or((0, 0.0))
+}
+
+/// The unnormalised correlation of `b` delayed by `lag` samples against `a`.
+fn correlation_at(a: &[f32], b: &[f32], lag: isize) -> f32 {
+    let (a, b) = if lag >= 0 {
+        (a, &b[lag as usize..])
+    } else {
+        (&a[lag.unsigned_abs()..], b)
+    };
+    a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>()
+}
@@ -0,0 +1,215 @@
+//! RNNoise-style noise suppression, using the `nnnoiseless` port of the model.
+//!
+//! The model only works on 480 frame blocks of 48 kHz audio, so the input is collected into blocks
+//! per channel and the processed output trails the input by exactly one block.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use nnnoiseless::DenoiseState;
+
+use crate::chain::{CpuUsage, ProcessStage, StageKind};
+
+/// The only sample rate the model was trained for.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// The latency, in frames, added by collecting input into blocks the model can process.
+pub const LATENCY_FRAMES: usize = DenoiseState::FRAME_SIZE;
+
+/// The model expects samples scaled like 16-bit integers rather than to `[-1.0, 1.0]`.
+const SCALE: f32 = i16::MAX as f32;
+
+struct Channel {
+    state: Box<DenoiseState<'static>>,
+    input: [f32; DenoiseState::FRAME_SIZE],
+    /// The block that was last handed to the model, kept around as the dry signal so it lines up
+    /// with the processed output.
+    dry: [f32; DenoiseState::FRAME_SIZE],
+    wet: [f32; DenoiseState::FRAME_SIZE],
+}
+
+pub struct Denoiser {
+    channels: Vec<Channel>,
+    /// Position inside the current block.
+    frame: usize,
+    /// The channel the next interleaved sample belongs to.
+    channel: usize,
+    mix: f32,
+    cpu: Arc<CpuUsage>,
+}
+
+impl Denoiser {
+    /// Creates a denoiser for `channels` interleaved channels, mixing `mix` of the denoised signal
+    /// with `1.0 - mix` of the original.
+    pub fn new(channels: usize, mix: f32) -> Self {
+        Denoiser {
+            channels: (0..channels)
+                .map(|_| Channel {
+                    state: DenoiseState::new(),
+                    input: [0.0; DenoiseState::FRAME_SIZE],
+                    dry: [0.0; DenoiseState::FRAME_SIZE],
+                    wet: [0.0; DenoiseState::FRAME_SIZE],
+                })
+                .collect(),
+            frame: 0,
+            channel: 0,
+            mix,
+            cpu: Arc::new(CpuUsage::default()),
+        }
+    }
+
+    /// A handle on the time spent processing, which stays valid after the denoiser has been moved
+    /// into an audio callback.
+    pub fn cpu_usage(&self) -> Arc<CpuUsage> {
+        Arc::clone(&self.cpu)
+    }
+
+    /// Denoises interleaved `samples` in place, delayed by `LATENCY_FRAMES`.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let start = Instant::now();
+
+        for sample in samples.iter_mut() {
+            let channel = &mut self.channels[self.channel];
+            let input = *sample;
+            *sample = self.mix * channel.wet[self.frame] / SCALE
+                + (1.0 - self.mix) * channel.dry[self.frame];
+            channel.input[self.frame] = input;
+
+            self.channel += 1;
+            if self.channel == self.channels.len() {
+                self.channel = 0;
+                self.frame += 1;
+                if self.frame == DenoiseState::FRAME_SIZE {
+                    self.frame = 0;
+                    for channel in &mut self.channels {
+                        channel.dry = channel.input;
+                        for sample in &mut channel.input {
+                            *sample *= SCALE;
+                        }
+                        channel
+                            .state
+                            .process_frame(&mut channel.wet, &channel.input);
+                    }
+                }
+            }
+        }
+
+        self.cpu
+            .record(start.elapsed().as_nanos() as u64, samples.len() as u64);
+    }
+
+    /// Denoises `frames` frames of each channel, one channel after another, in place. This has to
+    /// start on a whole frame, as it always does after whole frames of interleaved audio.
+    pub fn process_planar(&mut self, planes: &mut [f32], frames: usize) {
+        debug_assert_eq!(self.channel, 0);
+        let start = Instant::now();
+
+        // Go up to the end of a block at a time, so every channel's block fills up together.
+        let mut done = 0;
+        while done < frames {
+            let len = (DenoiseState::FRAME_SIZE - self.frame).min(frames - done);
+            for (c, channel) in self.channels.iter_mut().enumerate() {
+                let plane = &mut planes[c * frames + done..c * frames + done + len];
+                for (i, sample) in plane.iter_mut().enumerate() {
+                    let frame = self.frame + i;
+                    let input = *sample;
+                    *sample = self.mix * channel.wet[frame] / SCALE
+                        + (1.0 - self.mix) * channel.dry[frame];
+                    channel.input[frame] = input;
+                }
+            }
+            self.frame += len;
+            done += len;
+            if self.frame == DenoiseState::FRAME_SIZE {
+                self.frame = 0;
+                for channel in &mut self.channels {
+                    channel.dry = channel.input;
+                    for sample in &mut channel.input {
+                        *sample *= SCALE;
+                    }
+                    channel
+                        .state
+                        .process_frame(&mut channel.wet, &channel.input);
+                }
+            }
+        }
+
+        self.cpu.record(
+            start.elapsed().as_nanos() as u64,
+            (frames * self.channels.len()) as u64,
+        );
+    }
+}
+
+impl ProcessStage for Denoiser {
+    fn kind(&self) -> StageKind {
+        StageKind::Denoise
+    }
+
+    fn process(&mut self, frames: &mut [f32], channels: usize) {
+        debug_assert_eq!(channels, self.channels.len());
+        Denoiser::process(self, frames);
+    }
+
+    fn process_planar(&mut self, planes: &mut [f32], frames: usize) {
+        Denoiser::process_planar(self, planes, frames);
+    }
+
+    fn latency_frames(&self) -> usize {
+        LATENCY_FRAMES
+    }
+
+    fn reset(&mut self) {
+        for channel in &mut self.channels {
+            channel.state = DenoiseState::new();
+            channel.input = [0.0; DenoiseState::FRAME_SIZE];
+            channel.dry = [0.0; DenoiseState::FRAME_SIZE];
+            channel.wet = [0.0; DenoiseState::FRAME_SIZE];
+        }
+        self.frame = 0;
+        self.channel = 0;
+    }
+
+    fn memory_bytes(&self) -> usize {
+        let state = std::mem::size_of::<DenoiseState<'static>>();
+        self.channels.len() * (state + std::mem::size_of::<Channel>())
+    }
+
+    fn describe(&self) -> String {
+        format!("denoise (mix {})", self.mix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A denoiser that has been run on a few blocks of noise, enough of which gets through.
+    fn after_noise() -> Denoiser {
+        let mut denoiser = Denoiser::new(2, 1.0);
+        let mut random = 1u32;
+        let mut noise = (0..8 * LATENCY_FRAMES * 2)
+            .map(|_| {
+                random ^= random << 13;
+                random ^= random >> 17;
+                random ^= random << 5;
+                random as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect::<Vec<_>>();
+        denoiser.process(&mut noise);
+        denoiser
+    }
+
+    #[test]
+    fn reset_stage_is_silent_for_silent_input() {
+        let mut silence = vec![0.0; 4 * LATENCY_FRAMES * 2];
+        after_noise().process(&mut silence);
+        assert!(silence.iter().any(|&sample| sample != 0.0));
+
+        let mut denoiser = after_noise();
+        ProcessStage::reset(&mut denoiser);
+        let mut silence = vec![0.0; 4 * LATENCY_FRAMES * 2];
+        denoiser.process(&mut silence);
+        assert!(silence.iter().all(|&sample| sample == 0.0));
+    }
+}
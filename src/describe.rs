@@ -0,0 +1,484 @@
+//! What a running [`Pipeline`](crate::pipeline::Pipeline) is doing, resolved down to the devices'
+//! configs, for other programs to discover as JSON and for people to read at startup.
+//!
+//! Both come from the same [`PipelineDescription`], so they always agree. The JSON carries a
+//! `schema` number, which goes up whenever a field changes meaning or goes away.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::memory::format_bytes;
+use crate::pipeline::RingBufferSize;
+
+/// The version of the JSON layout.
+pub const SCHEMA: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PipelineDescription {
+    /// The audio host the devices were found through, like `CoreAudio`.
+    pub host: String,
+    pub sample_rate: u32,
+    /// The frames per callback asked of every device, unless each uses its default.
+    pub buffer_frames: Option<u32>,
+    /// Every device with a stream open, inputs first.
+    pub devices: Vec<DeviceDescription>,
+    /// The configured inputs, then the ones attached while running.
+    pub inputs: Vec<InputDescription>,
+    pub output: OutputDescription,
+    pub recording: RecordingDescription,
+    pub latency: LatencyDescription,
+    /// Everything allocated up front for the audio, as `--print-memory` breaks it down.
+    pub memory_bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceDescription {
+    pub name: String,
+    pub is_input: bool,
+    /// The channels its stream was opened with.
+    pub channels: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputDescription {
+    pub name: String,
+    pub device: String,
+    /// The device channels the input takes, counting from 0, or all of them if `None`.
+    pub channels: Option<Range<usize>>,
+    pub gain_db: f32,
+    pub muted: bool,
+    pub inverted: bool,
+    /// The stages of the input's chain, as `--print-chain` shows them.
+    pub chain: String,
+    pub chain_latency_frames: usize,
+    pub ring_buffer: RingBufferSize,
+    /// Whether the input's stream has been stopped, so it plays as silence.
+    pub stopped: bool,
+    /// Whether the input was added while running rather than configured.
+    pub attached: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputDescription {
+    pub device: String,
+    pub channels: u16,
+    /// How many of the channels, from the first, the inputs are mixed into.
+    pub used_channels: u16,
+    pub master_gain_db: f32,
+    pub master_muted: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordingDescription {
+    #[serde(serialize_with = "lossy_optional_path")]
+    pub output: Option<PathBuf>,
+    /// Where the output is also recorded to.
+    #[serde(serialize_with = "lossy_optional_path")]
+    pub mirror: Option<PathBuf>,
+    /// The inputs recorded before and after their chains.
+    pub ab_inputs: Vec<String>,
+    /// Whether the processed side is recorded before the inputs' gain, with its changes in the
+    /// sidecar, rather than after the whole chain.
+    pub ab_pre_fader: bool,
+    /// Counting from 0, which is the first set of recordings.
+    pub segment: usize,
+    pub cue_markers: bool,
+    #[serde(serialize_with = "lossy_path")]
+    pub marker_sidecar: PathBuf,
+    #[serde(rename = "replay_buffer_secs", with = "optional_secs")]
+    pub replay_buffer: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LatencyDescription {
+    /// The latency the ring buffers were prefilled with, which with `adaptive` is where it starts.
+    pub latency_ms: f32,
+    /// The bounds adaptive latency stays within, smallest first.
+    #[serde(with = "optional_bounds")]
+    pub adaptive: Option<(f32, f32)>,
+    /// The most latency any input's chain and resampling add, which the other inputs are delayed
+    /// to match.
+    pub max_chain_latency_frames: usize,
+    /// Everything the pipeline adds between the inputs and the output, not counting the devices'
+    /// own buffers.
+    pub total_ms: f32,
+}
+
+impl PipelineDescription {
+    /// The description as one line of JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&Versioned {
+            schema: SCHEMA,
+            description: self,
+        })
+        // Nothing in a description can fail to serialize: paths are written lossily.
+        .unwrap()
+    }
+
+    /// Reads a description back from [`PipelineDescription::to_json`], refusing other schemas.
+    pub fn from_json(json: &str) -> Result<Self, DescribeError> {
+        let versioned: Versioned<PipelineDescription> =
+            serde_json::from_str(json).map_err(DescribeError::Parse)?;
+        if versioned.schema != SCHEMA {
+            return Err(DescribeError::Schema(versioned.schema));
+        }
+        Ok(versioned.description)
+    }
+}
+
+/// Why JSON couldn't be read back as a [`PipelineDescription`].
+#[derive(Debug)]
+pub enum DescribeError {
+    Parse(serde_json::Error),
+    /// It's a description, but with a layout other than [`SCHEMA`].
+    Schema(u32),
+}
+
+impl fmt::Display for DescribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescribeError::Parse(_) => write!(f, "not a pipeline description"),
+            DescribeError::Schema(schema) => write!(
+                f,
+                "expected a description with schema {}, got schema {}",
+                SCHEMA, schema
+            ),
+        }
+    }
+}
+
+impl Error for DescribeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DescribeError::Parse(err) => Some(err),
+            DescribeError::Schema(_) => None,
+        }
+    }
+}
+
+/// A description next to the version of its layout.
+#[derive(Serialize, Deserialize)]
+struct Versioned<D> {
+    schema: u32,
+    #[serde(flatten)]
+    description: D,
+}
+
+fn lossy_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+fn lossy_optional_path<S: Serializer>(
+    path: &Option<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => serializer.serialize_some(&path.to_string_lossy()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A duration as a number of seconds, or `null`.
+mod optional_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration
+            .map(|duration| duration.as_secs_f32())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let secs = Option::<f32>::deserialize(deserializer)?;
+        Ok(secs.map(Duration::from_secs_f32))
+    }
+}
+
+/// Adaptive latency's bounds as `{"min_ms": .., "max_ms": ..}`, or `null`.
+mod optional_bounds {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Bounds {
+        min_ms: f32,
+        max_ms: f32,
+    }
+
+    pub fn serialize<S: Serializer>(
+        bounds: &Option<(f32, f32)>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bounds
+            .map(|(min_ms, max_ms)| Bounds { min_ms, max_ms })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<(f32, f32)>, D::Error> {
+        let bounds = Option::<Bounds>::deserialize(deserializer)?;
+        Ok(bounds.map(|bounds| (bounds.min_ms, bounds.max_ms)))
+    }
+}
+
+impl fmt::Display for PipelineDescription {
+    /// Several lines for people, with everything the JSON has that they'd want to check.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Mixing through {} at {} Hz", self.host, self.sample_rate)?;
+        match self.buffer_frames {
+            Some(frames) => writeln!(f, ", {} frames per callback:", frames)?,
+            None => writeln!(f, ", with each device's own buffer size:")?,
+        }
+        for device in &self.devices {
+            writeln!(
+                f,
+                "  {} device \"{}\" with {} channels",
+                if device.is_input { "Input" } else { "Output" },
+                device.name,
+                device.channels
+            )?;
+        }
+        for input in &self.inputs {
+            write!(f, "  Input \"{}\"", input.name)?;
+            if input.device != input.name || input.channels.is_some() {
+                write!(f, " from \"{}\"", input.device)?;
+            }
+            if let Some(channels) = &input.channels {
+                write!(f, " channels {}-{}", channels.start + 1, channels.end)?;
+            }
+            write!(f, " at {:+.1} dB", input.gain_db)?;
+            if input.muted {
+                write!(f, ", muted")?;
+            }
+            if input.inverted {
+                write!(f, ", inverted")?;
+            }
+            if input.stopped {
+                write!(f, ", stopped")?;
+            }
+            if input.attached {
+                write!(f, ", attached")?;
+            }
+            writeln!(
+                f,
+                ": {} ({} frames), ring buffer of {} frames prefilled with {}",
+                input.chain,
+                input.chain_latency_frames,
+                input.ring_buffer.capacity_frames,
+                input.ring_buffer.latency_frames
+            )?;
+        }
+        write!(
+            f,
+            "  Output \"{}\" with {} channels",
+            self.output.device, self.output.channels
+        )?;
+        if self.output.used_channels != self.output.channels {
+            write!(f, ", {} mixed into", self.output.used_channels)?;
+        }
+        write!(f, ", master at {:+.1} dB", self.output.master_gain_db)?;
+        writeln!(
+            f,
+            "{}",
+            if self.output.master_muted {
+                ", muted"
+            } else {
+                ""
+            }
+        )?;
+
+        let recording = &self.recording;
+        if let Some(path) = &recording.output {
+            write!(f, "  Recording the output to {}", path.display())?;
+            match &recording.mirror {
+                Some(mirror) => writeln!(f, " and {}", mirror.display())?,
+                None => writeln!(f)?,
+            }
+        }
+        if !recording.ab_inputs.is_empty() {
+            writeln!(
+                f,
+                "  Recording {} before and after their chains{}",
+                recording
+                    .ab_inputs
+                    .iter()
+                    .map(|input| format!("\"{}\"", input))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                if recording.ab_pre_fader {
+                    ", up to their gain"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        if recording.output.is_some() || !recording.ab_inputs.is_empty() {
+            writeln!(
+                f,
+                "  Segment {}, markers in {}{}",
+                recording.segment + 1,
+                recording.marker_sidecar.display(),
+                if recording.cue_markers {
+                    " and as cue points"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        if let Some(duration) = recording.replay_buffer {
+            writeln!(
+                f,
+                "  Keeping the last {}s of the output for clips",
+                duration.as_secs_f32()
+            )?;
+        }
+
+        let latency = &self.latency;
+        write!(f, "  Latency of {} ms", latency.latency_ms)?;
+        if let Some((min, max)) = latency.adaptive {
+            write!(f, " (adapting between {} and {} ms)", min, max)?;
+        }
+        writeln!(
+            f,
+            " plus {} frames of processing, {:.1} ms in all",
+            latency.max_chain_latency_frames, latency.total_ms
+        )?;
+        write!(
+            f,
+            "  {} preallocated for the audio",
+            format_bytes(self.memory_bytes)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A description with every optional part filled in, and names that need escaping.
+    fn description() -> PipelineDescription {
+        PipelineDescription {
+            host: "CoreAudio".to_owned(),
+            sample_rate: 48_000,
+            buffer_frames: Some(256),
+            devices: vec![
+                DeviceDescription {
+                    name: "Scarlett \"2i2\"".to_owned(),
+                    is_input: true,
+                    channels: 2,
+                },
+                DeviceDescription {
+                    name: "BlackHole 16ch".to_owned(),
+                    is_input: false,
+                    channels: 16,
+                },
+            ],
+            inputs: vec![InputDescription {
+                name: "Voice\n2".to_owned(),
+                device: "Scarlett \"2i2\"".to_owned(),
+                channels: Some(1..2),
+                gain_db: -3.5,
+                muted: false,
+                inverted: true,
+                chain: "gain -> reverb".to_owned(),
+                chain_latency_frames: 64,
+                ring_buffer: RingBufferSize {
+                    latency_frames: 7_200,
+                    capacity_frames: 14_400,
+                    channels: 1,
+                },
+                stopped: false,
+                attached: true,
+            }],
+            output: OutputDescription {
+                device: "BlackHole 16ch".to_owned(),
+                channels: 16,
+                used_channels: 2,
+                master_gain_db: 0.0,
+                master_muted: true,
+            },
+            recording: RecordingDescription {
+                output: Some(PathBuf::from("show.wav")),
+                mirror: Some(PathBuf::from("/Volumes/Backup/show.wav")),
+                ab_inputs: vec!["Voice\n2".to_owned()],
+                ab_pre_fader: true,
+                segment: 2,
+                cue_markers: true,
+                marker_sidecar: PathBuf::from("session.markers.json"),
+                replay_buffer: Some(Duration::from_secs(30)),
+            },
+            latency: LatencyDescription {
+                latency_ms: 150.0,
+                adaptive: Some((20.0, 300.0)),
+                max_chain_latency_frames: 64,
+                total_ms: 151.3,
+            },
+            memory_bytes: 1_048_576,
+        }
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let full = description();
+        assert_eq!(
+            PipelineDescription::from_json(&full.to_json()).unwrap(),
+            full
+        );
+
+        let mut bare = description();
+        bare.buffer_frames = None;
+        bare.inputs[0].channels = None;
+        bare.recording.output = None;
+        bare.recording.mirror = None;
+        bare.recording.replay_buffer = None;
+        bare.latency.adaptive = None;
+        assert_eq!(
+            PipelineDescription::from_json(&bare.to_json()).unwrap(),
+            bare
+        );
+    }
+
+    #[test]
+    fn lays_the_json_out_under_its_schema() {
+        let json = description().to_json();
+        assert!(!json.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["schema"], SCHEMA);
+        assert_eq!(value["inputs"][0]["name"], "Voice\n2");
+        assert_eq!(value["inputs"][0]["channels"]["start"], 1);
+        assert_eq!(value["inputs"][0]["ring_buffer"]["capacity_frames"], 14_400);
+        assert_eq!(value["recording"]["replay_buffer_secs"], 30.0);
+        assert_eq!(value["latency"]["adaptive"]["max_ms"], 300.0);
+
+        let newer = json.replace("\"schema\":1", "\"schema\":2");
+        assert_ne!(newer, json);
+        assert!(PipelineDescription::from_json(&newer).is_err());
+        assert!(PipelineDescription::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn prints_what_the_json_says_for_people() {
+        let printed = description().to_string();
+        let lines = printed.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "Mixing through CoreAudio at 48000 Hz, 256 frames per callback:"
+        );
+        assert!(lines.contains(
+            &"  Output \"BlackHole 16ch\" with 16 channels, 2 mixed into, master at +0.0 dB, muted"
+        ));
+        assert!(lines.contains(&"  Recording the output to show.wav and /Volumes/Backup/show.wav"));
+        assert!(lines.contains(&"  Latency of 150 ms (adapting between 20 and 300 ms) plus 64 frames of processing, 151.3 ms in all"));
+        assert_eq!(lines.last(), Some(&"  1.0 MB preallocated for the audio"));
+    }
+}
@@ -0,0 +1,401 @@
+//! Remembers devices by the identifiers backends keep for them, so a config keeps working when a
+//! device's name changes with the OS's language or a firmware update.
+//!
+//! Each device is recorded under the name it's configured by, with its ID and the name it last
+//! had, the first time it's used. From then on the device with that ID is used whatever it's
+//! called, and one only found by name is used with a warning that its ID has changed. With
+//! backends that have no IDs, devices are only ever found by name, the same as without this.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::DeviceInfo;
+use crate::pipeline::PipelineConfig;
+
+/// The devices recorded so far, as kept in the state file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceIds {
+    devices: Vec<KnownDevice>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct KnownDevice {
+    /// The name the config gives it.
+    configured: String,
+    is_input: bool,
+    /// What it was called when last found.
+    name: String,
+    id: String,
+}
+
+/// Where a configured device was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    /// The name to open it by.
+    pub name: String,
+    /// Anything worth warning about how it was found.
+    pub warning: Option<String>,
+}
+
+impl DeviceIds {
+    /// Reads the state file at `path`, which doesn't need to exist yet.
+    pub fn load(path: &Path) -> Result<Self, DeviceIdsError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(DeviceIds::default()),
+            Err(source) => {
+                return Err(DeviceIdsError::Read {
+                    path: path.to_owned(),
+                    source,
+                })
+            }
+        };
+        serde_json::from_str(&text).map_err(|source| DeviceIdsError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), DeviceIdsError> {
+        let json = serde_json::to_string_pretty(self).expect("the device IDs serialize");
+        std::fs::write(path, json + "\n").map_err(|source| DeviceIdsError::Write {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Finds the device configured as `configured` among `devices`, by its ID if one was recorded
+    /// for it and then by name, and records its ID if the backend has one.
+    pub fn resolve(
+        &mut self,
+        configured: &str,
+        is_input: bool,
+        devices: &[DeviceInfo],
+    ) -> Resolved {
+        let fits = |device: &&DeviceInfo| {
+            if is_input {
+                device.is_input
+            } else {
+                device.is_output
+            }
+        };
+        let known = self
+            .devices
+            .iter()
+            .position(|known| known.configured == configured && known.is_input == is_input);
+        if let Some(index) = known {
+            let known = &mut self.devices[index];
+            if let Some(device) = devices
+                .iter()
+                .filter(fits)
+                .find(|device| device.stable_id.as_deref() == Some(known.id.as_str()))
+            {
+                known.name = device.name.clone();
+                return Resolved {
+                    name: device.name.clone(),
+                    warning: (device.name != configured).then(|| {
+                        format!(
+                            "\"{}\" is called \"{}\" now, and was found by its ID",
+                            configured, device.name
+                        )
+                    }),
+                };
+            }
+        }
+
+        let Some(device) = devices
+            .iter()
+            .filter(fits)
+            .find(|device| device.name == configured)
+        else {
+            // Left to fail as a missing device once it's opened.
+            return Resolved {
+                name: configured.to_owned(),
+                warning: None,
+            };
+        };
+        let warning = known.map(|index| {
+            let recorded = &self.devices[index].id;
+            match &device.stable_id {
+                Some(id) => format!(
+                    "\"{}\" was only found by name, as its ID has changed from \"{}\" to \"{}\"",
+                    configured, recorded, id
+                ),
+                None => format!(
+                    "\"{}\" was only found by name, as it no longer has the ID \"{}\"",
+                    configured, recorded
+                ),
+            }
+        });
+        if let Some(id) = &device.stable_id {
+            let device = KnownDevice {
+                configured: configured.to_owned(),
+                is_input,
+                name: device.name.clone(),
+                id: id.clone(),
+            };
+            match known {
+                Some(index) => self.devices[index] = device,
+                None => self.devices.push(device),
+            }
+        }
+        Resolved {
+            name: device.name.clone(),
+            warning,
+        }
+    }
+
+    /// Resolves every device `config` uses in place, returning the warnings about how they were
+    /// found.
+    pub fn apply(&mut self, config: &mut PipelineConfig, devices: &[DeviceInfo]) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut resolve = |device: &mut String, is_input| {
+            let resolved = self.resolve(device, is_input, devices);
+            // A device split into several inputs is only warned about once.
+            if let Some(warning) = resolved.warning {
+                if !warnings.contains(&warning) {
+                    warnings.push(warning);
+                }
+            }
+            *device = resolved.name;
+        };
+        for input in &mut config.inputs {
+            resolve(&mut input.device, true);
+        }
+        resolve(&mut config.output, false);
+        warnings
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceIdsError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    Write {
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+impl fmt::Display for DeviceIdsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceIdsError::Read { path, .. } => write!(f, "couldn't read {}", path.display()),
+            DeviceIdsError::Parse { path, .. } => {
+                write!(f, "{} isn't a file of device IDs", path.display())
+            }
+            DeviceIdsError::Write { path, .. } => write!(f, "couldn't write {}", path.display()),
+        }
+    }
+}
+
+impl Error for DeviceIdsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeviceIdsError::Read { source, .. } | DeviceIdsError::Write { source, .. } => {
+                Some(source)
+            }
+            DeviceIdsError::Parse { source, .. } => Some(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{FakeProvider, Signal};
+    use crate::backend::{DeviceProvider, StreamConfig};
+    use crate::pipeline::{InputConfig, Pipeline, MARKER_SIDECAR};
+    use crate::recorder::SyncPolicy;
+    use crate::resample::Quality;
+    use std::time::Duration;
+
+    fn provider() -> FakeProvider {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(256),
+        };
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", config.clone(), Signal::Silence)
+            .add_input("USB Audio", config.clone(), Signal::Silence)
+            .add_output("Speakers", config);
+        provider.set_stable_id("Mic", "BuiltInMicrophoneDevice");
+        provider.set_stable_id("USB Audio", "AppleUSBAudioEngine:1");
+        provider.set_stable_id("Speakers", "BuiltInSpeakerDevice");
+        provider
+    }
+
+    fn pipeline_config() -> PipelineConfig {
+        PipelineConfig {
+            inputs: vec![InputConfig::new("Mic")],
+            output: "Speakers".to_owned(),
+            latency_ms: 20.0,
+            ringbuf_ms: None,
+            denoise_mix: 1.0,
+            segment: 0,
+            cue_markers: false,
+            negotiate: false,
+            output_channels: None,
+            adaptive_latency: None,
+            replay_buffer: None,
+            planar: false,
+            record_output: None,
+            record_mirror: None,
+            record_sync: SyncPolicy::default(),
+            record_ceiling: None,
+            record_continue: None,
+            stems_pre_fader: false,
+            rt_priority: false,
+            preroll: Duration::ZERO,
+            marker_sidecar: PathBuf::from(MARKER_SIDECAR),
+            record_dir: PathBuf::new(),
+            strict_routing: false,
+            resample_quality: Quality::default(),
+        }
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "loopback-device-ids-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn records_each_device_by_its_id_the_first_time_and_keeps_them() {
+        let provider = provider();
+        let mut ids = DeviceIds::default();
+        let mut config = pipeline_config();
+        assert!(ids
+            .apply(&mut config, &provider.devices().unwrap())
+            .is_empty());
+        assert_eq!(ids.devices.len(), 2);
+        assert_eq!(ids.devices[0].id, "BuiltInMicrophoneDevice");
+        assert_eq!(ids.devices[1].id, "BuiltInSpeakerDevice");
+
+        let path = state_path("round-trip");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(DeviceIds::load(&path).unwrap(), DeviceIds::default());
+        ids.save(&path).unwrap();
+        let loaded = DeviceIds::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), ids);
+    }
+
+    #[test]
+    fn finds_a_renamed_device_by_its_id() {
+        let provider = provider();
+        let mut ids = DeviceIds::default();
+        ids.apply(&mut pipeline_config(), &provider.devices().unwrap());
+
+        provider.rename("Mic", "Mikrofon");
+        let mut config = pipeline_config();
+        let warnings = ids.apply(&mut config, &provider.devices().unwrap());
+        assert_eq!(
+            warnings,
+            ["\"Mic\" is called \"Mikrofon\" now, and was found by its ID"]
+        );
+        assert_eq!(config.inputs[0].device, "Mikrofon");
+        // The input keeps the name it's known by everywhere else.
+        assert_eq!(config.inputs[0].name, "Mic");
+        assert_eq!(config.output, "Speakers");
+        assert!(Pipeline::build(&provider, &config).is_ok());
+    }
+
+    #[test]
+    fn prefers_the_id_over_a_device_that_took_the_name() {
+        let provider = provider();
+        let mut ids = DeviceIds::default();
+        ids.apply(&mut pipeline_config(), &provider.devices().unwrap());
+
+        provider.rename("Mic", "Built-in Microphone");
+        provider.rename("USB Audio", "Mic");
+        let mut config = pipeline_config();
+        ids.apply(&mut config, &provider.devices().unwrap());
+        assert_eq!(config.inputs[0].device, "Built-in Microphone");
+    }
+
+    #[test]
+    fn falls_back_on_the_name_with_a_warning_when_the_id_changed() {
+        let provider = provider();
+        let mut ids = DeviceIds::default();
+        ids.apply(&mut pipeline_config(), &provider.devices().unwrap());
+
+        provider.set_stable_id("Mic", "AppleUSBAudioEngine:2");
+        let mut config = pipeline_config();
+        let warnings = ids.apply(&mut config, &provider.devices().unwrap());
+        assert_eq!(
+            warnings,
+            [
+                "\"Mic\" was only found by name, as its ID has changed from \
+                 \"BuiltInMicrophoneDevice\" to \"AppleUSBAudioEngine:2\""
+            ]
+        );
+        assert_eq!(config.inputs[0].device, "Mic");
+        // The new ID is the one to go on from now.
+        assert!(ids
+            .apply(&mut config, &provider.devices().unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn matches_by_name_alone_where_the_backend_has_no_ids() {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(256),
+        };
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", config.clone(), Signal::Silence)
+            .add_output("Speakers", config);
+        let mut ids = DeviceIds::default();
+        let mut config = pipeline_config();
+        assert!(ids
+            .apply(&mut config, &provider.devices().unwrap())
+            .is_empty());
+        assert_eq!(ids, DeviceIds::default());
+        assert_eq!(config.inputs[0].device, "Mic");
+    }
+
+    #[test]
+    fn leaves_a_missing_device_to_fail_when_opened() {
+        let provider = provider();
+        let mut ids = DeviceIds::default();
+        let resolved = ids.resolve("Headset", true, &provider.devices().unwrap());
+        assert_eq!(
+            resolved,
+            Resolved {
+                name: "Headset".to_owned(),
+                warning: None
+            }
+        );
+        // Only outputs are looked for as outputs.
+        let resolved = ids.resolve("Mic", false, &provider.devices().unwrap());
+        assert_eq!(resolved.name, "Mic");
+        assert_eq!(ids, DeviceIds::default());
+    }
+
+    #[test]
+    fn refuses_a_state_file_that_isnt_one() {
+        let path = state_path("garbage");
+        std::fs::write(&path, "not json").unwrap();
+        let err = DeviceIds::load(&path).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(err, DeviceIdsError::Parse { .. }));
+        assert!(err.source().is_some());
+    }
+}
@@ -0,0 +1,436 @@
+//! One thread that lists the devices for everything that needs to know which there are.
+//!
+//! Listing devices isn't free, and on macOS doing it too often causes audible hiccups in other
+//! programs, so nothing lists them on its own. [`DeviceService::devices`] hands out a listing
+//! from the last moment or so, with every caller waiting at once sharing the same new one if it's
+//! stale. While anything is [watching](DeviceService::watch), the devices are listed again and
+//! again, less and less often while nothing changes, and the watchers are told whenever they do.
+//! [`WithDeviceService`] puts a provider's lookups behind the same listing.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::backend::{BackendError, DeviceInfo, DeviceProvider, InputSource, OutputSink};
+use crate::error;
+
+/// How long a listing is handed out for before it's stale.
+const CACHE_TTL: Duration = Duration::from_millis(500);
+/// How soon the devices are listed again while watched, right after they've changed.
+const MIN_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the wait between listings can grow to while nothing changes.
+const MAX_INTERVAL: Duration = Duration::from_secs(4);
+
+/// Lists every device currently available, inputs first.
+pub type ListDevices = Box<dyn FnMut() -> Result<Vec<DeviceInfo>, BackendError> + Send + 'static>;
+
+/// A delay that doubles each time it's taken, up to a cap, and starts over once reset. Each delay
+/// is moved up to a quarter either way at random, so things backing off together spread out.
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+    random: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        Backoff {
+            min,
+            max,
+            next: min,
+            // Xorshift never leaves 0.
+            random: nanos | 1,
+        }
+    }
+
+    /// The next delay, with the one after it twice as long.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        let jitter = self.random as f64 / u32::MAX as f64 * 0.5 + 0.75;
+        delay.mul_f64(jitter)
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+/// Owns the thread that lists the devices, which stops once this is dropped.
+pub struct DeviceService {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever there's something for the thread to do or a new listing.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// The latest listing that worked, and when it was made.
+    devices: Option<(Arc<Vec<DeviceInfo>>, Instant)>,
+    /// Why the latest listing failed, if it did.
+    error: Option<String>,
+    /// How many listings have been made, whether or not they worked.
+    listings: u64,
+    /// How many listings have differed from the one before.
+    version: u64,
+    /// Whether a caller is waiting on a new listing.
+    wanted: bool,
+    watchers: usize,
+    stop: bool,
+}
+
+impl DeviceService {
+    pub fn spawn(mut list: ListDevices) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        std::thread::Builder::new()
+            .name("devices".to_owned())
+            .spawn({
+                let shared = Arc::clone(&shared);
+                move || run(&shared, &mut list)
+            })
+            .expect("couldn't start the thread listing the devices");
+        DeviceService { shared }
+    }
+
+    /// The devices as listed at most [`CACHE_TTL`] ago, waiting for them to be listed again first
+    /// if they haven't been.
+    pub fn devices(&self) -> Result<Arc<Vec<DeviceInfo>>, BackendError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some((devices, at)) = &state.devices {
+            if at.elapsed() < CACHE_TTL {
+                return Ok(Arc::clone(devices));
+            }
+        }
+        let listings = state.listings;
+        state.wanted = true;
+        self.shared.changed.notify_all();
+        let state = self
+            .shared
+            .changed
+            .wait_while(state, |state| state.listings == listings)
+            .unwrap();
+        match (&state.error, &state.devices) {
+            (Some(error), _) => Err(BackendError::failed(error.clone())),
+            (None, Some((devices, _))) => Ok(Arc::clone(devices)),
+            (None, None) => unreachable!("a listing either works or has an error"),
+        }
+    }
+
+    /// Starts listing the devices every so often, and reporting each listing that differs from
+    /// the one before, from the next on. The first listing after spawning counts as a change.
+    pub fn watch(&self) -> DeviceWatch {
+        let mut state = self.shared.state.lock().unwrap();
+        state.watchers += 1;
+        self.shared.changed.notify_all();
+        DeviceWatch {
+            shared: Arc::clone(&self.shared),
+            seen: state.version,
+        }
+    }
+}
+
+impl Drop for DeviceService {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Hears about the devices changing, for as long as it's kept.
+pub struct DeviceWatch {
+    shared: Arc<Shared>,
+    /// The version of the last listing reported.
+    seen: u64,
+}
+
+impl DeviceWatch {
+    /// Waits up to `timeout` for the devices to change, returning them if they have.
+    pub fn changed(&mut self, timeout: Duration) -> Option<Arc<Vec<DeviceInfo>>> {
+        let state = self.shared.state.lock().unwrap();
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |state| {
+                state.version == self.seen && !state.stop
+            })
+            .unwrap();
+        if state.version == self.seen {
+            return None;
+        }
+        self.seen = state.version;
+        state
+            .devices
+            .as_ref()
+            .map(|(devices, _)| Arc::clone(devices))
+    }
+}
+
+impl Drop for DeviceWatch {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().watchers -= 1;
+    }
+}
+
+/// Opens devices through the wrapped provider, but only ones the service's listing has, and
+/// lists them from that too, so a lookup of a device that's gone lists nothing of its own.
+pub struct WithDeviceService<'a, P>(pub &'a DeviceService, pub P);
+
+impl<P: DeviceProvider> WithDeviceService<'_, P> {
+    fn check(&self, name: &str, is_input: bool) -> Result<(), BackendError> {
+        let listed = self.0.devices()?.iter().any(|device| {
+            device.name == name
+                && if is_input {
+                    device.is_input
+                } else {
+                    device.is_output
+                }
+        });
+        if !listed {
+            return Err(BackendError::DeviceNotFound(name.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+impl<P: DeviceProvider> DeviceProvider for WithDeviceService<'_, P> {
+    fn input_device(&self, name: &str) -> Result<Box<dyn InputSource>, BackendError> {
+        self.check(name, true)?;
+        self.1.input_device(name)
+    }
+
+    fn output_device(&self, name: &str) -> Result<Box<dyn OutputSink>, BackendError> {
+        self.check(name, false)?;
+        self.1.output_device(name)
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        Ok(self.0.devices()?.as_ref().clone())
+    }
+}
+
+fn run(shared: &Shared, list: &mut ListDevices) {
+    let mut backoff = Backoff::new(MIN_INTERVAL, MAX_INTERVAL);
+    // When to list the devices again, while they're watched.
+    let mut next = Instant::now();
+    loop {
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.stop {
+                return;
+            }
+            let now = Instant::now();
+            if state.wanted || (state.watchers > 0 && now >= next) {
+                break;
+            }
+            state = if state.watchers > 0 {
+                shared.changed.wait_timeout(state, next - now).unwrap().0
+            } else {
+                shared.changed.wait(state).unwrap()
+            };
+        }
+        drop(state);
+
+        // Listing can be slow, so callers can still get at the last listing meanwhile.
+        let listed = list();
+        let mut state = shared.state.lock().unwrap();
+        state.listings += 1;
+        state.wanted = false;
+        match listed {
+            Ok(devices) => {
+                let changed = state
+                    .devices
+                    .as_ref()
+                    .is_none_or(|(previous, _)| **previous != devices);
+                if changed {
+                    state.version += 1;
+                    backoff.reset();
+                }
+                state.devices = Some((Arc::new(devices), Instant::now()));
+                state.error = None;
+            }
+            Err(err) => {
+                let error = error::with_causes(&err);
+                log::warn!("couldn't list the devices: {}", error);
+                state.error = Some(error);
+            }
+        }
+        next = Instant::now() + backoff.next_delay();
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::FakeProvider;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    fn device(name: &str) -> DeviceInfo {
+        DeviceInfo {
+            name: name.to_owned(),
+            is_input: true,
+            is_output: false,
+            stable_id: None,
+        }
+    }
+
+    /// A service whose listings take `delay`, counting how many it's made in `listings`.
+    fn counted(delay: Duration, devices: Vec<DeviceInfo>) -> (DeviceService, Arc<AtomicUsize>) {
+        let listings = Arc::new(AtomicUsize::new(0));
+        let service = DeviceService::spawn(Box::new({
+            let listings = Arc::clone(&listings);
+            move || {
+                listings.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(delay);
+                Ok(devices.clone())
+            }
+        }));
+        (service, listings)
+    }
+
+    #[test]
+    fn backs_off_twice_as_long_each_time_while_jittering() {
+        let min = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+        let mut backoff = Backoff::new(min, max);
+        let taken = (0..8).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        let expected = [100, 200, 400, 800, 1_000, 1_000, 1_000, 1_000];
+        for (delay, expected) in taken.iter().zip(expected) {
+            let expected = Duration::from_millis(expected);
+            assert!(
+                expected.mul_f64(0.75) <= *delay && *delay <= expected.mul_f64(1.25),
+                "{:?}",
+                taken
+            );
+        }
+        // Moved about at random, rather than all by the same amount.
+        let secs = taken[4..]
+            .iter()
+            .map(|delay| delay.as_secs_f64())
+            .collect::<Vec<_>>();
+        assert!(
+            secs.windows(2).any(|pair| pair[0] != pair[1]),
+            "{:?}",
+            taken
+        );
+
+        backoff.reset();
+        let delay = backoff.next_delay();
+        assert!(min.mul_f64(0.75) <= delay && delay <= min.mul_f64(1.25));
+    }
+
+    #[test]
+    fn satisfies_everyone_waiting_at_once_with_one_listing() {
+        let (service, listings) = counted(Duration::from_millis(100), vec![device("Mic")]);
+        let barrier = Barrier::new(8);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    assert_eq!(*service.devices().unwrap(), [device("Mic")]);
+                });
+            }
+        });
+        assert_eq!(listings.load(Ordering::SeqCst), 1);
+
+        // Still fresh, so handed out again without listing.
+        service.devices().unwrap();
+        assert_eq!(listings.load(Ordering::SeqCst), 1);
+        std::thread::sleep(CACHE_TTL);
+        service.devices().unwrap();
+        assert_eq!(listings.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn lists_less_often_while_watched_and_nothing_changes() {
+        let (service, listings) = counted(Duration::ZERO, vec![device("Mic")]);
+        let mut watch = service.watch();
+        assert_eq!(
+            watch.changed(Duration::from_secs(1)).as_deref(),
+            Some(&vec![device("Mic")])
+        );
+        // Listed right away, then after about 0.5, 1 and 2 seconds, and nothing changed.
+        let started = Instant::now();
+        assert_eq!(watch.changed(Duration::from_millis(3_200)), None);
+        assert!(started.elapsed() >= Duration::from_millis(3_200));
+        let listed = listings.load(Ordering::SeqCst);
+        assert!((3..=5).contains(&listed), "{}", listed);
+
+        drop(watch);
+        std::thread::sleep(Duration::from_millis(100));
+        let listed = listings.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_secs(3));
+        assert_eq!(listings.load(Ordering::SeqCst), listed);
+    }
+
+    #[test]
+    fn tells_watchers_when_the_devices_change() {
+        let listings = Arc::new(AtomicUsize::new(0));
+        let service = DeviceService::spawn(Box::new({
+            let listings = Arc::clone(&listings);
+            move || {
+                // A second mic is plugged in from the third listing on.
+                let mut devices = vec![device("Mic")];
+                if listings.fetch_add(1, Ordering::SeqCst) >= 2 {
+                    devices.push(device("USB Mic"));
+                }
+                Ok(devices)
+            }
+        }));
+        let mut first = service.watch();
+        let mut second = service.watch();
+        for watch in [&mut first, &mut second] {
+            assert_eq!(watch.changed(Duration::from_secs(1)).unwrap().len(), 1);
+        }
+        for watch in [&mut first, &mut second] {
+            assert_eq!(
+                *watch.changed(Duration::from_secs(3)).unwrap(),
+                [device("Mic"), device("USB Mic")]
+            );
+        }
+    }
+
+    #[test]
+    fn only_opens_devices_the_listing_has() {
+        let fake = FakeProvider::new();
+        let config = cpal::StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        fake.add_input("Mic", config.clone(), crate::backend::fake::Signal::Silence);
+        fake.add_output("Speakers", config);
+        let service = DeviceService::spawn(Box::new({
+            let fake = fake.clone();
+            move || fake.devices()
+        }));
+        let provider = WithDeviceService(&service, fake);
+
+        assert_eq!(provider.input_device("Mic").unwrap().name(), "Mic");
+        assert_eq!(
+            provider.output_device("Speakers").unwrap().name(),
+            "Speakers"
+        );
+        let missing = provider.input_device("Speakers").err().unwrap();
+        assert_eq!(missing.to_string(), "couldn't find device \"Speakers\"");
+        assert!(provider.output_device("Mic").is_err());
+        assert_eq!(provider.devices().unwrap().len(), 2);
+    }
+}
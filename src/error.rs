@@ -0,0 +1,353 @@
+//! What can go wrong building and running a [`Pipeline`](crate::pipeline::Pipeline), in enough
+//! detail for a program embedding it to react differently to each, like asking for another
+//! device when one is missing but falling back to other settings when a format isn't supported.
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::negotiate::{ConfigRange, Plan};
+
+/// A failure from one of the layers underneath the pipeline, like a device backend.
+pub type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    /// The pipeline was asked for something that can't work whatever the devices, like two inputs
+    /// with the same name.
+    Invalid(String),
+    /// There's no device called `wanted`. `available` lists the ones there are, going the same
+    /// way.
+    DeviceNotFound {
+        wanted: String,
+        available: Vec<String>,
+        is_input: bool,
+    },
+    /// A device is there, but finding it or asking what it supports failed.
+    DeviceQuery {
+        device: String,
+        source: BoxError,
+    },
+    /// A device can't do what the pipeline needs of it.
+    ConfigNotSupported {
+        device: String,
+        requested: String,
+        supported: Vec<ConfigRange>,
+    },
+    /// The devices have no sample rate in common, and there's nothing to resample with.
+    NoCommonSampleRate {
+        plan: Plan,
+    },
+    StreamBuild {
+        device: String,
+        source: BoxError,
+    },
+    /// The output's stream couldn't be built because, going by what the backend said, another
+    /// application has the device to itself.
+    OutputBusy {
+        device: String,
+        source: BoxError,
+    },
+    StreamStart {
+        device: String,
+        source: BoxError,
+    },
+    /// These input devices were started but didn't deliver any audio in time.
+    StartTimeout {
+        devices: Vec<String>,
+        timeout: Duration,
+    },
+    /// The recordings or replay buffer couldn't be set up.
+    Recording {
+        source: BoxError,
+    },
+}
+
+impl PipelineError {
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        PipelineError::Invalid(message.into())
+    }
+}
+
+/// A value that couldn't be parsed, like an argument or a typed command, saying what was expected
+/// instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    message: String,
+}
+
+impl ParseError {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        ParseError {
+            message: message.into(),
+        }
+    }
+
+    /// Puts what was being parsed in front of the message.
+    pub(crate) fn context(self, context: impl fmt::Display) -> Self {
+        ParseError::new(format!("{}: {}", context, self.message))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// A [`ParseError`] with a message formatted like `format!`.
+macro_rules! parse_error {
+    ($($arg:tt)*) => {
+        $crate::error::ParseError::new(format!($($arg)*))
+    };
+}
+pub(crate) use parse_error;
+
+/// `error` and all of its causes, each after a `: `, the way `{:#}` prints an `anyhow::Error`.
+pub(crate) fn with_causes(error: &(dyn Error + 'static)) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// What the backends say, lowercased, when a device is held exclusively: ALSA's `EBUSY`, WASAPI's
+/// `AUDCLNT_E_DEVICE_IN_USE` as text, hex and a signed number, and CoreAudio's
+/// `kAudioDevicePermissionsError`, which is `'!hog'`, as a four-character code or a number.
+const HELD_EXCLUSIVELY: &[&str] = &[
+    "device or resource busy",
+    "ebusy",
+    "audclnt_e_device_in_use",
+    "device is already in use",
+    "0x8889000a",
+    "-2004287478",
+    "exclusive mode",
+    "hog mode",
+    "!hog",
+    "560492391",
+];
+
+/// Whether a backend's error `message`, with all of its causes, says another application holds
+/// the device exclusively.
+pub fn held_exclusively(message: &str) -> bool {
+    let message = message.to_lowercase();
+    HELD_EXCLUSIVELY
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+/// Lists `names` as `"A", "B" and "C"`.
+fn quoted_list(names: &[String]) -> String {
+    let quoted = names
+        .iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect::<Vec<_>>();
+    match quoted.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Invalid(message) => f.write_str(message),
+            PipelineError::DeviceNotFound {
+                wanted,
+                available,
+                is_input,
+            } => {
+                let direction = if *is_input { "input" } else { "output" };
+                write!(f, "couldn't find {} device \"{}\"", direction, wanted)?;
+                if available.is_empty() {
+                    write!(f, ", and there are no {} devices at all", direction)
+                } else {
+                    write!(
+                        f,
+                        ", the {} devices are {}",
+                        direction,
+                        quoted_list(available)
+                    )
+                }
+            }
+            PipelineError::DeviceQuery { device, .. } => {
+                write!(f, "couldn't get the details of \"{}\"", device)
+            }
+            PipelineError::ConfigNotSupported {
+                device,
+                requested,
+                supported,
+            } => {
+                write!(f, "\"{}\" doesn't support {}", device, requested)?;
+                if supported.is_empty() {
+                    return f.write_str(", and doesn't say what it does support");
+                }
+                let supported = supported
+                    .iter()
+                    .map(|range| {
+                        if range.min_sample_rate == range.max_sample_rate {
+                            format!(
+                                "{} channels at {} Hz",
+                                range.channels, range.min_sample_rate
+                            )
+                        } else {
+                            format!(
+                                "{} channels at {} to {} Hz",
+                                range.channels, range.min_sample_rate, range.max_sample_rate
+                            )
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, ", only {}", supported.join(", "))
+            }
+            PipelineError::NoCommonSampleRate { plan } => write!(
+                f,
+                "{}, but only the 44.1 kHz and 48 kHz families of rates can be resampled to each \
+                 other: pick devices that share a sample rate",
+                plan
+            ),
+            PipelineError::StreamBuild { device, .. } => {
+                write!(f, "couldn't build a stream for \"{}\"", device)
+            }
+            PipelineError::OutputBusy { device, .. } => write!(
+                f,
+                "\"{}\" is in use exclusively by another application, most likely a DAW, a game \
+                 or a call app set to take exclusive control of it, or anything holding it in hog \
+                 mode on macOS: close that or turn its exclusive mode off, or wait for it with \
+                 `--wait-for-output-free <duration>`",
+                device
+            ),
+            PipelineError::StreamStart { device, .. } => {
+                write!(f, "couldn't start the stream for \"{}\"", device)
+            }
+            PipelineError::StartTimeout { devices, timeout } => write!(
+                f,
+                "{} didn't deliver any audio within {}s of starting",
+                quoted_list(devices),
+                timeout.as_secs_f32()
+            ),
+            PipelineError::Recording { .. } => f.write_str("couldn't start recording"),
+        }
+    }
+}
+
+impl Error for PipelineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PipelineError::DeviceQuery { source, .. }
+            | PipelineError::StreamBuild { source, .. }
+            | PipelineError::OutputBusy { source, .. }
+            | PipelineError::StreamStart { source, .. }
+            | PipelineError::Recording { source } => Some(&**source),
+            PipelineError::Invalid(_)
+            | PipelineError::DeviceNotFound { .. }
+            | PipelineError::ConfigNotSupported { .. }
+            | PipelineError::NoCommonSampleRate { .. }
+            | PipelineError::StartTimeout { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_devices_held_exclusively_by_each_backend() {
+        let held = [
+            // ALSA, through cpal, with the rest of the chain of causes around it.
+            "couldn't build the output stream: A backend-specific error has occurred: ALSA \
+             function 'snd_pcm_open' failed with error 'EBUSY: Device or resource busy'",
+            "A backend-specific error has occurred: ALSA function 'snd_pcm_open' failed with \
+             error 'Sys(EBUSY)'",
+            // WASAPI, as the constant, the system's message for it, its code or a signed number.
+            "A backend-specific error has occurred: AUDCLNT_E_DEVICE_IN_USE",
+            "A backend-specific error has occurred: The device is already in use. (0x8889000A)",
+            "A backend-specific error has occurred: HRESULT(-2004287478)",
+            "the device is opened in exclusive mode by another application",
+            // CoreAudio's kAudioDevicePermissionsError, as its code or its number.
+            "A backend-specific error has occurred: '!hog'",
+            "A backend-specific error has occurred: Unknown(560492391)",
+            "the device is in hog mode",
+        ];
+        for message in held {
+            assert!(held_exclusively(message), "{}", message);
+        }
+    }
+
+    #[test]
+    fn leaves_other_failures_alone() {
+        let not_held = [
+            "The requested stream configuration is not supported by the device.",
+            "The requested device is no longer available. For example, it has been unplugged.",
+            "A backend-specific error has occurred: ALSA function 'snd_pcm_hw_params' failed \
+             with error 'EINVAL: Invalid argument'",
+            // AUDCLNT_E_DEVICE_INVALIDATED, right next to the code for being in use.
+            "A backend-specific error has occurred: The audio endpoint device has been \
+             unplugged. (0x88890004)",
+            "A backend-specific error has occurred: Unknown(1852797029)",
+            "",
+        ];
+        for message in not_held {
+            assert!(!held_exclusively(message), "{}", message);
+        }
+    }
+
+    #[test]
+    fn names_the_device_and_what_to_do_about_it() {
+        let busy = PipelineError::OutputBusy {
+            device: "Speakers".to_owned(),
+            source: "A backend-specific error has occurred: AUDCLNT_E_DEVICE_IN_USE".into(),
+        };
+        let message = busy.to_string();
+        assert!(message.starts_with("\"Speakers\" is in use exclusively by another application"));
+        assert!(message.ends_with("`--wait-for-output-free <duration>`"));
+        assert_eq!(
+            busy.source().unwrap().to_string(),
+            "A backend-specific error has occurred: AUDCLNT_E_DEVICE_IN_USE"
+        );
+    }
+
+    #[test]
+    fn finds_a_busy_device_among_the_causes() {
+        let error = crate::backend::BackendError::caused(
+            "couldn't build the output stream",
+            "A backend-specific error has occurred: AUDCLNT_E_DEVICE_IN_USE",
+        );
+        let message = with_causes(&error);
+        assert_eq!(
+            message,
+            "couldn't build the output stream: A backend-specific error has occurred: \
+             AUDCLNT_E_DEVICE_IN_USE"
+        );
+        assert!(held_exclusively(&message));
+        assert!(!held_exclusively(&error.to_string()));
+    }
+
+    #[test]
+    fn lists_names_in_quotes_joined_by_and() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(quoted_list(&names(&[])), "");
+        assert_eq!(quoted_list(&names(&["Mic"])), "\"Mic\"");
+        assert_eq!(
+            quoted_list(&names(&["Mic", "Line"])),
+            "\"Mic\" and \"Line\""
+        );
+        assert_eq!(
+            quoted_list(&names(&["Mic", "Line", "USB"])),
+            "\"Mic\", \"Line\" and \"USB\""
+        );
+    }
+}
@@ -0,0 +1,560 @@
+//! A C ABI over [`Pipeline`], for controlling the mixer from other programs such as OBS scripts.
+//!
+//! Functions that can fail return [`LOOPBACK_OK`] or a negative error code, and
+//! [`loopback_last_error`] describes the most recent failure on the calling thread. Strings are
+//! UTF-8 and NUL terminated in both directions. No panic crosses the boundary: one is reported
+//! as [`LOOPBACK_ERROR_PANIC`] instead.
+//!
+//! `include/loopback.h` is generated from this module with `cbindgen --config cbindgen.toml
+//! --output include/loopback.h`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::backend::cpal_host::CpalProvider;
+use crate::backend::DeviceProvider;
+use crate::error::PipelineError;
+use crate::pipeline::{InputConfig, OverrideGain, Pipeline, PipelineConfig, MARKER_SIDECAR};
+use crate::recorder::SyncPolicy;
+use crate::resample::Quality;
+
+pub const LOOPBACK_OK: i32 = 0;
+/// A pointer argument was null.
+pub const LOOPBACK_ERROR_NULL: i32 = -1;
+/// An argument was out of range or otherwise invalid.
+pub const LOOPBACK_ERROR_INVALID: i32 = -2;
+/// Something failed inside the library.
+pub const LOOPBACK_ERROR_FAILED: i32 = -3;
+/// The library panicked, which is a bug.
+pub const LOOPBACK_ERROR_PANIC: i32 = -4;
+/// A device named in the configuration isn't there.
+pub const LOOPBACK_ERROR_DEVICE_NOT_FOUND: i32 = -5;
+/// A device can't run the way the configuration needs it to.
+pub const LOOPBACK_ERROR_UNSUPPORTED: i32 = -6;
+/// Another application has the output device to itself.
+pub const LOOPBACK_ERROR_DEVICE_BUSY: i32 = -7;
+
+/// A running pipeline.
+pub struct LoopbackHandle {
+    pipeline: Pipeline,
+}
+
+/// The configuration accepted by [`loopback_create`], like
+/// `{"inputs": [{"name": "Mic", "denoise": true}], "output": "BlackHole 16ch"}`, or with an input
+/// split from a device's channel like `{"name": "Guest", "device": "Scarlett 2i2", "channel": 2}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    inputs: Vec<Input>,
+    output: String,
+    #[serde(default = "default_latency_ms")]
+    latency_ms: f32,
+    #[serde(default)]
+    ringbuf_ms: Option<f32>,
+    #[serde(default = "default_denoise_mix")]
+    denoise_mix: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Input {
+    name: String,
+    /// The device to split `channel` from, which is `name` itself by default.
+    #[serde(default)]
+    device: Option<String>,
+    /// The channel of `device` to take, counting from 1 as `--split` does.
+    #[serde(default)]
+    channel: Option<usize>,
+    #[serde(default)]
+    invert: bool,
+    #[serde(default)]
+    denoise: bool,
+}
+
+fn default_latency_ms() -> f32 {
+    50.0
+}
+
+fn default_denoise_mix() -> f32 {
+    1.0
+}
+
+impl Config {
+    fn into_pipeline_config(self) -> Result<PipelineConfig, Error> {
+        let inputs = self
+            .inputs
+            .into_iter()
+            .map(|input| {
+                let channels = match input.channel {
+                    Some(0) => {
+                        return Err(Error::invalid(format!(
+                            "input \"{}\" has channel 0, but channels count from 1",
+                            input.name
+                        )))
+                    }
+                    Some(channel) => Some(channel - 1..channel),
+                    None => None,
+                };
+                Ok(InputConfig {
+                    device: input.device.unwrap_or_else(|| input.name.clone()),
+                    channels,
+                    invert: input.invert,
+                    denoise: input.denoise,
+                    ..InputConfig::new(&input.name)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(PipelineConfig {
+            inputs,
+            output: self.output,
+            latency_ms: self.latency_ms,
+            ringbuf_ms: self.ringbuf_ms,
+            denoise_mix: self.denoise_mix,
+            segment: 0,
+            cue_markers: false,
+            negotiate: true,
+            output_channels: None,
+            adaptive_latency: None,
+            replay_buffer: None,
+            planar: false,
+            record_output: None,
+            record_mirror: None,
+            record_sync: SyncPolicy::default(),
+            record_ceiling: None,
+            record_continue: None,
+            stems_pre_fader: false,
+            rt_priority: false,
+            preroll: Duration::ZERO,
+            marker_sidecar: PathBuf::from(MARKER_SIDECAR),
+            record_dir: PathBuf::new(),
+            strict_routing: false,
+            resample_quality: Quality::default(),
+        })
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs can't be represented, so they're dropped rather than losing the message.
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+struct Error {
+    code: i32,
+    message: String,
+}
+
+impl Error {
+    fn null(what: &str) -> Self {
+        Error {
+            code: LOOPBACK_ERROR_NULL,
+            message: format!("`{}` is null", what),
+        }
+    }
+
+    fn invalid(message: String) -> Self {
+        Error {
+            code: LOOPBACK_ERROR_INVALID,
+            message,
+        }
+    }
+}
+
+impl From<PipelineError> for Error {
+    fn from(err: PipelineError) -> Self {
+        let code = match err {
+            PipelineError::Invalid(_) => LOOPBACK_ERROR_INVALID,
+            PipelineError::DeviceNotFound { .. } => LOOPBACK_ERROR_DEVICE_NOT_FOUND,
+            PipelineError::ConfigNotSupported { .. } | PipelineError::NoCommonSampleRate { .. } => {
+                LOOPBACK_ERROR_UNSUPPORTED
+            }
+            PipelineError::OutputBusy { .. } => LOOPBACK_ERROR_DEVICE_BUSY,
+            _ => LOOPBACK_ERROR_FAILED,
+        };
+        // Every cause goes in the message, since C can't follow the chain itself.
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        Error { code, message }
+    }
+}
+
+/// Runs `f`, turning errors and panics into a code and the last error, and returning `failed`
+/// whenever it doesn't succeed.
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, Error>) -> (T, i32) {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => (value, LOOPBACK_OK),
+        Ok(Err(err)) => {
+            set_last_error(err.message);
+            (failed, err.code)
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            set_last_error(format!("panicked: {}", message));
+            (failed, LOOPBACK_ERROR_PANIC)
+        }
+    }
+}
+
+/// # Safety
+///
+/// `handle` must be null or a pointer returned by [`loopback_create`] that hasn't been destroyed.
+unsafe fn handle_ref<'a>(handle: *const LoopbackHandle) -> Result<&'a LoopbackHandle, Error> {
+    handle.as_ref().ok_or_else(|| Error::null("handle"))
+}
+
+fn input_gain(handle: &LoopbackHandle, index: u32) -> Result<&OverrideGain, Error> {
+    let gains = handle.pipeline.override_gains();
+    gains
+        .get(index as usize)
+        .map(|(_, gain)| &**gain)
+        .ok_or_else(|| {
+            Error::invalid(format!(
+                "there is no input {}, there are {}",
+                index,
+                gains.len()
+            ))
+        })
+}
+
+/// Builds and starts a pipeline from a JSON configuration, returning null on failure. An input's
+/// `channel` counts from 1, as on the command line.
+///
+/// # Safety
+///
+/// `config_json` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn loopback_create(config_json: *const c_char) -> *mut LoopbackHandle {
+    create(&CpalProvider::new(), config_json)
+}
+
+/// [`loopback_create`] on the devices of `provider`.
+///
+/// # Safety
+///
+/// As for [`loopback_create`].
+unsafe fn create(provider: &dyn DeviceProvider, config_json: *const c_char) -> *mut LoopbackHandle {
+    guard(std::ptr::null_mut(), || {
+        if config_json.is_null() {
+            return Err(Error::null("config_json"));
+        }
+        let config_json = CStr::from_ptr(config_json)
+            .to_str()
+            .map_err(|err| Error::invalid(format!("the configuration isn't UTF-8: {}", err)))?;
+        let config: Config = serde_json::from_str(config_json)
+            .map_err(|err| Error::invalid(format!("invalid configuration: {}", err)))?;
+        let pipeline = Pipeline::from_config(provider, &config.into_pipeline_config()?)?;
+        Ok(Box::into_raw(Box::new(LoopbackHandle { pipeline })))
+    })
+    .0
+}
+
+/// Sets the level of the input at `index`, in the order the configuration listed them.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from [`loopback_create`].
+#[no_mangle]
+pub unsafe extern "C" fn loopback_set_gain(
+    handle: *mut LoopbackHandle,
+    index: u32,
+    db: f32,
+) -> i32 {
+    guard((), || {
+        if !db.is_finite() {
+            return Err(Error::invalid(format!("{} isn't a valid gain", db)));
+        }
+        input_gain(handle_ref(handle)?, index)?.set_level_db(db);
+        Ok(())
+    })
+    .1
+}
+
+/// Mutes or unmutes the input at `index`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from [`loopback_create`].
+#[no_mangle]
+pub unsafe extern "C" fn loopback_mute(
+    handle: *mut LoopbackHandle,
+    index: u32,
+    muted: bool,
+) -> i32 {
+    guard((), || {
+        input_gain(handle_ref(handle)?, index)?.set_muted(muted);
+        Ok(())
+    })
+    .1
+}
+
+/// Returns the pipeline's current state as JSON, or null on failure. The string must be freed
+/// with [`loopback_string_free`].
+///
+/// The output peak is the loudest sample since the previous call, and the true peak the loudest
+/// point between samples too. Each input's clipped samples are those that have arrived at full
+/// scale in runs long enough to be clipping, since the pipeline was created.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from [`loopback_create`].
+#[no_mangle]
+pub unsafe extern "C" fn loopback_stats_json(handle: *mut LoopbackHandle) -> *mut c_char {
+    guard(std::ptr::null_mut(), || {
+        let pipeline = &handle_ref(handle)?.pipeline;
+        let counters = pipeline.counters();
+        let inputs = pipeline
+            .override_gains()
+            .iter()
+            .map(|(name, gain)| {
+                let clipped_samples = pipeline
+                    .stats()
+                    .inputs()
+                    .find(|(input, _)| input == name)
+                    .map_or(0, |(_, counters)| counters.clipped_samples());
+                serde_json::json!({
+                    "name": name,
+                    "gain_db": gain.level_db(),
+                    "muted": gain.muted(),
+                    "clipped_samples": clipped_samples,
+                })
+            })
+            .collect::<Vec<_>>();
+        let stats = serde_json::json!({
+            "sample_rate": pipeline.sample_rate(),
+            "callbacks": counters.callbacks(),
+            "underruns": counters.underruns(),
+            "output_peak": counters.take_peak(),
+            "output_true_peak": counters.take_true_peak(),
+            "inputs": inputs,
+        });
+        // JSON never contains a NUL, serde escapes them.
+        Ok(CString::new(stats.to_string()).unwrap().into_raw())
+    })
+    .0
+}
+
+/// Returns what the pipeline is doing as JSON, laid out as described in
+/// [`crate::describe`], or null on failure. The string must be freed with
+/// [`loopback_string_free`].
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from [`loopback_create`].
+#[no_mangle]
+pub unsafe extern "C" fn loopback_describe_json(handle: *mut LoopbackHandle) -> *mut c_char {
+    guard(std::ptr::null_mut(), || {
+        let pipeline = &handle_ref(handle)?.pipeline;
+        let json = pipeline
+            .describe(cpal::default_host().id().name())
+            .to_json();
+        // Every string in it is escaped, NULs included.
+        Ok(CString::new(json).unwrap().into_raw())
+    })
+    .0
+}
+
+/// Frees a string returned by the library. Null is ignored.
+///
+/// # Safety
+///
+/// `string` must be null or a string returned by the library that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn loopback_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Describes the most recent failure on this thread, or returns null if nothing has failed. The
+/// string belongs to the library and stays valid until the next failure on this thread.
+#[no_mangle]
+pub extern "C" fn loopback_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Stops the pipeline and frees the handle. Null is ignored.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from [`loopback_create`], which can't be used again.
+#[no_mangle]
+pub unsafe extern "C" fn loopback_destroy(handle: *mut LoopbackHandle) {
+    if handle.is_null() {
+        return;
+    }
+    guard((), || {
+        drop(Box::from_raw(handle));
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backend::fake::{FakeProvider, Signal};
+    use crate::backend::StreamConfig;
+
+    fn stream_config(channels: u16) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(256),
+        }
+    }
+
+    fn last_error() -> String {
+        let message = loopback_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Takes a string the library returned, freeing it the way C has to.
+    fn take_string(string: *mut c_char) -> String {
+        assert!(!string.is_null(), "{}", last_error());
+        let owned = unsafe { CStr::from_ptr(string) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        unsafe { loopback_string_free(string) };
+        owned
+    }
+
+    /// Creates a pipeline on `provider`'s devices, with its clock running meanwhile, since it
+    /// only returns once the devices have delivered audio.
+    fn create_running(provider: &FakeProvider, config: &str) -> *mut LoopbackHandle {
+        let config = CString::new(config).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let clock = std::thread::spawn({
+            let provider = provider.clone();
+            let done = Arc::clone(&done);
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    provider.advance(1);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+        let handle = unsafe { create(provider, config.as_ptr()) };
+        done.store(true, Ordering::Relaxed);
+        clock.join().unwrap();
+        handle
+    }
+
+    #[test]
+    fn drives_a_pipeline_through_the_c_abi() {
+        let provider = FakeProvider::new();
+        // Only the second channel of the interface has anything on it.
+        let interface = [0.25, 0.5].repeat(48_000);
+        provider
+            .add_input("Mic", stream_config(1), Signal::Silence)
+            .add_input(
+                "Interface",
+                stream_config(2),
+                Signal::Samples(interface.into()),
+            )
+            .add_output("Speakers", stream_config(1));
+        let handle = create_running(
+            &provider,
+            r#"{"inputs": [{"name": "Mic"}, {"name": "Guest", "device": "Interface", "channel": 2}],
+                "output": "Speakers", "latency_ms": 20}"#,
+        );
+        assert!(!handle.is_null(), "{}", last_error());
+
+        provider.take_output("Speakers");
+        provider.advance(10);
+        let output = provider.take_output("Speakers");
+        assert!(output[output.len() - 256..]
+            .iter()
+            .all(|&sample| sample == 0.5));
+
+        unsafe {
+            assert_eq!(loopback_set_gain(handle, 1, -6.0), LOOPBACK_OK);
+            assert_eq!(loopback_mute(handle, 0, true), LOOPBACK_OK);
+            assert_eq!(loopback_set_gain(handle, 2, 0.0), LOOPBACK_ERROR_INVALID);
+            assert_eq!(last_error(), "there is no input 2, there are 2");
+            assert_eq!(
+                loopback_set_gain(handle, 0, f32::NAN),
+                LOOPBACK_ERROR_INVALID
+            );
+        }
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&take_string(unsafe { loopback_stats_json(handle) })).unwrap();
+        assert_eq!(stats["sample_rate"], 48_000);
+        assert_eq!(stats["inputs"][0]["name"], "Mic");
+        assert_eq!(stats["inputs"][0]["muted"], true);
+        assert_eq!(stats["inputs"][1]["name"], "Guest");
+        assert_eq!(stats["inputs"][1]["gain_db"], -6.0);
+        assert!(stats["output_peak"].as_f64().unwrap() > 0.0);
+
+        let describe: serde_json::Value =
+            serde_json::from_str(&take_string(unsafe { loopback_describe_json(handle) })).unwrap();
+        assert!(describe.is_object());
+        unsafe { loopback_destroy(handle) };
+    }
+
+    #[test]
+    fn reports_bad_arguments_without_crossing_the_boundary() {
+        unsafe {
+            assert!(loopback_create(std::ptr::null()).is_null());
+            assert_eq!(last_error(), "`config_json` is null");
+            assert_eq!(
+                loopback_set_gain(std::ptr::null_mut(), 0, 0.0),
+                LOOPBACK_ERROR_NULL
+            );
+            assert_eq!(
+                loopback_mute(std::ptr::null_mut(), 0, true),
+                LOOPBACK_ERROR_NULL
+            );
+            assert!(loopback_stats_json(std::ptr::null_mut()).is_null());
+            loopback_string_free(std::ptr::null_mut());
+            loopback_destroy(std::ptr::null_mut());
+        }
+
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", stream_config(1), Signal::Silence)
+            .add_output("Speakers", stream_config(1));
+        let create = |config: &str| {
+            let config = CString::new(config).unwrap();
+            let handle = unsafe { create(&provider, config.as_ptr()) };
+            assert!(handle.is_null());
+            last_error()
+        };
+        assert!(create("{").starts_with("invalid configuration"));
+        assert!(create(r#"{"inputs": [], "output": "Speakers", "gain": 1}"#)
+            .contains("unknown field `gain`"));
+        assert_eq!(
+            create(r#"{"inputs": [{"name": "Mic", "channel": 0}], "output": "Speakers"}"#),
+            "input \"Mic\" has channel 0, but channels count from 1"
+        );
+    }
+}
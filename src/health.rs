@@ -0,0 +1,307 @@
+//! Conditions under which an unattended run should give up rather than keep producing bad audio.
+//!
+//! The conditions are evaluated on the control thread from the counters the output callback
+//! keeps, never from inside the audio callbacks themselves.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::control::parse_duration;
+use crate::error::{parse_error, ParseError};
+
+/// Peaks below this count as silence, which is about -80 dBFS.
+pub const SILENCE_THRESHOLD: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailCondition {
+    /// More than `count` underruns within any `window`.
+    Underruns { count: u64, window: Duration },
+    /// Nothing but silence on the output for `duration`.
+    Silence { duration: Duration },
+}
+
+impl FailCondition {
+    /// Parses conditions like `underruns=100/min` or `silence=60s`.
+    ///
+    /// Underrun rates are per `s`, `min` or `h`, or per a duration like `30s`.
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let (name, threshold) = value.split_once('=').ok_or_else(|| {
+            parse_error!("expected a condition like `silence=60s`, got `{}`", value)
+        })?;
+        match name {
+            "underruns" => {
+                let (count, per) = threshold.split_once('/').ok_or_else(|| {
+                    parse_error!(
+                        "expected an underrun rate like `100/min`, got `{}`",
+                        threshold
+                    )
+                })?;
+                let count = count
+                    .parse()
+                    .map_err(|_| parse_error!("expected a number of underruns, got `{}`", count))?;
+                let window = match per {
+                    "s" => Duration::from_secs(1),
+                    "min" => Duration::from_secs(60),
+                    "h" => Duration::from_secs(60 * 60),
+                    other => parse_duration(other)?,
+                };
+                if window.is_zero() {
+                    return Err(parse_error!("the underrun window can't be empty"));
+                }
+                Ok(FailCondition::Underruns { count, window })
+            }
+            "silence" => Ok(FailCondition::Silence {
+                duration: parse_duration(threshold)?,
+            }),
+            other => Err(parse_error!(
+                "unknown condition `{}`, expected `underruns` or `silence`",
+                other
+            )),
+        }
+    }
+
+    /// The process exit code used when this condition trips, which is distinct per kind of
+    /// condition so a supervisor can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FailCondition::Underruns { .. } => 3,
+            FailCondition::Silence { .. } => 4,
+        }
+    }
+
+    /// The name used for this kind of condition on the command line and in the summary.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailCondition::Underruns { .. } => "underruns",
+            FailCondition::Silence { .. } => "silence",
+        }
+    }
+}
+
+impl fmt::Display for FailCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailCondition::Underruns { count, window } => write!(
+                f,
+                "more than {} underruns within {}s",
+                count,
+                window.as_secs_f32()
+            ),
+            FailCondition::Silence { duration } => {
+                write!(f, "silence for {}s", duration.as_secs_f32())
+            }
+        }
+    }
+}
+
+/// Keeps enough history to evaluate a set of conditions each time it is updated.
+pub struct HealthMonitor {
+    conditions: Vec<FailCondition>,
+    /// Total underrun counts seen at each update, oldest first, going back as far as the longest
+    /// underrun window.
+    underruns: VecDeque<(Instant, u64)>,
+    silent_since: Option<Instant>,
+}
+
+impl HealthMonitor {
+    /// `start` is when the pipeline started, when there had been no underruns yet.
+    pub fn new(conditions: Vec<FailCondition>, start: Instant) -> Self {
+        HealthMonitor {
+            conditions,
+            underruns: VecDeque::from([(start, 0)]),
+            silent_since: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    /// Records the latest measurements and returns the first condition that has tripped, if any.
+    ///
+    /// `total_underruns` is the running total since startup, and `output_peak` is the loudest
+    /// output sample since the previous update.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        total_underruns: u64,
+        output_peak: f32,
+    ) -> Option<FailCondition> {
+        let longest_window = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                FailCondition::Underruns { window, .. } => Some(*window),
+                FailCondition::Silence { .. } => None,
+            })
+            .max();
+        if let Some(longest_window) = longest_window {
+            self.underruns.push_back((now, total_underruns));
+            // Keep the newest entry that is at least a whole window old, so that the window is
+            // always fully covered once it has been running that long.
+            while self.underruns.len() > 1
+                && now.duration_since(self.underruns[1].0) >= longest_window
+            {
+                self.underruns.pop_front();
+            }
+        }
+
+        if output_peak < SILENCE_THRESHOLD {
+            self.silent_since.get_or_insert(now);
+        } else {
+            self.silent_since = None;
+        }
+
+        self.conditions
+            .iter()
+            .copied()
+            .find(|condition| match *condition {
+                FailCondition::Underruns { count, window } => {
+                    self.underruns_within(now, window, total_underruns) > count
+                }
+                FailCondition::Silence { duration } => self
+                    .silent_since
+                    .is_some_and(|since| now.duration_since(since) >= duration),
+            })
+    }
+
+    fn underruns_within(&self, now: Instant, window: Duration, total_underruns: u64) -> u64 {
+        let since = self
+            .underruns
+            .iter()
+            .find(|(at, _)| now.duration_since(*at) <= window)
+            .map_or(total_underruns, |(_, count)| *count);
+        total_underruns.saturating_sub(since)
+    }
+}
+
+/// Notices when the output callback stops running, which is how dead streams show up when the
+/// backend never reports an error, as CoreAudio doesn't after a sleep and wake.
+pub struct Watchdog {
+    timeout: Duration,
+    callbacks: u64,
+    progressed_at: Instant,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Watchdog {
+            timeout,
+            callbacks: 0,
+            progressed_at: now,
+        }
+    }
+
+    /// Starts over for a rebuilt pipeline, whose callback count starts again from zero.
+    pub fn reset(&mut self, now: Instant) {
+        self.callbacks = 0;
+        self.progressed_at = now;
+    }
+
+    /// Records the output's callback count and returns whether it has gone without changing for
+    /// longer than the timeout.
+    pub fn update(&mut self, now: Instant, callbacks: u64) -> bool {
+        if callbacks != self.callbacks {
+            self.callbacks = callbacks;
+            self.progressed_at = now;
+        }
+        now.duration_since(self.progressed_at) > self.timeout
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(
+            FailCondition::parse("underruns=100/min").unwrap(),
+            FailCondition::Underruns {
+                count: 100,
+                window: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(
+            FailCondition::parse("underruns=5/30s").unwrap(),
+            FailCondition::Underruns {
+                count: 5,
+                window: Duration::from_secs(30)
+            }
+        );
+        assert_eq!(
+            FailCondition::parse("silence=60s").unwrap(),
+            FailCondition::Silence {
+                duration: Duration::from_secs(60)
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_conditions() {
+        for value in [
+            "underruns",
+            "underruns=100",
+            "underruns=x/min",
+            "underruns=1/0s",
+            "silence=loud",
+            "clipping=1/s",
+        ] {
+            assert!(FailCondition::parse(value).is_err(), "{:?} parsed", value);
+        }
+    }
+
+    #[test]
+    fn exit_codes_and_names_tell_conditions_apart() {
+        let underruns = FailCondition::parse("underruns=1/s").unwrap();
+        let silence = FailCondition::parse("silence=1s").unwrap();
+        assert_eq!((underruns.exit_code(), underruns.name()), (3, "underruns"));
+        assert_eq!((silence.exit_code(), silence.name()), (4, "silence"));
+    }
+
+    #[test]
+    fn trips_on_too_many_underruns_within_the_window() {
+        let start = Instant::now();
+        let condition = FailCondition::parse("underruns=2/10s").unwrap();
+        let mut monitor = HealthMonitor::new(vec![condition], start);
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(monitor.update(at(1), 2, 1.0), None);
+        // The first two have left the window by now.
+        assert_eq!(monitor.update(at(12), 4, 1.0), None);
+        assert_eq!(monitor.update(at(13), 6, 1.0), None);
+        assert_eq!(monitor.update(at(14), 7, 1.0), Some(condition));
+    }
+
+    #[test]
+    fn trips_on_silence_that_lasts() {
+        let start = Instant::now();
+        let condition = FailCondition::parse("silence=5s").unwrap();
+        let mut monitor = HealthMonitor::new(vec![condition], start);
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(monitor.update(at(1), 0, 0.0), None);
+        assert_eq!(monitor.update(at(4), 0, 0.5), None);
+        assert_eq!(monitor.update(at(5), 0, 0.0), None);
+        assert_eq!(monitor.update(at(9), 0, 0.0), None);
+        assert_eq!(monitor.update(at(10), 0, 0.0), Some(condition));
+    }
+
+    #[test]
+    fn watchdog_trips_only_once_callbacks_stop_for_the_timeout() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut watchdog = Watchdog::new(Duration::from_secs(1), start);
+        assert!(!watchdog.update(at(900), 0));
+        assert!(!watchdog.update(at(1_500), 10));
+        assert!(!watchdog.update(at(2_500), 10));
+        assert!(watchdog.update(at(2_501), 10));
+
+        watchdog.reset(at(3_000));
+        assert!(!watchdog.update(at(3_500), 0));
+        assert!(watchdog.update(at(4_001), 0));
+    }
+}
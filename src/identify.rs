@@ -0,0 +1,151 @@
+//! Beeps on one output channel at a time, so it's clear which channel carries what downstream.
+//!
+//! Channel `n` (counting from 1) gets `n` short beeps. The control thread asks for a pattern
+//! through an [`IdentifyRequest`] and the output callback generates it frame by frame on top of
+//! the mix, without allocating.
+
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::pipeline::ms_to_frames;
+
+/// -20 dBFS.
+const AMPLITUDE: f32 = 0.1;
+const FREQUENCY: f32 = 1_000.0;
+const BEEP_MS: f32 = 120.0;
+const GAP_MS: f32 = 120.0;
+/// The silence after each pattern, so a count is easy to tell apart from the next.
+const PAUSE_MS: f32 = 600.0;
+/// Beeps fade in and out over this long so they don't click.
+const RAMP_MS: f32 = 5.0;
+/// How long a single channel's pattern repeats for.
+const REPEAT_FOR: Duration = Duration::from_secs(3);
+
+/// Asks for every channel in turn rather than just one.
+const ALL_CHANNELS: usize = usize::MAX;
+
+/// A request from the control thread, picked up by the output callback.
+#[derive(Debug, Default)]
+pub struct IdentifyRequest {
+    /// 0 for nothing requested, a channel counting from 1, or [`ALL_CHANNELS`].
+    channel: AtomicUsize,
+}
+
+impl IdentifyRequest {
+    /// Beeps on `channel`, counting from 1, or on every channel in turn if `None`. Replaces
+    /// whatever was already beeping.
+    pub fn request(&self, channel: Option<usize>) {
+        let channel = channel.unwrap_or(ALL_CHANNELS);
+        self.channel.store(channel.max(1), Ordering::Relaxed);
+    }
+
+    fn take(&self) -> Option<usize> {
+        match self.channel.swap(0, Ordering::Relaxed) {
+            0 => None,
+            channel => Some(channel),
+        }
+    }
+}
+
+/// The output callback's side, which turns requests into beeps.
+pub(crate) struct IdentifyGenerator {
+    channels: usize,
+    beep_frames: usize,
+    gap_frames: usize,
+    pause_frames: usize,
+    ramp_frames: usize,
+    repeat_frames: usize,
+    sample_rate: f32,
+    active: Option<Active>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Active {
+    /// Counting from 0.
+    channel: usize,
+    /// Whether to go on to the next channel once this one's pattern is done.
+    all: bool,
+    /// Frames into the current repetition of the pattern.
+    position: usize,
+    /// Frames since this channel's pattern started.
+    elapsed: usize,
+}
+
+impl IdentifyGenerator {
+    /// `channels` is how many output channels there are to identify.
+    pub(crate) fn new(channels: usize, sample_rate: u32) -> Self {
+        IdentifyGenerator {
+            channels,
+            beep_frames: ms_to_frames(BEEP_MS, sample_rate),
+            gap_frames: ms_to_frames(GAP_MS, sample_rate),
+            pause_frames: ms_to_frames(PAUSE_MS, sample_rate),
+            ramp_frames: ms_to_frames(RAMP_MS, sample_rate).max(1),
+            repeat_frames: ms_to_frames(REPEAT_FOR.as_secs_f32() * 1_000.0, sample_rate),
+            sample_rate: sample_rate as f32,
+            active: None,
+        }
+    }
+
+    /// Adds the pattern to interleaved `frames` with `channels` channels each.
+    pub(crate) fn process(
+        &mut self,
+        frames: &mut [f32],
+        channels: usize,
+        request: &IdentifyRequest,
+    ) {
+        if let Some(channel) = request.take() {
+            self.active = Some(Active {
+                channel: if channel == ALL_CHANNELS {
+                    0
+                } else {
+                    channel - 1
+                },
+                all: channel == ALL_CHANNELS,
+                position: 0,
+                elapsed: 0,
+            });
+        }
+
+        for frame in frames.chunks_exact_mut(channels) {
+            let Some(active) = &mut self.active else {
+                return;
+            };
+            if active.channel >= self.channels {
+                self.active = None;
+                return;
+            }
+
+            let beeps = active.channel + 1;
+            let period = self.beep_frames + self.gap_frames;
+            let pattern = beeps * period + self.pause_frames;
+            if active.position < beeps * period {
+                let into_beep = active.position % period;
+                if into_beep < self.beep_frames {
+                    let ramp = into_beep
+                        .min(self.beep_frames - 1 - into_beep)
+                        .min(self.ramp_frames) as f32
+                        / self.ramp_frames as f32;
+                    let phase = TAU * FREQUENCY * into_beep as f32 / self.sample_rate;
+                    if let Some(sample) = frame.get_mut(active.channel) {
+                        *sample += AMPLITUDE * ramp * phase.sin();
+                    }
+                }
+            }
+
+            active.position += 1;
+            active.elapsed += 1;
+            if active.position == pattern {
+                active.position = 0;
+                // One pattern per channel when going through all of them, otherwise repeat it
+                // until the time is up.
+                if active.all {
+                    active.channel += 1;
+                    active.elapsed = 0;
+                } else if active.elapsed >= self.repeat_frames {
+                    self.active = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Shrinks the output's buffering while the inputs keep up, and grows it back when they don't.
+//!
+//! The decisions are made in the output callback from how much every input has buffered, and the
+//! callback applies them to every input at once, so the inputs stay aligned with each other.
+
+use crate::control::parse_duration;
+use crate::error::{parse_error, ParseError};
+use crate::pipeline::ms_to_frames;
+
+/// How much the latency shrinks by at a time.
+const STEP_MS: f32 = 5.0;
+/// How long the inputs have to keep up before the latency shrinks again.
+const SETTLE_MS: f32 = 2_000.0;
+/// How long the audio from before a shrink fades into the audio after it.
+pub(crate) const CROSSFADE_MS: f32 = 2.0;
+
+/// The range adaptive latency stays within, starting at the top of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveLatency {
+    pub min_ms: f32,
+    pub max_ms: f32,
+}
+
+impl Default for AdaptiveLatency {
+    fn default() -> Self {
+        AdaptiveLatency {
+            min_ms: 40.0,
+            max_ms: 200.0,
+        }
+    }
+}
+
+impl AdaptiveLatency {
+    /// Parses `auto`, optionally followed by bounds like `auto:min=40ms,max=200ms`.
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let mut adaptive = AdaptiveLatency::default();
+        let bounds = match value.split_once(':') {
+            Some(("auto", bounds)) => bounds,
+            None if value == "auto" => return Ok(adaptive),
+            _ => {
+                return Err(parse_error!(
+                    "expected `auto` or `auto:min=40ms,max=200ms`, got `{}`",
+                    value
+                ))
+            }
+        };
+        for bound in bounds.split(',') {
+            let (name, duration) = bound
+                .split_once('=')
+                .ok_or_else(|| parse_error!("expected a bound like `min=40ms`, got `{}`", bound))?;
+            let ms = parse_duration(duration)?.as_secs_f32() * 1_000.0;
+            match name {
+                "min" => adaptive.min_ms = ms,
+                "max" => adaptive.max_ms = ms,
+                other => {
+                    return Err(parse_error!(
+                        "unknown bound `{}`, expected `min` or `max`",
+                        other
+                    ))
+                }
+            }
+        }
+        if adaptive.min_ms > adaptive.max_ms {
+            return Err(parse_error!(
+                "the minimum latency of {} ms is above the maximum of {} ms",
+                adaptive.min_ms,
+                adaptive.max_ms
+            ));
+        }
+        Ok(adaptive)
+    }
+}
+
+/// What the output callback should do with the inputs this time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Adjustment {
+    Mix,
+    /// Throw away this many frames from every input before mixing, fading across the gap.
+    Discard(usize),
+    /// Play silence and leave the inputs to fill up.
+    Wait,
+}
+
+/// Decides on an [`Adjustment`] every callback, without allocating.
+pub(crate) struct LatencyController {
+    min_frames: usize,
+    max_frames: usize,
+    step_frames: usize,
+    settle_frames: usize,
+    /// Frames played since the last change.
+    clean_frames: usize,
+    /// The least any input had buffered at the start of a callback since the last change.
+    low_water: usize,
+    /// Frames waited so far, while refilling after an underrun.
+    refilling: Option<usize>,
+    /// Frames to go before another refill, after one gave up. An input that has stopped
+    /// altogether shouldn't silence the others over and over.
+    cooldown: usize,
+    /// Whether a refill gave up short of the minimum, and should be tried again after the
+    /// cooldown.
+    short: bool,
+}
+
+impl LatencyController {
+    pub(crate) fn new(adaptive: AdaptiveLatency, sample_rate: u32) -> Self {
+        LatencyController {
+            min_frames: ms_to_frames(adaptive.min_ms, sample_rate),
+            max_frames: ms_to_frames(adaptive.max_ms, sample_rate),
+            step_frames: ms_to_frames(STEP_MS, sample_rate).max(1),
+            settle_frames: ms_to_frames(SETTLE_MS, sample_rate),
+            clean_frames: 0,
+            low_water: usize::MAX,
+            refilling: None,
+            cooldown: 0,
+            short: false,
+        }
+    }
+
+    /// `buffered` is the least any input has buffered, and `frames` is how many the callback is
+    /// about to play, both in frames.
+    pub(crate) fn next(&mut self, buffered: usize, frames: usize) -> Adjustment {
+        self.cooldown = self.cooldown.saturating_sub(frames);
+
+        if let Some(waited) = &mut self.refilling {
+            // Give up once the wait has been as long as the latency it's trying to get back to.
+            if buffered >= self.max_frames || *waited >= self.max_frames {
+                if buffered < self.max_frames {
+                    self.cooldown = self.settle_frames;
+                    self.short = buffered < self.min_frames;
+                }
+                self.refilling = None;
+                self.restart();
+                return Adjustment::Mix;
+            }
+            *waited += frames;
+            return Adjustment::Wait;
+        }
+
+        if buffered < frames {
+            // This callback runs short either way, and the ones after wait for the latency to be
+            // back at the top.
+            if self.cooldown == 0 {
+                self.refilling = Some(0);
+            }
+            self.restart();
+            return Adjustment::Mix;
+        }
+        if self.short && self.cooldown == 0 {
+            self.short = false;
+            if buffered < self.min_frames {
+                self.refilling = Some(0);
+                self.restart();
+                return Adjustment::Wait;
+            }
+        }
+
+        self.low_water = self.low_water.min(buffered);
+        self.clean_frames += frames;
+        if self.clean_frames < self.settle_frames {
+            return Adjustment::Mix;
+        }
+        // Only shrink if even the worst callback would have had another callback's worth to spare
+        // afterwards.
+        let shrink = self.low_water >= self.step_frames + 2 * frames
+            && self.low_water - self.step_frames >= self.min_frames;
+        self.restart();
+        if shrink {
+            Adjustment::Discard(self.step_frames)
+        } else {
+            Adjustment::Mix
+        }
+    }
+
+    fn restart(&mut self) {
+        self.clean_frames = 0;
+        self.low_water = usize::MAX;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounds() {
+        assert_eq!(
+            AdaptiveLatency::parse("auto").unwrap(),
+            AdaptiveLatency::default()
+        );
+        assert_eq!(
+            AdaptiveLatency::parse("auto:min=20ms,max=0.5s").unwrap(),
+            AdaptiveLatency {
+                min_ms: 20.0,
+                max_ms: 500.0,
+            }
+        );
+        assert_eq!(
+            AdaptiveLatency::parse("auto:max=100ms").unwrap().max_ms,
+            100.0
+        );
+        for bad in [
+            "50",
+            "auto:",
+            "auto:min=20",
+            "auto:low=20ms",
+            "auto:min=300ms",
+        ] {
+            assert!(AdaptiveLatency::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn shrinks_only_after_settling_and_never_below_the_minimum() {
+        let adaptive = AdaptiveLatency {
+            min_ms: 40.0,
+            max_ms: 100.0,
+        };
+        let mut controller = LatencyController::new(adaptive, 48_000);
+        let mut buffered = ms_to_frames(100.0, 48_000);
+        let mut discarded = 0;
+        for _ in 0..48_000 * 60 / 256 {
+            match controller.next(buffered, 256) {
+                Adjustment::Discard(frames) => {
+                    buffered -= frames;
+                    discarded += 1;
+                }
+                adjustment => assert_eq!(adjustment, Adjustment::Mix),
+            }
+        }
+        assert!(buffered >= ms_to_frames(40.0, 48_000));
+        assert_eq!(discarded, 12);
+
+        // Running short waits until it's back at the top.
+        assert_eq!(controller.next(100, 256), Adjustment::Mix);
+        assert_eq!(controller.next(1000, 256), Adjustment::Wait);
+        assert_eq!(controller.next(4800, 256), Adjustment::Mix);
+    }
+}
@@ -0,0 +1,40 @@
+//! Mixes several input devices into one output device, with a little processing on the way.
+//!
+//! [`pipeline::Pipeline`] does the work, on top of whichever [`backend::DeviceProvider`] it is
+//! given: real devices through cpal, or the fake devices in [`backend::fake`].
+
+pub mod attach;
+pub mod backend;
+pub mod chain;
+pub mod control;
+pub mod correlation;
+#[cfg(feature = "denoise")]
+pub mod denoise;
+pub mod describe;
+pub mod device_ids;
+pub mod enumerate;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod health;
+pub mod identify;
+pub mod latency;
+pub mod limiter;
+pub mod loudness;
+#[cfg(feature = "media-keys")]
+pub mod media_keys;
+pub mod memory;
+pub mod mix;
+pub mod negotiate;
+pub mod pipeline;
+pub mod priority;
+pub mod rate;
+pub mod recorder;
+pub mod resample;
+pub mod reverb;
+pub mod scene;
+pub mod session_log;
+pub mod title;
+pub mod true_peak;
+pub mod verify;
+pub mod wav;
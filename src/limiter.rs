@@ -0,0 +1,204 @@
+//! A true-peak limiter for recordings, which keeps the file under a ceiling however loud the
+//! output it's recording gets.
+//!
+//! It looks ahead by delaying the audio, so the gain has already come down by the time a peak
+//! arrives. The gain each frame needs is held at its lowest over the lookahead and then averaged
+//! over it, so it ramps down smoothly and can't be above what any frame it applies to needs. It
+//! recovers over [`RELEASE_MS`]. The peaks are measured with a [`TruePeakMeter`], so the ones
+//! between samples are kept under the ceiling too.
+//!
+//! It runs on the recorder's writer thread, never the audio threads.
+
+use crate::error::{parse_error, ParseError};
+use crate::pipeline::ms_to_frames;
+use crate::true_peak::{TruePeakMeter, PHASE_TAPS};
+
+/// How far ahead the gain is worked out.
+const LOOKAHEAD_MS: f32 = 1.5;
+/// How long the gain takes to recover most of the way after a peak.
+const RELEASE_MS: f32 = 100.0;
+/// Kept below the ceiling, for the little that a changing gain adds to the peaks between samples.
+const HEADROOM: f32 = 0.995;
+
+pub struct Limiter {
+    channels: usize,
+    ceiling: f32,
+    meter: TruePeakMeter,
+    release: f32,
+    /// The gain as it recovers from the last peak, before the hold.
+    envelope: f32,
+    /// The envelope over the last `hold.len()` frames, oldest at `position % hold.len()`.
+    hold: Vec<f32>,
+    /// The held gain over the last `smooth.len()` frames.
+    smooth: Vec<f32>,
+    /// The audio not yet let out, `delay_frames` frames of it.
+    delay: Vec<f32>,
+    /// Frames processed so far.
+    position: usize,
+}
+
+impl Limiter {
+    /// Keeps `channels` channels of audio at `sample_rate` under `ceiling_db` dBTP.
+    pub fn new(channels: usize, sample_rate: u32, ceiling_db: f32) -> Self {
+        let lookahead = ms_to_frames(LOOKAHEAD_MS, sample_rate).max(1);
+        // The meter's estimate for a frame depends on the frames either side of it, so the hold
+        // covers those as well as the lookahead.
+        let hold = lookahead + PHASE_TAPS - 1;
+        let release_frames = ms_to_frames(RELEASE_MS, sample_rate).max(1) as f32;
+        Limiter {
+            channels,
+            ceiling: 10f32.powf(ceiling_db / 20.0) * HEADROOM,
+            meter: TruePeakMeter::new(channels),
+            release: 1.0 - (-1.0 / release_frames).exp(),
+            envelope: 1.0,
+            hold: vec![1.0; hold],
+            smooth: vec![1.0; lookahead],
+            delay: vec![0.0; (lookahead + PHASE_TAPS - 2) * channels],
+            position: 0,
+        }
+    }
+
+    /// How many frames the audio comes out later than it goes in.
+    pub fn latency_frames(&self) -> usize {
+        self.delay.len() / self.channels
+    }
+
+    /// Limits interleaved `samples` in place, delayed by [`Limiter::latency_frames`].
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let delay_frames = self.latency_frames();
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let peak = self.meter.process(frame, self.channels);
+            let needed = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            };
+            self.envelope = if needed < self.envelope {
+                needed
+            } else {
+                self.envelope + (needed - self.envelope) * self.release
+            };
+
+            let hold = self.position % self.hold.len();
+            self.hold[hold] = self.envelope;
+            let held = self.hold.iter().copied().fold(1.0, f32::min);
+            let smooth = self.position % self.smooth.len();
+            self.smooth[smooth] = held;
+            // Summed afresh every frame, so a gain of exactly 1 stays exactly 1.
+            let gain = self.smooth.iter().sum::<f32>() / self.smooth.len() as f32;
+
+            if delay_frames == 0 {
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            } else {
+                let slot = self.position % delay_frames * self.channels;
+                let delayed = &mut self.delay[slot..slot + self.channels];
+                for (sample, delayed) in frame.iter_mut().zip(delayed) {
+                    let input = *sample;
+                    *sample = *delayed * gain;
+                    *delayed = input;
+                }
+            }
+            self.position += 1;
+        }
+    }
+}
+
+/// Parses a ceiling in dBTP, like `-1`, `-1dB` or `-1dBTP`.
+pub fn parse_ceiling(value: &str) -> Result<f32, ParseError> {
+    let number = value
+        .strip_suffix("dBTP")
+        .or_else(|| value.strip_suffix("dB"))
+        .unwrap_or(value);
+    let ceiling: f32 = number
+        .trim()
+        .parse()
+        .map_err(|_| parse_error!("expected a ceiling in dBTP, like `-1dBTP`, got `{}`", value))?;
+    if !ceiling.is_finite() || ceiling > 0.0 {
+        return Err(parse_error!("the ceiling can't be above 0 dBTP"));
+    }
+    Ok(ceiling)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A full-scale sine at a quarter of the rate, sampled 45 degrees off its peaks, which reads
+    /// 0 dBFS sample by sample but peaks 3 dB higher in between.
+    fn inter_sample_peaks(frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let phase =
+                    std::f32::consts::FRAC_PI_2 * frame as f32 + std::f32::consts::FRAC_PI_4;
+                let sample = phase.sin() * std::f32::consts::SQRT_2;
+                std::iter::repeat_n(sample, channels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_ceilings_with_or_without_a_unit() {
+        assert_eq!(parse_ceiling("-1dBTP").unwrap(), -1.0);
+        assert_eq!(parse_ceiling("-0.5dB").unwrap(), -0.5);
+        assert_eq!(parse_ceiling("-3").unwrap(), -3.0);
+        assert_eq!(parse_ceiling("0").unwrap(), 0.0);
+        assert!(parse_ceiling("1dBTP").is_err());
+        assert!(parse_ceiling("loud").is_err());
+        assert!(parse_ceiling("-infdB").is_err());
+    }
+
+    #[test]
+    fn keeps_inter_sample_peaks_under_the_ceiling() {
+        let mut samples = inter_sample_peaks(48_000, 2);
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = sample.clamp(-1.0, 1.0));
+        let mut meter = TruePeakMeter::new(2);
+        assert!(meter.process(&samples, 2) > 1.3);
+
+        let mut limiter = Limiter::new(2, 48_000, -1.0);
+        limiter.process(&mut samples);
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+        let mut meter = TruePeakMeter::new(2);
+        let peak = meter.process(&samples, 2);
+        assert!(peak <= ceiling, "{} dBTP", 20.0 * peak.log10());
+        assert!(peak > ceiling * 0.98, "{} dBTP", 20.0 * peak.log10());
+    }
+
+    #[test]
+    fn catches_a_sudden_peak_ahead_of_time() {
+        let mut samples = vec![0.0; 4_800];
+        samples[2_400] = 1.0;
+        samples[2_401] = -1.0;
+        let mut limiter = Limiter::new(1, 48_000, -6.0);
+        limiter.process(&mut samples);
+        let mut meter = TruePeakMeter::new(1);
+        assert!(meter.process(&samples, 1) <= 10f32.powf(-6.0 / 20.0));
+    }
+
+    #[test]
+    fn passes_quiet_audio_through_untouched_but_delayed() {
+        let input = (0..4_800)
+            .map(|i| (i as f32 * 0.05).sin() * 0.5)
+            .collect::<Vec<_>>();
+        let mut samples = input.clone();
+        let mut limiter = Limiter::new(2, 48_000, -1.0);
+        limiter.process(&mut samples);
+        let delay = limiter.latency_frames() * 2;
+        assert!(samples[..delay].iter().all(|&sample| sample == 0.0));
+        assert_eq!(samples[delay..], input[..input.len() - delay]);
+    }
+
+    #[test]
+    fn recovers_after_a_peak() {
+        let mut samples = inter_sample_peaks(4_800, 1);
+        samples.extend((0..48_000).map(|i| (i as f32 * 0.05).sin() * 0.5));
+        let mut limiter = Limiter::new(1, 48_000, -1.0);
+        limiter.process(&mut samples);
+        let tail = &samples[samples.len() - 4_800..];
+        let peak = tail
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - 0.5).abs() < 0.001, "{}", peak);
+    }
+}
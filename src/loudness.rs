@@ -0,0 +1,223 @@
+//! Short-term loudness as ITU-R BS.1770 measures it: the signal K-weighted, then its mean square
+//! over the last 3 seconds, in LUFS.
+//!
+//! K-weighting is a high shelf for how the head boosts the upper mids, then a high-pass for how
+//! little the lows count, each a biquad worked out for the sample rate. The mean square is kept in
+//! 100 ms blocks, so the window slides every block without keeping the samples. Every channel
+//! counts the same, as the front ones do in BS.1770, since nothing says which of the output's
+//! channels are surrounds.
+
+use std::f64::consts::PI;
+
+/// How long the window is.
+const WINDOW_BLOCKS: usize = 30;
+/// How many blocks a second is split into.
+const BLOCKS_PER_SECOND: u32 = 10;
+/// Anything quieter reads as silence, as below BS.1770's absolute gate.
+pub const SILENCE_LUFS: f32 = -70.0;
+
+/// One biquad section, in transposed direct form II.
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filters at `sample_rate`, after the constants libebur128 derives from the
+/// 48 kHz coefficients in BS.1770.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let (f0, gain_db, q) = (
+        1_681.974_450_955_533,
+        3.999_843_853_973_347,
+        0.707_175_236_955_419_6,
+    );
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    let (f0, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Measures the short-term loudness of interleaved audio, without allocating once made.
+pub struct LoudnessMeter {
+    filters: Vec<[Biquad; 2]>,
+    block_frames: usize,
+    /// The K-weighted sum of squares of the block being filled, over every channel, and how many
+    /// frames are in it so far.
+    block_sum: f64,
+    block_filled: usize,
+    /// The mean square of each of the last blocks, oldest overwritten first, and how many have
+    /// been filled, up to the window.
+    blocks: [f64; WINDOW_BLOCKS],
+    next_block: usize,
+    filled_blocks: usize,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        LoudnessMeter {
+            filters: vec![k_weighting(sample_rate); channels],
+            block_frames: (sample_rate / BLOCKS_PER_SECOND).max(1) as usize,
+            block_sum: 0.0,
+            block_filled: 0,
+            blocks: [0.0; WINDOW_BLOCKS],
+            next_block: 0,
+            filled_blocks: 0,
+        }
+    }
+
+    /// Measures `samples`, with `channels` channels to a frame, and returns whether a block just
+    /// finished so the loudness has moved on.
+    pub fn process(&mut self, samples: &[f32], channels: usize) -> bool {
+        let mut finished = false;
+        for frame in samples.chunks_exact(channels) {
+            for (sample, filters) in frame.iter().zip(&mut self.filters) {
+                let weighted = filters
+                    .iter_mut()
+                    .fold(*sample as f64, |x, filter| filter.process(x));
+                self.block_sum += weighted * weighted;
+            }
+            self.block_filled += 1;
+            if self.block_filled == self.block_frames {
+                self.blocks[self.next_block] = self.block_sum / self.block_frames as f64;
+                self.next_block = (self.next_block + 1) % WINDOW_BLOCKS;
+                self.filled_blocks = (self.filled_blocks + 1).min(WINDOW_BLOCKS);
+                self.block_sum = 0.0;
+                self.block_filled = 0;
+                finished = true;
+            }
+        }
+        finished
+    }
+
+    /// The K-weighted mean square over the last 3 seconds, summed over the channels, or over
+    /// however much has been measured if it's been less than that.
+    pub fn mean_square(&self) -> f32 {
+        if self.filled_blocks == 0 {
+            return 0.0;
+        }
+        (self.blocks.iter().sum::<f64>() / self.filled_blocks as f64) as f32
+    }
+
+    /// The short-term loudness, in LUFS, or `None` below [`SILENCE_LUFS`].
+    pub fn short_term(&self) -> Option<f32> {
+        lufs(self.mean_square())
+    }
+}
+
+/// The loudness of a K-weighted `mean_square`, or `None` below [`SILENCE_LUFS`].
+pub fn lufs(mean_square: f32) -> Option<f32> {
+    let lufs = -0.691 + 10.0 * mean_square.log10();
+    (lufs >= SILENCE_LUFS).then_some(lufs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    fn sine(frequency: f32, amplitude: f32, channels: usize, seconds: f32) -> Vec<f32> {
+        let frames = (RATE as f32 * seconds) as usize;
+        (0..frames * channels)
+            .map(|i| {
+                let t = (i / channels) as f32 / RATE as f32;
+                amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin()
+            })
+            .collect()
+    }
+
+    fn measure(samples: &[f32], channels: usize) -> Option<f32> {
+        let mut meter = LoudnessMeter::new(channels, RATE);
+        // In callback-sized pieces, which blocks don't line up with.
+        for chunk in samples.chunks(437 * channels) {
+            meter.process(chunk, channels);
+        }
+        meter.short_term()
+    }
+
+    #[test]
+    fn reads_a_full_scale_sine_as_bs_1770_does() {
+        // A 997 Hz sine at 0 dBFS reads -3.01 LUFS in one channel, and 0 in both of stereo.
+        let mono = measure(&sine(997.0, 1.0, 1, 3.0), 1).unwrap();
+        assert!((mono + 3.01).abs() < 0.05, "{}", mono);
+        let stereo = measure(&sine(997.0, 1.0, 2, 3.0), 2).unwrap();
+        assert!(stereo.abs() < 0.05, "{}", stereo);
+        let quiet = measure(&sine(997.0, 0.1, 2, 3.0), 2).unwrap();
+        assert!((quiet + 20.0).abs() < 0.05, "{}", quiet);
+    }
+
+    #[test]
+    fn counts_the_lows_for_less_and_the_highs_for_more() {
+        let low = measure(&sine(40.0, 0.5, 1, 3.0), 1).unwrap();
+        let mid = measure(&sine(997.0, 0.5, 1, 3.0), 1).unwrap();
+        let high = measure(&sine(8_000.0, 0.5, 1, 3.0), 1).unwrap();
+        assert!(mid - low > 1.0, "{} then {}", low, mid);
+        assert!(high - mid > 3.0, "{} then {}", mid, high);
+    }
+
+    #[test]
+    fn forgets_whatever_is_more_than_3_seconds_old() {
+        let mut meter = LoudnessMeter::new(2, RATE);
+        assert_eq!(meter.short_term(), None);
+        meter.process(&sine(997.0, 1.0, 2, 3.0), 2);
+        let silence = vec![0.0; RATE as usize / 10 * 2];
+        for _ in 0..10 {
+            meter.process(&silence, 2);
+        }
+        // A second of silence in the window takes a third of the energy away.
+        let fading = meter.short_term().unwrap();
+        assert!(
+            (fading - 10.0 * (2.0f32 / 3.0).log10()).abs() < 0.05,
+            "{}",
+            fading
+        );
+        // The filters ring on into the first block of silence, so it's silent once that has gone
+        // too.
+        for _ in 0..20 {
+            meter.process(&silence, 2);
+        }
+        assert!(meter.short_term().is_some());
+        meter.process(&silence, 2);
+        assert_eq!(meter.short_term(), None);
+    }
+
+    #[test]
+    fn says_when_a_block_finishes() {
+        let mut meter = LoudnessMeter::new(1, RATE);
+        assert!(!meter.process(&[0.5; 4_799], 1));
+        assert!(meter.process(&[0.5; 1], 1));
+        assert!(!meter.process(&[], 1));
+    }
+}
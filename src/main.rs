@@ -1,128 +1,2114 @@
 //! Feeds back the input stream directly into the output stream.
 //!
-//! Assumes that the input and output devices can use the same stream configuration and that they
-//! support the f32 sample format.
+//! Assumes that the input and output devices support the f32 sample format.
 //!
-//! Uses a delay of `LATENCY_MS` milliseconds in case the default input and output streams are not
-//! precisely synchronised.
+//! `--help` lists every option and command, and the README describes what each of them does.
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use ringbuf::{
-    ring_buffer::{RbRef, RbWrite},
-    HeapRb, Producer,
+use anyhow::{bail, Context};
+use loopback_clone::{
+    attach,
+    backend::{
+        cpal_host::CpalProvider,
+        null::{self, WithNullOutput},
+        DeviceInfo, DeviceProvider,
+    },
+    chain::{GainStage, InputChain, ProcessStage},
+    control::{
+        self, CompareCommand, Comparison, DuckHold, MasterControl, ReverbControl, SceneCommand,
+    },
+    correlation::{best_alignment, downmix, find_template, wide_alignment},
+    device_ids::DeviceIds,
+    enumerate::{Backoff, DeviceService, DeviceWatch, WithDeviceService},
+    error::PipelineError,
+    health::{FailCondition, HealthMonitor, Watchdog, SILENCE_THRESHOLD},
+    latency::AdaptiveLatency,
+    limiter, memory, mix,
+    pipeline::{
+        create_input_processing_fn, err_fn, ms_to_frames, parse_split, parse_subinput, InputConfig,
+        Pipeline, PipelineConfig, DEFAULT_REVERB_WET, MARKER_SIDECAR,
+    },
+    recorder::{FormatChange, MirrorSpec, Recorder, SyncPolicy},
+    resample::Quality,
+    scene::{self, Scene, Scenes},
+    session_log::SessionLog,
+    title::{self, TerminalTitle},
+    verify,
 };
+use ringbuf::{HeapConsumer, HeapRb};
+use serde::Serialize;
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// What `--help` prints.
+const USAGE: &str = "\
+Mixes the inputs into the output, until stopped.
+
+Usage: loopback-clone [command] [options]
+
+Commands:
+  polarity-check     Reports whether `--from` and `--against` are in phase
+  measure-latency    Measures the round trip from the output back into `--from`
+  sync-inputs        Measures the offset between `--from` and `--against` from a clap
+
+Devices:
+  --output <name>                 The output device, `null`, or `file:<path>` to record it
+                                  (or `opus-udp:<host:port>` with the `opus` feature)
+  --split <device=ch:name,...>    Splits a device's channels into separate inputs
+  --subinput <device=3-4:name>    Takes a range of a device's channels as an input
+  --channels-out <count>          Opens the output with this many channels
+  --strict-routing                Fails instead of remapping channels that don't fit
+  --no-negotiate                  Uses the first input's default config
+  --resample-quality <quality>    `fast`, `balanced` (the default) or `high`
+  --wait-for-output-free <time>   Waits this long for an output held by another application
+  --list-devices                  Lists every device and exits
+  --device-ids <path>             Finds the devices by the IDs recorded in this file
+
+Latency:
+  --latency-ms <ms>               The latency between the inputs and the output
+  --latency auto[:min=..,max=..]  Adapts the latency while running
+  --ringbuf-ms <ms>               The ring buffers' size
+  --delay <name=time>             Plays an input later than the others
+  --watchdog <time>               Rebuilds once the output stops for this long, 0 for never
+
+Processing:
+  --invert <name>                 Flips an input's polarity
+  --denoise <name>                Suppresses an input's noise, with the `denoise` feature
+  --denoise-mix <0-1>             How much of the suppressed signal to use
+  --reverb <name>                 Gives an input a reverb send, off to start with
+  --reverb-wet <0-1>              How loud the reverb is once on
+  --duck <name>                   The input `duck-hold` fades
+  --scene <scene>                 Defines a scene, like `gameplay: Game=0dB, Music=mute`
+  --scenes <path>                 Defines the scenes in this file, one to a line
+  --scene-transition <time>       How long switching scenes takes
+  --planar                        Runs the chains on deinterleaved audio
+  --rt-priority                   Raises the audio threads' priority
+
+Recording:
+  --record-ab <name>              Records an input before and after its chain
+  --stems-pre-fader               Records the processed stems before their gain
+  --record-mirror <path>          Records the output to a second file as well
+  --record-sync <policy>          How often recordings reach the disk, like `5s,fsync`
+  --record-mirror-sync <policy>   The same, for the mirror
+  --record-ceiling <dBTP>         Limits the output's recording to this true peak
+  --record-continue <policy>      Carries recordings on through rebuilds, with `split`,
+                                  `resample` or `abort` for ones whose format changes
+  --record-preroll <time>         Starts every recording with this much silence
+  --replay-buffer <time>          Keeps this much of the output for `clip`
+  --cue-markers                   Embeds markers in the recordings as cue points
+
+Inputs that come and go:
+  --auto-attach <pattern>         Attaches input devices whose names match
+  --auto-pan                      Spreads the attached inputs across the stereo field
+
+Monitoring:
+  --fail-on <condition>           Exits once `underruns=<n>/<time>` or `silence=<time>` trips
+  --session-log <path>            Appends everything that happens to this file
+  --session-log-json <path>       The same, as lines of JSON
+  --describe-json <path>          Writes what the pipeline is doing to this file as JSON
+  --print-chain                   Prints every input's chain at startup
+  --print-memory                  Prints what the audio buffers take at startup
+  --no-title                      Leaves the terminal's title alone
+  --verify-passthrough            Checks that audio passes through unchanged, and exits
+  --from <name>, --against <name> The inputs the commands measure
+
+Typed on stdin while running:
+  status, describe, duck-hold on|off|<time>, marker <label>, identify [channel],
+  clip [time], add-input <name>, remove-input <name>, input stop|start <name>,
+  compare <name> <name> <time>, compare stop, arm [on|off], volume-up, volume-down,
+  play-pause, fx reverb on|off|wet <level>, scene <name> [time], scene list
+
+Each input's real sample rate is measured against the clock and shown by `status`. Drift is
+only measured and warned about: nothing resamples to correct it.
+";
+
+const MICROPHONE_NAME: &str = "MacBook Pro Microphone";
+const GAME_CAPTURE_NAME: &str = "Game Capture HD60 X";
+const OUTPUT_NAME: &str = "BlackHole 16ch";
+
+const LATENCY_MS: f32 = 50.0;
+
+/// How much of both inputs `polarity-check` records before correlating them.
+const POLARITY_CHECK_WINDOW_MS: f32 = 500.0;
+/// The largest offset between the inputs, in either direction, that `polarity-check` considers.
+const POLARITY_CHECK_MAX_LAG_MS: f32 = 20.0;
+/// Below this normalised correlation the inputs are considered unrelated rather than in or out of
+/// phase.
+const POLARITY_CHECK_MIN_CORRELATION: f32 = 0.3;
+
+/// How much longer than it should take the capturing commands wait for their inputs, before
+/// giving up on the ones that haven't delivered.
+const CAPTURE_GRACE: Duration = Duration::from_secs(3);
+
+/// How much of both inputs `sync-inputs` records, which has to take in the clap.
+const SYNC_INPUTS_WINDOW_MS: f32 = 3_000.0;
+/// The largest offset between the inputs, in either direction, that `sync-inputs` considers.
+const SYNC_INPUTS_MAX_LAG_MS: f32 = 500.0;
+/// Below this normalised correlation `sync-inputs` doesn't trust the offset it found.
+const SYNC_INPUTS_MIN_CORRELATION: f32 = 0.3;
+
+/// How long `measure-latency` records the input for, which bounds the latency it can measure.
+const MEASURE_LATENCY_CAPTURE_MS: f32 = 2_000.0;
+/// How far into its output `measure-latency` plays the chirp.
+const MEASURE_LATENCY_CHIRP_START_MS: f32 = 250.0;
+const MEASURE_LATENCY_CHIRP_MS: f32 = 50.0;
+/// Below this normalised correlation the chirp is considered not to have been heard at all.
+const MEASURE_LATENCY_MIN_CORRELATION: f32 = 0.3;
 
-fn create_input_processing_fn<R>(
-    mut producer: Producer<f32, R>,
-) -> impl FnMut(&[f32], &cpal::InputCallbackInfo)
-where
-    R: RbRef,
-    <R as RbRef>::Rb: RbWrite<f32>,
-{
-    move |data: &[f32], _: &cpal::InputCallbackInfo| {
-        let mut output_fell_behind = false;
-        if producer.push_slice(data) != data.len() {
-            output_fell_behind = true;
+/// How often the stats are printed while running.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+/// How often timers started by commands are checked.
+const CONTROL_TICK: Duration = Duration::from_millis(50);
+/// How long switching scenes takes, unless `--scene-transition` or the command says otherwise.
+const SCENE_TRANSITION: Duration = Duration::from_millis(500);
+/// The least time between warnings about underruns, which come in bursts.
+const UNDERRUN_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the `--fail-on` conditions are evaluated.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the output callback can go without running before the pipeline is rebuilt.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait between attempts at rebuilding the pipeline, while devices are still coming
+/// back after a wake, at first and at most.
+const REBUILD_RETRY_MIN: Duration = Duration::from_secs(1);
+const REBUILD_RETRY_MAX: Duration = Duration::from_secs(30);
+/// How long to wait between attempts at opening an output another application holds, at first and
+/// at most.
+const OUTPUT_FREE_RETRY_MIN: Duration = Duration::from_millis(500);
+const OUTPUT_FREE_RETRY_MAX: Duration = Duration::from_secs(5);
+/// Makes the provider each pipeline is built from, and that devices are listed and attached from.
+/// A fresh one each time, so the devices are looked up again rather than reused from before.
+type Providers = Arc<dyn Fn() -> Box<dyn DeviceProvider> + Send + Sync>;
+
+enum Command {
+    Run,
+    PolarityCheck,
+    MeasureLatency,
+    SyncInputs,
+}
+
+struct Args {
+    command: Command,
+    latency_ms: f32,
+    ringbuf_ms: Option<f32>,
+    invert: Vec<String>,
+    denoise: Vec<String>,
+    denoise_mix: f32,
+    reverb: Vec<String>,
+    reverb_wet: f32,
+    print_chain: bool,
+    print_memory: bool,
+    list_devices: bool,
+    device_ids: Option<PathBuf>,
+    verify_passthrough: bool,
+    measure_from: String,
+    fail_on: Vec<FailCondition>,
+    record_ab: Vec<String>,
+    watchdog: Duration,
+    wait_for_output_free: Option<Duration>,
+    splits: Vec<Vec<InputConfig>>,
+    duck: String,
+    scenes: Vec<Scene>,
+    scene_transition: Duration,
+    cue_markers: bool,
+    no_title: bool,
+    negotiate: bool,
+    channels_out: Option<u16>,
+    strict_routing: bool,
+    resample_quality: Quality,
+    adaptive_latency: Option<AdaptiveLatency>,
+    replay_buffer: Option<Duration>,
+    planar: bool,
+    output: String,
+    record_output: Option<PathBuf>,
+    record_mirror: Option<PathBuf>,
+    record_sync: SyncPolicy,
+    record_mirror_sync: SyncPolicy,
+    record_ceiling: Option<f32>,
+    record_continue: Option<FormatChange>,
+    stems_pre_fader: bool,
+    auto_attach: Vec<String>,
+    auto_pan: bool,
+    delays: Vec<(String, Duration)>,
+    sync_against: String,
+    rt_priority: bool,
+    preroll: Duration,
+    session_log: Option<PathBuf>,
+    session_log_json: Option<PathBuf>,
+    describe_json: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        Args::parse_from(std::env::args().skip(1))
+    }
+
+    /// Parses `arguments`, which don't include the program's name.
+    fn parse_from(arguments: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = Args {
+            command: Command::Run,
+            latency_ms: LATENCY_MS,
+            ringbuf_ms: None,
+            invert: Vec::new(),
+            denoise: Vec::new(),
+            denoise_mix: 1.0,
+            reverb: Vec::new(),
+            reverb_wet: DEFAULT_REVERB_WET,
+            print_chain: false,
+            print_memory: false,
+            list_devices: false,
+            device_ids: None,
+            verify_passthrough: false,
+            measure_from: MICROPHONE_NAME.to_owned(),
+            fail_on: Vec::new(),
+            record_ab: Vec::new(),
+            watchdog: WATCHDOG_TIMEOUT,
+            wait_for_output_free: None,
+            splits: Vec::new(),
+            duck: GAME_CAPTURE_NAME.to_owned(),
+            scenes: Vec::new(),
+            scene_transition: SCENE_TRANSITION,
+            cue_markers: false,
+            no_title: false,
+            negotiate: true,
+            channels_out: None,
+            strict_routing: false,
+            resample_quality: Quality::default(),
+            adaptive_latency: None,
+            replay_buffer: None,
+            planar: false,
+            output: OUTPUT_NAME.to_owned(),
+            record_output: None,
+            record_mirror: None,
+            record_sync: SyncPolicy::default(),
+            record_mirror_sync: SyncPolicy::default(),
+            record_ceiling: None,
+            record_continue: None,
+            stems_pre_fader: false,
+            auto_attach: Vec::new(),
+            auto_pan: false,
+            delays: Vec::new(),
+            sync_against: GAME_CAPTURE_NAME.to_owned(),
+            rt_priority: false,
+            preroll: Duration::ZERO,
+            session_log: None,
+            session_log_json: None,
+            describe_json: None,
+        };
+
+        let mut iter = arguments.into_iter().peekable();
+        if let Some(command) = iter.next_if(|arg| !arg.starts_with('-')) {
+            args.command = match command.as_str() {
+                "polarity-check" => Command::PolarityCheck,
+                "measure-latency" => Command::MeasureLatency,
+                "sync-inputs" => Command::SyncInputs,
+                other => bail!("unknown command `{}`", other),
+            };
         }
-        if output_fell_behind {
-            eprintln!("output stream fell behind: try increasing latency");
+
+        while let Some(arg) = iter.next() {
+            let mut value = |name: &str| {
+                iter.next()
+                    .with_context(|| format!("`{}` expects a value", name))
+            };
+            match arg.as_str() {
+                "--latency-ms" => args.latency_ms = parse_ms(&arg, &value(&arg)?)?,
+                "--latency" => {
+                    args.adaptive_latency = Some(
+                        AdaptiveLatency::parse(&value(&arg)?)
+                            .with_context(|| format!("in `{}`", arg))?,
+                    )
+                }
+                "--ringbuf-ms" => args.ringbuf_ms = Some(parse_ms(&arg, &value(&arg)?)?),
+                "--invert" => args.invert.push(value(&arg)?),
+                "--denoise" => args.denoise.push(value(&arg)?),
+                "--reverb" => args.reverb.push(value(&arg)?),
+                "--reverb-wet" => {
+                    let value = value(&arg)?;
+                    args.reverb_wet = value
+                        .parse()
+                        .ok()
+                        .filter(|wet| (0.0..=1.0).contains(wet))
+                        .with_context(|| {
+                            format!("`{}` expects a number from 0 to 1, got `{}`", arg, value)
+                        })?;
+                }
+                "--denoise-mix" => {
+                    let value = value(&arg)?;
+                    args.denoise_mix = value
+                        .parse()
+                        .ok()
+                        .filter(|mix| (0.0..=1.0).contains(mix))
+                        .with_context(|| {
+                        format!("`{}` expects a number from 0 to 1, got `{}`", arg, value)
+                    })?;
+                }
+                "--help" | "-h" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--print-chain" => args.print_chain = true,
+                "--print-memory" => args.print_memory = true,
+                "--cue-markers" => args.cue_markers = true,
+                "--no-title" => args.no_title = true,
+                "--planar" => args.planar = true,
+                "--rt-priority" => args.rt_priority = true,
+                "--auto-pan" => args.auto_pan = true,
+                "--strict-routing" => args.strict_routing = true,
+                "--stems-pre-fader" => args.stems_pre_fader = true,
+                "--output" => {
+                    let output = value(&arg)?;
+                    match output.strip_prefix("file:") {
+                        Some("") => bail!("`{}` expects a path after `file:`", arg),
+                        Some(path) => {
+                            args.output = null::NAME.to_owned();
+                            args.record_output = Some(PathBuf::from(path));
+                        }
+                        None if output.starts_with("opus-udp:") => {
+                            opus_target(&output).with_context(|| format!("in `{}`", arg))?;
+                            args.output = output;
+                        }
+                        None => args.output = output,
+                    }
+                }
+                "--no-negotiate" => args.negotiate = false,
+                "--resample-quality" => {
+                    args.resample_quality =
+                        Quality::parse(&value(&arg)?).with_context(|| format!("in `{}`", arg))?
+                }
+                "--channels-out" => {
+                    let value = value(&arg)?;
+                    args.channels_out = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|channels| *channels > 0)
+                            .with_context(|| {
+                                format!("`{}` expects a number of channels, got `{}`", arg, value)
+                            })?,
+                    );
+                }
+                "--list-devices" => args.list_devices = true,
+                "--device-ids" => args.device_ids = Some(PathBuf::from(value(&arg)?)),
+                "--verify-passthrough" => args.verify_passthrough = true,
+                "--from" => args.measure_from = value(&arg)?,
+                "--against" => args.sync_against = value(&arg)?,
+                "--delay" => {
+                    let value = value(&arg)?;
+                    let (name, delay) = value.rsplit_once('=').with_context(|| {
+                        format!(
+                            "`{}` expects an input and a delay like `Mic=12ms`, got `{}`",
+                            arg, value
+                        )
+                    })?;
+                    args.delays.push((
+                        name.to_owned(),
+                        control::parse_duration(delay).with_context(|| format!("in `{}`", arg))?,
+                    ));
+                }
+                "--fail-on" => args.fail_on.push(
+                    FailCondition::parse(&value(&arg)?).with_context(|| format!("in `{}`", arg))?,
+                ),
+                "--record-ab" => args.record_ab.push(value(&arg)?),
+                "--auto-attach" => args.auto_attach.push(value(&arg)?),
+                "--split" => args
+                    .splits
+                    .push(parse_split(&value(&arg)?).with_context(|| format!("in `{}`", arg))?),
+                "--subinput" => {
+                    args.splits
+                        .push(vec![parse_subinput(&value(&arg)?)
+                            .with_context(|| format!("in `{}`", arg))?])
+                }
+                "--duck" => args.duck = value(&arg)?,
+                "--scene" => args.scenes.push(Scene::parse(&value(&arg)?)?),
+                "--scenes" => args.scenes.extend(scene::load(Path::new(&value(&arg)?))?),
+                "--scene-transition" => {
+                    args.scene_transition = control::parse_duration(&value(&arg)?)
+                        .with_context(|| format!("in `{}`", arg))?
+                }
+                "--replay-buffer" => {
+                    args.replay_buffer = Some(
+                        control::parse_duration(&value(&arg)?)
+                            .with_context(|| format!("in `{}`", arg))?,
+                    )
+                }
+                "--record-mirror" => args.record_mirror = Some(PathBuf::from(value(&arg)?)),
+                "--record-sync" => {
+                    args.record_sync =
+                        SyncPolicy::parse(&value(&arg)?).with_context(|| format!("in `{}`", arg))?
+                }
+                "--record-mirror-sync" => {
+                    args.record_mirror_sync =
+                        SyncPolicy::parse(&value(&arg)?).with_context(|| format!("in `{}`", arg))?
+                }
+                "--record-ceiling" => {
+                    args.record_ceiling = Some(
+                        limiter::parse_ceiling(&value(&arg)?)
+                            .with_context(|| format!("in `{}`", arg))?,
+                    )
+                }
+                "--record-continue" => {
+                    args.record_continue = Some(
+                        FormatChange::parse(&value(&arg)?)
+                            .with_context(|| format!("in `{}`", arg))?,
+                    )
+                }
+                "--record-preroll" => {
+                    args.preroll = control::parse_duration(&value(&arg)?)
+                        .with_context(|| format!("in `{}`", arg))?
+                }
+                "--session-log" => args.session_log = Some(PathBuf::from(value(&arg)?)),
+                "--session-log-json" => args.session_log_json = Some(PathBuf::from(value(&arg)?)),
+                "--describe-json" => args.describe_json = Some(PathBuf::from(value(&arg)?)),
+                "--watchdog" => {
+                    args.watchdog = control::parse_duration(&value(&arg)?)
+                        .with_context(|| format!("in `{}`", arg))?
+                }
+                "--wait-for-output-free" => {
+                    args.wait_for_output_free = Some(
+                        control::parse_duration(&value(&arg)?)
+                            .with_context(|| format!("in `{}`", arg))?,
+                    )
+                }
+                other => bail!("unknown argument `{}`", other),
+            }
         }
+
+        Ok(args)
     }
 }
 
+fn latency_status(pipeline: &Pipeline, adaptive: Option<AdaptiveLatency>) -> String {
+    let buffered_ms =
+        pipeline.counters().buffered_frames() as f32 * 1_000.0 / pipeline.sample_rate() as f32;
+    match adaptive {
+        Some(adaptive) => format!(
+            "Latency: {:.1} ms buffered, adapting between {} and {} ms.",
+            buffered_ms, adaptive.min_ms, adaptive.max_ms
+        ),
+        None => format!("Latency: {:.1} ms buffered.", buffered_ms),
+    }
+}
+
+fn parse_ms(name: &str, value: &str) -> anyhow::Result<f32> {
+    let ms: f32 = value.parse().with_context(|| {
+        format!(
+            "`{}` expects a number of milliseconds, got `{}`",
+            name, value
+        )
+    })?;
+    if !ms.is_finite() || ms < 0.0 {
+        bail!("`{}` must be a non-negative number of milliseconds", name);
+    }
+    Ok(ms)
+}
+
+fn inverted(args: &Args, device_name: &str) -> bool {
+    args.invert.iter().any(|name| name == device_name)
+}
+
+/// Checks that every input named by `flag` is one of `inputs`.
+fn validate_input_names(
+    flag: &str,
+    names: &[String],
+    inputs: &[InputConfig],
+) -> anyhow::Result<()> {
+    for name in names {
+        if !inputs.iter().any(|input| input.name == *name) {
+            bail!(
+                "`{}` names \"{}\", which isn't one of the inputs",
+                flag,
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints what the library logs as it always printed it: warnings and errors to stderr, and
+/// everything else to stdout.
+struct Printer;
+
+impl log::Log for Printer {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("loopback_clone")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}", record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
 fn main() -> anyhow::Result<()> {
-    let host = cpal::default_host();
-
-    // Find devices.
-    let microphone = host
-        .input_devices()?
-        .find(|device| {
-            device
-                .name()
-                .map(|name| name == "MacBook Pro Microphone")
-                .unwrap_or(false)
-        })
-        .expect("microphone input device exists");
-    let game_capture = host
-        .input_devices()?
-        .find(|device| {
-            device
-                .name()
-                .map(|name| name == "Game Capture HD60 X")
-                .unwrap_or(false)
+    log::set_logger(&Printer).expect("nothing else sets a logger");
+    log::set_max_level(log::LevelFilter::Info);
+    let args = Args::parse()?;
+    if args.list_devices {
+        return list_devices(&CpalProvider::new());
+    }
+    if args.verify_passthrough {
+        return verify_passthrough();
+    }
+    match args.command {
+        Command::Run => run(&args),
+        Command::PolarityCheck => polarity_check(&args),
+        Command::MeasureLatency => measure_latency(&args),
+        Command::SyncInputs => sync_inputs(&args),
+    }
+}
+
+fn verify_passthrough() -> anyhow::Result<()> {
+    let mut failed = false;
+    for report in verify::verify_passthrough(&std::env::temp_dir())? {
+        match &report.mismatch {
+            None => println!(
+                "{}: all {} samples passed through unchanged.",
+                report.case, report.samples
+            ),
+            Some(mismatch) => {
+                println!("{}: {}.", report.case, mismatch);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        bail!("the pipeline changed samples it should have passed through untouched");
+    }
+    Ok(())
+}
+
+fn list_devices(provider: &dyn DeviceProvider) -> anyhow::Result<()> {
+    for device in provider.devices()? {
+        println!("{}", device_line(&device));
+    }
+    Ok(())
+}
+
+/// How `--list-devices` lists `device`, by name and stable ID.
+fn device_line(device: &DeviceInfo) -> String {
+    let kind = match (device.is_input, device.is_output) {
+        (true, true) => "input/output",
+        (true, false) => "input",
+        _ => "output",
+    };
+    match &device.stable_id {
+        Some(id) => format!("{} \"{}\" (ID {})", kind, device.name, id),
+        None => format!("{} \"{}\" (no stable ID)", kind, device.name),
+    }
+}
+
+/// Finds the devices `config` names by the IDs recorded for them in `path`, recording the IDs of
+/// any that haven't been yet.
+fn resolve_devices(
+    config: &mut PipelineConfig,
+    path: &Path,
+    provider: &dyn DeviceProvider,
+) -> anyhow::Result<()> {
+    let mut ids = DeviceIds::load(path)?;
+    for warning in ids.apply(config, &provider.devices()?) {
+        eprintln!("{}", warning);
+    }
+    ids.save(path)?;
+    Ok(())
+}
+
+fn run(args: &Args) -> anyhow::Result<()> {
+    let mut config = pipeline_config(args)?;
+    let providers: Providers = Arc::new(|| Box::new(CpalProvider::new()));
+    if let Some(path) = &args.device_ids {
+        resolve_devices(&mut config, path, &*providers())?;
+    }
+    let mut log = SessionLog::open(
+        args.session_log.as_deref(),
+        args.session_log_json.as_deref(),
+    )?;
+    let mut pipeline = match args.wait_for_output_free {
+        Some(timeout) => start_when_output_free(
+            &providers,
+            &config,
+            args.print_chain,
+            args.print_memory,
+            timeout,
+        )?,
+        None => start_pipeline(
+            providers(),
+            &config,
+            args.print_chain,
+            args.print_memory,
+            &mut None,
+        )?,
+    };
+    write_description(args.describe_json.as_deref(), &pipeline);
+    log.event("start", &describe_pipeline(&config, &pipeline));
+    let mut session = Session::new(args, config, log, &pipeline, providers)?;
+
+    let (sender, commands) = mpsc::channel();
+    #[cfg(feature = "media-keys")]
+    if let Err(err) = loopback_clone::media_keys::spawn(session.master.armed_flag(), sender.clone())
+    {
+        eprintln!(
+            "couldn't catch the media keys, so only the commands work: {}",
+            err
+        );
+    }
+    spawn_stdin_commands(sender);
+    loop {
+        match commands.recv_timeout(CONTROL_TICK) {
+            Ok(command) => session.handle(&mut pipeline, command),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            // Without stdin there is nothing left to control, but the audio keeps going.
+            Err(mpsc::RecvTimeoutError::Disconnected) => std::thread::sleep(CONTROL_TICK),
+        }
+        session.auto_attach(&mut pipeline);
+
+        let now = Instant::now();
+        session.tick(&pipeline, now);
+        if session.output_stopped(&pipeline, now) {
+            pipeline = session.recover(pipeline)?;
+            continue;
+        }
+        session.update(&mut pipeline, now);
+        if let Some(condition) = session.failed(&pipeline, now) {
+            session.stop(pipeline, condition, now);
+        }
+    }
+}
+
+/// Works out the pipeline's configuration from the arguments, refusing any that name inputs there
+/// aren't.
+fn pipeline_config(args: &Args) -> anyhow::Result<PipelineConfig> {
+    // Splitting a device replaces it with the inputs split from it, or adds them if it isn't
+    // already an input. Splitting it again adds to the inputs already split from it.
+    let mut inputs = vec![
+        InputConfig::new(MICROPHONE_NAME),
+        InputConfig::new(GAME_CAPTURE_NAME),
+    ];
+    for split in &args.splits {
+        let device = &split[0].device;
+        let whole = inputs
+            .iter()
+            .position(|input| input.device == *device && input.channels.is_none());
+        let last_split = inputs
+            .iter()
+            .rposition(|input| input.device == *device && input.channels.is_some());
+        match (whole, last_split) {
+            (Some(position), _) => {
+                inputs.splice(position..=position, split.iter().cloned());
+            }
+            (None, Some(position)) => {
+                inputs.splice(position + 1..position + 1, split.iter().cloned());
+            }
+            (None, None) => inputs.extend(split.iter().cloned()),
+        }
+    }
+    if !inputs.iter().any(|input| input.name == args.duck) {
+        bail!(
+            "duck-hold fades \"{}\", which isn't one of the inputs: name the one it should fade \
+             with `--duck`",
+            args.duck
+        );
+    }
+
+    validate_input_names("--invert", &args.invert, &inputs)?;
+    validate_input_names("--denoise", &args.denoise, &inputs)?;
+    validate_input_names("--reverb", &args.reverb, &inputs)?;
+    if let Some(scene) = args.scenes.iter().find(|scene| scene.sets_reverb()) {
+        if args.reverb.is_empty() {
+            bail!(
+                "scene \"{}\" sets the reverb, but no input has one: add one with `--reverb`",
+                scene.name()
+            );
+        }
+    }
+    validate_input_names("--record-ab", &args.record_ab, &inputs)?;
+    match (&args.record_output, &args.record_mirror) {
+        (None, Some(_)) => bail!("`--record-mirror` needs `--output file:<path>` to mirror"),
+        (Some(path), Some(mirror)) if path == mirror => {
+            bail!("`--record-mirror` has to be a different file from the recording")
+        }
+        _ => {}
+    }
+    let delayed = args
+        .delays
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    validate_input_names("--delay", &delayed, &inputs)?;
+
+    for input in &mut inputs {
+        input.invert = inverted(args, &input.name);
+        input.denoise = args.denoise.contains(&input.name);
+        input.reverb = args.reverb.contains(&input.name);
+        input.record_ab = args.record_ab.contains(&input.name);
+        // The last `--delay` for an input wins.
+        if let Some((_, delay)) = args
+            .delays
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == input.name)
+        {
+            input.delay = *delay;
+        }
+    }
+    Ok(PipelineConfig {
+        inputs,
+        output: args.output.clone(),
+        latency_ms: args.latency_ms,
+        ringbuf_ms: args.ringbuf_ms,
+        denoise_mix: args.denoise_mix,
+        segment: 0,
+        cue_markers: args.cue_markers,
+        negotiate: args.negotiate,
+        output_channels: args.channels_out,
+        adaptive_latency: args.adaptive_latency,
+        replay_buffer: args.replay_buffer,
+        planar: args.planar,
+        record_output: args.record_output.clone(),
+        record_mirror: args.record_mirror.clone().map(|path| MirrorSpec {
+            path,
+            sync: args.record_mirror_sync,
+        }),
+        record_sync: args.record_sync,
+        record_ceiling: args.record_ceiling,
+        record_continue: args.record_continue,
+        stems_pre_fader: args.stems_pre_fader,
+        rt_priority: args.rt_priority,
+        preroll: args.preroll,
+        marker_sidecar: PathBuf::from(MARKER_SIDECAR),
+        record_dir: PathBuf::new(),
+        strict_routing: args.strict_routing,
+        resample_quality: args.resample_quality,
+    })
+}
+
+/// Everything the control thread keeps track of while the pipeline runs, across rebuilds.
+struct Session<'a> {
+    args: &'a Args,
+    config: PipelineConfig,
+    log: SessionLog,
+    duck_hold: DuckHold,
+    master: MasterControl,
+    reverb: ReverbControl,
+    comparison: Option<Comparison>,
+    scenes: Scenes,
+    started: Instant,
+    health: HealthMonitor,
+    watchdog: Option<Watchdog>,
+    /// Underruns from pipelines that have since been rebuilt, as the counters start over each
+    /// time.
+    earlier_underruns: u64,
+    /// How many underruns the session log has accounted for.
+    logged_underruns: u64,
+    /// Clipped samples from pipelines that have since been rebuilt.
+    earlier_clipped: u64,
+    /// Recordings given up on by every pipeline so far, and how many of them were the current
+    /// one's.
+    recording_failures: Vec<String>,
+    noted_failures: usize,
+    /// Recordings carried on into a stream of another format, the same way.
+    format_changes: Vec<String>,
+    noted_format_changes: usize,
+    providers: Providers,
+    devices: DeviceService,
+    device_watch: Option<DeviceWatch>,
+    /// Inputs attached because they matched `--auto-attach`, and devices that matched but
+    /// couldn't be attached, which aren't tried again until they go away and come back.
+    auto_attached: Vec<String>,
+    unattachable: Vec<String>,
+    next_stats: Instant,
+    next_health: Instant,
+    /// How many underruns have been warned about, and when they can be again.
+    warned_underruns: u64,
+    next_underrun_warning: Instant,
+    title: Option<TerminalTitle>,
+    next_title: Instant,
+}
+
+impl<'a> Session<'a> {
+    fn new(
+        args: &'a Args,
+        config: PipelineConfig,
+        log: SessionLog,
+        pipeline: &Pipeline,
+        providers: Providers,
+    ) -> anyhow::Result<Self> {
+        let duck_hold = DuckHold::new(
+            &args.duck,
+            pipeline
+                .override_gain(&args.duck)
+                .context("the input to duck is one of the inputs")?,
+        );
+        let started = Instant::now();
+        let list = Arc::clone(&providers);
+        let devices = DeviceService::spawn(Box::new(move || list().devices()));
+        Ok(Session {
+            args,
+            config,
+            log,
+            duck_hold,
+            master: MasterControl::new(Arc::clone(pipeline.master_gain())),
+            reverb: ReverbControl::new(pipeline.reverbs(), args.reverb_wet),
+            comparison: None,
+            scenes: Scenes::new(args.scenes.clone(), args.scene_transition)?,
+            started,
+            health: HealthMonitor::new(args.fail_on.clone(), started),
+            watchdog: (!args.watchdog.is_zero()).then(|| Watchdog::new(args.watchdog, started)),
+            earlier_underruns: 0,
+            logged_underruns: 0,
+            earlier_clipped: 0,
+            recording_failures: Vec::new(),
+            noted_failures: 0,
+            format_changes: Vec::new(),
+            noted_format_changes: 0,
+            device_watch: (!args.auto_attach.is_empty()).then(|| devices.watch()),
+            providers,
+            devices,
+            auto_attached: Vec::new(),
+            unattachable: Vec::new(),
+            next_stats: started + STATS_INTERVAL,
+            next_health: started + HEALTH_INTERVAL,
+            warned_underruns: 0,
+            next_underrun_warning: started,
+            title: (!args.no_title).then(TerminalTitle::new).flatten(),
+            next_title: started,
         })
-        .expect("game capture input device exists");
-    let output_device = host
-        .output_devices()?
-        .find(|device| {
-            device
-                .name()
-                .map(|name| name == "BlackHole 16ch")
-                .unwrap_or(false)
+    }
+
+    /// Carries out a command read from stdin.
+    fn handle(&mut self, pipeline: &mut Pipeline, command: control::Command) {
+        match command {
+            control::Command::DuckHold(command) => {
+                let now = Instant::now();
+                self.duck_hold.apply(command, now);
+                println!("{}", self.duck_hold.status(now));
+                self.log.event("duck-hold", &self.duck_hold.status(now));
+            }
+            control::Command::Describe => {
+                println!("{}", pipeline.describe(host_name()).to_json());
+            }
+            control::Command::Status => self.print_status(pipeline),
+            control::Command::AddInput(name) => {
+                if attach_input(
+                    pipeline,
+                    &self.devices,
+                    &self.providers,
+                    &mut self.log,
+                    &name,
+                ) && self.args.auto_pan
+                {
+                    auto_pan(pipeline, &mut self.log);
+                }
+            }
+            control::Command::RemoveInput(name) => match pipeline.detach_input(&name) {
+                Ok(()) => {
+                    self.auto_attached.retain(|input| *input != name);
+                    println!("Detached input \"{}\".", name);
+                    self.log.event("detach", &format!("\"{}\"", name));
+                    if self.args.auto_pan {
+                        auto_pan(pipeline, &mut self.log);
+                    }
+                }
+                Err(err) => eprintln!("{}", err),
+            },
+            control::Command::StopInput(name) => match pipeline.stop_input(&name) {
+                Ok(()) => {
+                    println!("Stopped input \"{}\".", name);
+                    self.log.event("input-stop", &format!("\"{}\"", name));
+                }
+                Err(err) => eprintln!("{}", err),
+            },
+            control::Command::StartInput(name) => {
+                let provider = WithDeviceService(&self.devices, (self.providers)());
+                match pipeline.start_input(&provider, &name) {
+                    Ok(()) => {
+                        println!("Started input \"{}\" again.", name);
+                        self.log.event("input-start", &format!("\"{}\"", name));
+                    }
+                    Err(err) => eprintln!(
+                        "couldn't start \"{}\": {:#}",
+                        name,
+                        anyhow::Error::from(err)
+                    ),
+                }
+            }
+            control::Command::Compare(CompareCommand::Start { inputs, period }) => {
+                // Whatever was being compared goes back first, so that's what is restored later.
+                if let Some(mut previous) = self.comparison.take() {
+                    previous.stop();
+                }
+                let now = Instant::now();
+                match Comparison::start(inputs, period, pipeline.override_gains(), now) {
+                    Ok(started) => {
+                        println!("{}", started.status(now));
+                        self.log.event("compare", &started.status(now));
+                        self.comparison = Some(started);
+                    }
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            control::Command::Compare(CompareCommand::Stop) => match self.comparison.take() {
+                Some(mut stopped) => {
+                    stopped.stop();
+                    println!("compare: stopped, and every input is back how it was");
+                    self.log.event("compare", "stopped");
+                }
+                None => eprintln!("nothing is being compared"),
+            },
+            control::Command::Arm(armed) => {
+                self.master.arm(armed);
+                println!("{}", self.master.status());
+            }
+            control::Command::Key(key) => {
+                if self.master.key(key) {
+                    println!("{}", self.master.status());
+                    self.log.event("master", &self.master.status());
+                } else {
+                    eprintln!("the media keys aren't armed: `arm` them first");
+                }
+            }
+            control::Command::Reverb(command) => match self.reverb.apply(command) {
+                Ok(()) => {
+                    let status = self.reverb.status().unwrap_or_default();
+                    println!("{}", status);
+                    self.log.event("reverb", &status);
+                }
+                Err(err) => eprintln!("{}", err),
+            },
+            control::Command::Scene(SceneCommand::List) => println!("{}", self.scenes.list()),
+            control::Command::Scene(SceneCommand::Switch { name, transition }) => {
+                let now = Instant::now();
+                match self.scenes.switch(
+                    &name,
+                    transition,
+                    pipeline.override_gains(),
+                    &mut self.duck_hold,
+                    &mut self.reverb,
+                    now,
+                ) {
+                    Ok(missing) => {
+                        for input in missing {
+                            eprintln!(
+                                "scene {} sets \"{}\", which isn't one of the inputs right now",
+                                name, input
+                            );
+                        }
+                        let status = self.scenes.status(now).unwrap_or_default();
+                        println!("{}", status);
+                        self.log.event("scene", &status);
+                    }
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            control::Command::Marker(label) => {
+                if pipeline.marker(&label) {
+                    self.log.event("marker", &format!("\"{}\"", label));
+                } else {
+                    eprintln!("nothing is being recorded to mark");
+                }
+            }
+            control::Command::Clip(duration) => {
+                if pipeline.clip(duration) {
+                    self.log.event(
+                        "clip",
+                        &match duration {
+                            Some(duration) => format!("last {}s", duration.as_secs_f32()),
+                            None => "all of the replay buffer".to_owned(),
+                        },
+                    );
+                } else {
+                    eprintln!("there's nothing to clip without `--replay-buffer`");
+                }
+            }
+            control::Command::Identify(channel) => {
+                if let Err(err) = pipeline.identify(channel) {
+                    eprintln!("{}", err);
+                }
+            }
+        }
+    }
+
+    fn print_status(&self, pipeline: &Pipeline) {
+        println!("{}", self.duck_hold.status(Instant::now()));
+        if let Some(comparison) = &self.comparison {
+            println!("{}", comparison.status(Instant::now()));
+        }
+        println!("{}", latency_status(pipeline, self.config.adaptive_latency));
+        println!("{}", self.master.status());
+        if let Some(status) = self.reverb.status() {
+            println!("{}", status);
+        }
+        if let Some(status) = self.scenes.status(Instant::now()) {
+            println!("{}", status);
+        }
+        for (input, status) in pipeline.input_statuses() {
+            println!("Input \"{}\": {}.", input, status);
+        }
+        for input in pipeline.attached_inputs() {
+            println!("Attached input: \"{}\".", input);
+        }
+        for (input, rate) in pipeline.stats().rates() {
+            println!("Input \"{}\" runs at {}.", input, rate);
+        }
+        for failure in &self.recording_failures {
+            println!("Recording failed: {}.", failure);
+        }
+        for change in &self.format_changes {
+            println!("Recording changed format: {}.", change);
+        }
+    }
+
+    /// Attaches the input devices that have appeared and match `--auto-attach`, and detaches the
+    /// ones attached that way that have gone, if the devices have changed.
+    fn auto_attach(&mut self, pipeline: &mut Pipeline) {
+        let Some(listed) = self
+            .device_watch
+            .as_mut()
+            .and_then(|watch| watch.changed(Duration::ZERO))
+        else {
+            return;
+        };
+        let devices = listed
+            .iter()
+            .filter(|device| device.is_input)
+            .map(|device| device.name.clone())
+            .collect::<Vec<_>>();
+        let previous = pipeline
+            .attached_inputs()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        for name in self.auto_attached.clone() {
+            if !devices.contains(&name) && pipeline.detach_input(&name).is_ok() {
+                println!("Detached input \"{}\", which has gone away.", name);
+                self.log
+                    .event("detach", &format!("\"{}\", which has gone away", name));
+            }
+        }
+        self.auto_attached.retain(|name| devices.contains(name));
+        self.unattachable.retain(|name| devices.contains(name));
+        for name in devices {
+            let matches = self
+                .args
+                .auto_attach
+                .iter()
+                .any(|pattern| control::glob_match(pattern, &name));
+            if !matches
+                || self.auto_attached.contains(&name)
+                || self.unattachable.contains(&name)
+                || pipeline.attached_inputs().any(|input| input == name)
+                || self.config.inputs.iter().any(|input| input.device == name)
+            {
+                continue;
+            }
+            if attach_input(
+                pipeline,
+                &self.devices,
+                &self.providers,
+                &mut self.log,
+                &name,
+            ) {
+                self.auto_attached.push(name);
+            } else {
+                self.unattachable.push(name);
+            }
+        }
+        if self.args.auto_pan
+            && pipeline
+                .attached_inputs()
+                .ne(previous.iter().map(String::as_str))
+        {
+            auto_pan(pipeline, &mut self.log);
+        }
+    }
+
+    /// Moves the timers that commands started along.
+    fn tick(&mut self, pipeline: &Pipeline, now: Instant) {
+        if self.duck_hold.tick(now) {
+            println!("duck-hold on \"{}\" released", self.args.duck);
+            self.log.event("duck-hold", &self.duck_hold.status(now));
+        }
+        update_comparison(&mut self.comparison, pipeline, &mut self.log);
+        if let Some(comparison) = &mut self.comparison {
+            comparison.tick(now);
+        }
+        if self.scenes.tick(now, &mut self.reverb) {
+            self.log
+                .event("scene", &self.scenes.status(now).unwrap_or_default());
+        }
+    }
+
+    /// Whether the output has gone for longer than `--watchdog` without asking for audio.
+    fn output_stopped(&mut self, pipeline: &Pipeline, now: Instant) -> bool {
+        self.watchdog
+            .as_mut()
+            .is_some_and(|watchdog| watchdog.update(now, pipeline.counters().callbacks()))
+    }
+
+    /// Replaces `pipeline`, whose output has stopped, with a new one, and puts back everything
+    /// that was attached to it.
+    fn recover(&mut self, pipeline: Pipeline) -> anyhow::Result<Pipeline> {
+        let timeout = self.args.watchdog.as_secs_f32();
+        eprintln!(
+            "The output hasn't asked for audio in over {}s, which usually means the streams died \
+             when the machine slept: rebuilding the pipeline to recover.",
+            timeout
+        );
+        self.log.event(
+            "rebuild",
+            &format!("the output stopped asking for audio for over {}s", timeout),
+        );
+        self.earlier_underruns += pipeline.counters().underruns();
+        self.earlier_clipped += clipped_samples(&pipeline);
+        self.note_recording_failures(&pipeline);
+        let previous_rate = pipeline.sample_rate();
+        let attached = pipeline
+            .attached_inputs()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let recorder = match self.config.record_continue {
+            Some(_) => pipeline.into_recorder(),
+            None => {
+                drop(pipeline);
+                None
+            }
+        };
+        // A recorder carried on keeps its failures, so they've been noted already.
+        if recorder.is_none() {
+            self.noted_failures = 0;
+            self.noted_format_changes = 0;
+        }
+        self.config.segment += 1;
+        let mut pipeline = rebuild_pipeline(&self.providers, &self.config, &self.devices, recorder);
+        write_description(self.args.describe_json.as_deref(), &pipeline);
+        for change in &pipeline.recording_format_changes()[self.noted_format_changes..] {
+            self.log.event("recording-format", change);
+            self.format_changes.push(change.clone());
+        }
+        self.noted_format_changes = pipeline.recording_format_changes().len();
+        // Each segment's files have the right header for their own rate, but joining them up
+        // afterwards needs to know that they differ.
+        if pipeline.sample_rate() != previous_rate
+            && self.config.record_continue.is_none()
+            && self.config.inputs.iter().any(|input| input.record_ab)
+        {
+            eprintln!(
+                "The devices now run at {} Hz rather than {} Hz, so the recordings from here on \
+                 are at a different rate from the earlier ones and need resampling to be joined \
+                 to them.",
+                pipeline.sample_rate(),
+                previous_rate
+            );
+        }
+        self.duck_hold.attach(
+            pipeline
+                .override_gain(&self.args.duck)
+                .context("the input to duck is one of the inputs")?,
+        );
+        self.master.attach(Arc::clone(pipeline.master_gain()));
+        self.reverb.attach(pipeline.reverbs());
+        // Whatever doesn't come back is attached again once it does, if it matches
+        // `--auto-attach`.
+        for name in attached {
+            if !attach_input(
+                &mut pipeline,
+                &self.devices,
+                &self.providers,
+                &mut self.log,
+                &name,
+            ) {
+                self.auto_attached.retain(|input| *input != name);
+            }
+        }
+        if self.args.auto_pan {
+            auto_pan(&pipeline, &mut self.log);
+        }
+        self.scenes
+            .attach(pipeline.override_gains(), &mut self.reverb);
+        update_comparison(&mut self.comparison, &pipeline, &mut self.log);
+        println!("Wake recovery: the pipeline has been rebuilt and is running again.");
+        self.log
+            .event("rebuilt", &describe_pipeline(&self.config, &pipeline));
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.reset(Instant::now());
+        }
+        Ok(pipeline)
+    }
+
+    /// Reports what has happened since the last call, and prints the stats when they're due.
+    fn update(&mut self, pipeline: &mut Pipeline, now: Instant) {
+        pipeline.stats().measure_rates(now);
+        pipeline.report_priorities();
+        let underruns = self.earlier_underruns + pipeline.counters().underruns();
+        if underruns > self.warned_underruns && now >= self.next_underrun_warning {
+            eprintln!(
+                "An input fell behind the output {} times: try increasing latency.",
+                underruns - self.warned_underruns
+            );
+            self.warned_underruns = underruns;
+            self.next_underrun_warning = now + UNDERRUN_WARNING_INTERVAL;
+        }
+        if let Some(title) = &mut self.title {
+            if now >= self.next_title {
+                self.next_title = now + title::INTERVAL;
+                title.update(
+                    now,
+                    pipeline
+                        .stats()
+                        .inputs()
+                        .map(|(name, counters)| (name, counters.take_peak())),
+                    pipeline.is_recording(),
+                    pipeline.counters().short_term_loudness(),
+                );
+            }
+        }
+        self.note_recording_failures(pipeline);
+        if now >= self.next_stats {
+            self.next_stats += STATS_INTERVAL;
+            pipeline.stats().print();
+            memory::debug_assert_no_callback_allocations();
+            if underruns > self.logged_underruns {
+                self.log.event(
+                    "underruns",
+                    &format!(
+                        "{} in the last {}s",
+                        underruns - self.logged_underruns,
+                        STATS_INTERVAL.as_secs()
+                    ),
+                );
+                self.logged_underruns = underruns;
+            }
+            let true_peak = pipeline.counters().take_true_peak();
+            if true_peak > 1.0 {
+                println!(
+                    "The output's true peak reached {:+.1} dBTP, so it may clip once converted.",
+                    20.0 * true_peak.log10()
+                );
+            }
+        }
+    }
+
+    /// Adds the recordings `pipeline` has given up on since they were last noted to the failures,
+    /// and to the session log.
+    fn note_recording_failures(&mut self, pipeline: &Pipeline) {
+        for failure in pipeline
+            .recording_failures()
+            .into_iter()
+            .skip(self.noted_failures)
+        {
+            self.noted_failures += 1;
+            self.log.event("recording-failed", &failure);
+            self.recording_failures.push(failure);
+        }
+    }
+
+    /// The first `--fail-on` condition to have tripped, once they're due to be checked.
+    fn failed(&mut self, pipeline: &Pipeline, now: Instant) -> Option<FailCondition> {
+        if self.health.is_empty() || now < self.next_health {
+            return None;
+        }
+        self.next_health += HEALTH_INTERVAL;
+        let counters = pipeline.counters();
+        let underruns = self.earlier_underruns + counters.underruns();
+        self.health.update(now, underruns, counters.take_peak())
+    }
+
+    /// Stops the run because `condition` tripped, printing and logging a summary of it, and exits
+    /// with the condition's code.
+    fn stop(&mut self, pipeline: Pipeline, condition: FailCondition, now: Instant) -> ! {
+        eprintln!("Stopping: {}.", condition);
+        let underruns = self.earlier_underruns + pipeline.counters().underruns();
+        let clipped = self.earlier_clipped + clipped_samples(&pipeline);
+        if let Some(mut stopped) = self.comparison.take() {
+            stopped.stop();
+            self.log.event("compare", "stopped, as the run is stopping");
+        }
+        drop(pipeline);
+        let uptime = now.duration_since(self.started).as_secs_f64();
+        let summary = serde_json::to_string(&StopSummary {
+            exit_reason: condition.name(),
+            exit_code: condition.exit_code(),
+            detail: condition.to_string(),
+            underruns,
+            clipped_samples: clipped,
+            uptime_secs: (uptime * 10.0).round() / 10.0,
+            recording_failures: &self.recording_failures,
+            recording_format_changes: &self.format_changes,
         })
-        .expect("blackhole output device exists");
-
-    println!("Using input device: \"{}\"", microphone.name()?);
-    println!("Using input device: \"{}\"", game_capture.name()?);
-    println!("Using output device: \"{}\"", output_device.name()?);
-
-    // We'll try and use the same configuration between streams to keep it simple.
-    let config: cpal::StreamConfig = microphone.default_input_config()?.into();
-
-    // The buffer to share samples
-    let (producer_mic, mut consumer_mic) = HeapRb::<f32>::new(10_240).split();
-    let (producer_capture, mut consumer_capture) = HeapRb::<f32>::new(10_240).split();
-
-    let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        let mut input_fell_behind = false;
-        if consumer_mic.len() < data.len() || consumer_capture.len() < data.len() {
-            input_fell_behind = true;
-        }
-        consumer_mic
-            .pop_iter()
-            .map(Some)
-            .chain(std::iter::repeat(None))
-            .zip(
-                consumer_capture
-                    .pop_iter()
-                    .map(Some)
-                    .chain(std::iter::repeat(None)),
-            )
-            .zip(data)
-            .for_each(|((mic_sample, capture_sample), sample)| {
-                *sample = mic_sample.unwrap_or(0.0) + capture_sample.unwrap_or(0.0)
-            });
-        if input_fell_behind {
-            eprintln!("input stream fell behind: try increasing latency");
+        .expect("the summary serializes");
+        println!("{}", summary);
+        self.log.event("stop", &summary);
+        std::process::exit(condition.exit_code());
+    }
+}
+
+/// What a run that stopped on a `--fail-on` condition prints and logs as its last word.
+#[derive(Serialize)]
+struct StopSummary<'a> {
+    exit_reason: &'a str,
+    exit_code: i32,
+    detail: String,
+    underruns: u64,
+    clipped_samples: u64,
+    uptime_secs: f64,
+    recording_failures: &'a [String],
+    recording_format_changes: &'a [String],
+}
+
+/// Reads commands from stdin on their own thread into `sender`, reporting any that don't parse.
+fn spawn_stdin_commands(sender: mpsc::Sender<control::Command>) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match control::Command::parse(&line) {
+                Ok(command) => {
+                    if sender.send(command).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => eprintln!("{:#}", err),
+            }
+        }
+    });
+}
+
+/// Attaches the input device `name` to the running pipeline, if `devices` lists it, announcing how
+/// that went and returning whether it worked.
+fn attach_input(
+    pipeline: &mut Pipeline,
+    devices: &DeviceService,
+    providers: &Providers,
+    log: &mut SessionLog,
+    name: &str,
+) -> bool {
+    match pipeline.attach_input(&WithDeviceService(devices, providers()), name) {
+        Ok(()) => {
+            println!("Attached input \"{}\".", name);
+            log.event("attach", &format!("\"{}\"", name));
+            true
+        }
+        Err(err) => {
+            eprintln!(
+                "couldn't attach \"{}\": {:#}",
+                name,
+                anyhow::Error::from(err)
+            );
+            false
+        }
+    }
+}
+
+/// Pans the attached inputs where [`attach::auto_pan`] puts them.
+fn auto_pan(pipeline: &Pipeline, log: &mut SessionLog) {
+    let positions = attach::auto_pan(pipeline.attached_inputs());
+    if positions.is_empty() {
+        return;
+    }
+    let mut assignment = Vec::with_capacity(positions.len());
+    for (name, position) in positions {
+        // Every attached input can be panned, and the position is always in range.
+        let _ = pipeline.pan_attached(name, position);
+        assignment.push(format!("\"{}\" at {:+.2}", name, position));
+    }
+    let assignment = assignment.join(", ");
+    println!("Auto-pan: {}.", assignment);
+    log.event("auto-pan", &assignment);
+}
+
+/// Keeps a comparison in step with the inputs the pipeline has, ending it if one of the inputs
+/// being compared has gone.
+fn update_comparison(
+    comparison: &mut Option<Comparison>,
+    pipeline: &Pipeline,
+    log: &mut SessionLog,
+) {
+    if let Some(current) = comparison {
+        if let Err(err) = current.update(pipeline.override_gains()) {
+            eprintln!("{}", err);
+            log.event("compare", &err.to_string());
+            *comparison = None;
+        }
+    }
+}
+
+/// How many samples have arrived clipped on every input.
+fn clipped_samples(pipeline: &Pipeline) -> u64 {
+    pipeline
+        .stats()
+        .inputs()
+        .map(|(_, counters)| counters.clipped_samples())
+        .sum()
+}
+
+/// Sums up a freshly started pipeline for the session log.
+fn describe_pipeline(config: &PipelineConfig, pipeline: &Pipeline) -> String {
+    let inputs = config
+        .inputs
+        .iter()
+        .map(|input| format!("\"{}\"", input.name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let recording =
+        config.inputs.iter().any(|input| input.record_ab) || config.record_output.is_some();
+    format!(
+        "{} into \"{}\" at {} Hz{}",
+        inputs,
+        config.output,
+        pipeline.sample_rate(),
+        if recording {
+            format!(", recording segment {}", config.segment + 1)
+        } else {
+            String::new()
         }
+    )
+}
+
+/// The name of the audio host every device is found through.
+fn host_name() -> &'static str {
+    cpal::default_host().id().name()
+}
+
+/// Writes the description of `pipeline` to `path` as JSON, if there is one.
+fn write_description(path: Option<&Path>, pipeline: &Pipeline) {
+    let Some(path) = path else {
+        return;
     };
+    let json = pipeline.describe(host_name()).to_json();
+    if let Err(err) = std::fs::write(path, json + "\n") {
+        eprintln!("couldn't write {}: {}", path.display(), err);
+    }
+}
+
+/// Checks an `opus-udp:` output's target up front, rather than once the inputs have started.
+#[cfg(feature = "opus")]
+fn opus_target(output: &str) -> anyhow::Result<()> {
+    use loopback_clone::backend::opus_udp::{OpusTarget, PREFIX};
+    OpusTarget::parse(&output[PREFIX.len()..])?;
+    Ok(())
+}
 
-    // Build streams.
+#[cfg(not(feature = "opus"))]
+fn opus_target(_output: &str) -> anyhow::Result<()> {
+    bail!("streaming over UDP needs the `opus` feature")
+}
+
+/// Builds and starts the pipeline from `config`, carrying on the recordings of `recorder` if there
+/// is one, and prints what it's doing and then the chains and the memory if asked to.
+fn start_pipeline(
+    provider: Box<dyn DeviceProvider>,
+    config: &PipelineConfig,
+    print_chain: bool,
+    print_memory: bool,
+    recorder: &mut Option<Recorder>,
+) -> anyhow::Result<Pipeline> {
+    let provider = WithNullOutput(provider);
+    #[cfg(feature = "opus")]
+    let provider = loopback_clone::backend::opus_udp::WithOpusOutput(provider);
+    let pipeline = Pipeline::from_config_continuing(&provider, config, recorder)?;
+    println!("{}", pipeline.describe(host_name()));
+    if print_chain {
+        for chain in pipeline.chains() {
+            println!(
+                "Chain for \"{}\": {}, {} frames ({:.1} ms) of latency in total.",
+                chain.input,
+                chain.stages,
+                chain.latency_frames,
+                chain.latency_frames as f32 * 1_000.0 / pipeline.sample_rate() as f32
+            );
+        }
+    }
+    if print_memory {
+        println!("{}", pipeline.allocations());
+    }
+    Ok(pipeline)
+}
+
+/// Keeps trying to start the pipeline for as long as another application holds the output
+/// exclusively, up to `timeout`, waiting longer between each try.
+fn start_when_output_free(
+    providers: &Providers,
+    config: &PipelineConfig,
+    print_chain: bool,
+    print_memory: bool,
+    timeout: Duration,
+) -> anyhow::Result<Pipeline> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Backoff::new(OUTPUT_FREE_RETRY_MIN, OUTPUT_FREE_RETRY_MAX);
+    loop {
+        let err = match start_pipeline(providers(), config, print_chain, print_memory, &mut None) {
+            Ok(pipeline) => return Ok(pipeline),
+            Err(err) => err,
+        };
+        let now = Instant::now();
+        let Some(PipelineError::OutputBusy { device, .. }) = err.downcast_ref() else {
+            return Err(err);
+        };
+        if now >= deadline {
+            return Err(err);
+        }
+        let delay = backoff.next_delay().min(deadline - now);
+        eprintln!(
+            "\"{}\" is in use exclusively by another application, trying again in {:.1}s",
+            device,
+            delay.as_secs_f32()
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+/// Keeps trying to start the pipeline until it works, since after a wake the devices can take a
+/// while to come back. Each try waits longer than the last, unless the devices change.
+fn rebuild_pipeline(
+    providers: &Providers,
+    config: &PipelineConfig,
+    devices: &DeviceService,
+    mut recorder: Option<Recorder>,
+) -> Pipeline {
+    let mut backoff = Backoff::new(REBUILD_RETRY_MIN, REBUILD_RETRY_MAX);
+    let mut watch = devices.watch();
+    loop {
+        match start_pipeline(providers(), config, false, false, &mut recorder) {
+            Ok(pipeline) => return pipeline,
+            Err(err) => {
+                let delay = backoff.next_delay();
+                eprintln!(
+                    "couldn't rebuild the pipeline, retrying in {:.1}s or once the devices \
+                     change: {:#}",
+                    delay.as_secs_f32(),
+                    err
+                );
+                watch.changed(delay);
+            }
+        }
+    }
+}
+
+fn polarity_check(args: &Args) -> anyhow::Result<()> {
+    let provider = CpalProvider::new();
+    let (first_name, second_name) = (args.measure_from.as_str(), args.sync_against.as_str());
+    if first_name == second_name {
+        bail!(
+            "`polarity-check` needs two different inputs, but was given \"{}\" twice",
+            first_name
+        );
+    }
+
+    let first = provider.input_device(first_name)?;
+    let second = provider.input_device(second_name)?;
     println!(
-        "Attempting to build both streams with f32 samples and `{:?}`.",
-        config
+        "Checking polarity of \"{}\" against \"{}\".",
+        first_name, second_name
     );
-    let microphone_stream =
-        microphone.build_input_stream(&config, create_input_processing_fn(producer_mic), err_fn)?;
-    let game_capture_stream = game_capture.build_input_stream(
+
+    let config = first.default_config()?;
+    let channels = config.channels as usize;
+    let window_samples = ms_to_frames(POLARITY_CHECK_WINDOW_MS, config.sample_rate.0) * channels;
+
+    // Only the polarity applies here, anything with latency would skew the measured alignment.
+    let polarity_chain = |name: &str| {
+        let stages: Vec<Box<dyn ProcessStage>> =
+            vec![Box::new(GainStage::new(&config, inverted(args, name)))];
+        InputChain::new(channels, stages)
+    };
+    let (first_producer, first_consumer) = HeapRb::<f32>::new(window_samples).split();
+    let (second_producer, second_consumer) = HeapRb::<f32>::new(window_samples).split();
+    let first_stream = first.build_input_stream(
+        &config,
+        Box::new(create_input_processing_fn(
+            first_producer,
+            polarity_chain(first_name)?,
+            None,
+            Arc::default(),
+        )),
+        Box::new(err_fn),
+    )?;
+    let second_stream = second.build_input_stream(
         &config,
-        create_input_processing_fn(producer_capture),
-        err_fn,
+        Box::new(create_input_processing_fn(
+            second_producer,
+            polarity_chain(second_name)?,
+            None,
+            Arc::default(),
+        )),
+        Box::new(err_fn),
     )?;
-    let output_stream = output_device.build_output_stream(&config, output_data_fn, err_fn)?;
-    println!("Successfully built streams.");
 
-    // Play the streams.
-    println!("Starting the input and output streams",);
-    microphone_stream.play()?;
-    game_capture_stream.play()?;
-    output_stream.play()?;
+    println!(
+        "Recording {} ms from both inputs, play something both can hear.",
+        POLARITY_CHECK_WINDOW_MS
+    );
+    first_stream.play()?;
+    second_stream.play()?;
 
-    loop {
-        std::thread::park();
+    let captured = capture_windows(
+        vec![(first_name, first_consumer), (second_name, second_consumer)],
+        window_samples,
+        Duration::from_secs_f32(POLARITY_CHECK_WINDOW_MS / 1_000.0),
+    );
+    drop(first_stream);
+    drop(second_stream);
+    let [first_samples, second_samples] = <[_; 2]>::try_from(captured?).unwrap();
+
+    let max_lag = ms_to_frames(POLARITY_CHECK_MAX_LAG_MS, config.sample_rate.0);
+    let (lag, correlation) = best_alignment(
+        &downmix(&first_samples, channels),
+        &downmix(&second_samples, channels),
+        max_lag,
+    );
+    println!(
+        "Best alignment: \"{}\" is {} samples ({:.2} ms) behind \"{}\", correlation {:.2}.",
+        second_name,
+        lag,
+        lag as f32 * 1_000.0 / config.sample_rate.0 as f32,
+        first_name,
+        correlation
+    );
+    if correlation.abs() < POLARITY_CHECK_MIN_CORRELATION {
+        println!("The inputs don't appear to be picking up the same source.");
+    } else if correlation > 0.0 {
+        println!("The inputs appear to be in phase.");
+    } else {
+        println!(
+            "The inputs appear to be out of phase: try `--invert \"{}\"`.",
+            second_name
+        );
     }
+
+    Ok(())
 }
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
+fn sync_inputs(args: &Args) -> anyhow::Result<()> {
+    let provider = CpalProvider::new();
+    let (first_name, second_name) = (args.measure_from.as_str(), args.sync_against.as_str());
+    if first_name == second_name {
+        bail!(
+            "`sync-inputs` needs two different inputs, but was given \"{}\" twice",
+            first_name
+        );
+    }
+
+    let first = provider.input_device(first_name)?;
+    let second = provider.input_device(second_name)?;
+    let config = first.default_config()?;
+    let channels = config.channels as usize;
+    let window_samples = ms_to_frames(SYNC_INPUTS_WINDOW_MS, config.sample_rate.0) * channels;
+
+    let (first_producer, first_consumer) = HeapRb::<f32>::new(window_samples).split();
+    let (second_producer, second_consumer) = HeapRb::<f32>::new(window_samples).split();
+    let first_stream = first.build_input_stream(
+        &config,
+        Box::new(create_input_processing_fn(
+            first_producer,
+            InputChain::new(channels, vec![])?,
+            None,
+            Arc::default(),
+        )),
+        Box::new(err_fn),
+    )?;
+    let second_stream = second.build_input_stream(
+        &config,
+        Box::new(create_input_processing_fn(
+            second_producer,
+            InputChain::new(channels, vec![])?,
+            None,
+            Arc::default(),
+        )),
+        Box::new(err_fn),
+    )?;
+
+    println!(
+        "Recording {} ms from \"{}\" and \"{}\": clap, or make another sharp sound both pick up.",
+        SYNC_INPUTS_WINDOW_MS, first_name, second_name
+    );
+    first_stream.play()?;
+    second_stream.play()?;
+
+    let captured = capture_windows(
+        vec![(first_name, first_consumer), (second_name, second_consumer)],
+        window_samples,
+        Duration::from_secs_f32(SYNC_INPUTS_WINDOW_MS / 1_000.0),
+    );
+    drop(first_stream);
+    drop(second_stream);
+    let [first_samples, second_samples] = <[_; 2]>::try_from(captured?).unwrap();
+    for (name, samples) in [(first_name, &first_samples), (second_name, &second_samples)] {
+        if mix::peak(samples) < SILENCE_THRESHOLD {
+            bail!(
+                "\"{}\" heard nothing but silence, so there's no clap to line up: check that it's \
+                 unmuted and can hear the sound",
+                name
+            );
+        }
+    }
+
+    let max_lag = ms_to_frames(SYNC_INPUTS_MAX_LAG_MS, config.sample_rate.0);
+    let (lag, correlation) = wide_alignment(
+        &downmix(&first_samples, channels),
+        &downmix(&second_samples, channels),
+        max_lag,
+    );
+    let lag_ms = lag as f32 * 1_000.0 / config.sample_rate.0 as f32;
+    println!(
+        "Best alignment: \"{}\" is {} samples ({:.2} ms) behind \"{}\", correlation {:.2}.",
+        second_name,
+        lag,
+        lag_ms,
+        first_name,
+        correlation.abs()
+    );
+    if correlation.abs() < SYNC_INPUTS_MIN_CORRELATION {
+        println!(
+            "That's too weak a match to go on, so nothing should be delayed: try again with a \
+             louder, sharper sound."
+        );
+    } else if lag == 0 {
+        println!("The inputs are already lined up.");
+    } else {
+        // Whichever is ahead gets delayed to meet the other.
+        let ahead = if lag > 0 { first_name } else { second_name };
+        println!(
+            "To line them up, run with `--delay \"{}={:.1}ms\"`.",
+            ahead,
+            lag_ms.abs()
+        );
+    }
+
+    Ok(())
+}
+
+/// Copies `samples` samples out of each of `inputs`, by name, as the streams feeding them deliver
+/// them. Gives up with an error naming the inputs that are short if they haven't all delivered
+/// them within [`CAPTURE_GRACE`] of the `expected` time, as happens with an unplugged device.
+fn capture_windows(
+    inputs: Vec<(&str, HeapConsumer<f32>)>,
+    samples: usize,
+    expected: Duration,
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    let deadline = Instant::now() + expected + CAPTURE_GRACE;
+    let mut captured = vec![Vec::with_capacity(samples); inputs.len()];
+    let mut inputs = inputs;
+    // Copy the blocks out here rather than correlating anything inside the audio callbacks.
+    while captured
+        .iter()
+        .any(|samples_so_far| samples_so_far.len() < samples)
+    {
+        if Instant::now() >= deadline {
+            let short = inputs
+                .iter()
+                .zip(&captured)
+                .filter(|(_, samples_so_far)| samples_so_far.len() < samples)
+                .map(|((name, _), samples_so_far)| {
+                    format!(
+                        "\"{}\" ({}% of it)",
+                        name,
+                        samples_so_far.len() * 100 / samples
+                    )
+                })
+                .collect::<Vec<_>>();
+            bail!(
+                "gave up waiting for {} to deliver {:.1}s of audio, after {:.1}s: check that it's \
+                 plugged in and not in use by another application",
+                short.join(" and "),
+                expected.as_secs_f32(),
+                (expected + CAPTURE_GRACE).as_secs_f32()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        for ((_, consumer), samples_so_far) in inputs.iter_mut().zip(&mut captured) {
+            let wanted = samples - samples_so_far.len();
+            samples_so_far.extend(consumer.pop_iter().take(wanted));
+        }
+    }
+    Ok(captured)
+}
+
+/// A Hann-windowed linear sweep from 200 Hz to 8 kHz, which has a single sharp correlation peak.
+fn chirp(sample_rate: u32) -> Vec<f32> {
+    const START_HZ: f64 = 200.0;
+    const END_HZ: f64 = 8_000.0;
+    let frames = ms_to_frames(MEASURE_LATENCY_CHIRP_MS, sample_rate);
+    let duration = frames as f64 / sample_rate as f64;
+    (0..frames)
+        .map(|frame| {
+            let t = frame as f64 / sample_rate as f64;
+            let phase = std::f64::consts::TAU
+                * (START_HZ * t + (END_HZ - START_HZ) * t * t / (2.0 * duration));
+            let window = 0.5 - 0.5 * (std::f64::consts::TAU * frame as f64 / frames as f64).cos();
+            (0.5 * window * phase.sin()) as f32
+        })
+        .collect()
+}
+
+fn measure_latency(args: &Args) -> anyhow::Result<()> {
+    let provider = CpalProvider::new();
+
+    let input = provider.input_device(&args.measure_from)?;
+    let output = provider.output_device(OUTPUT_NAME)?;
+    println!(
+        "Measuring the round trip from \"{}\" back into \"{}\".",
+        output.name(),
+        input.name()
+    );
+
+    let input_config = input.default_config()?;
+    let output_config = output.default_config()?;
+    let input_channels = input_config.channels as usize;
+    let output_channels = output_config.channels as usize;
+    let capture_samples =
+        ms_to_frames(MEASURE_LATENCY_CAPTURE_MS, input_config.sample_rate.0) * input_channels;
+
+    // When each stream's first callback happened, which ties the two streams' frame counts to one
+    // clock. The input also records how many frames its first callback delivered, because they
+    // were captured before the callback ran.
+    let input_started = Arc::new(OnceLock::<(Instant, usize)>::new());
+    let output_started = Arc::new(OnceLock::<Instant>::new());
+
+    let (producer, consumer) = HeapRb::<f32>::new(capture_samples).split();
+    let mut capture = create_input_processing_fn(
+        producer,
+        InputChain::new(input_channels, vec![])?,
+        None,
+        Arc::default(),
+    );
+    let input_stream = input.build_input_stream(
+        &input_config,
+        Box::new({
+            let input_started = Arc::clone(&input_started);
+            move |data: &[f32]| {
+                input_started.get_or_init(|| (Instant::now(), data.len() / input_channels));
+                capture(data);
+            }
+        }),
+        Box::new(err_fn),
+    )?;
+
+    let played = chirp(output_config.sample_rate.0);
+    let chirp_start = ms_to_frames(MEASURE_LATENCY_CHIRP_START_MS, output_config.sample_rate.0);
+    let mut position: usize = 0;
+    let output_stream = output.build_output_stream(
+        &output_config,
+        Box::new({
+            let output_started = Arc::clone(&output_started);
+            move |data: &mut [f32]| {
+                output_started.get_or_init(Instant::now);
+                for frame in data.chunks_mut(output_channels) {
+                    let sample = position
+                        .checked_sub(chirp_start)
+                        .and_then(|index| played.get(index))
+                        .copied()
+                        .unwrap_or(0.0);
+                    frame.fill(sample);
+                    position += 1;
+                }
+            }
+        }),
+        Box::new(err_fn),
+    )?;
+
+    println!(
+        "Playing a chirp and recording for {} ms.",
+        MEASURE_LATENCY_CAPTURE_MS
+    );
+    input_stream.play()?;
+    output_stream.play()?;
+
+    let captured = capture_windows(
+        vec![(input.name(), consumer)],
+        capture_samples,
+        Duration::from_secs_f32(MEASURE_LATENCY_CAPTURE_MS / 1_000.0),
+    );
+    drop(output_stream);
+    drop(input_stream);
+    let recorded = captured?.pop().unwrap();
+
+    let heard = find_template(
+        &chirp(input_config.sample_rate.0),
+        &downmix(&recorded, input_channels),
+    )
+    .filter(|(_, correlation)| *correlation >= MEASURE_LATENCY_MIN_CORRELATION);
+    let Some((offset, correlation)) = heard else {
+        bail!(
+            "couldn't find the chirp in what \"{}\" recorded: check that the output is actually \
+             wired back into that input, that neither side is muted, and that the levels are high \
+             enough for the chirp to be heard clearly",
+            input.name()
+        );
+    };
+
+    let (input_started, first_frames) = *input_started.get().context("the input never ran")?;
+    let output_started = *output_started.get().context("the output never ran")?;
+    let seconds = |frames: usize, config: &cpal::StreamConfig| {
+        Duration::from_secs_f64(frames as f64 / config.sample_rate.0 as f64)
+    };
+    let recording_started = input_started
+        .checked_sub(seconds(first_frames, &input_config))
+        .unwrap_or(input_started);
+    let heard_at = recording_started + seconds(offset, &input_config);
+    let played_at = output_started + seconds(chirp_start, &output_config);
+    let Some(round_trip) = heard_at.checked_duration_since(played_at) else {
+        bail!("the chirp was heard before it was played, so the streams' clocks can't be trusted");
+    };
+
+    println!(
+        "Round-trip latency: {:.1} ms ({:.0} samples at {} Hz), correlation {:.2}.",
+        round_trip.as_secs_f64() * 1_000.0,
+        round_trip.as_secs_f64() * input_config.sample_rate.0 as f64,
+        input_config.sample_rate.0,
+        correlation
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loopback_clone::backend::{
+        fake::{FakeProvider, Signal},
+        StreamConfig,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Waits up to a couple of seconds for `done`, for what happens on the devices' threads.
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn lists_devices_by_name_and_stable_id() {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(256),
+        };
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", config.clone(), Signal::Silence)
+            .add_input("Interface", config.clone(), Signal::Silence)
+            .add_output("Interface", config);
+        provider.set_stable_id("Mic", "BuiltInMicrophoneDevice");
+        let lines = provider
+            .devices()
+            .unwrap()
+            .iter()
+            .map(device_line)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "input \"Mic\" (ID BuiltInMicrophoneDevice)",
+                "input/output \"Interface\" (no stable ID)"
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilds_the_pipeline_once_when_the_output_stops_calling_back() {
+        let provider = FakeProvider::new();
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(256),
+        };
+        let tone = Signal::Sine {
+            frequency: 440.0,
+            amplitude: 0.5,
+        };
+        provider.add_input(MICROPHONE_NAME, config.clone(), tone.clone());
+        provider.add_input(GAME_CAPTURE_NAME, config.clone(), tone);
+        provider.add_output(OUTPUT_NAME, config);
+
+        // The devices call back for as long as `delivering` is set, as they would until the
+        // machine sleeps.
+        let delivering = Arc::new(AtomicBool::new(true));
+        let finished = Arc::new(AtomicBool::new(false));
+        let clock = std::thread::spawn({
+            let provider = provider.clone();
+            let delivering = Arc::clone(&delivering);
+            let finished = Arc::clone(&finished);
+            move || {
+                while !finished.load(Ordering::Relaxed) {
+                    if delivering.load(Ordering::Relaxed) {
+                        provider.advance(1);
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        let log_path = std::env::temp_dir().join(format!(
+            "loopback-wake-recovery-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&log_path);
+        let args = Args::parse_from(
+            ["--watchdog", "1s", "--no-title", "--session-log-json"]
+                .into_iter()
+                .map(str::to_owned)
+                .chain([log_path.display().to_string()]),
+        )
+        .unwrap();
+        let devices = provider.clone();
+        let providers: Providers = Arc::new(move || Box::new(devices.clone()));
+        let config = pipeline_config(&args).unwrap();
+        let pipeline = start_pipeline(providers(), &config, false, false, &mut None).unwrap();
+        let log = SessionLog::open(None, args.session_log_json.as_deref()).unwrap();
+        let mut session = Session::new(&args, config, log, &pipeline, providers).unwrap();
+        let streams = provider.playing_streams(OUTPUT_NAME);
+        assert_eq!(streams.len(), 1);
+        wait_for(|| pipeline.counters().callbacks() > 0);
+        assert!(!session.output_stopped(&pipeline, Instant::now()));
+
+        delivering.store(false, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(20));
+        let stopped = Instant::now();
+        assert!(!session.output_stopped(&pipeline, stopped));
+        assert!(!session.output_stopped(&pipeline, stopped + Duration::from_millis(900)));
+        assert!(session.output_stopped(&pipeline, stopped + Duration::from_millis(1100)));
+
+        // The devices are back by the time it rebuilds, as they are after a wake.
+        delivering.store(true, Ordering::Relaxed);
+        let pipeline = session.recover(pipeline).unwrap();
+        let rebuilt = provider.playing_streams(OUTPUT_NAME);
+        assert_eq!(rebuilt.len(), 1);
+        assert_ne!(rebuilt, streams);
+        for input in [MICROPHONE_NAME, GAME_CAPTURE_NAME] {
+            assert_eq!(provider.playing_streams(input).len(), 1);
+        }
+        wait_for(|| pipeline.counters().callbacks() > 0);
+        let running = Instant::now();
+        for _ in 0..20 {
+            std::thread::sleep(Duration::from_millis(5));
+            assert!(!session.output_stopped(&pipeline, Instant::now()));
+        }
+        assert!(!session.output_stopped(&pipeline, running + Duration::from_millis(900)));
+
+        finished.store(true, Ordering::Relaxed);
+        clock.join().unwrap();
+        drop(pipeline);
+        drop(session);
+        let events = std::fs::read_to_string(&log_path).unwrap();
+        let _ = std::fs::remove_file(&log_path);
+        let kinds = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["rebuild", "rebuilt"]);
+    }
 }
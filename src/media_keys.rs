@@ -0,0 +1,336 @@
+//! Turns the keyboard's volume and play/pause keys into [`Command::Key`]s, on macOS.
+//!
+//! The keys are caught with an event tap, which only works once the terminal (or whatever runs the
+//! binary) is allowed to monitor input under Privacy & Security > Accessibility. While the keys are
+//! armed they are swallowed, so they change the mix and not the system volume, and otherwise they
+//! pass through untouched. What they do is up to [`crate::control::MasterControl`], the same as the
+//! `volume-up`, `volume-down` and `play-pause` commands that stand in for them everywhere else.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc};
+
+use crate::control::{Command, MediaKey};
+
+/// The key codes of the keys caught, from `NX_KEYTYPE_*` in IOKit's `ev_keymap.h`.
+const KEY_SOUND_UP: i64 = 0;
+const KEY_SOUND_DOWN: i64 = 1;
+const KEY_PLAY: i64 = 16;
+/// The key state of a key going down, rather than up (`0xB`).
+const KEY_DOWN: i64 = 0xA;
+
+/// Catches the media keys from now on, sending what they do to `commands` while `armed` is set.
+pub fn spawn(armed: Arc<AtomicBool>, commands: mpsc::Sender<Command>) -> Result<(), String> {
+    platform::spawn(armed, commands)
+}
+
+/// One of the keys caught going down or up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KeyEvent {
+    key: MediaKey,
+    down: bool,
+    /// Whether it's a repeat from the key being held down.
+    repeat: bool,
+}
+
+/// Picks the keys caught out of the `data1` of a media key event, which is the key code, then its
+/// state, then a repeat flag.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn decode(data1: i64) -> Option<KeyEvent> {
+    let key = match (data1 >> 16) & 0xFFFF {
+        KEY_SOUND_UP => MediaKey::VolumeUp,
+        KEY_SOUND_DOWN => MediaKey::VolumeDown,
+        KEY_PLAY => MediaKey::PlayPause,
+        _ => return None,
+    };
+    Some(KeyEvent {
+        key,
+        down: (data1 >> 8) & 0xFF == KEY_DOWN,
+        repeat: data1 & 1 == 1,
+    })
+}
+
+/// The command a key event makes, if any, and whether to swallow it so nothing else sees it.
+/// Holding a volume key down keeps nudging, while holding play/pause only toggles once.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn handle(event: KeyEvent, armed: bool) -> (Option<Command>, bool) {
+    if !armed {
+        return (None, false);
+    }
+    let command = (event.down && !(event.repeat && event.key == MediaKey::PlayPause))
+        .then_some(Command::Key(event.key));
+    (command, true)
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::{c_char, c_void};
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    use super::{decode, handle};
+    use crate::control::Command;
+
+    type CFMachPortRef = *mut c_void;
+    type CFRunLoopSourceRef = *mut c_void;
+    type CFRunLoopRef = *mut c_void;
+    type CFStringRef = *const c_void;
+    type CGEventRef = *mut c_void;
+    type CGEventTapProxy = *mut c_void;
+    type TapCallback = extern "C" fn(CGEventTapProxy, u32, CGEventRef, *mut c_void) -> CGEventRef;
+    type Id = *mut c_void;
+    type Sel = *mut c_void;
+
+    const SESSION_EVENT_TAP: u32 = 1;
+    const HEAD_INSERT_EVENT_TAP: u32 = 0;
+    const TAP_OPTION_DEFAULT: u32 = 0;
+    /// `NX_SYSDEFINED`, the event type the media keys arrive as.
+    const SYSTEM_DEFINED: u32 = 14;
+    /// `NX_SUBTYPE_AUX_CONTROL_BUTTONS`, the media keys' subtype of it.
+    const AUX_CONTROL_BUTTONS: i16 = 8;
+    const TAP_DISABLED_BY_TIMEOUT: u32 = 0xFFFF_FFFE;
+    const TAP_DISABLED_BY_USER_INPUT: u32 = 0xFFFF_FFFF;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGEventTapCreate(
+            tap: u32,
+            place: u32,
+            options: u32,
+            events_of_interest: u64,
+            callback: TapCallback,
+            user_info: *mut c_void,
+        ) -> CFMachPortRef;
+        fn CGEventTapEnable(tap: CFMachPortRef, enable: bool);
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFRunLoopCommonModes: CFStringRef;
+        fn CFMachPortCreateRunLoopSource(
+            allocator: *const c_void,
+            port: CFMachPortRef,
+            order: isize,
+        ) -> CFRunLoopSourceRef;
+        fn CFRunLoopGetCurrent() -> CFRunLoopRef;
+        fn CFRunLoopAddSource(
+            run_loop: CFRunLoopRef,
+            source: CFRunLoopSourceRef,
+            mode: CFStringRef,
+        );
+        fn CFRunLoopRun();
+    }
+
+    // NSEvent, the only thing that can read a media key event's `data1`.
+    #[link(name = "AppKit", kind = "framework")]
+    extern "C" {}
+
+    #[link(name = "objc")]
+    extern "C" {
+        fn objc_getClass(name: *const c_char) -> Id;
+        fn sel_registerName(name: *const c_char) -> Sel;
+        fn objc_msgSend();
+        fn objc_autoreleasePoolPush() -> *mut c_void;
+        fn objc_autoreleasePoolPop(pool: *mut c_void);
+    }
+
+    /// What the tap's callback needs, which lives for as long as the process.
+    struct Tap {
+        armed: Arc<AtomicBool>,
+        commands: mpsc::Sender<Command>,
+        port: AtomicPtr<c_void>,
+    }
+
+    pub fn spawn(armed: Arc<AtomicBool>, commands: mpsc::Sender<Command>) -> Result<(), String> {
+        let (started, result) = mpsc::sync_channel(1);
+        std::thread::Builder::new()
+            .name("media keys".to_owned())
+            .spawn(move || {
+                let tap: &'static Tap = Box::leak(Box::new(Tap {
+                    armed,
+                    commands,
+                    port: AtomicPtr::new(ptr::null_mut()),
+                }));
+                // SAFETY: `tap` is never freed, and the port and its source are only used on this
+                // thread, whose run loop runs until the process exits.
+                unsafe {
+                    let port = CGEventTapCreate(
+                        SESSION_EVENT_TAP,
+                        HEAD_INSERT_EVENT_TAP,
+                        TAP_OPTION_DEFAULT,
+                        1 << SYSTEM_DEFINED,
+                        on_event,
+                        tap as *const Tap as *mut c_void,
+                    );
+                    if port.is_null() {
+                        let _ = started.send(Err("macOS refused to let it monitor the keyboard: \
+                                                  allow the terminal under Privacy & Security > \
+                                                  Accessibility"
+                            .to_owned()));
+                        return;
+                    }
+                    tap.port.store(port, Ordering::Relaxed);
+                    let source = CFMachPortCreateRunLoopSource(ptr::null(), port, 0);
+                    CFRunLoopAddSource(CFRunLoopGetCurrent(), source, kCFRunLoopCommonModes);
+                    CGEventTapEnable(port, true);
+                    let _ = started.send(Ok(()));
+                    CFRunLoopRun();
+                }
+            })
+            .map_err(|err| format!("couldn't start its thread: {}", err))?;
+        result
+            .recv()
+            .unwrap_or_else(|_| Err("its thread exited".to_owned()))
+    }
+
+    extern "C" fn on_event(
+        _proxy: CGEventTapProxy,
+        kind: u32,
+        event: CGEventRef,
+        user_info: *mut c_void,
+    ) -> CGEventRef {
+        // SAFETY: `user_info` is the `Tap` leaked in `spawn`.
+        let tap = unsafe { &*(user_info as *const Tap) };
+        if kind == TAP_DISABLED_BY_TIMEOUT || kind == TAP_DISABLED_BY_USER_INPUT {
+            // macOS turns a tap off when it's slow to answer, so it's turned straight back on.
+            // SAFETY: the port was stored before the run loop that calls this started.
+            unsafe { CGEventTapEnable(tap.port.load(Ordering::Relaxed), true) };
+            return event;
+        }
+        if kind != SYSTEM_DEFINED {
+            return event;
+        }
+        // SAFETY: `event` is the system-defined event the tap was called with.
+        let Some(key) = unsafe { media_key_data(event) }.and_then(decode) else {
+            return event;
+        };
+        let (command, swallow) = handle(key, tap.armed.load(Ordering::Relaxed));
+        if let Some(command) = command {
+            let _ = tap.commands.send(command);
+        }
+        if swallow {
+            ptr::null_mut()
+        } else {
+            event
+        }
+    }
+
+    /// The `data1` of a system-defined event, if it's a media key's.
+    unsafe fn media_key_data(event: CGEventRef) -> Option<i64> {
+        let msg_send = objc_msgSend as unsafe extern "C" fn();
+        let with_event = std::mem::transmute::<
+            unsafe extern "C" fn(),
+            unsafe extern "C" fn(Id, Sel, CGEventRef) -> Id,
+        >(msg_send);
+        let short = std::mem::transmute::<
+            unsafe extern "C" fn(),
+            unsafe extern "C" fn(Id, Sel) -> i16,
+        >(msg_send);
+        let integer = std::mem::transmute::<
+            unsafe extern "C" fn(),
+            unsafe extern "C" fn(Id, Sel) -> isize,
+        >(msg_send);
+
+        let pool = objc_autoreleasePoolPush();
+        let ns_event = with_event(
+            objc_getClass(c"NSEvent".as_ptr()),
+            sel_registerName(c"eventWithCGEvent:".as_ptr()),
+            event,
+        );
+        let data = (!ns_event.is_null()
+            && short(ns_event, sel_registerName(c"subtype".as_ptr())) == AUX_CONTROL_BUTTONS)
+            .then(|| integer(ns_event, sel_registerName(c"data1".as_ptr())) as i64);
+        objc_autoreleasePoolPop(pool);
+        data
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{mpsc, Arc};
+
+    use crate::control::Command;
+
+    pub fn spawn(_armed: Arc<AtomicBool>, _commands: mpsc::Sender<Command>) -> Result<(), String> {
+        Err(
+            "the media keys can only be caught on macOS, so bind `volume-up`, `volume-down` and \
+             `play-pause` to hotkeys instead"
+                .to_owned(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data1(code: i64, state: i64, repeat: bool) -> i64 {
+        code << 16 | state << 8 | repeat as i64
+    }
+
+    #[test]
+    fn decodes_the_volume_and_play_keys_only() {
+        assert_eq!(
+            decode(data1(KEY_SOUND_UP, 0xA, false)),
+            Some(KeyEvent {
+                key: MediaKey::VolumeUp,
+                down: true,
+                repeat: false
+            })
+        );
+        assert_eq!(
+            decode(data1(KEY_SOUND_DOWN, 0xB, false)),
+            Some(KeyEvent {
+                key: MediaKey::VolumeDown,
+                down: false,
+                repeat: false
+            })
+        );
+        assert_eq!(
+            decode(data1(KEY_PLAY, 0xA, true)),
+            Some(KeyEvent {
+                key: MediaKey::PlayPause,
+                down: true,
+                repeat: true
+            })
+        );
+        // Brightness, mute and next track are left alone.
+        for code in [2, 7, 17] {
+            assert_eq!(decode(data1(code, 0xA, false)), None);
+        }
+    }
+
+    #[test]
+    fn passes_keys_through_until_armed_and_swallows_them_after() {
+        let down = |key| KeyEvent {
+            key,
+            down: true,
+            repeat: false,
+        };
+        assert_eq!(handle(down(MediaKey::VolumeUp), false), (None, false));
+        assert_eq!(
+            handle(down(MediaKey::VolumeUp), true),
+            (Some(Command::Key(MediaKey::VolumeUp)), true)
+        );
+        let up = KeyEvent {
+            down: false,
+            ..down(MediaKey::VolumeUp)
+        };
+        assert_eq!(handle(up, true), (None, true));
+    }
+
+    #[test]
+    fn holding_a_volume_key_repeats_but_holding_play_toggles_once() {
+        let held = |key| KeyEvent {
+            key,
+            down: true,
+            repeat: true,
+        };
+        assert_eq!(
+            handle(held(MediaKey::VolumeDown), true),
+            (Some(Command::Key(MediaKey::VolumeDown)), true)
+        );
+        assert_eq!(handle(held(MediaKey::PlayPause), true), (None, true));
+    }
+}
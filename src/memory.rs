@@ -0,0 +1,173 @@
+//! What a pipeline sets aside up front, so its memory use is known before it runs.
+//!
+//! Everything the audio callbacks use is allocated while the pipeline is built, and registered in
+//! its [`Allocations`] with what it's for and how big it is. Inputs attached while running are the
+//! exception, since they're built on the control thread when they arrive.
+//!
+//! With the `rt-check` feature, every allocation made from inside an audio callback once a
+//! pipeline is running is counted, by wrapping the global allocator, and debug builds assert
+//! there are none.
+
+use std::fmt;
+
+/// One buffer, or one group of buffers for the same thing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// What it's for, like `ring buffer for "Microphone"`.
+    pub label: String,
+    pub bytes: usize,
+}
+
+/// Everything a pipeline preallocated, in the order it was registered.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Allocations {
+    entries: Vec<Allocation>,
+}
+
+impl Allocations {
+    pub fn register(&mut self, label: impl Into<String>, bytes: usize) {
+        self.entries.push(Allocation {
+            label: label.into(),
+            bytes,
+        });
+    }
+
+    /// Registers `samples` f32 samples.
+    pub fn register_samples(&mut self, label: impl Into<String>, samples: usize) {
+        self.register(label, samples * std::mem::size_of::<f32>());
+    }
+
+    pub fn entries(&self) -> &[Allocation] {
+        &self.entries
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.entries.iter().map(|entry| entry.bytes).sum()
+    }
+}
+
+/// Like `512 B`, `12.5 KB` or `3.2 MB`, in powers of 1024.
+pub fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..=1_023 => format!("{} B", bytes),
+        1_024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1_024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+impl fmt::Display for Allocations {
+    /// The total, then every entry biggest first.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Preallocated {} in all:",
+            format_bytes(self.total_bytes())
+        )?;
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.bytes));
+        for entry in entries {
+            write!(f, "\n  {:>9}  {}", format_bytes(entry.bytes), entry.label)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rt-check")]
+mod rt_check {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    thread_local! {
+        /// Whether this thread is inside an audio callback.
+        pub static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+    }
+    /// How many pipelines have started their output and not been dropped since.
+    pub static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    pub static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+    /// The system allocator, counting what the audio callbacks allocate while running.
+    pub struct CountingAllocator;
+
+    fn count() {
+        // The thread-local can be gone while the thread is being torn down.
+        let in_callback = IN_CALLBACK.try_with(Cell::get).unwrap_or(false);
+        if in_callback && RUNNING.load(Ordering::Relaxed) > 0 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // SAFETY: every call goes straight through to the system allocator.
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count();
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+}
+
+/// Runs `callback` as an audio callback, whose allocations `rt-check` counts.
+#[inline]
+pub(crate) fn in_callback<R>(callback: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "rt-check")]
+    {
+        rt_check::IN_CALLBACK.with(|flag| flag.set(true));
+        let result = callback();
+        rt_check::IN_CALLBACK.with(|flag| flag.set(false));
+        result
+    }
+    #[cfg(not(feature = "rt-check"))]
+    callback()
+}
+
+/// Notes that a pipeline has started running, or with `false` that it's stopped.
+pub(crate) fn set_running(running: bool) {
+    #[cfg(feature = "rt-check")]
+    {
+        use std::sync::atomic::Ordering;
+        if running {
+            rt_check::RUNNING.fetch_add(1, Ordering::Relaxed);
+        } else {
+            rt_check::RUNNING.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    #[cfg(not(feature = "rt-check"))]
+    let _ = running;
+}
+
+/// How many times the audio callbacks have allocated while a pipeline was running, which is only
+/// counted with `rt-check`.
+pub fn callback_allocations() -> Option<u64> {
+    #[cfg(feature = "rt-check")]
+    return Some(rt_check::ALLOCATIONS.load(std::sync::atomic::Ordering::Relaxed));
+    #[cfg(not(feature = "rt-check"))]
+    None
+}
+
+/// Asserts, in debug builds with `rt-check`, that no audio callback has allocated while running.
+pub fn debug_assert_no_callback_allocations() {
+    if let Some(allocations) = callback_allocations() {
+        debug_assert_eq!(
+            allocations, 0,
+            "the audio callbacks allocated {} times while running",
+            allocations
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! The loops the audio callbacks spend most of their time in, over whole slices at a time.
+//!
+//! The plain versions are written so the compiler vectorizes them for whatever the build targets.
+//! With the `simd` feature, x86-64 machines that have AVX use explicit 8-wide versions instead,
+//! which give exactly the same results as the plain ones.
+
+/// Adds `input` into `output`, over as much as both have.
+pub fn add(output: &mut [f32], input: &[f32]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if std::is_x86_feature_detected!("avx") {
+        // SAFETY: AVX was just detected.
+        return unsafe { avx::add(output, input) };
+    }
+    add_scalar(output, input);
+}
+
+/// Multiplies every sample by `gain`.
+pub fn scale(samples: &mut [f32], gain: f32) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if std::is_x86_feature_detected!("avx") {
+        // SAFETY: AVX was just detected.
+        return unsafe { avx::scale(samples, gain) };
+    }
+    scale_scalar(samples, gain);
+}
+
+/// The highest absolute value of `samples`, or 0 if there are none.
+pub fn peak(samples: &[f32]) -> f32 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if std::is_x86_feature_detected!("avx") {
+        // SAFETY: AVX was just detected.
+        return unsafe { avx::peak(samples) };
+    }
+    peak_scalar(samples)
+}
+
+fn add_scalar(output: &mut [f32], input: &[f32]) {
+    for (output, input) in output.iter_mut().zip(input) {
+        *output += input;
+    }
+}
+
+fn scale_scalar(samples: &mut [f32], gain: f32) {
+    for sample in samples {
+        *sample *= gain;
+    }
+}
+
+fn peak_scalar(samples: &[f32]) -> f32 {
+    // Separate running maximums let the compiler keep one per lane. Taking the maximum doesn't
+    // depend on the order, so this is the same as a single one.
+    let mut lanes = [0.0f32; 8];
+    let mut chunks = samples.chunks_exact(lanes.len());
+    for chunk in &mut chunks {
+        for (lane, sample) in lanes.iter_mut().zip(chunk) {
+            *lane = lane.max(sample.abs());
+        }
+    }
+    chunks
+        .remainder()
+        .iter()
+        .chain(&lanes)
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod avx {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 8;
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn add(output: &mut [f32], input: &[f32]) {
+        let len = output.len().min(input.len());
+        let (output, input) = (&mut output[..len], &input[..len]);
+        let mut output_chunks = output.chunks_exact_mut(LANES);
+        let mut input_chunks = input.chunks_exact(LANES);
+        for (output, input) in (&mut output_chunks).zip(&mut input_chunks) {
+            let sum = _mm256_add_ps(
+                _mm256_loadu_ps(output.as_ptr()),
+                _mm256_loadu_ps(input.as_ptr()),
+            );
+            _mm256_storeu_ps(output.as_mut_ptr(), sum);
+        }
+        super::add_scalar(output_chunks.into_remainder(), input_chunks.remainder());
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn scale(samples: &mut [f32], gain: f32) {
+        let gains = _mm256_set1_ps(gain);
+        let mut chunks = samples.chunks_exact_mut(LANES);
+        for chunk in &mut chunks {
+            let scaled = _mm256_mul_ps(_mm256_loadu_ps(chunk.as_ptr()), gains);
+            _mm256_storeu_ps(chunk.as_mut_ptr(), scaled);
+        }
+        super::scale_scalar(chunks.into_remainder(), gain);
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn peak(samples: &[f32]) -> f32 {
+        // Clearing the sign bit is exactly `abs`.
+        let magnitude = _mm256_castsi256_ps(_mm256_set1_epi32(i32::MAX));
+        let mut peaks = _mm256_setzero_ps();
+        let mut chunks = samples.chunks_exact(LANES);
+        for chunk in &mut chunks {
+            let chunk = _mm256_and_ps(_mm256_loadu_ps(chunk.as_ptr()), magnitude);
+            // With the chunk first, a NaN sample leaves the running peak alone, as `f32::max`
+            // does.
+            peaks = _mm256_max_ps(chunk, peaks);
+        }
+        let mut lanes = [0.0f32; LANES];
+        _mm256_storeu_ps(lanes.as_mut_ptr(), peaks);
+        super::peak_scalar(chunks.remainder())
+            .max(lanes.iter().fold(0.0f32, |peak, lane| peak.max(*lane)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples that aren't round numbers, with some above full scale and some negative.
+    fn samples(len: usize, seed: f32) -> Vec<f32> {
+        (0..len)
+            .map(|i| ((i as f32 + seed) * 0.731).sin() * 1.7)
+            .collect()
+    }
+
+    /// Every length up to a few chunks, to cover the remainders the wide paths leave.
+    fn lengths() -> impl Iterator<Item = usize> {
+        (0..70).chain([255, 256, 257, 1000])
+    }
+
+    #[test]
+    fn adds_exactly_like_the_plain_loop() {
+        for len in lengths() {
+            let input = samples(len, 0.5);
+            let mut output = samples(len, 3.0);
+            let mut expected = output.clone();
+            add(&mut output, &input);
+            add_scalar(&mut expected, &input);
+            assert_eq!(
+                output.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+                expected.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+                "{} samples",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn adds_only_as_much_as_both_have() {
+        let mut output = vec![1.0; 20];
+        add(&mut output, &[1.0; 11]);
+        assert_eq!(output[..11], [2.0; 11]);
+        assert_eq!(output[11..], [1.0; 9]);
+
+        let mut output = vec![1.0; 11];
+        add(&mut output, &[1.0; 20]);
+        assert_eq!(output, [2.0; 11]);
+    }
+
+    #[test]
+    fn scales_exactly_like_the_plain_loop() {
+        for len in lengths() {
+            for gain in [0.0, 0.3, 1.0, -2.5] {
+                let mut scaled = samples(len, 1.0);
+                let mut expected = scaled.clone();
+                scale(&mut scaled, gain);
+                scale_scalar(&mut expected, gain);
+                assert_eq!(
+                    scaled.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+                    expected.iter().map(|s| s.to_bits()).collect::<Vec<_>>(),
+                    "{} samples at {}",
+                    len,
+                    gain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finds_the_same_peak_as_a_plain_fold() {
+        for len in lengths() {
+            let samples = samples(len, 2.0);
+            let expected = samples
+                .iter()
+                .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            assert_eq!(peak(&samples).to_bits(), expected.to_bits(), "{}", len);
+            assert_eq!(
+                peak_scalar(&samples).to_bits(),
+                expected.to_bits(),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn finds_negative_peaks_and_passes_over_nan() {
+        let mut samples = vec![0.25; 40];
+        samples[3] = f32::NAN;
+        samples[17] = -0.75;
+        samples[38] = f32::NAN;
+        assert_eq!(peak(&samples), 0.75);
+        assert_eq!(peak(&[]), 0.0);
+    }
+}
@@ -0,0 +1,366 @@
+//! Picks a sample rate every device can run at, from what each of them says it supports, and
+//! works out how the channels asked for fit on an output that has a different number of them.
+//!
+//! The planning itself is pure, so it doesn't need any devices to run.
+
+use std::fmt;
+use std::ops::Range;
+
+/// A span of sample rates a device supports at some channel count, with f32 samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+}
+
+impl ConfigRange {
+    /// Just the rate and channel count of `config`.
+    pub fn exactly(config: &cpal::StreamConfig) -> Self {
+        ConfigRange {
+            channels: config.channels,
+            min_sample_rate: config.sample_rate.0,
+            max_sample_rate: config.sample_rate.0,
+        }
+    }
+}
+
+/// What one device can do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    pub name: String,
+    /// The channel count the device will be opened with.
+    pub channels: u16,
+    /// The rate the device runs at when left alone, which it is pinned to when nothing is common.
+    pub default_sample_rate: u32,
+    pub ranges: Vec<ConfigRange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Every device runs at the same rate.
+    Common { sample_rate: u32 },
+    /// Nothing is common, so each device runs at its own default rate, in the order given.
+    PerDevice { sample_rates: Vec<u32> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub strategy: Strategy,
+    /// Why the strategy was picked.
+    pub reason: String,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+/// Picks the highest sample rate that every device supports at its channel count, or `preferred`
+/// if it is one of them, and falls back to each device's own default rate if there's none.
+pub fn plan(devices: &[DeviceCapabilities], preferred: Option<u32>) -> Plan {
+    let supports = |device: &DeviceCapabilities, rate: u32| {
+        device.ranges.iter().any(|range| {
+            range.channels == device.channels
+                && range.min_sample_rate <= rate
+                && rate <= range.max_sample_rate
+        })
+    };
+
+    // The highest common rate is always the top of some device's range, so only those need
+    // checking.
+    let highest = devices
+        .iter()
+        .flat_map(|device| {
+            device
+                .ranges
+                .iter()
+                .filter(|range| range.channels == device.channels)
+        })
+        .map(|range| range.max_sample_rate)
+        .filter(|&rate| devices.iter().all(|device| supports(device, rate)))
+        .max();
+
+    let count = match devices.len() {
+        1 => "the only device".to_owned(),
+        2 => "both devices".to_owned(),
+        n => format!("all {} devices", n),
+    };
+    match (preferred, highest) {
+        (Some(rate), Some(_)) if devices.iter().all(|device| supports(device, rate)) => Plan {
+            strategy: Strategy::Common { sample_rate: rate },
+            reason: format!("{} Hz, as preferred, is common to {}", rate, count),
+        },
+        (_, Some(rate)) => Plan {
+            strategy: Strategy::Common { sample_rate: rate },
+            reason: format!("{} Hz is the highest rate common to {}", rate, count),
+        },
+        (_, None) => {
+            let pinned = devices
+                .iter()
+                .map(|device| {
+                    format!(
+                        "\"{}\" pinned at {} Hz",
+                        device.name, device.default_sample_rate
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            Plan {
+                strategy: Strategy::PerDevice {
+                    sample_rates: devices
+                        .iter()
+                        .map(|device| device.default_sample_rate)
+                        .collect(),
+                },
+                reason: format!("no rate is common to {}, so {}, resampling", count, pinned),
+            }
+        }
+    }
+}
+
+/// Where the channels asked for go on an output with a different number of them, counting from 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelRemap {
+    /// The channels that keep their places, a stereo pair at a time, with a lone last channel
+    /// when an odd number were asked for.
+    pub kept: Vec<Range<usize>>,
+    /// The first half of a pair the output only has room for one channel of.
+    pub split: Option<usize>,
+    /// The channels asked for that the output has no room for.
+    pub dropped: Range<usize>,
+    /// The output's channels that nothing was asked for, which are left silent.
+    pub silent: Range<usize>,
+}
+
+/// Fits `wanted` channels onto an output with `available`, so a config written for one variant
+/// of a device carries on with what another has. Pairs are kept whole and in place as far as
+/// they fit, so left and right stay together, and the same counts always give the same remap.
+pub fn remap_channels(wanted: u16, available: u16) -> ChannelRemap {
+    let (wanted, available) = (wanted as usize, available as usize);
+    let fits = wanted.min(available);
+    let mut kept = (0..fits)
+        .step_by(2)
+        .map(|start| start..(start + 2).min(wanted))
+        .collect::<Vec<_>>();
+    // A pair that only half fits is split, rather than moved, so nothing after it shifts.
+    let split = match kept.last() {
+        Some(last) if last.end > available => Some(last.start),
+        _ => None,
+    };
+    if split.is_some() {
+        kept.pop();
+    }
+    ChannelRemap {
+        kept,
+        split,
+        dropped: fits..wanted,
+        silent: fits..available,
+    }
+}
+
+#[cfg(test)]
+// A single pair kept is just what some remaps come to.
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    /// A device opened with `channels`, supporting each of `ranges` as `(channels, min, max)`.
+    fn device(
+        name: &str,
+        channels: u16,
+        default: u32,
+        ranges: &[(u16, u32, u32)],
+    ) -> DeviceCapabilities {
+        DeviceCapabilities {
+            name: name.to_owned(),
+            channels,
+            default_sample_rate: default,
+            ranges: ranges
+                .iter()
+                .map(|&(channels, min, max)| ConfigRange {
+                    channels,
+                    min_sample_rate: min,
+                    max_sample_rate: max,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn plans_the_highest_common_rate_or_falls_back_per_device() {
+        let mic = device("Mic", 1, 48_000, &[(1, 8_000, 48_000), (2, 8_000, 96_000)]);
+        let interface = device("Interface", 2, 96_000, &[(2, 44_100, 192_000)]);
+        let airpods = device(
+            "AirPods",
+            1,
+            24_000,
+            &[(1, 16_000, 16_000), (1, 24_000, 24_000)],
+        );
+        let fixed = device("Fixed", 2, 44_100, &[(2, 44_100, 44_100)]);
+        let cases: &[(&[&DeviceCapabilities], Option<u32>, Strategy, &str)] = &[
+            (
+                &[&interface],
+                None,
+                Strategy::Common { sample_rate: 192_000 },
+                "192000 Hz is the highest rate common to the only device",
+            ),
+            (
+                &[&mic, &interface],
+                None,
+                Strategy::Common { sample_rate: 48_000 },
+                "48000 Hz is the highest rate common to both devices",
+            ),
+            (
+                &[&mic, &interface, &fixed],
+                None,
+                Strategy::Common { sample_rate: 44_100 },
+                "44100 Hz is the highest rate common to all 3 devices",
+            ),
+            (
+                &[&mic, &interface],
+                Some(44_100),
+                Strategy::Common { sample_rate: 44_100 },
+                "44100 Hz, as preferred, is common to both devices",
+            ),
+            // A preferred rate that isn't common is passed over for one that is.
+            (
+                &[&mic, &interface],
+                Some(96_000),
+                Strategy::Common { sample_rate: 48_000 },
+                "48000 Hz is the highest rate common to both devices",
+            ),
+            // Only the ranges at the channel count the device is opened with count.
+            (
+                &[&mic, &device("Wide", 2, 96_000, &[(2, 96_000, 96_000)])],
+                None,
+                Strategy::PerDevice { sample_rates: vec![48_000, 96_000] },
+                "no rate is common to both devices, so \"Mic\" pinned at 48000 Hz, \"Wide\" pinned \
+                 at 96000 Hz, resampling",
+            ),
+            (
+                &[&mic, &interface, &airpods],
+                Some(48_000),
+                Strategy::PerDevice { sample_rates: vec![48_000, 96_000, 24_000] },
+                "no rate is common to all 3 devices, so \"Mic\" pinned at 48000 Hz, \"Interface\" \
+                 pinned at 96000 Hz, \"AirPods\" pinned at 24000 Hz, resampling",
+            ),
+        ];
+        for (devices, preferred, strategy, reason) in cases {
+            let devices = devices
+                .iter()
+                .map(|&device| device.clone())
+                .collect::<Vec<_>>();
+            let plan = plan(&devices, *preferred);
+            assert_eq!(&plan.strategy, strategy, "{}", plan);
+            assert_eq!(plan.to_string(), *reason);
+        }
+    }
+
+    #[test]
+    fn drops_what_a_smaller_output_has_no_room_for() {
+        assert_eq!(
+            remap_channels(16, 2),
+            ChannelRemap {
+                kept: vec![0..2],
+                split: None,
+                dropped: 2..16,
+                silent: 2..2,
+            }
+        );
+        assert_eq!(
+            remap_channels(64, 16),
+            ChannelRemap {
+                kept: vec![0..2, 2..4, 4..6, 6..8, 8..10, 10..12, 12..14, 14..16],
+                split: None,
+                dropped: 16..64,
+                silent: 16..16,
+            }
+        );
+    }
+
+    #[test]
+    fn splits_a_pair_that_only_half_fits_without_moving_the_rest() {
+        assert_eq!(
+            remap_channels(16, 3),
+            ChannelRemap {
+                kept: vec![0..2],
+                split: Some(2),
+                dropped: 3..16,
+                silent: 3..3,
+            }
+        );
+        assert_eq!(
+            remap_channels(2, 1),
+            ChannelRemap {
+                kept: vec![],
+                split: Some(0),
+                dropped: 1..2,
+                silent: 1..1,
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_what_a_bigger_output_has_spare_silent() {
+        assert_eq!(
+            remap_channels(2, 16),
+            ChannelRemap {
+                kept: vec![0..2],
+                split: None,
+                dropped: 2..2,
+                silent: 2..16,
+            }
+        );
+        assert_eq!(
+            remap_channels(4, 16),
+            ChannelRemap {
+                kept: vec![0..2, 2..4],
+                split: None,
+                dropped: 4..4,
+                silent: 4..16,
+            }
+        );
+        // An odd channel asked for is kept alone, since there's room for its pair.
+        assert_eq!(
+            remap_channels(3, 16),
+            ChannelRemap {
+                kept: vec![0..2, 2..3],
+                split: None,
+                dropped: 3..3,
+                silent: 3..16,
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_everything_in_place_when_the_counts_match() {
+        assert_eq!(
+            remap_channels(8, 8),
+            ChannelRemap {
+                kept: vec![0..2, 2..4, 4..6, 6..8],
+                split: None,
+                dropped: 8..8,
+                silent: 8..8,
+            }
+        );
+    }
+
+    #[test]
+    fn an_exact_config_supports_only_its_own_rate() {
+        let config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(44_100),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        assert_eq!(
+            ConfigRange::exactly(&config),
+            ConfigRange {
+                channels: 2,
+                min_sample_rate: 44_100,
+                max_sample_rate: 44_100,
+            }
+        );
+    }
+}
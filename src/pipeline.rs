@@ -0,0 +1,4208 @@
+//! Builds the streams that feed every input into a ring buffer and mix them into the output.
+
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    Arc, Mutex, OnceLock,
+};
+use std::time::{Duration, Instant};
+
+use ringbuf::{
+    ring_buffer::{RbRef, RbWrite},
+    HeapConsumer, HeapRb, Producer,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::attach::{self, AttachedMix, Attacher, MAX_ATTACHED};
+use crate::backend::{
+    BackendError, DeviceInfo, DeviceProvider, InputSource, OutputSink, Stream, StreamConfig,
+    StreamError,
+};
+use crate::chain::{CpuUsage, GainStage, InputChain, ProcessStage, StageKind};
+#[cfg(feature = "denoise")]
+use crate::denoise;
+use crate::describe::{
+    DeviceDescription, InputDescription, LatencyDescription, OutputDescription,
+    PipelineDescription, RecordingDescription,
+};
+use crate::error::{self, parse_error, ParseError, PipelineError};
+use crate::identify::{IdentifyGenerator, IdentifyRequest};
+use crate::latency::{self, AdaptiveLatency, Adjustment, LatencyController};
+use crate::loudness::{self, LoudnessMeter};
+use crate::memory::{self, Allocations};
+use crate::mix;
+use crate::negotiate::{self, remap_channels, DeviceCapabilities, Strategy};
+use crate::priority::{RaiseOnce, RaiseReport};
+use crate::rate::{self, Rate, RateEstimator};
+use crate::recorder::{
+    FormatChange, MarkerOptions, MirrorSpec, RecordTap, Recorder, RecorderError, ReplaySpec,
+    SyncPolicy, TrackSpec,
+};
+use crate::resample::{self, Quality, Resampler};
+use crate::reverb::{Reverb, ReverbSend};
+use crate::true_peak::TruePeakMeter;
+
+/// Input callbacks process at most this many samples at a time, so that a scratch buffer can live
+/// on the stack.
+const SCRATCH_SAMPLES: usize = 1_024;
+
+/// Where markers are listed while recording, next to the recordings, unless the config says
+/// otherwise.
+pub const MARKER_SIDECAR: &str = "session.markers.json";
+
+/// How long inputs get to deliver their first callback once started.
+const START_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The callback size we plan around when the stream config leaves the buffer size up to the host.
+const ASSUMED_BUFFER_FRAMES: u32 = 512;
+
+/// Samples at least this loud count as full scale.
+const CLIP_LEVEL: f32 = 0.999;
+/// How many full-scale samples in a row count as clipping, rather than a loud peak.
+const CLIP_RUN: usize = 4;
+/// How loud a reverb send is until it's changed.
+pub const DEFAULT_REVERB_WET: f32 = 0.3;
+/// The least time between warnings that an input is clipping.
+const CLIP_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the output does with a configured input, which the control thread changes as the input's
+/// stream is stopped and started: while running, it's mixed in.
+const INPUT_RUNNING: u8 = 0;
+/// While stopped, whatever is left in its ring buffer is thrown away, and it plays as silence.
+const INPUT_STOPPED: u8 = 1;
+/// Once started again, it plays as silence until its ring buffer holds its latency again, and is
+/// then faded in.
+const INPUT_STARTING: u8 = 2;
+/// Just after it's started again, whatever is still left in its ring buffer from before it was
+/// stopped is thrown away, and it's then starting.
+const INPUT_RESTARTING: u8 = 3;
+
+/// Everything needed to build a [`Pipeline`].
+#[derive(Clone, Debug)]
+pub struct PipelineConfig {
+    pub inputs: Vec<InputConfig>,
+    pub output: String,
+    pub latency_ms: f32,
+    pub ringbuf_ms: Option<f32>,
+    /// How much of the denoised signal to use on inputs with `denoise` set, from 0 to 1.
+    pub denoise_mix: f32,
+    /// How many times the pipeline has been rebuilt, so recordings from each build go to their
+    /// own files rather than overwriting the previous ones.
+    pub segment: usize,
+    /// Embeds markers in the recordings as cue points, on top of listing them in the sidecar.
+    pub cue_markers: bool,
+    /// Picks a sample rate every device supports, rather than using the first input's default.
+    pub negotiate: bool,
+    /// Opens the output with this many channels rather than as many as the inputs have.
+    pub output_channels: Option<u16>,
+    /// Adjusts the latency within these bounds while running, starting from the top, rather than
+    /// keeping `latency_ms`.
+    pub adaptive_latency: Option<AdaptiveLatency>,
+    /// Keeps this much of the output in memory, for `clip` to save after the fact.
+    pub replay_buffer: Option<Duration>,
+    /// Runs every input's chain on deinterleaved audio.
+    pub planar: bool,
+    /// Records the output, as it is played.
+    pub record_output: Option<PathBuf>,
+    /// Also records the output to this file, which carries on if the other fails and the other
+    /// way round. Only used along with `record_output`.
+    pub record_mirror: Option<MirrorSpec>,
+    /// How every recording but the mirror is synced to disk.
+    pub record_sync: SyncPolicy,
+    /// Carries recordings on in the same files when the pipeline is rebuilt through
+    /// [`Pipeline::build_continuing`], rather than starting new ones, doing this to any whose
+    /// stream comes back at another rate or channel count.
+    pub record_continue: Option<FormatChange>,
+    /// Limits the output's recording, and its mirror, to this many dBTP, however loud the output
+    /// itself gets.
+    pub record_ceiling: Option<f32>,
+    /// Records the processed side of `record_ab` before the input's gain, and the gain's changes
+    /// in the marker sidecar, so the files don't change level when the gain does.
+    pub stems_pre_fader: bool,
+    /// Tries to raise the threads running the callbacks to real-time priority.
+    pub rt_priority: bool,
+    /// Silence to start the first segment's recordings with, to share a zero point with
+    /// something that started recording earlier.
+    pub preroll: Duration,
+    /// Where markers and when each recording started are listed, usually [`MARKER_SIDECAR`].
+    pub marker_sidecar: PathBuf,
+    /// Where `record_ab` writes its files, usually the current directory.
+    pub record_dir: PathBuf,
+    /// Fails when the output has fewer channels than `output_channels`, rather than keeping as
+    /// many as it has.
+    pub strict_routing: bool,
+    /// How inputs that can't run at the output's rate are resampled to it, when negotiating.
+    pub resample_quality: Quality,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputConfig {
+    /// What the input is called everywhere else, which is the device's name unless the device has
+    /// been split into several inputs.
+    pub name: String,
+    pub device: String,
+    /// The channels of the device this input takes, repeated across every channel of the
+    /// pipeline, or the whole device if `None`.
+    pub channels: Option<Range<usize>>,
+    pub invert: bool,
+    pub denoise: bool,
+    /// Records the input both before and after its chain, lined up so they can be compared.
+    pub record_ab: bool,
+    /// Sends the input to a reverb, which starts off.
+    pub reverb: bool,
+    /// Plays the input this much later than the others, to line it up with them.
+    pub delay: Duration,
+}
+
+impl InputConfig {
+    pub fn new(name: &str) -> Self {
+        InputConfig {
+            name: name.to_owned(),
+            device: name.to_owned(),
+            channels: None,
+            invert: false,
+            denoise: false,
+            record_ab: false,
+            reverb: false,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Parses a split like `Scarlett Solo=left:Me,right:Cohost` into an input per named channel of
+/// the device. Channels are `left`, `right`, or a number counting from 1, and any channel that
+/// isn't named is ignored.
+pub fn parse_split(value: &str) -> Result<Vec<InputConfig>, ParseError> {
+    let (device, parts) = value.split_once('=').ok_or_else(|| {
+        parse_error!(
+            "expected a split like `Device=left:Me,right:Cohost`, got `{}`",
+            value
+        )
+    })?;
+    parts
+        .split(',')
+        .map(|part| {
+            let (channel, name) = part
+                .split_once(':')
+                .ok_or_else(|| parse_error!("expected a channel like `left:Me`, got `{}`", part))?;
+            let channel = match channel {
+                "left" => 0,
+                "right" => 1,
+                other => match other.parse::<usize>() {
+                    Ok(channel) if channel > 0 => channel - 1,
+                    _ => {
+                        return Err(parse_error!(
+                            "unknown channel `{}`, expected `left`, `right` or a number from 1",
+                            other
+                        ))
+                    }
+                },
+            };
+            if name.is_empty() {
+                return Err(parse_error!(
+                    "channel `{}` of \"{}\" needs a name",
+                    part,
+                    device
+                ));
+            }
+            Ok(InputConfig {
+                device: device.to_owned(),
+                channels: Some(channel..channel + 1),
+                ..InputConfig::new(name)
+            })
+        })
+        .collect()
+}
+
+/// Parses a sub-input like `Game Capture HD60 X=3-4:Chat` into an input taking that range of the
+/// device's channels, counting from 1. A single channel like `3:Chat` works too.
+pub fn parse_subinput(value: &str) -> Result<InputConfig, ParseError> {
+    let (device, part) = value.split_once('=').ok_or_else(|| {
+        parse_error!(
+            "expected a sub-input like `Device=3-4:Chat`, got `{}`",
+            value
+        )
+    })?;
+    let (channels, name) = part
+        .split_once(':')
+        .ok_or_else(|| parse_error!("expected channels like `3-4:Chat`, got `{}`", part))?;
+    let (first, last) = channels.split_once('-').unwrap_or((channels, channels));
+    let channel = |channel: &str| match channel.parse::<usize>() {
+        Ok(channel) if channel > 0 => Ok(channel - 1),
+        _ => Err(parse_error!(
+            "expected a channel number from 1, got `{}`",
+            channel
+        )),
+    };
+    let (first, last) = (channel(first)?, channel(last)?);
+    if last < first {
+        return Err(parse_error!("channels `{}` run backwards", channels));
+    }
+    if name.is_empty() {
+        return Err(parse_error!(
+            "channels `{}` of \"{}\" need a name",
+            channels,
+            device
+        ));
+    }
+    Ok(InputConfig {
+        device: device.to_owned(),
+        channels: Some(first..last + 1),
+        ..InputConfig::new(name)
+    })
+}
+
+/// Resolved sizes for one ring buffer, all in frames unless stated otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RingBufferSize {
+    pub latency_frames: usize,
+    pub capacity_frames: usize,
+    pub channels: usize,
+}
+
+impl RingBufferSize {
+    /// `delay_frames` is prefilled on top of the latency, to compensate for processing on other
+    /// inputs or to delay the input on purpose.
+    pub fn new(
+        sample_rate: u32,
+        channels: u16,
+        buffer_frames: u32,
+        latency_ms: f32,
+        delay_frames: usize,
+        ringbuf_ms: Option<f32>,
+    ) -> Result<Self, PipelineError> {
+        let latency_frames = ms_to_frames(latency_ms, sample_rate) + delay_frames;
+        let buffer_frames = buffer_frames as usize;
+        let capacity_frames = match ringbuf_ms {
+            Some(ms) => ms_to_frames(ms, sample_rate),
+            None => (latency_frames * 2).max(buffer_frames * 4),
+        };
+
+        // The latency is prefilled as silence, so on top of it there must be room for the input to
+        // deliver at least one more callback before the output gets to drain anything.
+        if latency_frames + buffer_frames > capacity_frames {
+            return Err(PipelineError::invalid(format!(
+                "a latency of {} ms plus {} frames of delay compensation ({} frames) plus a {} \
+                 frame callback doesn't fit in a ring buffer of {} frames: increase \
+                 `--ringbuf-ms` or decrease `--latency-ms`",
+                latency_ms, delay_frames, latency_frames, buffer_frames, capacity_frames
+            )));
+        }
+
+        Ok(RingBufferSize {
+            latency_frames,
+            capacity_frames,
+            channels: channels as usize,
+        })
+    }
+
+    pub fn latency_samples(&self) -> usize {
+        self.latency_frames * self.channels
+    }
+
+    pub fn capacity_samples(&self) -> usize {
+        self.capacity_frames * self.channels
+    }
+}
+
+pub fn ms_to_frames(ms: f32, sample_rate: u32) -> usize {
+    ((ms / 1_000.0) * sample_rate as f32).round() as usize
+}
+
+pub fn buffer_frames(buffer_size: &cpal::BufferSize) -> u32 {
+    match buffer_size {
+        cpal::BufferSize::Fixed(frames) => *frames,
+        cpal::BufferSize::Default => ASSUMED_BUFFER_FRAMES,
+    }
+}
+
+/// A gain on top of everything else an input does, which the control thread can change at any
+/// time and the input callback fades towards.
+///
+/// It is made of the input's level, whether it is muted, and an override for temporary changes
+/// like duck-hold, which all multiply together.
+#[derive(Debug)]
+pub struct OverrideGain {
+    target: AtomicU32,
+    level_db: AtomicU32,
+    muted: AtomicBool,
+}
+
+impl OverrideGain {
+    pub(crate) fn new() -> Self {
+        OverrideGain {
+            target: AtomicU32::new(1f32.to_bits()),
+            level_db: AtomicU32::new(0f32.to_bits()),
+            muted: AtomicBool::new(false),
+        }
+    }
+
+    /// Sets the override, as a linear gain.
+    pub fn set(&self, gain: f32) {
+        self.target.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_level_db(&self, db: f32) {
+        self.level_db.store(db.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn level_db(&self) -> f32 {
+        f32::from_bits(self.level_db.load(Ordering::Relaxed))
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// The linear gain all of the above add up to.
+    pub fn target(&self) -> f32 {
+        if self.muted() {
+            return 0.0;
+        }
+        f32::from_bits(self.target.load(Ordering::Relaxed)) * 10f32.powf(self.level_db() / 20.0)
+    }
+}
+
+/// What the output callback has seen, for the control thread to read.
+#[derive(Debug, Default)]
+pub struct OutputCounters {
+    callbacks: AtomicU64,
+    underruns: AtomicU64,
+    /// The loudest sample since the peak was last taken. Comparing the bits of non-negative floats
+    /// as integers orders them the same way as the floats, so this can use `fetch_max`.
+    peak: AtomicU32,
+    /// The same, but for the true peak, which includes the peaks between samples.
+    true_peak: AtomicU32,
+    /// The output's K-weighted mean square over the last 3 seconds, for the terminal title.
+    loudness: AtomicU32,
+    /// The least any input had buffered at the start of the last callback.
+    buffered_frames: AtomicUsize,
+}
+
+impl OutputCounters {
+    /// The number of times the output callback has run, which stops going up if the streams die
+    /// without reporting an error.
+    pub fn callbacks(&self) -> u64 {
+        self.callbacks.load(Ordering::Relaxed)
+    }
+
+    /// The number of callbacks in which some input didn't have enough samples buffered.
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Returns and resets the output's highest true peak since the last call, which can be above 1
+    /// even when no sample is.
+    pub fn take_true_peak(&self) -> f32 {
+        f32::from_bits(self.true_peak.swap(0, Ordering::Relaxed))
+    }
+
+    /// How much the output is currently buffering, in frames, which is its latency on top of the
+    /// devices' own.
+    pub fn buffered_frames(&self) -> usize {
+        self.buffered_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns and resets the loudest absolute sample played since the last call.
+    pub fn take_peak(&self) -> f32 {
+        f32::from_bits(self.peak.swap(0, Ordering::Relaxed))
+    }
+
+    /// The output's short-term loudness, in LUFS, or `None` if it's been silent for 3 seconds.
+    pub fn short_term_loudness(&self) -> Option<f32> {
+        loudness::lufs(f32::from_bits(self.loudness.load(Ordering::Relaxed)))
+    }
+}
+
+/// Looks up input device `name`, telling a device that isn't there apart from one that is but
+/// couldn't be opened.
+fn find_input(
+    provider: &dyn DeviceProvider,
+    name: &str,
+) -> Result<Box<dyn InputSource>, PipelineError> {
+    provider
+        .input_device(name)
+        .map_err(|source| lookup_error(provider, name, true, source))
+}
+
+fn find_output(
+    provider: &dyn DeviceProvider,
+    name: &str,
+) -> Result<Box<dyn OutputSink>, PipelineError> {
+    provider
+        .output_device(name)
+        .map_err(|source| lookup_error(provider, name, false, source))
+}
+
+fn lookup_error(
+    provider: &dyn DeviceProvider,
+    wanted: &str,
+    is_input: bool,
+    source: BackendError,
+) -> PipelineError {
+    let goes_the_same_way = |device: &DeviceInfo| {
+        if is_input {
+            device.is_input
+        } else {
+            device.is_output
+        }
+    };
+    match provider.devices() {
+        Ok(devices)
+            if !devices
+                .iter()
+                .any(|device| device.name == wanted && goes_the_same_way(device)) =>
+        {
+            PipelineError::DeviceNotFound {
+                wanted: wanted.to_owned(),
+                available: devices
+                    .into_iter()
+                    .filter(goes_the_same_way)
+                    .map(|device| device.name)
+                    .collect(),
+                is_input,
+            }
+        }
+        // Without a list to go on, it's only known that the lookup failed.
+        _ => PipelineError::DeviceQuery {
+            device: wanted.to_owned(),
+            source: source.into(),
+        },
+    }
+}
+
+fn query_error(device: &str) -> impl FnOnce(BackendError) -> PipelineError + '_ {
+    move |source| PipelineError::DeviceQuery {
+        device: device.to_owned(),
+        source: source.into(),
+    }
+}
+
+fn stream_build_error(device: &str) -> impl FnOnce(BackendError) -> PipelineError + '_ {
+    move |source| PipelineError::StreamBuild {
+        device: device.to_owned(),
+        source: source.into(),
+    }
+}
+
+/// Like [`stream_build_error`], but telling apart an output another application holds exclusively.
+fn output_build_error(device: &str) -> impl FnOnce(BackendError) -> PipelineError + '_ {
+    move |source| {
+        if error::held_exclusively(&error::with_causes(&source)) {
+            PipelineError::OutputBusy {
+                device: device.to_owned(),
+                source: source.into(),
+            }
+        } else {
+            stream_build_error(device)(source)
+        }
+    }
+}
+
+fn stream_start_error(device: &str) -> impl FnOnce(BackendError) -> PipelineError + '_ {
+    move |source| PipelineError::StreamStart {
+        device: device.to_owned(),
+        source: source.into(),
+    }
+}
+
+/// An input's chain, with the handles on its override gain and any reverb send.
+type BuiltChain = (InputChain, Arc<OverrideGain>, Option<Arc<ReverbSend>>);
+
+/// Builds the chain for `input`, returning the handles on its override gain and any reverb send
+/// alongside it.
+fn build_chain(
+    input: &InputConfig,
+    denoise_mix: f32,
+    planar: bool,
+    config: &StreamConfig,
+    stats: &mut Stats,
+) -> Result<BuiltChain, PipelineError> {
+    let mut stages: Vec<Box<dyn ProcessStage>> = Vec::new();
+
+    if input.denoise {
+        #[cfg(feature = "denoise")]
+        {
+            if config.sample_rate.0 != denoise::SAMPLE_RATE {
+                return Err(PipelineError::invalid(format!(
+                    "denoising \"{}\" requires {} Hz but the stream runs at {} Hz",
+                    input.name,
+                    denoise::SAMPLE_RATE,
+                    config.sample_rate.0
+                )));
+            }
+            let denoiser = denoise::Denoiser::new(config.channels as usize, denoise_mix);
+            stats.cpu.push((
+                input.name.clone(),
+                "Denoising",
+                config.clone(),
+                denoiser.cpu_usage(),
+            ));
+            stages.push(Box::new(denoiser));
+        }
+        #[cfg(not(feature = "denoise"))]
+        {
+            let _ = (denoise_mix, stats);
+            return Err(PipelineError::invalid(
+                "`--denoise` requires building with the `denoise` feature",
+            ));
+        }
+    }
+
+    let gain = GainStage::new(config, input.invert);
+    let override_gain = gain.override_gain();
+    stages.push(Box::new(gain));
+
+    let send = input.reverb.then(|| {
+        let send = Arc::new(ReverbSend::new(DEFAULT_REVERB_WET));
+        let reverb = Reverb::new(
+            config.channels as usize,
+            config.sample_rate.0,
+            Arc::clone(&send),
+        );
+        log::info!(
+            "Reverb for \"{}\": {} KB of delay lines, {} comb and allpass taps a frame.",
+            input.name,
+            reverb.memory_bytes().div_ceil(1024),
+            reverb.taps_per_frame()
+        );
+        stats.cpu.push((
+            input.name.clone(),
+            "The reverb for",
+            config.clone(),
+            reverb.cpu_usage(),
+        ));
+        stages.push(Box::new(reverb));
+        send
+    });
+
+    let chain = InputChain::new(config.channels as usize, stages)
+        .map_err(|err| PipelineError::invalid(err.to_string()))?;
+    let mut chain = if planar {
+        // The input callback hands the chain at most this much at a time.
+        chain.planar(SCRATCH_SAMPLES / config.channels as usize)
+    } else {
+        chain
+    };
+    // Every stream starts on a reset chain, whether it's built for a new or rebuilt pipeline, for
+    // an attached input, or carried over by `start_input`.
+    chain.reset();
+    Ok((chain, override_gain, send))
+}
+
+/// What an input's callback counts, for the control thread to report on.
+#[derive(Debug, Default)]
+pub struct InputCounters {
+    /// Frames dropped because the ring buffer was full, since the stats were last printed.
+    dropped_frames: AtomicU64,
+    /// Samples that arrived at full scale in runs long enough to be clipping, over the whole run.
+    clipped_samples: AtomicU64,
+    /// Frames that arrived, over the whole run.
+    frames: AtomicU64,
+    /// The loudest sample that arrived since the peak was last taken, as f32 bits.
+    peak: AtomicU32,
+}
+
+impl InputCounters {
+    pub fn clipped_samples(&self) -> u64 {
+        self.clipped_samples.load(Ordering::Relaxed)
+    }
+
+    /// Returns and resets the loudest absolute sample that arrived since the last call, before
+    /// the chain.
+    pub fn take_peak(&self) -> f32 {
+        f32::from_bits(self.peak.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Counts the samples of each channel that are part of a run at full scale, which is what
+/// clipping before the signal ever reached the pipeline looks like.
+struct ClipDetector {
+    /// How many full-scale samples in a row each channel has had.
+    runs: Vec<usize>,
+}
+
+impl ClipDetector {
+    fn new(channels: usize) -> Self {
+        ClipDetector {
+            runs: vec![0; channels],
+        }
+    }
+
+    /// Returns how many of `samples` were clipped.
+    fn process(&mut self, samples: &[f32]) -> u64 {
+        let mut clipped = 0;
+        for frame in samples.chunks_exact(self.runs.len()) {
+            for (run, sample) in self.runs.iter_mut().zip(frame) {
+                if sample.abs() < CLIP_LEVEL {
+                    *run = 0;
+                    continue;
+                }
+                *run += 1;
+                // The run counts from its start once it's long enough.
+                match (*run).cmp(&CLIP_RUN) {
+                    std::cmp::Ordering::Less => {}
+                    std::cmp::Ordering::Equal => clipped += CLIP_RUN as u64,
+                    std::cmp::Ordering::Greater => clipped += 1,
+                }
+            }
+        }
+        clipped
+    }
+}
+
+/// Measurements that are worth printing every so often while running.
+#[derive(Default)]
+pub struct Stats {
+    inputs: Vec<InputStats>,
+    /// The buffer counters of every device with a stream, by name.
+    devices: Vec<(String, Arc<BufferCounters>)>,
+    /// The time spent in stages that report it, by input or device, and what the stage does.
+    cpu: Vec<(String, &'static str, StreamConfig, Arc<CpuUsage>)>,
+}
+
+struct InputStats {
+    name: String,
+    counters: Arc<InputCounters>,
+    /// How many clipped samples had been warned about, and when the last warning was.
+    clip_warning: Mutex<(u64, Option<Instant>)>,
+    /// The input's real sample rate, and whether it has been reported yet.
+    rate: Mutex<(RateEstimator, bool)>,
+}
+
+impl Stats {
+    fn add_input(&mut self, name: &str, counters: Arc<InputCounters>, sample_rate: u32) {
+        self.inputs.push(InputStats {
+            name: name.to_owned(),
+            counters,
+            clip_warning: Mutex::new((0, None)),
+            rate: Mutex::new((RateEstimator::new(sample_rate), false)),
+        });
+    }
+
+    /// Notes how far every input has got, which should be done every so often from the same
+    /// thread, and reports each input's real sample rate once it's been measured. Inputs running
+    /// too far from their nominal rate are warned about.
+    pub fn measure_rates(&self, now: Instant) {
+        for input in &self.inputs {
+            let mut state = input.rate.lock().unwrap();
+            let (estimator, reported) = &mut *state;
+            estimator.observe(now, input.counters.frames.load(Ordering::Relaxed));
+            let Some(measured) = estimator.rate().filter(|_| !*reported) else {
+                continue;
+            };
+            *reported = true;
+            if measured.ppm().abs() > rate::WARNING_PPM {
+                log::warn!(
+                    "Input \"{}\" runs at {} rather than its nominal rate, so it will drift away \
+                     from anything recorded on another clock, as nothing corrects it.",
+                    input.name,
+                    measured
+                );
+            } else {
+                log::info!("Input \"{}\" runs at {}.", input.name, measured);
+            }
+        }
+    }
+
+    /// Every input's measured sample rate, by name, for the inputs measured for long enough.
+    pub fn rates(&self) -> impl Iterator<Item = (&str, Rate)> {
+        self.inputs.iter().filter_map(|input| {
+            let rate = input.rate.lock().unwrap().0.rate()?;
+            Some((input.name.as_str(), rate))
+        })
+    }
+
+    fn add_device(&mut self, name: &str) -> Arc<BufferCounters> {
+        let counters = Arc::new(BufferCounters::default());
+        self.devices.push((name.to_owned(), Arc::clone(&counters)));
+        counters
+    }
+
+    /// Every input's counters, by name.
+    pub fn inputs(&self) -> impl Iterator<Item = (&str, &InputCounters)> {
+        self.inputs
+            .iter()
+            .map(|input| (input.name.as_str(), &*input.counters))
+    }
+
+    /// Prints what has been measured since the last call.
+    pub fn print(&self) {
+        for input in &self.inputs {
+            let dropped = input.counters.dropped_frames.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                log::info!(
+                    "Dropped {} frames of \"{}\" because the output fell behind.",
+                    dropped,
+                    input.name
+                );
+            }
+
+            let clipped = input.counters.clipped_samples();
+            let mut clip_warning = input.clip_warning.lock().unwrap();
+            let (warned_about, warned_at) = &mut *clip_warning;
+            if clipped > *warned_about
+                && warned_at.is_none_or(|at| at.elapsed() >= CLIP_WARNING_INTERVAL)
+            {
+                log::warn!(
+                    "Input \"{}\" appears to be clipping at the source ({} samples at full scale \
+                     so far): reduce its hardware gain.",
+                    input.name,
+                    clipped
+                );
+                *warned_about = clipped;
+                *warned_at = Some(Instant::now());
+            }
+        }
+        for (name, counters) in &self.devices {
+            let empty = counters.empty.swap(0, Ordering::Relaxed);
+            let misaligned = counters.misaligned.swap(0, Ordering::Relaxed);
+            if empty > 0 || misaligned > 0 {
+                log::info!(
+                    "\"{}\" had {} empty buffers and {} that ended partway through a frame.",
+                    name,
+                    empty,
+                    misaligned
+                );
+            }
+        }
+        for (name, what, config, cpu) in &self.cpu {
+            let (busy_nanos, samples) = cpu.take();
+            let audio_nanos =
+                samples as f64 * 1e9 / (config.sample_rate.0 as f64 * config.channels as f64);
+            if audio_nanos > 0.0 {
+                log::info!(
+                    "{} \"{}\" takes {:.2}% of real time.",
+                    what,
+                    name,
+                    busy_nanos as f64 / audio_nanos * 100.0
+                );
+            }
+        }
+    }
+}
+
+/// How a device's stream has been cutting up its buffers, since the stats were last printed.
+#[derive(Debug, Default)]
+pub struct BufferCounters {
+    /// Callbacks with no samples at all, which are ignored.
+    empty: AtomicU64,
+    /// Callbacks that ended partway through a frame.
+    misaligned: AtomicU64,
+}
+
+/// What an input device's stream hands its samples to, on their way through any resampling and
+/// splitting to each input's chain and ring buffer.
+trait InputHandler: Send {
+    fn process(&mut self, data: &[f32]);
+
+    /// Forgets everything kept from the samples handled so far, so that a new stream starts
+    /// afresh. Only done while no stream is running the handler, so it may allocate.
+    fn reset(&mut self);
+}
+
+/// Hands `on_data` only whole frames of `channels` samples, however the device cuts up its
+/// buffers: a frame split across callbacks is held back until the rest of it arrives.
+struct AlignInputFrames {
+    channels: usize,
+    counters: Arc<BufferCounters>,
+    partial: Vec<f32>,
+    on_data: Box<dyn InputHandler>,
+}
+
+impl AlignInputFrames {
+    fn new(channels: usize, counters: Arc<BufferCounters>, on_data: Box<dyn InputHandler>) -> Self {
+        AlignInputFrames {
+            channels,
+            counters,
+            partial: Vec::with_capacity(channels),
+            on_data,
+        }
+    }
+}
+
+impl InputHandler for AlignInputFrames {
+    fn process(&mut self, mut data: &[f32]) {
+        if data.is_empty() {
+            self.counters.empty.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if !self.partial.is_empty() {
+            let needed = (self.channels - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+            if self.partial.len() == self.channels {
+                self.on_data.process(&self.partial);
+                self.partial.clear();
+            }
+        }
+        let whole = data.len() - data.len() % self.channels;
+        if whole > 0 {
+            self.on_data.process(&data[..whole]);
+        }
+        self.partial.extend_from_slice(&data[whole..]);
+        if !self.partial.is_empty() {
+            self.counters.misaligned.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.partial.clear();
+        self.on_data.reset();
+    }
+}
+
+/// The input frames to resample at a time, and the samples of room their output needs.
+fn resampling_scratch(resampler: &Resampler) -> (usize, usize) {
+    let channels = resampler.channels();
+    let chunk_frames = resampler
+        .max_input_frames(SCRATCH_SAMPLES / channels)
+        .max(1);
+    (
+        chunk_frames,
+        resampler.max_output_frames(chunk_frames) * channels,
+    )
+}
+
+/// Resamples a device's whole frames to the pipeline's rate before handing them to `on_data`, a
+/// chunk at a time so that the resampled audio fits in a buffer allocated up front.
+struct ResampleInput {
+    resampler: Resampler,
+    chunk_frames: usize,
+    scratch: Vec<f32>,
+    on_data: Box<dyn InputHandler>,
+}
+
+impl ResampleInput {
+    fn new(resampler: Resampler, on_data: Box<dyn InputHandler>) -> Self {
+        let (chunk_frames, scratch_samples) = resampling_scratch(&resampler);
+        ResampleInput {
+            resampler,
+            chunk_frames,
+            scratch: vec![0.0; scratch_samples],
+            on_data,
+        }
+    }
+}
+
+impl InputHandler for ResampleInput {
+    fn process(&mut self, data: &[f32]) {
+        let channels = self.resampler.channels();
+        for chunk in data.chunks(self.chunk_frames * channels) {
+            let written = self.resampler.process(chunk, &mut self.scratch);
+            if written > 0 {
+                self.on_data.process(&self.scratch[..written]);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.resampler.reset();
+        self.on_data.reset();
+    }
+}
+
+/// Asks `fill` for only whole frames of `channels` samples, however the device cuts up its
+/// buffers: when one ends partway through a frame, the whole frame is mixed and the rest of it
+/// starts the next buffer.
+fn align_output_frames(
+    channels: usize,
+    counters: Arc<BufferCounters>,
+    mut fill: impl FnMut(&mut [f32]),
+) -> impl FnMut(&mut [f32]) {
+    let mut frame = vec![0.0; channels];
+    // The samples of `frame` still to be played.
+    let mut held = channels..channels;
+    move |data: &mut [f32]| {
+        if data.is_empty() {
+            counters.empty.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let from_held = held.len().min(data.len());
+        let (start, data) = data.split_at_mut(from_held);
+        start.copy_from_slice(&frame[held.start..held.start + from_held]);
+        held.start += from_held;
+        let whole = data.len() - data.len() % channels;
+        let (frames, rest) = data.split_at_mut(whole);
+        if !frames.is_empty() {
+            fill(frames);
+        }
+        if !rest.is_empty() {
+            fill(&mut frame);
+            rest.copy_from_slice(&frame[..rest.len()]);
+            held = rest.len()..channels;
+        }
+        if !held.is_empty() {
+            counters.misaligned.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Processes an input's samples and queues them for the output.
+///
+/// Only whole frames are ever pushed, so a full ring buffer drops the frames that don't fit
+/// rather than splitting one and shifting every channel after it. Dropped frames, and samples
+/// that arrived clipped, are added to `counters`.
+pub fn create_input_processing_fn<R>(
+    producer: Producer<f32, R>,
+    chain: InputChain,
+    ab: Option<AbTaps>,
+    counters: Arc<InputCounters>,
+) -> impl FnMut(&[f32])
+where
+    R: RbRef,
+    <R as RbRef>::Rb: RbWrite<f32>,
+{
+    let mut processor = ProcessInput::new(producer, chain, ab, counters);
+    move |data: &[f32]| processor.process(data)
+}
+
+/// What [`create_input_processing_fn`] processes with, which the pipeline keeps hold of itself so
+/// that it can be reset.
+struct ProcessInput<R>
+where
+    R: RbRef,
+    <R as RbRef>::Rb: RbWrite<f32>,
+{
+    producer: Producer<f32, R>,
+    chain: InputChain,
+    ab: Option<AbTaps>,
+    counters: Arc<InputCounters>,
+    clip_detector: ClipDetector,
+    /// The fader's gain as last noted, which is never equal to anything at first.
+    fader: f32,
+}
+
+impl<R> ProcessInput<R>
+where
+    R: RbRef,
+    <R as RbRef>::Rb: RbWrite<f32>,
+{
+    fn new(
+        producer: Producer<f32, R>,
+        chain: InputChain,
+        ab: Option<AbTaps>,
+        counters: Arc<InputCounters>,
+    ) -> Self {
+        ProcessInput {
+            clip_detector: ClipDetector::new(chain.channels()),
+            producer,
+            chain,
+            ab,
+            counters,
+            fader: f32::NAN,
+        }
+    }
+
+    fn process(&mut self, data: &[f32]) {
+        let ProcessInput {
+            producer,
+            chain,
+            ab,
+            counters,
+            clip_detector,
+            fader,
+        } = self;
+        let channels = chain.channels();
+        counters
+            .frames
+            .fetch_add((data.len() / channels) as u64, Ordering::Relaxed);
+        counters
+            .peak
+            .fetch_max(mix::peak(data).to_bits(), Ordering::Relaxed);
+        let clipped = clip_detector.process(data);
+        if clipped > 0 {
+            counters
+                .clipped_samples
+                .fetch_add(clipped, Ordering::Relaxed);
+        }
+
+        let mut push_frames = |samples: &[f32]| {
+            let fits = producer.free_len() - producer.free_len() % channels;
+            let pushed = producer.push_slice(&samples[..samples.len().min(fits)]);
+            (samples.len() - pushed) / channels
+        };
+
+        let dropped = if chain.is_empty() && ab.is_none() {
+            push_frames(data)
+        } else {
+            // Keep whole frames together in every chunk.
+            let chunk_samples = SCRATCH_SAMPLES - SCRATCH_SAMPLES % channels;
+            let mut scratch = [0.0; SCRATCH_SAMPLES];
+            let mut dropped = 0;
+            for chunk in data.chunks(chunk_samples) {
+                let scratch = &mut scratch[..chunk.len()];
+                scratch.copy_from_slice(chunk);
+                match ab {
+                    Some(AbTaps {
+                        raw,
+                        processed,
+                        pre_fader: Some(gain),
+                    }) => {
+                        raw.write(scratch);
+                        // The gain stage picks up a new target at the start of the chunk too.
+                        let target = gain.target();
+                        if target != *fader {
+                            *fader = target;
+                            processed.fader(target);
+                        }
+                        chain.process_tapped(scratch, StageKind::Gain, &mut |samples| {
+                            processed.write(samples)
+                        });
+                    }
+                    Some(AbTaps { raw, processed, .. }) => {
+                        raw.write(scratch);
+                        chain.process(scratch);
+                        processed.write(scratch);
+                    }
+                    None => chain.process(scratch),
+                }
+                dropped += push_frames(scratch);
+            }
+            dropped
+        };
+        if dropped > 0 {
+            counters
+                .dropped_frames
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<R> InputHandler for ProcessInput<R>
+where
+    R: RbRef + Send,
+    <R as RbRef>::Rb: RbWrite<f32>,
+{
+    fn process(&mut self, data: &[f32]) {
+        ProcessInput::process(self, data)
+    }
+
+    fn reset(&mut self) {
+        self.chain.reset();
+        self.clip_detector = ClipDetector::new(self.chain.channels());
+        self.fader = f32::NAN;
+    }
+}
+
+/// Feeds each range of a device's `device_channels` channels to the input split from it, as if
+/// that input were a device with `channels` channels, repeating the range across them.
+struct SplitInput {
+    device_channels: usize,
+    channels: usize,
+    parts: Vec<(Range<usize>, Box<dyn InputHandler>)>,
+}
+
+impl InputHandler for SplitInput {
+    fn process(&mut self, data: &[f32]) {
+        let (device_channels, channels) = (self.device_channels, self.channels);
+        let chunk_frames = SCRATCH_SAMPLES / device_channels.max(channels);
+        let mut scratch = [0.0; SCRATCH_SAMPLES];
+        for chunk in data.chunks(chunk_frames * device_channels) {
+            let scratch = &mut scratch[..chunk.len() / device_channels * channels];
+            for (range, on_data) in &mut self.parts {
+                for (frame, source) in scratch
+                    .chunks_exact_mut(channels)
+                    .zip(chunk.chunks_exact(device_channels))
+                {
+                    let source = &source[range.clone()];
+                    for (channel, sample) in frame.iter_mut().enumerate() {
+                        *sample = source[channel % source.len()];
+                    }
+                }
+                on_data.process(scratch);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for (_, on_data) in &mut self.parts {
+            on_data.reset();
+        }
+    }
+}
+
+/// Where an input's signal is recorded from on either side of its chain.
+pub struct AbTaps {
+    pub raw: RecordTap,
+    pub processed: RecordTap,
+    /// Records the processed side before this gain rather than after the whole chain, noting its
+    /// changes on the tap instead.
+    pub pre_fader: Option<Arc<OverrideGain>>,
+}
+
+/// The files `--record-ab` writes for `input` in `dir`, raw first. Segments after the first get a
+/// number.
+pub fn ab_paths(dir: &Path, input: &str, segment: usize) -> (PathBuf, PathBuf) {
+    let stem = input
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let stem = match segment {
+        0 => stem,
+        segment => format!("{}-{}", stem, segment + 1),
+    };
+    (
+        dir.join(format!("{}-raw.wav", stem)),
+        dir.join(format!("{}-processed.wav", stem)),
+    )
+}
+
+/// `path`, numbered for segments after the first like the A/B recordings.
+fn segment_path(path: &Path, segment: usize) -> PathBuf {
+    if segment == 0 {
+        return path.to_owned();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, segment + 1, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, segment + 1),
+    };
+    path.with_file_name(name)
+}
+
+/// Sums whatever each input has buffered into the output, treating missing samples as silence.
+///
+/// Each input's `skip` is a number of samples to throw away before mixing it, which the control
+/// thread sets to line the inputs up before the output starts. Each input's `state` says whether
+/// its stream is running, and `prefills` how many samples to wait for once it's started again.
+/// With a `controller`, the inputs are shrunk or left to fill up together, as it decides. Inputs
+/// attached while running are mixed in by `attached` on the same terms. The mix is faded by
+/// `master`, identification beeps go on top of it, and the result is measured, for its peaks and
+/// its `loudness`, and fed to `output_taps`, the output's recording and replay buffer.
+#[allow(clippy::too_many_arguments)]
+fn create_output_mixing_fn(
+    mut consumers: Vec<HeapConsumer<f32>>,
+    skips: Vec<Arc<AtomicUsize>>,
+    states: Vec<Arc<AtomicU8>>,
+    prefills: Vec<usize>,
+    counters: Arc<OutputCounters>,
+    layout: MixLayout,
+    mut controller: Option<LatencyController>,
+    crossfade_frames: usize,
+    identify: Arc<IdentifyRequest>,
+    mut generator: IdentifyGenerator,
+    mut true_peak: TruePeakMeter,
+    mut loudness: LoudnessMeter,
+    mut output_taps: Vec<RecordTap>,
+    mut attached: AttachedMix,
+    mut master: GainStage,
+) -> impl FnMut(&mut [f32]) {
+    let same_layout = layout.input_channels == layout.output_channels
+        && layout.used_channels == layout.output_channels;
+    // The difference between what each input would have played and what it plays after a
+    // discard, over the crossfade.
+    let mut fades = consumers
+        .iter()
+        .map(|_| Vec::with_capacity(crossfade_frames * layout.input_channels))
+        .collect::<Vec<Vec<f32>>>();
+    move |data: &mut [f32]| {
+        counters.callbacks.fetch_add(1, Ordering::Relaxed);
+        let mut input_fell_behind = false;
+        // Unused channels stay silent from this alone.
+        data.fill(0.0);
+        let frames = data.len() / layout.output_channels;
+        for (consumer, skip) in consumers.iter_mut().zip(&skips) {
+            if skip.load(Ordering::Relaxed) > 0 {
+                consumer.skip(skip.swap(0, Ordering::Relaxed));
+            }
+        }
+        // Inputs that aren't running would only hold the others back.
+        let buffered = consumers
+            .iter()
+            .zip(&states)
+            .filter(|(_, state)| state.load(Ordering::Relaxed) == INPUT_RUNNING)
+            .map(|(consumer, _)| consumer.len() / layout.input_channels)
+            .min()
+            .unwrap_or(0);
+        counters.buffered_frames.store(buffered, Ordering::Relaxed);
+        let adjustment = match &mut controller {
+            Some(controller) => controller.next(buffered, frames),
+            None => Adjustment::Mix,
+        };
+
+        for (((consumer, fade), state), &prefill) in consumers
+            .iter_mut()
+            .zip(&mut fades)
+            .zip(&states)
+            .zip(&prefills)
+        {
+            fade.clear();
+            let wanted = frames * layout.input_channels;
+            match state.load(Ordering::Relaxed) {
+                INPUT_STOPPED => {
+                    consumer.skip(consumer.len());
+                    continue;
+                }
+                INPUT_RESTARTING => {
+                    consumer.skip(consumer.len());
+                    // Unless the control thread has stopped it again meanwhile.
+                    let _ = state.compare_exchange(
+                        INPUT_RESTARTING,
+                        INPUT_STARTING,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                    continue;
+                }
+                INPUT_STARTING => {
+                    if consumer.len() < prefill + wanted {
+                        continue;
+                    }
+                    state.store(INPUT_RUNNING, Ordering::Relaxed);
+                    // A fade from silence is a fade from the difference back to nothing.
+                    let faded = crossfade_frames.min(frames) * layout.input_channels;
+                    fade.extend(consumer.iter().take(faded).map(|sample| -sample));
+                }
+                _ => match adjustment {
+                    Adjustment::Wait => continue,
+                    Adjustment::Discard(discard) => {
+                        let faded =
+                            crossfade_frames.min(frames).min(discard) * layout.input_channels;
+                        fade.extend(consumer.pop_iter().take(faded));
+                        consumer.skip(discard * layout.input_channels - faded);
+                        for (before, after) in fade.iter_mut().zip(consumer.iter()) {
+                            *before -= after;
+                        }
+                    }
+                    Adjustment::Mix => {}
+                },
+            }
+
+            if consumer.len() < wanted {
+                input_fell_behind = true;
+            }
+            if same_layout {
+                let (first, second) = consumer.as_slices();
+                let (start, end) = data.split_at_mut(first.len().min(data.len()));
+                mix::add(start, first);
+                mix::add(end, second);
+                let mixed = data.len().min(first.len() + second.len());
+                consumer.skip(mixed);
+            } else {
+                for (index, input_sample) in consumer.pop_iter().take(wanted).enumerate() {
+                    if let Some(output_index) = layout.output_index(index) {
+                        data[output_index] += input_sample;
+                    }
+                }
+            }
+
+            // Turns what was just mixed in into a fade from the audio before the discard.
+            let fade_frames = fade.len() / layout.input_channels;
+            for (index, difference) in fade.iter().enumerate() {
+                if let Some(output_index) = layout.output_index(index) {
+                    let frame = index / layout.input_channels;
+                    let before = 1.0 - (frame as f32 + 0.5) / fade_frames as f32;
+                    data[output_index] += difference * before;
+                }
+            }
+        }
+        input_fell_behind |= attached.mix(data, &layout, adjustment);
+        master.process(data, layout.output_channels);
+        generator.process(data, layout.output_channels, &identify);
+        let peak = mix::peak(data);
+        counters.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        for tap in &mut output_taps {
+            tap.write(data);
+        }
+        let highest = true_peak.process(data, layout.output_channels);
+        counters
+            .true_peak
+            .fetch_max(highest.to_bits(), Ordering::Relaxed);
+        if loudness.process(data, layout.output_channels) {
+            counters
+                .loudness
+                .store(loudness.mean_square().to_bits(), Ordering::Relaxed);
+        }
+        if input_fell_behind {
+            counters.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How the inputs' channels map onto the output's: each input channel goes to the output channel
+/// with the same index, as long as it is one of the first `used_channels`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MixLayout {
+    pub(crate) input_channels: usize,
+    pub(crate) output_channels: usize,
+    pub(crate) used_channels: usize,
+}
+
+impl MixLayout {
+    /// Where the sample at `index` of interleaved input goes in the output, if anywhere.
+    pub(crate) fn output_index(&self, index: usize) -> Option<usize> {
+        let channel = index % self.input_channels;
+        (channel < self.input_channels.min(self.used_channels))
+            .then(|| index / self.input_channels * self.output_channels + channel)
+    }
+}
+
+/// Explains how `wanted` output channels fit on a device with only `available`, as
+/// [`remap_channels`] fits them.
+fn print_compacted_channels(device: &str, wanted: u16, available: u16) {
+    log::warn!(
+        "\"{}\" only has {} of the {} channels asked for, so they are remapped:",
+        device,
+        available,
+        wanted
+    );
+    let remap = remap_channels(wanted, available);
+    for pair in &remap.kept {
+        log::warn!(
+            "  {} -> {}",
+            describe_channels(pair),
+            describe_channels(pair)
+        );
+    }
+    if let Some(channel) = remap.split {
+        let channel = channel..channel + 1;
+        log::warn!(
+            "  {} -> {}, without its other half",
+            describe_channels(&channel),
+            describe_channels(&channel)
+        );
+    }
+    log::warn!("  {} -> dropped", describe_channels(&remap.dropped));
+}
+
+/// Describes a range of channels counting from 1, like `channel 3` or `channels 3-4`.
+fn describe_channels(range: &Range<usize>) -> String {
+    if range.len() == 1 {
+        format!("channel {}", range.start + 1)
+    } else {
+        format!("channels {}-{}", range.start + 1, range.end)
+    }
+}
+
+pub fn err_fn(err: StreamError) {
+    log::error!("an error occurred on stream: {}", err);
+}
+
+/// What an input's chain turned out to be once the pipeline was built.
+#[derive(Clone, Debug)]
+pub struct ChainSummary {
+    pub input: String,
+    pub stages: String,
+    pub latency_frames: usize,
+}
+
+/// An input device's stream, along with the inputs it feeds.
+struct InputStream {
+    /// `None` while stopped.
+    stream: Option<Box<dyn Stream>>,
+    device: String,
+    config: StreamConfig,
+    members: Vec<usize>,
+    shared: Arc<StreamShared>,
+}
+
+/// What an input device's streams share with the pipeline, which outlives the stream when it's
+/// stopped.
+#[derive(Default)]
+struct StreamShared {
+    /// When the first stream delivered its first callback.
+    started: OnceLock<Instant>,
+    /// Whether the stream has said its device went away.
+    disconnected: AtomicBool,
+    /// The stream's callback, handed back once the stream drops it, so that a new stream can carry
+    /// on with the same chains and ring buffers.
+    reclaimed: Mutex<Option<Box<dyn InputHandler>>>,
+}
+
+/// Holds a stream's callback, and hands it back to `shared` when the stream is dropped.
+struct Reclaim {
+    on_data: Option<Box<dyn InputHandler>>,
+    shared: Arc<StreamShared>,
+}
+
+impl Drop for Reclaim {
+    fn drop(&mut self) {
+        *self.shared.reclaimed.lock().unwrap() = self.on_data.take();
+    }
+}
+
+/// Builds a stream on input device `input`, with `on_data` handed back through `shared` once the
+/// stream is dropped.
+fn build_input_stream(
+    input: &dyn InputSource,
+    config: &StreamConfig,
+    on_data: Box<dyn InputHandler>,
+    shared: &Arc<StreamShared>,
+    mut raise: RaiseOnce,
+) -> Result<Box<dyn Stream>, PipelineError> {
+    let mut reclaim = Reclaim {
+        on_data: Some(on_data),
+        shared: Arc::clone(shared),
+    };
+    let on_error = {
+        let shared = Arc::clone(shared);
+        move |err: StreamError| {
+            if matches!(err, StreamError::DeviceNotAvailable) {
+                shared.disconnected.store(true, Ordering::Relaxed);
+            }
+            err_fn(err)
+        }
+    };
+    input
+        .build_input_stream(
+            config,
+            Box::new(move |data: &[f32]| {
+                raise.poll();
+                memory::in_callback(|| {
+                    reclaim.shared.started.get_or_init(Instant::now);
+                    if let Some(on_data) = &mut reclaim.on_data {
+                        on_data.process(data)
+                    }
+                })
+            }),
+            Box::new(on_error),
+        )
+        .map_err(stream_build_error(input.name()))
+}
+
+/// What a configured input is doing, as `status` shows it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputStatus {
+    Playing,
+    Muted,
+    /// Its stream was stopped with [`Pipeline::stop_input`].
+    Stopped,
+    /// Its stream said the device went away.
+    Disconnected,
+}
+
+impl fmt::Display for InputStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputStatus::Playing => "playing",
+            InputStatus::Muted => "muted",
+            InputStatus::Stopped => "stopped",
+            InputStatus::Disconnected => "disconnected",
+        })
+    }
+}
+
+/// An input attached while running, which the output mixes in on top of the configured ones.
+struct AttachedInput {
+    id: u64,
+    name: String,
+    stream: Box<dyn Stream>,
+    chain: ChainSummary,
+    ring_buffer: RingBufferSize,
+    /// As f32 bits, which the output callback ramps towards.
+    pan: Arc<AtomicU32>,
+}
+
+/// The built streams, which run for as long as this is kept around.
+pub struct Pipeline {
+    input_streams: Vec<InputStream>,
+    output_stream: Box<dyn Stream>,
+    output_name: String,
+    /// Samples for the output to skip from each input, in the order the inputs were configured.
+    skips: Vec<Arc<AtomicUsize>>,
+    /// Whether each input's stream is running, as one of the `INPUT_` states.
+    input_states: Vec<Arc<AtomicU8>>,
+    /// How many frames each input was ahead of the last one to start, and skipped to line up.
+    start_offsets: Vec<(String, usize)>,
+    channels: usize,
+    output_channels: usize,
+    identify: Arc<IdentifyRequest>,
+    /// The level and mute of the whole mix.
+    master_gain: Arc<OverrideGain>,
+    override_gains: Vec<(String, Arc<OverrideGain>)>,
+    reverbs: Vec<(String, Arc<ReverbSend>)>,
+    chains: Vec<ChainSummary>,
+    sample_rate: u32,
+    counters: Arc<OutputCounters>,
+    stats: Stats,
+    /// What's needed to attach inputs like the configured ones.
+    attacher: Attacher,
+    stream_config: StreamConfig,
+    /// What the pipeline was built from, and what came of it, for describing it.
+    config: PipelineConfig,
+    ring_buffers: Vec<RingBufferSize>,
+    used_output_channels: u16,
+    planar: bool,
+    rt_priority: bool,
+    ringbuf_ms: Option<f32>,
+    max_chain_latency: usize,
+    attached: Vec<AttachedInput>,
+    next_attached_id: u64,
+    allocations: Allocations,
+    /// Whether the output has been started, so the pipeline counts as running.
+    running: bool,
+    /// How raising each stream's thread went, until it's been reported.
+    raise_reports: Vec<Arc<RaiseReport>>,
+    /// Declared after the streams so they are dropped first, and everything they tapped has been
+    /// queued by the time the recorder finishes its files.
+    recorder: Option<Recorder>,
+}
+
+impl Pipeline {
+    pub fn build(
+        provider: &dyn DeviceProvider,
+        config: &PipelineConfig,
+    ) -> Result<Self, PipelineError> {
+        Pipeline::build_continuing(provider, config, &mut None)
+    }
+
+    /// Builds the pipeline like [`Pipeline::build`], carrying on the recordings of `recorder`,
+    /// from an earlier pipeline's [`Pipeline::into_recorder`], rather than starting new files if
+    /// `record_continue` is set. The recorder is only taken once the pipeline has been built, so
+    /// it can be tried again on a failure.
+    pub fn build_continuing(
+        provider: &dyn DeviceProvider,
+        config: &PipelineConfig,
+        recorder: &mut Option<Recorder>,
+    ) -> Result<Self, PipelineError> {
+        if config.inputs.is_empty() {
+            return Err(PipelineError::invalid("at least one input is needed"));
+        }
+
+        for (i, input) in config.inputs.iter().enumerate() {
+            if config.inputs[..i]
+                .iter()
+                .any(|other| other.name == input.name)
+            {
+                return Err(PipelineError::invalid(format!(
+                    "there are two inputs called \"{}\"",
+                    input.name
+                )));
+            }
+        }
+
+        // Inputs split from the same device share its stream, so group them by device.
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (i, input) in config.inputs.iter().enumerate() {
+            match groups
+                .iter_mut()
+                .find(|(device, _)| *device == input.device)
+            {
+                Some((_, members)) => members.push(i),
+                None => groups.push((&input.device, vec![i])),
+            }
+        }
+        for (device, members) in &groups {
+            let split = members
+                .iter()
+                .filter(|&&i| config.inputs[i].channels.is_some())
+                .count();
+            if split != 0 && split != members.len() {
+                return Err(PipelineError::invalid(format!(
+                    "\"{}\" can't be used whole and split at the same time",
+                    device
+                )));
+            }
+            if split == 0 && members.len() > 1 {
+                return Err(PipelineError::invalid(format!(
+                    "\"{}\" is used as an input more than once",
+                    device
+                )));
+            }
+        }
+
+        // Find devices.
+        let inputs = groups
+            .iter()
+            .map(|(device, _)| find_input(provider, device))
+            .collect::<Result<Vec<_>, _>>()?;
+        let output = find_output(provider, &config.output)?;
+        for input in &inputs {
+            log::info!("Using input device: \"{}\"", input.name());
+        }
+        log::info!("Using output device: \"{}\"", output.name());
+
+        // We'll try and use the same configuration between streams to keep it simple.
+        let mut stream_config = inputs[0]
+            .default_config()
+            .map_err(query_error(inputs[0].name()))?;
+        // Except that split devices are opened with all of their channels, so that any of them can
+        // be taken.
+        let device_channels = groups
+            .iter()
+            .zip(&inputs)
+            .map(|((_, members), input)| {
+                if config.inputs[members[0]].channels.is_some() {
+                    Ok(input
+                        .default_config()
+                        .map_err(query_error(input.name()))?
+                        .channels)
+                } else {
+                    Ok(stream_config.channels)
+                }
+            })
+            .collect::<Result<Vec<_>, PipelineError>>()?;
+
+        // The output can have its own channel count, of which only `used_output_channels` are
+        // mixed into when the device insists on opening all of them.
+        let output_ranges = output
+            .supported_configs()
+            .map_err(query_error(output.name()))?;
+        let (output_channels, used_output_channels) = match config.output_channels {
+            None => (stream_config.channels, stream_config.channels),
+            Some(channels) if output_ranges.iter().any(|range| range.channels == channels) => {
+                (channels, channels)
+            }
+            Some(channels) => {
+                let full = output
+                    .default_config()
+                    .map_err(query_error(output.name()))?
+                    .channels;
+                if channels > full && config.strict_routing {
+                    return Err(PipelineError::ConfigNotSupported {
+                        device: output.name().to_owned(),
+                        requested: format!("{} channels", channels),
+                        supported: output_ranges,
+                    });
+                }
+                if channels > full {
+                    print_compacted_channels(output.name(), channels, full);
+                    (full, full)
+                } else {
+                    log::warn!(
+                        "\"{}\" can't be opened with {} channels, so it is opened with all {} and \
+                     the rest are left silent",
+                        output.name(),
+                        channels,
+                        full
+                    );
+                    (full, channels)
+                }
+            }
+        };
+
+        // The rate each input device is opened at, which is the pipeline's unless it's resampled.
+        let mut device_rates = vec![stream_config.sample_rate.0; inputs.len()];
+        if config.negotiate {
+            let mut devices = Vec::with_capacity(inputs.len() + 1);
+            for (input, &channels) in inputs.iter().zip(&device_channels) {
+                devices.push(DeviceCapabilities {
+                    name: input.name().to_owned(),
+                    channels,
+                    default_sample_rate: input
+                        .default_config()
+                        .map_err(query_error(input.name()))?
+                        .sample_rate
+                        .0,
+                    ranges: input
+                        .supported_configs()
+                        .map_err(query_error(input.name()))?,
+                });
+            }
+            devices.push(DeviceCapabilities {
+                name: output.name().to_owned(),
+                channels: output_channels,
+                default_sample_rate: output
+                    .default_config()
+                    .map_err(query_error(output.name()))?
+                    .sample_rate
+                    .0,
+                ranges: output_ranges,
+            });
+
+            // Denoising only works at one rate, so go for it when every device can.
+            #[cfg(feature = "denoise")]
+            let preferred = config
+                .inputs
+                .iter()
+                .any(|input| input.denoise)
+                .then_some(denoise::SAMPLE_RATE);
+            #[cfg(not(feature = "denoise"))]
+            let preferred = None;
+
+            let plan = negotiate::plan(&devices, preferred);
+            match &plan.strategy {
+                Strategy::Common { sample_rate } => {
+                    log::info!("Negotiated stream config: {}.", plan);
+                    stream_config.sample_rate = cpal::SampleRate(*sample_rate);
+                }
+                Strategy::PerDevice { sample_rates } => {
+                    // The output runs at its own rate, and every input not at it is resampled.
+                    let (&output_rate, input_rates) = sample_rates.split_last().unwrap();
+                    let resampleable = input_rates
+                        .iter()
+                        .all(|&rate| rate == output_rate || resample::supported(rate, output_rate));
+                    if !resampleable {
+                        return Err(PipelineError::NoCommonSampleRate { plan });
+                    }
+                    log::info!("Negotiated stream config: {}.", plan);
+                    stream_config.sample_rate = cpal::SampleRate(output_rate);
+                    device_rates = input_rates.to_vec();
+                }
+            }
+        }
+
+        for (((device, members), input), &channels) in
+            groups.iter().zip(&inputs).zip(&device_channels)
+        {
+            for (j, &i) in members.iter().enumerate() {
+                let Some(range) = &config.inputs[i].channels else {
+                    continue;
+                };
+                let requested = if channels < 2 {
+                    Some("being split, since it's mono".to_owned())
+                } else if range.end > channels as usize {
+                    Some(format!(
+                        "{} for \"{}\"",
+                        describe_channels(range),
+                        config.inputs[i].name
+                    ))
+                } else {
+                    None
+                };
+                if let Some(requested) = requested {
+                    return Err(PipelineError::ConfigNotSupported {
+                        device: device.to_string(),
+                        requested,
+                        supported: input.supported_configs().map_err(query_error(device))?,
+                    });
+                }
+                for &other in &members[..j] {
+                    let other = &config.inputs[other];
+                    let overlap = other
+                        .channels
+                        .as_ref()
+                        .is_some_and(|other| other.start < range.end && range.start < other.end);
+                    if overlap {
+                        return Err(PipelineError::invalid(format!(
+                            "\"{}\" and \"{}\" both take channels of \"{}\" in {} and {}",
+                            other.name,
+                            config.inputs[i].name,
+                            device,
+                            describe_channels(other.channels.as_ref().unwrap()),
+                            describe_channels(range)
+                        )));
+                    }
+                }
+            }
+        }
+
+        let mut allocations = Allocations::default();
+        let mut resamplers = groups
+            .iter()
+            .zip(&device_rates)
+            .zip(&device_channels)
+            .map(|(((device, _), &rate), &channels)| {
+                let resampler = Resampler::new(
+                    rate,
+                    stream_config.sample_rate.0,
+                    channels as usize,
+                    config.resample_quality,
+                )?;
+                log::info!(
+                    "Resampling \"{}\": {}, {} frames of latency.",
+                    device,
+                    resampler.describe(),
+                    resampler.latency_frames()
+                );
+                allocations.register(
+                    format!("resampler for \"{}\"", device),
+                    resampler.memory_bytes()
+                        + resampling_scratch(&resampler).1 * std::mem::size_of::<f32>(),
+                );
+                Some(resampler)
+            })
+            .collect::<Vec<_>>();
+        // How much later each input's audio comes out of its device's resampler.
+        let mut resample_latency = vec![0; config.inputs.len()];
+        for ((_, members), resampler) in groups.iter().zip(&resamplers) {
+            for &i in members {
+                resample_latency[i] = resampler.as_ref().map_or(0, Resampler::latency_frames);
+            }
+        }
+
+        let mut stats = Stats::default();
+        let mut chains = Vec::with_capacity(inputs.len());
+        let mut override_gains = Vec::with_capacity(inputs.len());
+        let mut reverbs = Vec::new();
+        for input in &config.inputs {
+            let (chain, override_gain, send) = build_chain(
+                input,
+                config.denoise_mix,
+                config.planar,
+                &stream_config,
+                &mut stats,
+            )?;
+            if chain.memory_bytes() > 0 {
+                allocations.register(
+                    format!("chain for \"{}\"", input.name),
+                    chain.memory_bytes(),
+                );
+            }
+            chains.push(chain);
+            override_gains.push((input.name.clone(), override_gain));
+            if let Some(send) = send {
+                reverbs.push((input.name.clone(), send));
+            }
+        }
+        let summaries = config
+            .inputs
+            .iter()
+            .zip(&chains)
+            .map(|(input, chain)| ChainSummary {
+                input: input.name.clone(),
+                stages: chain.describe(),
+                latency_frames: chain.latency_frames(),
+            })
+            .collect();
+
+        // Delay every input by however much more processing the others do, so they stay aligned.
+        let max_chain_latency = chains
+            .iter()
+            .zip(&resample_latency)
+            .map(|(chain, resampling)| chain.latency_frames() + resampling)
+            .max()
+            .unwrap_or(0);
+
+        let mut producers = Vec::with_capacity(inputs.len());
+        let mut ring_buffers = Vec::with_capacity(inputs.len());
+        let mut consumers = Vec::with_capacity(inputs.len());
+        for ((input, chain), resampling) in config.inputs.iter().zip(&chains).zip(&resample_latency)
+        {
+            let size = RingBufferSize::new(
+                stream_config.sample_rate.0,
+                stream_config.channels,
+                buffer_frames(&stream_config.buffer_size),
+                config
+                    .adaptive_latency
+                    .map_or(config.latency_ms, |adaptive| adaptive.max_ms),
+                max_chain_latency - chain.latency_frames() - resampling
+                    + ms_to_frames(
+                        input.delay.as_secs_f32() * 1_000.0,
+                        stream_config.sample_rate.0,
+                    ),
+                config.ringbuf_ms,
+            )?;
+            log::info!(
+                "Ring buffer for \"{}\": {} frames x {} channels = {} samples, prefilled with {} \
+                 frames of latency.",
+                input.name,
+                size.capacity_frames,
+                size.channels,
+                size.capacity_samples(),
+                size.latency_frames,
+            );
+
+            // The buffer to share samples
+            let (mut producer, consumer) = HeapRb::<f32>::new(size.capacity_samples()).split();
+            allocations.register_samples(
+                format!("ring buffer for \"{}\"", input.name),
+                size.capacity_samples(),
+            );
+
+            // Fill the buffer with silence equal to the latency delay.
+            for _ in 0..size.latency_samples() {
+                // The ring buffer has been sized so that the latency always fits.
+                producer.push(0.0).unwrap();
+            }
+
+            producers.push(producer);
+            ring_buffers.push(size);
+            consumers.push(consumer);
+        }
+
+        // The raw tap comes before the chain's latency, so it starts with that much silence to
+        // line up with the processed tap. Only the first segment gets the pre-roll, since the
+        // later ones carry on from it.
+        let preroll = if config.segment == 0 {
+            ms_to_frames(
+                config.preroll.as_secs_f32() * 1_000.0,
+                stream_config.sample_rate.0,
+            )
+        } else {
+            0
+        };
+        let mut tracks = Vec::new();
+        for (input, chain) in config.inputs.iter().zip(&chains) {
+            if input.record_ab {
+                let (raw, processed) = ab_paths(&config.record_dir, &input.name, config.segment);
+                let track = |path, leading_silence_frames, fader| TrackSpec {
+                    path,
+                    channels: stream_config.channels,
+                    sample_rate: stream_config.sample_rate.0,
+                    leading_silence_frames: preroll + leading_silence_frames,
+                    sync: config.record_sync,
+                    mirror: None,
+                    fader,
+                    ceiling: None,
+                };
+                if config.stems_pre_fader {
+                    let latency = chain.latency_frames_before(StageKind::Gain);
+                    tracks.push(track(raw, latency, None));
+                    tracks.push(track(processed, 0, Some(input.name.clone())));
+                } else {
+                    tracks.push(track(raw, chain.latency_frames(), None));
+                    tracks.push(track(processed, 0, None));
+                }
+            }
+        }
+        // The output's own recording goes last.
+        if let Some(path) = &config.record_output {
+            tracks.push(TrackSpec {
+                path: segment_path(path, config.segment),
+                channels: output_channels,
+                sample_rate: stream_config.sample_rate.0,
+                leading_silence_frames: preroll,
+                sync: config.record_sync,
+                mirror: config.record_mirror.as_ref().map(|mirror| MirrorSpec {
+                    path: segment_path(&mirror.path, config.segment),
+                    sync: mirror.sync,
+                }),
+                fader: None,
+                ceiling: config.record_ceiling,
+            });
+        }
+        let replay = config.replay_buffer.map(|duration| ReplaySpec {
+            channels: output_channels,
+            sample_rate: stream_config.sample_rate.0,
+            duration,
+        });
+        let recording_error = |source: RecorderError| PipelineError::Recording {
+            source: source.into(),
+        };
+        // Whether the recorder being carried on ends up in this pipeline.
+        let mut continuing = false;
+        let (new_recorder, mut taps, replay_tap) = match (recorder.as_mut(), config.record_continue)
+        {
+            (Some(recorder), Some(on_change)) => {
+                let (taps, replay_tap) = recorder
+                    .continue_with(
+                        tracks,
+                        replay,
+                        on_change,
+                        config.resample_quality,
+                        &mut allocations,
+                    )
+                    .map_err(recording_error)?;
+                continuing = true;
+                (None, taps.into_iter(), replay_tap)
+            }
+            _ if tracks.is_empty() && replay.is_none() => (None, Vec::new().into_iter(), None),
+            _ => {
+                let (recorder, taps, replay_tap) = Recorder::start(
+                    tracks,
+                    MarkerOptions {
+                        sidecar: config.marker_sidecar.clone(),
+                        new_session: config.segment == 0,
+                        cues: config.cue_markers,
+                    },
+                    replay,
+                    &mut allocations,
+                )
+                .map_err(recording_error)?;
+                (Some(recorder), taps.into_iter(), replay_tap)
+            }
+        };
+        let ab_taps = config
+            .inputs
+            .iter()
+            .zip(&override_gains)
+            .map(|(input, (_, gain))| {
+                input.record_ab.then(|| AbTaps {
+                    // Both were pushed for every input recording A/B.
+                    raw: taps.next().unwrap(),
+                    processed: taps.next().unwrap(),
+                    pre_fader: config.stems_pre_fader.then(|| Arc::clone(gain)),
+                })
+            })
+            .collect::<Vec<_>>();
+        let output_taps = taps.chain(replay_tap).collect();
+
+        // Build streams.
+        let counters = Arc::new(OutputCounters::default());
+        log::info!(
+            "Attempting to build all streams with f32 samples and `{:?}`.",
+            stream_config
+        );
+        let mut callbacks = producers
+            .into_iter()
+            .zip(chains)
+            .zip(ab_taps)
+            .zip(&config.inputs)
+            .map(|(((producer, chain), ab), input)| {
+                let counters = Arc::new(InputCounters::default());
+                stats.add_input(
+                    &input.name,
+                    Arc::clone(&counters),
+                    stream_config.sample_rate.0,
+                );
+                let callback: Box<dyn InputHandler> =
+                    Box::new(ProcessInput::new(producer, chain, ab, counters));
+                Some(callback)
+            })
+            .collect::<Vec<_>>();
+        let mut raise_reports = Vec::new();
+        let input_streams = groups
+            .iter()
+            .zip(&inputs)
+            .zip(&device_channels)
+            .zip(&device_rates)
+            .zip(&mut resamplers)
+            .map(
+                |(((((device, members), input), &channels), &rate), resampler)| {
+                    let mut parts = members
+                        .iter()
+                        // Every input belongs to exactly one group.
+                        .map(|&i| {
+                            (
+                                config.inputs[i].channels.clone(),
+                                callbacks[i].take().unwrap(),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    let on_data = match parts.as_slice() {
+                        [(None, _)] => parts.pop().unwrap().1,
+                        _ => Box::new(SplitInput {
+                            device_channels: channels as usize,
+                            channels: stream_config.channels as usize,
+                            parts: parts
+                                .into_iter()
+                                .map(|(range, callback)| (range.unwrap(), callback))
+                                .collect(),
+                        }),
+                    };
+                    let device_config = StreamConfig {
+                        channels,
+                        sample_rate: cpal::SampleRate(rate),
+                        ..stream_config.clone()
+                    };
+                    let on_data = match resampler.take() {
+                        Some(resampler) => {
+                            stats.cpu.push((
+                                device.to_string(),
+                                "Resampling",
+                                device_config.clone(),
+                                resampler.cpu_usage(),
+                            ));
+                            Box::new(ResampleInput::new(resampler, on_data))
+                        }
+                        None => on_data,
+                    };
+                    let on_data = Box::new(AlignInputFrames::new(
+                        channels as usize,
+                        stats.add_device(device),
+                        on_data,
+                    ));
+                    let shared = Arc::new(StreamShared::default());
+                    let raise = RaiseOnce::new(config.rt_priority, input.name(), &device_config);
+                    raise_reports.extend(raise.report());
+                    let stream =
+                        build_input_stream(&**input, &device_config, on_data, &shared, raise)?;
+                    Ok(InputStream {
+                        stream: Some(stream),
+                        device: device.to_string(),
+                        config: device_config,
+                        members: members.clone(),
+                        shared,
+                    })
+                },
+            )
+            .collect::<Result<Vec<_>, PipelineError>>()?;
+        let skips = (0..consumers.len())
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect::<Vec<_>>();
+        let input_states = (0..consumers.len())
+            .map(|_| Arc::new(AtomicU8::new(INPUT_RUNNING)))
+            .collect::<Vec<_>>();
+        let output_config = StreamConfig {
+            channels: output_channels,
+            ..stream_config.clone()
+        };
+        if output_config.channels != stream_config.channels {
+            log::info!(
+                "Opening the output with {} channels, {} of them mixed into.",
+                output_config.channels,
+                used_output_channels
+            );
+        }
+        let identify = Arc::new(IdentifyRequest::default());
+        let (attacher, attached_mix) = attach::channel(stream_config.sample_rate.0);
+        let master = GainStage::new(&output_config, false);
+        let master_gain = master.override_gain();
+        let crossfade_frames = ms_to_frames(latency::CROSSFADE_MS, stream_config.sample_rate.0);
+        allocations.register_samples(
+            "output crossfades",
+            consumers.len() * crossfade_frames * stream_config.channels as usize,
+        );
+        let mut raise = RaiseOnce::new(config.rt_priority, output.name(), &output_config);
+        raise_reports.extend(raise.report());
+        let mix = create_output_mixing_fn(
+            consumers,
+            skips.clone(),
+            input_states.clone(),
+            ring_buffers
+                .iter()
+                .map(RingBufferSize::latency_samples)
+                .collect(),
+            Arc::clone(&counters),
+            MixLayout {
+                input_channels: stream_config.channels as usize,
+                output_channels: output_channels as usize,
+                used_channels: used_output_channels as usize,
+            },
+            config
+                .adaptive_latency
+                .map(|adaptive| LatencyController::new(adaptive, stream_config.sample_rate.0)),
+            crossfade_frames,
+            Arc::clone(&identify),
+            IdentifyGenerator::new(output_channels as usize, stream_config.sample_rate.0),
+            TruePeakMeter::new(output_channels as usize),
+            LoudnessMeter::new(output_channels as usize, stream_config.sample_rate.0),
+            output_taps,
+            attached_mix,
+            master,
+        );
+        let mut mix = align_output_frames(
+            output_channels as usize,
+            stats.add_device(output.name()),
+            mix,
+        );
+        let output_stream = output
+            .build_output_stream(
+                &output_config,
+                Box::new(move |data: &mut [f32]| {
+                    raise.poll();
+                    memory::in_callback(|| mix(data))
+                }),
+                Box::new(err_fn),
+            )
+            .map_err(output_build_error(output.name()))?;
+        // Some hosts start streams as soon as they're built, but nothing should run until the
+        // start is sequenced below. Hosts that can't pause haven't started them either.
+        for stream in input_streams
+            .iter()
+            .filter_map(|input| input.stream.as_ref())
+        {
+            let _ = stream.pause();
+        }
+        let _ = output_stream.pause();
+        log::info!("Successfully built streams.");
+
+        Ok(Pipeline {
+            input_streams,
+            output_stream,
+            output_name: output.name().to_owned(),
+            skips,
+            input_states,
+            start_offsets: Vec::new(),
+            channels: stream_config.channels as usize,
+            output_channels: output_channels as usize,
+            identify,
+            master_gain,
+            override_gains,
+            reverbs,
+            chains: summaries,
+            sample_rate: stream_config.sample_rate.0,
+            counters,
+            stats,
+            attacher,
+            stream_config,
+            config: config.clone(),
+            ring_buffers,
+            used_output_channels,
+            planar: config.planar,
+            rt_priority: config.rt_priority,
+            ringbuf_ms: config.ringbuf_ms,
+            max_chain_latency,
+            attached: Vec::new(),
+            next_attached_id: 0,
+            allocations,
+            running: false,
+            raise_reports,
+            recorder: if continuing {
+                recorder.take()
+            } else {
+                new_recorder
+            },
+        })
+    }
+
+    /// Builds the pipeline and starts it, which is all it takes to get from a config to audio
+    /// playing.
+    pub fn from_config(
+        provider: &dyn DeviceProvider,
+        config: &PipelineConfig,
+    ) -> Result<Self, PipelineError> {
+        Pipeline::from_config_continuing(provider, config, &mut None)
+    }
+
+    /// Like [`Pipeline::from_config`], carrying on the recordings of `recorder` like
+    /// [`Pipeline::build_continuing`]. If the pipeline fails to start, its recorder is handed
+    /// back in `recorder` to be tried again with.
+    pub fn from_config_continuing(
+        provider: &dyn DeviceProvider,
+        config: &PipelineConfig,
+        recorder: &mut Option<Recorder>,
+    ) -> Result<Self, PipelineError> {
+        let mut pipeline = Pipeline::build_continuing(provider, config, recorder)?;
+        if let Err(err) = pipeline.play() {
+            if config.record_continue.is_some() {
+                *recorder = pipeline.into_recorder();
+            }
+            return Err(err);
+        }
+        Ok(pipeline)
+    }
+
+    /// Starts the input streams and then the output stream.
+    pub fn play(&mut self) -> Result<(), PipelineError> {
+        self.start_inputs()?;
+        let deadline = Instant::now() + START_TIMEOUT;
+        while !self.inputs_started() {
+            if Instant::now() >= deadline {
+                return Err(PipelineError::StartTimeout {
+                    devices: self
+                        .input_streams
+                        .iter()
+                        .filter(|input| input.shared.started.get().is_none())
+                        .map(|input| input.device.clone())
+                        .collect(),
+                    timeout: START_TIMEOUT,
+                });
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        log::info!("Every input has delivered its first callback.");
+        self.start_output()
+    }
+
+    /// Starts the input streams, which fill their prefilled ring buffers until the output starts.
+    pub fn start_inputs(&self) -> Result<(), PipelineError> {
+        for input in &self.input_streams {
+            let Some(stream) = &input.stream else {
+                continue;
+            };
+            log::info!("Starting input stream for \"{}\".", input.device);
+            stream.play().map_err(stream_start_error(&input.device))?;
+        }
+        Ok(())
+    }
+
+    /// Whether every input stream has delivered its first callback.
+    pub fn inputs_started(&self) -> bool {
+        self.input_streams
+            .iter()
+            .all(|input| input.shared.started.get().is_some())
+    }
+
+    /// Lines the inputs up by when each delivered its first callback, by having the output skip
+    /// whatever the earlier ones captured before the last one started, and then starts the output.
+    pub fn start_output(&mut self) -> Result<(), PipelineError> {
+        let latest = self
+            .input_streams
+            .iter()
+            .filter_map(|input| input.shared.started.get().copied())
+            .max();
+        self.start_offsets = vec![(String::new(), 0); self.skips.len()];
+        for input in &self.input_streams {
+            let ahead = match (input.shared.started.get(), latest) {
+                (Some(started), Some(latest)) => latest.duration_since(*started),
+                _ => {
+                    log::warn!(
+                        "\"{}\" hasn't delivered any audio yet, so it can't be lined up",
+                        input.device
+                    );
+                    Duration::ZERO
+                }
+            };
+            let frames = (ahead.as_secs_f64() * self.sample_rate as f64).round() as usize;
+            for &i in &input.members {
+                self.skips[i].store(frames * self.channels, Ordering::Relaxed);
+                self.start_offsets[i] = (self.override_gains[i].0.clone(), frames);
+            }
+        }
+        let skew = self
+            .start_offsets
+            .iter()
+            .map(|(_, frames)| *frames)
+            .max()
+            .unwrap_or(0);
+        log::info!(
+            "Inputs started within {} frames ({:.1} ms) of each other.",
+            skew,
+            skew as f32 * 1_000.0 / self.sample_rate as f32
+        );
+        for (input, frames) in &self.start_offsets {
+            if *frames > 0 {
+                log::info!(
+                    "Skipping the first {} frames of \"{}\" to line it up.",
+                    frames,
+                    input
+                );
+            }
+        }
+
+        log::info!("Starting output stream.");
+        self.output_stream
+            .play()
+            .map_err(stream_start_error(&self.output_name))?;
+        if !self.running {
+            self.running = true;
+            memory::set_running(true);
+        }
+        Ok(())
+    }
+
+    /// Says how raising each stream's thread to real-time priority went, for every thread that's
+    /// been tried since the last call. The callbacks leave it for the control thread to say.
+    pub fn report_priorities(&mut self) {
+        self.raise_reports.retain(|report| !report.report());
+    }
+
+    /// Every buffer the pipeline allocated while it was built, which is all the memory its audio
+    /// callbacks use.
+    pub fn allocations(&self) -> &Allocations {
+        &self.allocations
+    }
+
+    /// How many frames each input started ahead of the last one, which were skipped so that they
+    /// all share the same zero point.
+    pub fn start_offsets(&self) -> &[(String, usize)] {
+        &self.start_offsets
+    }
+
+    /// The index of configured input `name`, and of the stream it alone is fed by.
+    fn lone_input(&self, name: &str) -> Result<(usize, usize), PipelineError> {
+        let Some(i) = self
+            .config
+            .inputs
+            .iter()
+            .position(|input| input.name == name)
+        else {
+            return Err(PipelineError::invalid(
+                if self.attached.iter().any(|input| input.name == name) {
+                    format!(
+                        "\"{}\" was attached while running, so it can only be removed",
+                        name
+                    )
+                } else {
+                    format!("there's no input called \"{}\"", name)
+                },
+            ));
+        };
+        // Every input belongs to exactly one stream.
+        let stream = self
+            .input_streams
+            .iter()
+            .position(|stream| stream.members.contains(&i))
+            .unwrap();
+        let others = self.input_streams[stream]
+            .members
+            .iter()
+            .filter(|&&member| member != i)
+            .map(|&member| format!("\"{}\"", self.config.inputs[member].name))
+            .collect::<Vec<_>>();
+        if !others.is_empty() {
+            return Err(PipelineError::invalid(format!(
+                "\"{}\" shares \"{}\" with {}, so its stream can't be stopped or started on its own",
+                name,
+                self.input_streams[stream].device,
+                others.join(", ")
+            )));
+        }
+        Ok((i, stream))
+    }
+
+    /// Stops configured input `name`'s stream and closes its device, keeping its gain, chain and
+    /// ring buffer for [`Pipeline::start_input`]. The input plays as silence meanwhile.
+    pub fn stop_input(&mut self, name: &str) -> Result<(), PipelineError> {
+        let (i, stream) = self.lone_input(name)?;
+        let Some(stream) = self.input_streams[stream].stream.take() else {
+            return Err(PipelineError::invalid(format!(
+                "\"{}\" is already stopped",
+                name
+            )));
+        };
+        self.input_states[i].store(INPUT_STOPPED, Ordering::Relaxed);
+        let _ = stream.pause();
+        drop(stream);
+        Ok(())
+    }
+
+    /// Opens configured input `name`'s device again after [`Pipeline::stop_input`], and fades it
+    /// back in once its ring buffer holds the latency it was prefilled with. Its chain starts
+    /// afresh, and nothing left in its ring buffer from before is played.
+    pub fn start_input(
+        &mut self,
+        provider: &dyn DeviceProvider,
+        name: &str,
+    ) -> Result<(), PipelineError> {
+        let (i, stream) = self.lone_input(name)?;
+        let input = &mut self.input_streams[stream];
+        if input.stream.is_some() {
+            return Err(PipelineError::invalid(format!(
+                "\"{}\" isn't stopped",
+                name
+            )));
+        }
+        let device = find_input(provider, &input.device)?;
+        let Some(mut on_data) = input.shared.reclaimed.lock().unwrap().take() else {
+            return Err(PipelineError::invalid(format!(
+                "\"{}\" hasn't finished stopping yet",
+                name
+            )));
+        };
+        // Nothing from the old stream should carry on into the new one.
+        on_data.reset();
+        input.shared.disconnected.store(false, Ordering::Relaxed);
+        // A stream that fails to build or start hands the callback back when it's dropped.
+        let raise = RaiseOnce::new(self.rt_priority, device.name(), &input.config);
+        let report = raise.report();
+        let built = build_input_stream(&*device, &input.config, on_data, &input.shared, raise)?;
+        built.play().map_err(stream_start_error(&input.device))?;
+        input.stream = Some(built);
+        self.raise_reports.extend(report);
+        self.input_states[i].store(INPUT_RESTARTING, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// What every configured input is doing, in the order they were configured.
+    pub fn input_statuses(&self) -> impl Iterator<Item = (&str, InputStatus)> {
+        self.input_streams.iter().flat_map(move |stream| {
+            stream.members.iter().map(move |&i| {
+                let (name, gain) = &self.override_gains[i];
+                let status = if stream.shared.disconnected.load(Ordering::Relaxed) {
+                    InputStatus::Disconnected
+                } else if stream.stream.is_none() {
+                    InputStatus::Stopped
+                } else if gain.muted() {
+                    InputStatus::Muted
+                } else {
+                    InputStatus::Playing
+                };
+                (name.as_str(), status)
+            })
+        })
+    }
+
+    /// The override gain of the input called `name`, if there is one.
+    pub fn override_gain(&self, name: &str) -> Option<Arc<OverrideGain>> {
+        self.override_gains
+            .iter()
+            .find(|(input, _)| input == name)
+            .map(|(_, gain)| Arc::clone(gain))
+    }
+
+    /// Marks the current position of every recording with `label`, returning whether anything is
+    /// being recorded.
+    pub fn marker(&self, label: &str) -> bool {
+        self.recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.marker(label))
+    }
+
+    /// Stops the pipeline, handing back its recorder for [`Pipeline::build_continuing`] to carry
+    /// on with, with everything the streams tapped queued.
+    pub fn into_recorder(mut self) -> Option<Recorder> {
+        let recorder = self.recorder.take();
+        drop(self);
+        recorder
+    }
+
+    /// Every time a recording was carried on into a stream of another format, and what was done
+    /// about it.
+    pub fn recording_format_changes(&self) -> &[String] {
+        self.recorder
+            .as_ref()
+            .map_or(&[], |recorder| recorder.format_changes())
+    }
+
+    /// Every recording that had to be given up on, which is always one of two mirrored files
+    /// unless the other was given up on too.
+    pub fn recording_failures(&self) -> Vec<String> {
+        self.recorder
+            .as_ref()
+            .map_or_else(Vec::new, Recorder::failures)
+    }
+
+    /// Whether anything is being recorded to a file, which a replay buffer alone isn't.
+    pub fn is_recording(&self) -> bool {
+        self.recorder.as_ref().is_some_and(Recorder::has_tracks)
+    }
+
+    /// Saves the last `duration` of the output, or as much as the replay buffer holds, returning
+    /// whether there is a replay buffer.
+    pub fn clip(&self, duration: Option<Duration>) -> bool {
+        self.recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.clip(duration))
+    }
+
+    /// Beeps on output `channel`, counting from 1, as many times as its number, or on every
+    /// output channel in turn if `None`.
+    pub fn identify(&self, channel: Option<usize>) -> Result<(), PipelineError> {
+        if let Some(channel) = channel {
+            if channel == 0 || channel > self.output_channels {
+                return Err(PipelineError::invalid(format!(
+                    "there is no output channel {}, the output has channels 1 to {}",
+                    channel, self.output_channels
+                )));
+            }
+        }
+        self.identify.request(channel);
+        Ok(())
+    }
+
+    /// Opens the input device `name` and fades it into the running output, with the default chain
+    /// and as much buffered as the other inputs have.
+    pub fn attach_input(
+        &mut self,
+        provider: &dyn DeviceProvider,
+        name: &str,
+    ) -> Result<(), PipelineError> {
+        if self.override_gains.iter().any(|(input, _)| input == name) {
+            return Err(PipelineError::invalid(format!(
+                "there's already an input called \"{}\"",
+                name
+            )));
+        }
+        if self.attached.len() >= MAX_ATTACHED {
+            return Err(PipelineError::invalid(format!(
+                "at most {} inputs can be attached at once",
+                MAX_ATTACHED
+            )));
+        }
+        let input = find_input(provider, name)?;
+        let (chain, override_gain, _) = build_chain(
+            &InputConfig::new(name),
+            0.0,
+            self.planar,
+            &self.stream_config,
+            &mut self.stats,
+        )?;
+        let size = RingBufferSize::new(
+            self.sample_rate,
+            self.stream_config.channels,
+            buffer_frames(&self.stream_config.buffer_size),
+            0.0,
+            self.counters.buffered_frames() + self.max_chain_latency - chain.latency_frames(),
+            self.ringbuf_ms,
+        )?;
+        let (mut producer, consumer) = HeapRb::<f32>::new(size.capacity_samples()).split();
+        for _ in 0..size.latency_samples() {
+            // The ring buffer has been sized so that the latency always fits.
+            producer.push(0.0).unwrap();
+        }
+        let summary = ChainSummary {
+            input: name.to_owned(),
+            stages: chain.describe(),
+            latency_frames: chain.latency_frames(),
+        };
+
+        let counters = Arc::new(InputCounters::default());
+        let mut raise = RaiseOnce::new(self.rt_priority, name, &self.stream_config);
+        self.raise_reports.extend(raise.report());
+        let mut on_data = AlignInputFrames::new(
+            self.stream_config.channels as usize,
+            self.stats.add_device(name),
+            Box::new(ProcessInput::new(
+                producer,
+                chain,
+                None,
+                Arc::clone(&counters),
+            )),
+        );
+        let stream = input
+            .build_input_stream(
+                &self.stream_config,
+                Box::new(move |data: &[f32]| {
+                    raise.poll();
+                    memory::in_callback(|| on_data.process(data))
+                }),
+                Box::new(err_fn),
+            )
+            .map_err(stream_build_error(name))?;
+        stream.play().map_err(stream_start_error(name))?;
+
+        let id = self.next_attached_id;
+        self.next_attached_id += 1;
+        let pan = Arc::new(AtomicU32::new(0f32.to_bits()));
+        self.attacher.attach(id, consumer, Arc::clone(&pan));
+        self.stats.add_input(name, counters, self.sample_rate);
+        self.override_gains.push((name.to_owned(), override_gain));
+        self.attached.push(AttachedInput {
+            id,
+            name: name.to_owned(),
+            stream,
+            chain: summary,
+            ring_buffer: size,
+            pan,
+        });
+        Ok(())
+    }
+
+    /// Pans an input added by [`Pipeline::attach_input`] from -1 (left) to 1 (right), if the
+    /// pipeline is stereo. The input moves there over a few milliseconds.
+    pub fn pan_attached(&self, name: &str, position: f32) -> Result<(), PipelineError> {
+        if !(-1.0..=1.0).contains(&position) {
+            return Err(PipelineError::invalid(format!(
+                "a pan runs from -1 to 1, not {}",
+                position
+            )));
+        }
+        let Some(input) = self.attached.iter().find(|input| input.name == name) else {
+            return Err(PipelineError::invalid(format!(
+                "\"{}\" isn't an attached input",
+                name
+            )));
+        };
+        input.pan.store(position.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Fades out and closes an input added by [`Pipeline::attach_input`].
+    pub fn detach_input(&mut self, name: &str) -> Result<(), PipelineError> {
+        let Some(index) = self.attached.iter().position(|input| input.name == name) else {
+            return Err(PipelineError::invalid(format!(
+                "\"{}\" isn't an attached input",
+                name
+            )));
+        };
+        let input = self.attached.remove(index);
+        self.attacher.detach(input.id);
+        let _ = input.stream.pause();
+        self.stats.inputs.retain(|input| input.name != name);
+        if let Some(index) = self
+            .stats
+            .devices
+            .iter()
+            .rposition(|(device, _)| device == name)
+        {
+            self.stats.devices.remove(index);
+        }
+        // Attached inputs come after the configured ones, which are never removed.
+        let first_attached = self.skips.len();
+        if let Some(index) = self.override_gains[first_attached..]
+            .iter()
+            .position(|(input, _)| input == name)
+        {
+            self.override_gains.remove(first_attached + index);
+        }
+        Ok(())
+    }
+
+    /// Everything the pipeline is doing, as it is now. `host` is the audio host its devices were
+    /// found through.
+    pub fn describe(&self, host: &str) -> PipelineDescription {
+        let mut devices = self
+            .input_streams
+            .iter()
+            .map(|input| DeviceDescription {
+                name: input.device.clone(),
+                is_input: true,
+                channels: input.config.channels,
+            })
+            .chain(self.attached.iter().map(|input| DeviceDescription {
+                name: input.name.clone(),
+                is_input: true,
+                channels: self.stream_config.channels,
+            }))
+            .collect::<Vec<_>>();
+        devices.push(DeviceDescription {
+            name: self.output_name.clone(),
+            is_input: false,
+            channels: self.output_channels as u16,
+        });
+
+        let gain = |name: &str| {
+            self.override_gain(name)
+                .map_or((0.0, false), |gain| (gain.level_db(), gain.muted()))
+        };
+        let configured = self
+            .config
+            .inputs
+            .iter()
+            .zip(&self.chains)
+            .zip(&self.ring_buffers)
+            .zip(&self.input_states)
+            .map(|(((input, chain), ring_buffer), state)| {
+                let (gain_db, muted) = gain(&input.name);
+                InputDescription {
+                    name: input.name.clone(),
+                    device: input.device.clone(),
+                    channels: input.channels.clone(),
+                    gain_db,
+                    muted,
+                    inverted: input.invert,
+                    chain: chain.stages.clone(),
+                    chain_latency_frames: chain.latency_frames,
+                    ring_buffer: *ring_buffer,
+                    stopped: state.load(Ordering::Relaxed) == INPUT_STOPPED,
+                    attached: false,
+                }
+            });
+        let attached = self.attached.iter().map(|input| {
+            let (gain_db, muted) = gain(&input.name);
+            InputDescription {
+                name: input.name.clone(),
+                device: input.name.clone(),
+                channels: None,
+                gain_db,
+                muted,
+                inverted: false,
+                chain: input.chain.stages.clone(),
+                chain_latency_frames: input.chain.latency_frames,
+                ring_buffer: input.ring_buffer,
+                stopped: false,
+                attached: true,
+            }
+        });
+
+        let config = &self.config;
+        let latency_ms = config
+            .adaptive_latency
+            .map_or(config.latency_ms, |adaptive| adaptive.max_ms);
+        PipelineDescription {
+            host: host.to_owned(),
+            sample_rate: self.sample_rate,
+            buffer_frames: match self.stream_config.buffer_size {
+                cpal::BufferSize::Fixed(frames) => Some(frames),
+                cpal::BufferSize::Default => None,
+            },
+            devices,
+            inputs: configured.chain(attached).collect(),
+            output: OutputDescription {
+                device: self.output_name.clone(),
+                channels: self.output_channels as u16,
+                used_channels: self.used_output_channels,
+                master_gain_db: self.master_gain.level_db(),
+                master_muted: self.master_gain.muted(),
+            },
+            recording: RecordingDescription {
+                output: config.record_output.clone(),
+                mirror: config
+                    .record_output
+                    .as_ref()
+                    .and(config.record_mirror.as_ref())
+                    .map(|mirror| mirror.path.clone()),
+                ab_inputs: config
+                    .inputs
+                    .iter()
+                    .filter(|input| input.record_ab)
+                    .map(|input| input.name.clone())
+                    .collect(),
+                ab_pre_fader: config.stems_pre_fader,
+                segment: config.segment,
+                cue_markers: config.cue_markers,
+                marker_sidecar: config.marker_sidecar.clone(),
+                replay_buffer: config.replay_buffer,
+            },
+            latency: LatencyDescription {
+                latency_ms,
+                adaptive: config
+                    .adaptive_latency
+                    .map(|adaptive| (adaptive.min_ms, adaptive.max_ms)),
+                max_chain_latency_frames: self.max_chain_latency,
+                total_ms: latency_ms
+                    + self.max_chain_latency as f32 * 1_000.0 / self.sample_rate as f32,
+            },
+            memory_bytes: self.allocations.total_bytes(),
+        }
+    }
+
+    /// The names of the inputs added by [`Pipeline::attach_input`], in the order they were added.
+    pub fn attached_inputs(&self) -> impl Iterator<Item = &str> {
+        self.attached.iter().map(|input| input.name.as_str())
+    }
+
+    /// The gain of the whole mix, before identification beeps. Its level and mute apply like an
+    /// input's, while its override is left alone.
+    pub fn master_gain(&self) -> &Arc<OverrideGain> {
+        &self.master_gain
+    }
+
+    /// Every input's override gain, in the order the inputs were configured, followed by the
+    /// attached inputs'.
+    pub fn override_gains(&self) -> &[(String, Arc<OverrideGain>)] {
+        &self.override_gains
+    }
+
+    /// The reverb send of every input configured with one.
+    pub fn reverbs(&self) -> &[(String, Arc<ReverbSend>)] {
+        &self.reverbs
+    }
+
+    /// Every input's chain, in the order the inputs were configured.
+    pub fn chains(&self) -> &[ChainSummary] {
+        &self.chains
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn counters(&self) -> &OutputCounters {
+        &self.counters
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        if self.running {
+            memory::set_running(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{Failure, FakeProvider, Signal};
+    use crate::wav;
+    use std::time::SystemTime;
+
+    const RATE: u32 = 48000;
+    const PERIOD: u32 = 256;
+
+    fn stream_config(channels: u16) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(RATE),
+            buffer_size: cpal::BufferSize::Fixed(PERIOD),
+        }
+    }
+
+    fn config(inputs: &[&str], output: &str) -> PipelineConfig {
+        PipelineConfig {
+            inputs: inputs.iter().map(|name| InputConfig::new(name)).collect(),
+            output: output.to_owned(),
+            latency_ms: 20.0,
+            ringbuf_ms: None,
+            denoise_mix: 1.0,
+            segment: 0,
+            cue_markers: false,
+            negotiate: false,
+            output_channels: None,
+            adaptive_latency: None,
+            replay_buffer: None,
+            planar: false,
+            record_output: None,
+            record_mirror: None,
+            record_sync: SyncPolicy::default(),
+            record_ceiling: None,
+            record_continue: None,
+            stems_pre_fader: false,
+            rt_priority: false,
+            preroll: Duration::ZERO,
+            marker_sidecar: PathBuf::from(MARKER_SIDECAR),
+            record_dir: PathBuf::new(),
+            strict_routing: false,
+            resample_quality: Quality::default(),
+        }
+    }
+
+    /// Builds the pipeline and starts it the way [`Pipeline::play`] would, without waiting on a
+    /// clock that only moves when it's advanced.
+    fn start(provider: &FakeProvider, config: &PipelineConfig) -> Pipeline {
+        let mut pipeline = Pipeline::build(provider, config).unwrap();
+        pipeline.start_inputs().unwrap();
+        provider.advance(1);
+        pipeline.start_output().unwrap();
+        pipeline
+    }
+
+    #[test]
+    fn sizes_ring_buffers_from_the_latency_and_callback_size() {
+        let cases = [
+            // rate, channels, callback frames, latency ms, delay frames, latency, capacity
+            (48_000, 2, 512, 20.0, 0, 960, 2048),
+            (48_000, 2, 256, 100.0, 0, 4800, 9600),
+            (44_100, 1, 512, 50.0, 0, 2205, 4410),
+            (96_000, 6, 128, 5.0, 0, 480, 960),
+            (48_000, 8, 1024, 0.0, 0, 0, 4096),
+            // Delay compensation is prefilled along with the latency.
+            (48_000, 2, 256, 20.0, 480, 1440, 2880),
+        ];
+        for (rate, channels, callback, latency_ms, delay, latency, capacity) in cases {
+            let size =
+                RingBufferSize::new(rate, channels, callback, latency_ms, delay, None).unwrap();
+            assert_eq!(
+                size,
+                RingBufferSize {
+                    latency_frames: latency,
+                    capacity_frames: capacity,
+                    channels: channels as usize,
+                }
+            );
+            assert_eq!(size.latency_samples(), latency * channels as usize);
+            assert_eq!(size.capacity_samples(), capacity * channels as usize);
+        }
+    }
+
+    #[test]
+    fn ring_buffer_overrides_must_fit_the_latency_and_a_callback() {
+        let size = RingBufferSize::new(48_000, 2, 512, 20.0, 0, Some(500.0)).unwrap();
+        assert_eq!((size.latency_frames, size.capacity_frames), (960, 24_000));
+        // 960 frames of latency and a 512 frame callback need 1472.
+        assert!(RingBufferSize::new(48_000, 2, 512, 20.0, 0, Some(30.7)).is_ok());
+        assert!(RingBufferSize::new(48_000, 2, 512, 20.0, 0, Some(30.6)).is_err());
+        assert!(RingBufferSize::new(48_000, 2, 512, 200.0, 0, Some(100.0)).is_err());
+    }
+
+    /// Frame `frame` of a signal that numbers every sample by its frame and channel.
+    fn numbered(frames: Range<usize>, channels: usize) -> Vec<f32> {
+        frames
+            .flat_map(|frame| (0..channels).map(move |channel| (frame * 10 + channel) as f32))
+            .collect()
+    }
+
+    #[test]
+    fn pushes_only_whole_frames_into_a_full_ring_buffer() {
+        for channels in [1, 2, 6] {
+            // Room for ten frames and most of another.
+            let (producer, mut consumer) = HeapRb::<f32>::new(11 * channels - 1).split();
+            let counters = Arc::new(InputCounters::default());
+            let chain = InputChain::new(channels, Vec::new()).unwrap();
+            let mut process =
+                create_input_processing_fn(producer, chain, None, Arc::clone(&counters));
+
+            process(&numbered(0..3, channels));
+            process(&numbered(3..7, channels));
+            // Only three of these fit.
+            process(&numbered(7..12, channels));
+            assert_eq!(consumer.len(), 10 * channels);
+            assert_eq!(counters.dropped_frames.load(Ordering::Relaxed), 2);
+
+            // Leaving part of a frame free still only takes whole ones.
+            let popped = channels / 2 + 1;
+            consumer.skip(popped);
+            process(&numbered(12..14, channels));
+            assert_eq!(counters.dropped_frames.load(Ordering::Relaxed), 3);
+            let mut expected = numbered(0..10, channels);
+            expected.extend(numbered(12..13, channels));
+            assert_eq!(consumer.pop_iter().collect::<Vec<_>>(), expected[popped..]);
+        }
+    }
+
+    /// Hands on whatever it's given, for checking what comes out of the other handlers.
+    struct Collect(Arc<Mutex<Vec<Vec<f32>>>>);
+
+    impl InputHandler for Collect {
+        fn process(&mut self, data: &[f32]) {
+            self.0.lock().unwrap().push(data.to_vec());
+        }
+
+        fn reset(&mut self) {}
+    }
+
+    #[test]
+    fn aligns_odd_callbacks_to_whole_frames() {
+        for channels in [1, 2, 6] {
+            let collected = Arc::new(Mutex::new(Vec::new()));
+            let counters = Arc::new(BufferCounters::default());
+            let mut align = AlignInputFrames::new(
+                channels,
+                Arc::clone(&counters),
+                Box::new(Collect(Arc::clone(&collected))),
+            );
+            let signal = numbered(0..40, channels);
+            let mut rest = &signal[..];
+            for length in [0, 1, 5, 7, 13, 2, 0, 3].into_iter().cycle() {
+                let (callback, after) = rest.split_at(length.min(rest.len()));
+                align.process(callback);
+                rest = after;
+                if rest.is_empty() {
+                    break;
+                }
+            }
+            let collected = collected.lock().unwrap();
+            assert!(collected
+                .iter()
+                .all(|frames| !frames.is_empty() && frames.len() % channels == 0));
+            assert_eq!(collected.concat(), signal);
+            assert!(counters.empty.load(Ordering::Relaxed) > 0);
+            assert_eq!(
+                counters.misaligned.load(Ordering::Relaxed) > 0,
+                channels > 1
+            );
+        }
+    }
+
+    /// Plays a numbered stereo signal from one input straight through, with the input and output
+    /// cutting up each period's samples by `input` and `output` rather than delivering them in
+    /// one buffer, and checks every frame comes out whole and in order. Only the odd lengths
+    /// are fair game: each device gets the same number of samples a period.
+    fn passes_through_buffers(input: &[usize], output: &[usize]) {
+        let provider = FakeProvider::new();
+        let signal = numbered(0..40 * PERIOD as usize, 2);
+        provider
+            .add_input(
+                "Mic",
+                stream_config(2),
+                Signal::Samples(signal.clone().into()),
+            )
+            .add_output("Speakers", stream_config(2));
+        provider.set_buffers("Mic", input);
+        provider.set_buffers("Speakers", output);
+        // Room in the ring buffer for two periods on top of the latency, however long they are.
+        let config = PipelineConfig {
+            latency_ms: 50.0,
+            ..config(&["Mic"], "Speakers")
+        };
+        let pipeline = start(&provider, &config);
+        provider.advance(8);
+
+        let played = provider.take_output("Speakers");
+        assert_eq!(played.len(), 8 * output.iter().sum::<usize>());
+        let latency = pipeline.ring_buffers[0].latency_frames;
+        let skip = skip(&pipeline, "Mic");
+        for (i, &sample) in played.iter().enumerate() {
+            let expected = (i / 2 + skip)
+                .checked_sub(latency)
+                .map_or(0.0, |frame| signal[frame * 2 + i % 2]);
+            assert_eq!(
+                sample, expected,
+                "sample {} of {:?} into {:?}",
+                i, input, output
+            );
+        }
+        assert_eq!(pipeline.counters().underruns(), 0);
+        let dropped = pipeline
+            .stats()
+            .inputs()
+            .map(|(_, counters)| counters.dropped_frames.load(Ordering::Relaxed));
+        assert_eq!(dropped.sum::<u64>(), 0);
+        for (name, lengths) in [("Mic", input), ("Speakers", output)] {
+            let (_, counters) = pipeline
+                .stats()
+                .devices
+                .iter()
+                .find(|(device, _)| device == name)
+                .unwrap();
+            assert_eq!(
+                counters.empty.load(Ordering::Relaxed) > 0,
+                lengths.contains(&0),
+                "{}",
+                name
+            );
+            assert_eq!(
+                counters.misaligned.load(Ordering::Relaxed) > 0,
+                lengths.iter().any(|length| length % 2 == 1),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn plays_empty_single_sample_and_odd_input_buffers_whole() {
+        let period = 2 * PERIOD as usize;
+        passes_through_buffers(&[0, 1, 3, period - 4], &[period]);
+    }
+
+    #[test]
+    fn fills_empty_single_sample_and_odd_output_buffers_whole() {
+        let period = 2 * PERIOD as usize;
+        passes_through_buffers(&[period], &[0, 1, 3, period - 4]);
+    }
+
+    #[test]
+    fn handles_buffers_bigger_than_the_scratch_on_either_side() {
+        // Over the scratch by a frame and a half, once a single sample has come in.
+        let oversized = [0, 1, SCRATCH_SAMPLES + 3];
+        let period = oversized.iter().sum::<usize>();
+        passes_through_buffers(&oversized, &oversized);
+        passes_through_buffers(&oversized, &[period]);
+        passes_through_buffers(&[period], &oversized);
+    }
+
+    /// A quarter-scale sine of `frames` frames, to feed a mono input.
+    fn tone(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                let t = frame as f32 / RATE as f32;
+                0.25 * (t * frequency * std::f32::consts::TAU).sin()
+            })
+            .collect()
+    }
+
+    /// `signal` as the output plays it, `frames` frames in, from an input that is prefilled with
+    /// `latency` frames of silence and the output skipped `skip` frames of.
+    fn played(signal: &[f32], frames: usize, latency: usize, skip: usize) -> f32 {
+        (frames + skip)
+            .checked_sub(latency)
+            .and_then(|frame| signal.get(frame))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    fn skip(pipeline: &Pipeline, input: &str) -> usize {
+        pipeline
+            .start_offsets
+            .iter()
+            .find(|(name, _)| name == input)
+            .unwrap()
+            .1
+    }
+
+    fn assert_mixed(output: &[f32], from: usize, pipeline: &Pipeline, inputs: &[(&str, &[f32])]) {
+        let latency = pipeline.ring_buffers[0].latency_frames;
+        for (i, &sample) in output.iter().enumerate() {
+            let expected = inputs
+                .iter()
+                .map(|(name, signal)| played(signal, from + i, latency, skip(pipeline, name)))
+                .sum::<f32>();
+            assert!(
+                (sample - expected).abs() < 1e-6,
+                "frame {} is {} rather than {}",
+                from + i,
+                sample,
+                expected
+            );
+        }
+    }
+
+    fn two_tones(provider: &FakeProvider) -> (Vec<f32>, Vec<f32>) {
+        let (low, high) = (tone(440.0, RATE as usize), tone(1000.0, RATE as usize));
+        provider
+            .add_input("Low", stream_config(1), Signal::Samples(low.clone().into()))
+            .add_input(
+                "High",
+                stream_config(1),
+                Signal::Samples(high.clone().into()),
+            )
+            .add_output("Speakers", stream_config(1));
+        (low, high)
+    }
+
+    #[test]
+    fn mixes_two_tones_after_the_prefilled_latency() {
+        let provider = FakeProvider::new();
+        let (low, high) = two_tones(&provider);
+        let pipeline = start(&provider, &config(&["Low", "High"], "Speakers"));
+        provider.advance(20);
+
+        let output = provider.take_output("Speakers");
+        assert_eq!(output.len(), 20 * PERIOD as usize);
+        let latency = pipeline.ring_buffers[0].latency_frames;
+        assert_eq!(latency, ms_to_frames(20.0, RATE));
+        // Nothing but the prefilled silence comes out before the latency is up.
+        assert!(
+            output[..latency - skip(&pipeline, "Low").max(skip(&pipeline, "High"))]
+                .iter()
+                .all(|&sample| sample == 0.0)
+        );
+        assert_mixed(&output, 0, &pipeline, &[("Low", &low), ("High", &high)]);
+    }
+
+    #[test]
+    fn from_config_starts_inputs_and_output_on_a_running_clock() {
+        let provider = FakeProvider::new();
+        let (low, high) = two_tones(&provider);
+        let config = PipelineConfig {
+            // Room for however many periods go by before the output starts.
+            ringbuf_ms: Some(1000.0),
+            ..config(&["Low", "High"], "Speakers")
+        };
+        let done = Arc::new(AtomicBool::new(false));
+        let clock = std::thread::spawn({
+            let provider = provider.clone();
+            let done = Arc::clone(&done);
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    provider.advance(1);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+        let pipeline = Pipeline::from_config(&provider, &config);
+        done.store(true, Ordering::Relaxed);
+        clock.join().unwrap();
+        let pipeline = pipeline.unwrap();
+        provider.advance(20);
+
+        let output = provider.take_output("Speakers");
+        assert!(output.len() >= 20 * PERIOD as usize);
+        assert_mixed(&output, 0, &pipeline, &[("Low", &low), ("High", &high)]);
+    }
+
+    #[test]
+    fn counts_underruns_and_carries_on_mixing_while_an_input_stalls() {
+        let provider = FakeProvider::new();
+        let (low, high) = two_tones(&provider);
+        let pipeline = start(&provider, &config(&["Low", "High"], "Speakers"));
+        provider.advance(10);
+        assert_eq!(pipeline.counters().underruns(), 0);
+
+        // Longer than the latency it has buffered.
+        provider.stall("High", 10);
+        provider.advance(30);
+        assert!(pipeline.counters().underruns() > 0);
+        let output = provider.take_output("Speakers");
+        assert_eq!(output.len(), 40 * PERIOD as usize);
+
+        // The input that kept up is still mixed in where it was, and the stalled one is back on
+        // top of it.
+        let latency = pipeline.ring_buffers[0].latency_frames;
+        let tail = 30 * PERIOD as usize..40 * PERIOD as usize;
+        let residual = tail
+            .clone()
+            .map(|frame| {
+                let sample = output[frame] - played(&low, frame, latency, skip(&pipeline, "Low"));
+                sample * sample
+            })
+            .sum::<f32>();
+        let expected = high[tail].iter().map(|sample| sample * sample).sum::<f32>();
+        assert!(
+            (residual / expected - 1.0).abs() < 0.05,
+            "{}",
+            residual / expected
+        );
+    }
+
+    #[test]
+    fn plays_a_lost_input_again_once_it_comes_back() {
+        let provider = FakeProvider::new();
+        let (low, _) = two_tones(&provider);
+        let mut pipeline = start(&provider, &config(&["Low", "High"], "Speakers"));
+        provider.advance(10);
+
+        provider.disconnect("High");
+        let status = |pipeline: &Pipeline| {
+            pipeline
+                .input_statuses()
+                .find(|(name, _)| *name == "High")
+                .unwrap()
+                .1
+        };
+        assert_eq!(status(&pipeline), InputStatus::Disconnected);
+        provider.advance(10);
+        provider.take_output("Speakers");
+        // Only the input still there is played, once the lost one's buffer has run out.
+        provider.advance(10);
+        let output = provider.take_output("Speakers");
+        assert_mixed(&output, 20 * PERIOD as usize, &pipeline, &[("Low", &low)]);
+
+        provider.reconnect("High");
+        pipeline.stop_input("High").unwrap();
+        pipeline.start_input(&provider, "High").unwrap();
+        assert_eq!(status(&pipeline), InputStatus::Playing);
+        let latency = pipeline.ring_buffers[0].latency_frames;
+        provider.advance(20);
+        let output = provider.take_output("Speakers");
+        let tail = &output[10 * PERIOD as usize..];
+        let residual = tail
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let frame = 40 * PERIOD as usize + i;
+                sample - played(&low, frame, latency, skip(&pipeline, "Low"))
+            })
+            .map(|sample| sample * sample)
+            .sum::<f32>();
+        // Back to a quarter-scale tone on top of the other, give or take where it was cut off.
+        let tone = 0.25f32.powi(2) / 2.0 * tail.len() as f32;
+        assert!((residual / tone - 1.0).abs() < 0.05, "{}", residual / tone);
+    }
+
+    #[test]
+    fn adaptive_latency_shrinks_while_inputs_keep_up_and_backs_off_after_a_stall() {
+        let provider = FakeProvider::new();
+        let sine = Signal::Sine {
+            frequency: 440.0,
+            amplitude: 0.25,
+        };
+        provider
+            .add_input("Mic", stream_config(1), sine)
+            .add_output("Speakers", stream_config(1));
+        let config = PipelineConfig {
+            adaptive_latency: Some(AdaptiveLatency {
+                min_ms: 40.0,
+                max_ms: 100.0,
+            }),
+            ..config(&["Mic"], "Speakers")
+        };
+        let pipeline = start(&provider, &config);
+        let (min, max) = (ms_to_frames(40.0, RATE), ms_to_frames(100.0, RATE));
+        let buffered = || pipeline.counters().buffered_frames();
+        provider.advance(1);
+        assert!(buffered() >= max - PERIOD as usize, "{}", buffered());
+
+        // Half a minute of audio, by which time it has come all the way down, 5 ms every 2 s.
+        provider.advance(30 * RATE as u64 / PERIOD as u64);
+        assert_eq!(pipeline.counters().underruns(), 0);
+        assert!(buffered() >= min, "{}", buffered());
+        assert!(buffered() < min + ms_to_frames(5.0, RATE), "{}", buffered());
+        provider.take_output("Speakers");
+
+        // A stall a little longer than what's buffered waits for the input to refill to about
+        // the maximum, less whatever was waited while it was still stalled.
+        provider.stall("Mic", 10);
+        provider.advance(10);
+        assert!(pipeline.counters().underruns() > 0);
+        provider.advance(RATE as u64 / PERIOD as u64);
+        assert!(buffered() >= max - 4 * PERIOD as usize, "{}", buffered());
+        let output = provider.take_output("Speakers");
+        assert!(output[output.len() - PERIOD as usize..]
+            .iter()
+            .any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn records_the_output_as_it_was_played() {
+        let dir = std::env::temp_dir().join(format!("loopback-record-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.wav");
+        let provider = FakeProvider::new();
+        two_tones(&provider);
+        let config = PipelineConfig {
+            record_output: Some(path.clone()),
+            marker_sidecar: dir.join(MARKER_SIDECAR),
+            ..config(&["Low", "High"], "Speakers")
+        };
+        let pipeline = start(&provider, &config);
+        provider.advance(20);
+        // The recording is finished once the pipeline is dropped.
+        drop(pipeline);
+
+        let output = provider.take_output("Speakers");
+        let recorded = wav::read_samples(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(recorded, output);
+    }
+
+    #[test]
+    fn carries_the_output_recording_on_through_a_rebuild_at_another_rate() {
+        let dir = std::env::temp_dir().join(format!("loopback-continue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.wav");
+        let at = |sample_rate| StreamConfig {
+            sample_rate: cpal::SampleRate(sample_rate),
+            ..stream_config(1)
+        };
+        let mut config = PipelineConfig {
+            record_output: Some(path.clone()),
+            record_continue: Some(FormatChange::Resample),
+            marker_sidecar: dir.join(MARKER_SIDECAR),
+            ..config(&["Mic"], "Speakers")
+        };
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", at(RATE), Signal::Samples(vec![0.5; 1 << 20].into()))
+            .add_output("Speakers", at(RATE));
+        let pipeline = start(&provider, &config);
+        provider.advance(20);
+        let mut recorder = pipeline.into_recorder();
+        let first = provider.take_output("Speakers");
+
+        // The devices come back at 44.1 kHz, as after switching to a headset.
+        let provider = FakeProvider::new();
+        provider
+            .add_input(
+                "Mic",
+                at(44_100),
+                Signal::Samples(vec![0.25; 1 << 20].into()),
+            )
+            .add_output("Speakers", at(44_100));
+        config.segment += 1;
+        let mut pipeline = Pipeline::build_continuing(&provider, &config, &mut recorder).unwrap();
+        assert!(recorder.is_none());
+        pipeline.start_inputs().unwrap();
+        provider.advance(1);
+        pipeline.start_output().unwrap();
+        provider.advance(20);
+        let changes = pipeline.recording_format_changes().to_vec();
+        drop(pipeline);
+        let second = provider.take_output("Speakers");
+
+        let recorded = wav::read_samples(&path).unwrap();
+        let split = dir.join("output-2.wav").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!split);
+        assert_eq!(changes.len(), 1, "{:?}", changes);
+        assert_eq!(recorded[..first.len()], first);
+        let resampled = second.len() * RATE as usize / 44_100;
+        assert!(recorded.len().abs_diff(first.len() + resampled) <= 1);
+        // Well away from the edges, where the filter rings.
+        let middle = first.len() + resampled / 2;
+        assert!(
+            (recorded[middle] - 0.25).abs() < 1e-3,
+            "{}",
+            recorded[middle]
+        );
+    }
+
+    #[test]
+    fn limits_the_output_recording_to_its_ceiling() {
+        let dir = std::env::temp_dir().join(format!("loopback-ceiling-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.wav");
+        // A quiet tone, then a full-scale one at a quarter of the rate sampled 45 degrees off its
+        // peaks, which peaks 3 dB over full scale between the samples.
+        let quiet = 10 * PERIOD as usize;
+        let samples = (0..40 * PERIOD as usize)
+            .map(|i| match i {
+                i if i < quiet => (i as f32 * 0.05).sin() * 0.25,
+                i => {
+                    (std::f32::consts::FRAC_PI_2 * i as f32 + std::f32::consts::FRAC_PI_4).sin()
+                        * std::f32::consts::SQRT_2
+                }
+            })
+            .collect::<Vec<_>>();
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", stream_config(1), Signal::Samples(samples.into()))
+            .add_output("Speakers", stream_config(1));
+        let config = PipelineConfig {
+            record_output: Some(path.clone()),
+            record_ceiling: Some(-1.0),
+            marker_sidecar: dir.join(MARKER_SIDECAR),
+            ..config(&["Mic"], "Speakers")
+        };
+        let pipeline = start(&provider, &config);
+        provider.advance(30);
+        let played_peak = pipeline.counters().take_true_peak();
+        drop(pipeline);
+
+        let output = provider.take_output("Speakers");
+        let recorded = wav::read_samples(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(played_peak > 1.0, "{} dBTP", 20.0 * played_peak.log10());
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+        let recorded_peak = TruePeakMeter::new(1).process(&recorded, 1);
+        assert!(
+            recorded_peak <= ceiling,
+            "{} dBTP",
+            20.0 * recorded_peak.log10()
+        );
+        // Lined up with what was played, and untouched until the limiter sees the peaks coming.
+        assert_eq!(recorded.len(), output.len());
+        assert_eq!(recorded[..quiet], output[..quiet]);
+    }
+
+    /// A temporary directory for a test's recordings, named after it.
+    fn recording_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loopback-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn records_both_sides_of_the_chain_lined_up_to_the_sample() {
+        let dir = recording_dir("record-ab");
+        // A tone with an impulse on top, through a chain that inverts it and, where there is a
+        // denoiser, delays it by a block without changing it.
+        let impulse = 3_000;
+        let mut samples = tone(440.0, 20 * PERIOD as usize);
+        samples[impulse] = 0.9;
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", stream_config(1), Signal::Samples(samples.into()))
+            .add_output("Speakers", stream_config(1));
+        let mut config = PipelineConfig {
+            denoise_mix: 0.0,
+            record_dir: dir.clone(),
+            marker_sidecar: dir.join(MARKER_SIDECAR),
+            ..config(&["Mic"], "Speakers")
+        };
+        config.inputs[0].record_ab = true;
+        config.inputs[0].invert = true;
+        config.inputs[0].denoise = cfg!(feature = "denoise");
+        let pipeline = start(&provider, &config);
+        let latency = pipeline.chains()[0].latency_frames;
+        assert_eq!(latency, if cfg!(feature = "denoise") { 480 } else { 0 });
+        provider.advance(30);
+        drop(pipeline);
+
+        let (raw, processed) = ab_paths(&dir, "Mic", 0);
+        let raw = wav::read_samples(&raw).unwrap();
+        let processed = wav::read_samples(&processed).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let peak = |samples: &[f32]| {
+            (0..samples.len())
+                .max_by(|&a, &b| samples[a].abs().total_cmp(&samples[b].abs()))
+                .unwrap()
+        };
+        assert_eq!(peak(&raw), impulse + latency);
+        assert_eq!(peak(&processed), impulse + latency);
+        assert_eq!(
+            (raw[impulse + latency], processed[impulse + latency]),
+            (0.9, -0.9)
+        );
+        // With the polarity flipped back, the two cancel out completely.
+        assert!(raw.len().min(processed.len()) > 20 * PERIOD as usize);
+        assert!(raw
+            .iter()
+            .zip(&processed)
+            .all(|(raw, processed)| (raw + processed).abs() < 1e-6));
+    }
+
+    #[test]
+    fn records_stems_before_the_fader_and_notes_its_changes() {
+        let dir = recording_dir("pre-fader");
+        let provider = FakeProvider::new();
+        provider
+            .add_input(
+                "Mic",
+                stream_config(1),
+                Signal::Samples(vec![0.5; 40 * PERIOD as usize].into()),
+            )
+            .add_output("Speakers", stream_config(1));
+        let mut config = PipelineConfig {
+            stems_pre_fader: true,
+            record_dir: dir.clone(),
+            marker_sidecar: dir.join(MARKER_SIDECAR),
+            ..config(&["Mic"], "Speakers")
+        };
+        config.inputs[0].record_ab = true;
+        let pipeline = start(&provider, &config);
+        provider.advance(10);
+        // The first period came in as the pipeline started.
+        let changed_at = 11 * PERIOD as usize;
+        pipeline.override_gain("Mic").unwrap().set(0.25);
+        provider.advance(20);
+        drop(pipeline);
+
+        let output = provider.take_output("Speakers");
+        let (_, processed) = ab_paths(&dir, "Mic", 0);
+        let stem = wav::read_samples(&processed).unwrap();
+        let sidecar = std::fs::read_to_string(dir.join(MARKER_SIDECAR)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // The mix follows the fader, while the stem stays where it was.
+        assert_eq!(output.last(), Some(&0.125));
+        assert_eq!(stem.len(), 31 * PERIOD as usize);
+        assert!(stem.iter().all(|&sample| sample == 0.5));
+
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&sidecar).unwrap();
+        let faders = entries
+            .iter()
+            .filter(|entry| entry["fader"] == "Mic")
+            .map(|entry| {
+                assert_eq!(
+                    entry["files"][0]["file"],
+                    processed.to_string_lossy().as_ref()
+                );
+                assert_eq!(entry["fade_ms"], crate::chain::OVERRIDE_FADE_MS as f64);
+                (
+                    entry["gain"].as_f64().unwrap(),
+                    entry["files"][0]["frame"].as_u64().unwrap() as usize,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(faders, [(1.0, 0), (0.25, changed_at)]);
+    }
+
+    #[test]
+    fn starts_every_first_segment_recording_with_the_preroll_and_notes_when() {
+        let dir = recording_dir("preroll");
+        let provider = FakeProvider::new();
+        provider
+            .add_input(
+                "Mic",
+                stream_config(1),
+                Signal::Samples(vec![0.5; 100 * PERIOD as usize].into()),
+            )
+            .add_output("Speakers", stream_config(1));
+        let mut config = PipelineConfig {
+            preroll: Duration::from_millis(50),
+            record_output: Some(dir.join("output.wav")),
+            record_dir: dir.clone(),
+            marker_sidecar: dir.join(MARKER_SIDECAR),
+            ..config(&["Mic"], "Speakers")
+        };
+        config.inputs[0].record_ab = true;
+        let preroll = ms_to_frames(50.0, RATE);
+        let periods = |periods: usize| periods * PERIOD as usize;
+
+        let before = SystemTime::now();
+        let pipeline = start(&provider, &config);
+        // The writer thread sees the first period before any more come in.
+        let waited = Instant::now();
+        while std::fs::read_to_string(dir.join(MARKER_SIDECAR))
+            .unwrap_or_default()
+            .matches("\"start\"")
+            .count()
+            < 2
+        {
+            assert!(waited.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let after = SystemTime::now();
+        provider.advance(20);
+        drop(pipeline);
+        let played = provider.take_output("Speakers");
+
+        // The rotated segment carries on from the first, so it starts straight away.
+        config.segment = 1;
+        let pipeline = start(&provider, &config);
+        provider.advance(20);
+        drop(pipeline);
+        let played_again = provider.take_output("Speakers");
+
+        let read = |name: &str| wav::read_samples(&dir.join(name)).unwrap();
+        let (output, output_2) = (read("output.wav"), read("output-2.wav"));
+        let stems = [read("mic-raw.wav"), read("mic-processed.wav")];
+        let stems_2 = [read("mic-2-raw.wav"), read("mic-2-processed.wav")];
+        let sidecar = std::fs::read_to_string(dir.join(MARKER_SIDECAR)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output[..preroll].iter().all(|&sample| sample == 0.0));
+        assert_eq!(output[preroll..], played);
+        assert_eq!(output_2, played_again);
+        for stem in &stems {
+            assert_eq!(stem.len(), preroll + periods(21));
+            assert!(stem[..preroll].iter().all(|&sample| sample == 0.0));
+            assert!(stem[preroll..].iter().all(|&sample| sample == 0.5));
+        }
+        for stem in &stems_2 {
+            assert_eq!(stem.len(), periods(21));
+            assert!(stem.iter().all(|&sample| sample == 0.5));
+        }
+
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&sidecar).unwrap();
+        let starts = entries
+            .iter()
+            .filter(|entry| entry["start"] == true)
+            .flat_map(|entry| {
+                let files = entry["files"].as_array().unwrap();
+                files.iter().map(move |file| {
+                    let path = PathBuf::from(file["file"].as_str().unwrap());
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, file["frame"].as_u64().unwrap() as usize, entry)
+                })
+            })
+            .collect::<Vec<_>>();
+        let frame = |name: &str| {
+            let matching = starts.iter().filter(|(file, ..)| file == name);
+            assert_eq!(matching.clone().count(), 1, "{}", name);
+            matching.map(|(_, frame, _)| *frame).next().unwrap()
+        };
+        for name in ["output.wav", "mic-raw.wav", "mic-processed.wav"] {
+            assert_eq!(frame(name), preroll, "{}", name);
+        }
+        for name in ["output-2.wav", "mic-2-raw.wav", "mic-2-processed.wav"] {
+            assert_eq!(frame(name), 0, "{}", name);
+        }
+
+        // The stems' first sample came in as the pipeline started, give or take the period it
+        // was in and the sidecar's rounding to the millisecond.
+        let unix_time = |at: SystemTime| {
+            at.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64()
+        };
+        let earliest = unix_time(before) - PERIOD as f64 / RATE as f64 - 0.001;
+        let latest = unix_time(after) + 0.001;
+        for (name, _, entry) in &starts {
+            if name.starts_with("mic-raw") || name.starts_with("mic-processed") {
+                let at = entry["unix_time"].as_f64().unwrap();
+                assert!((earliest..=latest).contains(&at), "{} at {}", name, at);
+            }
+        }
+    }
+
+    #[test]
+    fn restarted_input_plays_nothing_from_before_it_was_stopped() {
+        let provider = FakeProvider::new();
+        // Enough for the periods before the stop, and silence after.
+        let periods = 11;
+        let samples = vec![0.5; (periods * PERIOD) as usize].into();
+        provider
+            .add_input("Mic", stream_config(1), Signal::Samples(samples))
+            .add_output("Speakers", stream_config(1));
+        let mut pipeline = start(&provider, &config(&["Mic"], "Speakers"));
+        provider.advance(periods as u64 - 1);
+        assert!(provider.take_output("Speakers").contains(&0.5));
+
+        // The ring buffer is still full of the signal when the input is started straight away.
+        pipeline.stop_input("Mic").unwrap();
+        pipeline.start_input(&provider, "Mic").unwrap();
+        provider.advance(20);
+        let output = provider.take_output("Speakers");
+        assert_eq!(output.len(), 20 * PERIOD as usize);
+        assert!(output.iter().all(|&sample| sample == 0.0), "{:?}", output);
+    }
+
+    /// A 441 Hz sine at `amplitude`, clamped to full scale the way a converter would.
+    fn sine(frames: usize, amplitude: f32) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                let phase = 2.0 * std::f32::consts::PI * 441.0 * frame as f32 / RATE as f32;
+                (phase.sin() * amplitude).clamp(-1.0, 1.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_the_outputs_short_term_loudness() {
+        let provider = FakeProvider::new();
+        provider
+            .add_input(
+                "Mic",
+                stream_config(1),
+                Signal::Sine {
+                    frequency: 997.0,
+                    amplitude: 0.5,
+                },
+            )
+            .add_output("Speakers", stream_config(1));
+        let pipeline = start(&provider, &config(&["Mic"], "Speakers"));
+        assert_eq!(pipeline.counters().short_term_loudness(), None);
+        provider.advance(2 * RATE as u64 / PERIOD as u64);
+        let loudness = pipeline.counters().short_term_loudness().unwrap();
+        // A mono sine 6 dB under full scale, which reads 3 dB under that again.
+        assert!((loudness + 9.03).abs() < 0.3, "{}", loudness);
+    }
+
+    #[test]
+    fn counts_a_clipped_sine_but_not_a_merely_loud_one() {
+        let clipped = sine(4_800, 1.5);
+        let mut detector = ClipDetector::new(1);
+        // Every half cycle spends about a sixth of its samples at full scale, all in one run.
+        let counted = detector.process(&clipped);
+        let at_full_scale = clipped
+            .iter()
+            .filter(|sample| sample.abs() >= CLIP_LEVEL)
+            .count();
+        assert_eq!(counted, at_full_scale as u64);
+        assert!(counted > 1_000, "{}", counted);
+
+        let mut detector = ClipDetector::new(1);
+        assert_eq!(detector.process(&sine(4_800, 0.99)), 0);
+        // A full-scale sine only touches full scale for a sample or two at a time.
+        assert_eq!(detector.process(&sine(4_800, 1.0)), 0);
+    }
+
+    #[test]
+    fn counts_runs_that_span_callbacks_on_each_channel_alone() {
+        let mut detector = ClipDetector::new(2);
+        // The left channel is at full scale for three frames, then two more in the next callback.
+        assert_eq!(
+            detector.process(&[0.5, 0.0, 1.0, 0.0, -1.0, 1.0, 1.0, 0.0]),
+            0
+        );
+        assert_eq!(detector.process(&[-1.0, 1.0, 1.0, 0.0, 0.2, 1.0]), 5);
+        // The right channel's full-scale samples never ran for long enough.
+        assert_eq!(detector.process(&[0.0, 1.0, 0.0, 1.0]), 0);
+    }
+
+    #[test]
+    fn counts_clipping_as_it_arrives_on_an_input() {
+        let (producer, _consumer) = HeapRb::<f32>::new(9_600).split();
+        let counters = Arc::new(InputCounters::default());
+        let chain = InputChain::new(1, Vec::new()).unwrap();
+        let mut process = create_input_processing_fn(producer, chain, None, Arc::clone(&counters));
+        process(&sine(2_400, 0.8));
+        assert_eq!(counters.clipped_samples(), 0);
+        process(&sine(2_400, 2.0));
+        assert!(counters.clipped_samples() > 1_000);
+    }
+
+    /// A microphone and speakers, both stereo at the same rate.
+    fn mic_and_speakers() -> FakeProvider {
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", stream_config(2), Signal::Silence)
+            .add_output("Speakers", stream_config(2));
+        provider
+    }
+
+    fn build_error(provider: &FakeProvider, config: &PipelineConfig) -> PipelineError {
+        match Pipeline::build(provider, config) {
+            Ok(_) => panic!("the pipeline built"),
+            Err(err) => err,
+        }
+    }
+
+    /// What the error's source says, which is what the backend said.
+    fn source(err: &PipelineError) -> String {
+        std::error::Error::source(err)
+            .expect("the error has a source")
+            .to_string()
+    }
+
+    #[test]
+    fn refuses_what_cant_work_whatever_the_devices() {
+        let err = build_error(&mic_and_speakers(), &config(&[], "Speakers"));
+        assert!(matches!(err, PipelineError::Invalid(_)), "{:?}", err);
+        assert!(std::error::Error::source(&err).is_none());
+    }
+
+    #[test]
+    fn names_the_devices_there_are_when_one_is_missing() {
+        let err = build_error(&mic_and_speakers(), &config(&["Mic", "Webcam"], "Speakers"));
+        let PipelineError::DeviceNotFound {
+            wanted,
+            available,
+            is_input,
+        } = &err
+        else {
+            panic!("{:?}", err);
+        };
+        assert_eq!((wanted.as_str(), is_input), ("Webcam", &true));
+        assert_eq!(available, &["Mic"]);
+        assert_eq!(
+            err.to_string(),
+            "couldn't find input device \"Webcam\", the input devices are \"Mic\""
+        );
+
+        let err = build_error(&mic_and_speakers(), &config(&["Mic"], "BlackHole 16ch"));
+        assert!(
+            matches!(&err, PipelineError::DeviceNotFound { is_input: false, available, .. }
+                if available == &["Speakers"]),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn passes_on_what_the_backend_said_when_a_device_cant_be_queried() {
+        let provider = mic_and_speakers();
+        provider.fail("Mic", Failure::Query, "the driver crashed");
+        let err = build_error(&provider, &config(&["Mic"], "Speakers"));
+        assert!(
+            matches!(&err, PipelineError::DeviceQuery { device, .. } if device == "Mic"),
+            "{:?}",
+            err
+        );
+        assert_eq!(source(&err), "the driver crashed");
+    }
+
+    #[test]
+    fn lists_what_a_device_supports_when_it_cant_do_whats_asked() {
+        let mut config = config(&["Mic"], "Speakers");
+        config.output_channels = Some(16);
+        config.strict_routing = true;
+        let err = build_error(&mic_and_speakers(), &config);
+        let PipelineError::ConfigNotSupported {
+            device, requested, ..
+        } = &err
+        else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(
+            (device.as_str(), requested.as_str()),
+            ("Speakers", "16 channels")
+        );
+        assert_eq!(
+            err.to_string(),
+            "\"Speakers\" doesn't support 16 channels, only 2 channels at 48000 Hz"
+        );
+    }
+
+    #[test]
+    fn refuses_rates_that_cant_be_resampled_to_each_other() {
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", stream_config(2), Signal::Silence)
+            .add_output(
+                "Speakers",
+                StreamConfig {
+                    sample_rate: cpal::SampleRate(32_000),
+                    ..stream_config(2)
+                },
+            );
+        let mut config = config(&["Mic"], "Speakers");
+        config.negotiate = true;
+        let err = build_error(&provider, &config);
+        assert!(
+            matches!(err, PipelineError::NoCommonSampleRate { .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn tells_an_output_held_exclusively_from_one_that_fails_to_build() {
+        let provider = mic_and_speakers();
+        provider.fail("Mic", Failure::Build, "out of buffers");
+        let err = build_error(&provider, &config(&["Mic"], "Speakers"));
+        assert!(
+            matches!(&err, PipelineError::StreamBuild { device, .. } if device == "Mic"),
+            "{:?}",
+            err
+        );
+        assert_eq!(source(&err), "out of buffers");
+
+        let provider = mic_and_speakers();
+        provider.fail("Speakers", Failure::Build, "Device or resource busy");
+        let err = build_error(&provider, &config(&["Mic"], "Speakers"));
+        assert!(
+            matches!(&err, PipelineError::OutputBusy { device, .. } if device == "Speakers"),
+            "{:?}",
+            err
+        );
+        assert_eq!(source(&err), "Device or resource busy");
+    }
+
+    #[test]
+    fn parses_splits_into_an_input_per_named_channel() {
+        let inputs = parse_split("Scarlett Solo=left:Me,right:Cohost").unwrap();
+        let parsed = inputs
+            .iter()
+            .map(|input| {
+                (
+                    input.name.as_str(),
+                    input.device.as_str(),
+                    input.channels.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parsed,
+            [
+                ("Me", "Scarlett Solo", Some(0..1)),
+                ("Cohost", "Scarlett Solo", Some(1..2))
+            ]
+        );
+        // Only naming one channel ignores the other.
+        let inputs = parse_split("Scarlett Solo=2:Cohost").unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].channels, Some(1..2));
+
+        for bad in [
+            "Scarlett Solo",
+            "Solo=middle:Me",
+            "Solo=0:Me",
+            "Solo=left",
+            "Solo=left:",
+        ] {
+            assert!(parse_split(bad).is_err(), "{}", bad);
+        }
+    }
+
+    /// Plays `inputs` taken from one `device_channels` channel device whose channel `c` is always
+    /// `c + 1` tenths, and returns the last frame of the output before and after `muted` is faded
+    /// out. The inputs repeat their channels across as many as the device has.
+    fn mute_one_part(
+        device_channels: u16,
+        inputs: Vec<InputConfig>,
+        muted: &str,
+    ) -> (Vec<f32>, Vec<f32>) {
+        let frame = (1..=device_channels)
+            .map(|channel| channel as f32 / 10.0)
+            .collect::<Vec<_>>();
+        let provider = FakeProvider::new();
+        provider
+            .add_input(
+                "Device",
+                stream_config(device_channels),
+                Signal::Samples(frame.repeat(100 * PERIOD as usize).into()),
+            )
+            .add_output("Speakers", stream_config(device_channels));
+        let config = PipelineConfig {
+            inputs,
+            ..config(&[], "Speakers")
+        };
+        let pipeline = start(&provider, &config);
+        let last_frame = || {
+            let output = provider.take_output("Speakers");
+            output[output.len() - device_channels as usize..].to_vec()
+        };
+        provider.advance(20);
+        let before = last_frame();
+        pipeline.override_gain(muted).unwrap().set_muted(true);
+        provider.advance(20);
+        (before, last_frame())
+    }
+
+    #[test]
+    fn plays_each_side_of_a_split_device_as_an_input_of_its_own() {
+        let inputs = parse_split("Device=left:Me,right:Cohost").unwrap();
+        let (before, after) = mute_one_part(2, inputs, "Me");
+        // Each side is played on both channels of the output.
+        let close =
+            |frame: &[f32], expected: f32| frame.iter().all(|s| (s - expected).abs() < 1e-6);
+        assert!(close(&before, 0.3), "{:?}", before);
+        assert!(close(&after, 0.2), "{:?}", after);
+    }
+
+    #[test]
+    fn parses_subinputs_as_ranges_of_channels() {
+        let input = parse_subinput("Game Capture HD60 X=3-4:Chat").unwrap();
+        assert_eq!(
+            (input.name.as_str(), input.device.as_str(), input.channels),
+            ("Chat", "Game Capture HD60 X", Some(2..4))
+        );
+        assert_eq!(parse_subinput("HD60=3:Chat").unwrap().channels, Some(2..3));
+        for bad in [
+            "HD60",
+            "HD60=3-4",
+            "HD60=4-3:Chat",
+            "HD60=0-1:Chat",
+            "HD60=x:Chat",
+            "HD60=3:",
+        ] {
+            assert!(parse_subinput(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn ducks_one_subinput_while_leaving_the_other_alone() {
+        let inputs = ["Device=1-2:Game", "Device=3-4:Chat"]
+            .into_iter()
+            .map(|subinput| parse_subinput(subinput).unwrap())
+            .collect();
+        let (before, after) = mute_one_part(4, inputs, "Game");
+        let close = |frame: &[f32], expected: [f32; 4]| {
+            frame
+                .iter()
+                .zip(expected)
+                .all(|(s, expected)| (s - expected).abs() < 1e-6)
+        };
+        assert_eq!(before.len(), 4);
+        assert!(close(&before, [0.4, 0.6, 0.4, 0.6]), "{:?}", before);
+        assert!(close(&after, [0.3, 0.4, 0.3, 0.4]), "{:?}", after);
+    }
+
+    /// Builds `inputs` from a mono microphone and a four channel capture device.
+    fn build_split(inputs: Vec<InputConfig>) -> Result<Pipeline, PipelineError> {
+        let provider = FakeProvider::new();
+        provider
+            .add_input("Mic", stream_config(1), Signal::Silence)
+            .add_input("HD60", stream_config(4), Signal::Silence)
+            .add_output("Speakers", stream_config(2));
+        Pipeline::build(
+            &provider,
+            &PipelineConfig {
+                inputs,
+                ..config(&[], "Speakers")
+            },
+        )
+    }
+
+    #[test]
+    fn refuses_to_split_a_mono_device() {
+        let err = build_split(parse_split("Mic=left:Me").unwrap())
+            .err()
+            .unwrap();
+        let PipelineError::ConfigNotSupported {
+            device, requested, ..
+        } = &err
+        else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(
+            (device.as_str(), requested.as_str()),
+            ("Mic", "being split, since it's mono")
+        );
+        assert_eq!(
+            err.to_string(),
+            "\"Mic\" doesn't support being split, since it's mono, only 1 channels at 48000 Hz"
+        );
+    }
+
+    #[test]
+    fn refuses_a_device_used_whole_and_split_at_once() {
+        let mut inputs = vec![InputConfig::new("HD60")];
+        inputs.extend(parse_split("HD60=left:Game").unwrap());
+        let err = build_split(inputs).err().unwrap();
+        assert!(matches!(err, PipelineError::Invalid(_)), "{:?}", err);
+        assert_eq!(
+            err.to_string(),
+            "\"HD60\" can't be used whole and split at the same time"
+        );
+
+        let twice = InputConfig {
+            device: "Mic".to_owned(),
+            ..InputConfig::new("Mic again")
+        };
+        let err = build_split(vec![InputConfig::new("Mic"), twice])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "\"Mic\" is used as an input more than once"
+        );
+    }
+
+    #[test]
+    fn refuses_subinputs_that_overlap() {
+        let inputs = ["HD60=1-2:Game", "HD60=2-3:Chat"]
+            .into_iter()
+            .map(|subinput| parse_subinput(subinput).unwrap())
+            .collect();
+        let err = build_split(inputs).err().unwrap();
+        assert!(matches!(err, PipelineError::Invalid(_)), "{:?}", err);
+        assert_eq!(
+            err.to_string(),
+            "\"Game\" and \"Chat\" both take channels of \"HD60\" in channels 1-2 and channels 2-3"
+        );
+    }
+
+    #[test]
+    fn refuses_subinputs_past_the_devices_channels() {
+        let inputs = ["HD60=1-2:Game", "HD60=4-5:Chat"]
+            .into_iter()
+            .map(|subinput| parse_subinput(subinput).unwrap())
+            .collect();
+        let err = build_split(inputs).err().unwrap();
+        let PipelineError::ConfigNotSupported {
+            device,
+            requested,
+            supported,
+        } = &err
+        else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(
+            (device.as_str(), requested.as_str()),
+            ("HD60", "channels 4-5 for \"Chat\"")
+        );
+        assert_eq!(supported.len(), 1);
+        assert_eq!(
+            err.to_string(),
+            "\"HD60\" doesn't support channels 4-5 for \"Chat\", only 4 channels at 48000 Hz"
+        );
+    }
+
+    #[test]
+    fn passes_on_what_the_backend_said_when_a_stream_wont_start() {
+        let provider = mic_and_speakers();
+        provider.fail("Mic", Failure::Start, "the device is asleep");
+        let pipeline = Pipeline::build(&provider, &config(&["Mic"], "Speakers")).unwrap();
+        let err = pipeline.start_inputs().unwrap_err();
+        assert!(
+            matches!(&err, PipelineError::StreamStart { device, .. } if device == "Mic"),
+            "{:?}",
+            err
+        );
+        assert_eq!(source(&err), "the device is asleep");
+    }
+
+    #[test]
+    fn names_the_inputs_that_never_delivered() {
+        let provider = mic_and_speakers();
+        // The fake clock never moves unless it's advanced, so the input never delivers.
+        let mut pipeline = Pipeline::build(&provider, &config(&["Mic"], "Speakers")).unwrap();
+        let err = pipeline.play().unwrap_err();
+        assert!(
+            matches!(&err, PipelineError::StartTimeout { devices, timeout }
+                if devices == &["Mic"] && *timeout == START_TIMEOUT),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "\"Mic\" didn't deliver any audio within 2s of starting"
+        );
+    }
+
+    #[test]
+    fn passes_on_why_a_recording_couldnt_start() {
+        let missing = std::env::temp_dir().join(format!("loopback-missing-{}", std::process::id()));
+        let mut config = config(&["Mic"], "Speakers");
+        config.record_output = Some(missing.join("out.wav"));
+        config.marker_sidecar = missing.join(MARKER_SIDECAR);
+        let err = build_error(&mic_and_speakers(), &config);
+        assert!(matches!(err, PipelineError::Recording { .. }), "{:?}", err);
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
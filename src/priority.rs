@@ -0,0 +1,183 @@
+//! Raises the audio callbacks' threads to real-time priority, where the platform allows it.
+//!
+//! Only the threads the backend runs callbacks on are raised, from inside their first callback.
+//! Every other thread, like the recorder's writer and the device monitor, is spawned by the control
+//! thread and stays at normal priority. How that went is reported from the control thread, so the
+//! callbacks never write to the terminal.
+//!
+//! With the `rt-priority` feature, audio_thread_priority is tried first: it asks MMCSS for the
+//! "Pro Audio" class on Windows, rtkit for real-time scheduling on Linux, and Mach for a
+//! time-constraint policy on macOS. What the platform allows directly is the fallback.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use crate::backend::StreamConfig;
+
+/// Raises the priority of the calling thread as far as the platform lets this process, returning
+/// what was done or why it couldn't be. The thread renders `period_frames` at a time at
+/// `sample_rate`, with 0 frames for a period that isn't known.
+pub fn raise_current_thread(period_frames: u32, sample_rate: u32) -> Result<&'static str, String> {
+    #[cfg(feature = "rt-priority")]
+    {
+        library::raise(period_frames, sample_rate)
+            .or_else(|why| platform::raise().map_err(|fallback| format!("{}; {}", why, fallback)))
+    }
+    #[cfg(not(feature = "rt-priority"))]
+    {
+        let _ = (period_frames, sample_rate);
+        platform::raise()
+    }
+}
+
+/// How raising one thread went, once it has been tried.
+#[derive(Debug)]
+pub struct RaiseReport {
+    thread: String,
+    result: OnceLock<Result<&'static str, String>>,
+    reported: AtomicBool,
+}
+
+impl RaiseReport {
+    /// Says how raising the thread went, as a warning if it didn't work, the first time this is
+    /// called after it's been tried. Returns whether it has been reported.
+    pub fn report(&self) -> bool {
+        let Some(result) = self.result.get() else {
+            return false;
+        };
+        if self.reported.swap(true, Ordering::Relaxed) {
+            return true;
+        }
+        match result {
+            Ok(how) => log::info!("Raised the \"{}\" audio thread to {}.", self.thread, how),
+            Err(why) => log::warn!(
+                "couldn't raise the \"{}\" audio thread's priority, so it stays at normal \
+                 priority: {}",
+                self.thread,
+                why
+            ),
+        }
+        true
+    }
+}
+
+/// Raises the thread it's first polled on, for calling at the top of a callback.
+pub struct RaiseOnce {
+    /// Where to leave how it went, until the thread has been raised.
+    pending: Option<Arc<RaiseReport>>,
+    period_frames: u32,
+    sample_rate: u32,
+}
+
+impl RaiseOnce {
+    /// Does nothing at all unless `enabled`. The thread's callbacks are for a stream of `config`.
+    pub fn new(enabled: bool, thread: &str, config: &StreamConfig) -> Self {
+        RaiseOnce {
+            period_frames: match config.buffer_size {
+                cpal::BufferSize::Fixed(frames) => frames,
+                cpal::BufferSize::Default => 0,
+            },
+            sample_rate: config.sample_rate.0,
+            pending: enabled.then(|| {
+                Arc::new(RaiseReport {
+                    thread: thread.to_owned(),
+                    result: OnceLock::new(),
+                    reported: AtomicBool::new(false),
+                })
+            }),
+        }
+    }
+
+    /// Where the control thread can find out how raising the thread went, unless it won't be.
+    pub fn report(&self) -> Option<Arc<RaiseReport>> {
+        self.pending.clone()
+    }
+
+    pub fn poll(&mut self) {
+        if let Some(report) = self.pending.take() {
+            let _ = report
+                .result
+                .set(raise_current_thread(self.period_frames, self.sample_rate));
+        }
+    }
+}
+
+#[cfg(feature = "rt-priority")]
+mod library {
+    /// What the library does on this platform, for the report.
+    const HOW: &str = if cfg!(windows) {
+        "the MMCSS \"Pro Audio\" class"
+    } else if cfg!(target_os = "linux") {
+        "real-time scheduling through rtkit"
+    } else {
+        "a real-time time-constraint policy"
+    };
+
+    pub fn raise(period_frames: u32, sample_rate: u32) -> Result<&'static str, String> {
+        match audio_thread_priority::promote_current_thread_to_real_time(period_frames, sample_rate)
+        {
+            // The handle is only needed to demote the thread again, and it stays raised until it
+            // exits.
+            Ok(_handle) => Ok(HOW),
+            Err(err) => Err(format!("audio_thread_priority couldn't: {}", err)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    /// Low enough to leave the kernel's own real-time threads alone.
+    const PRIORITY: libc::c_int = 10;
+
+    pub fn raise() -> Result<&'static str, String> {
+        let param = libc::sched_param {
+            sched_priority: PRIORITY,
+        };
+        // SAFETY: `param` outlives the call, and `pthread_self` is always a valid thread.
+        let err = unsafe {
+            libc::pthread_setschedparam(
+                libc::pthread_self(),
+                libc::SCHED_FIFO | libc::SCHED_RESET_ON_FORK,
+                &param,
+            )
+        };
+        match err {
+            0 => Ok("SCHED_FIFO"),
+            libc::EPERM => Err(format!(
+                "SCHED_FIFO at priority {} isn't permitted: allow it with an `rtprio` of at least {} \
+                 in /etc/security/limits.conf (or `ulimit -r`), or give the binary CAP_SYS_NICE",
+                PRIORITY, PRIORITY
+            )),
+            err => Err(format!(
+                "pthread_setschedparam failed: {}",
+                std::io::Error::from_raw_os_error(err)
+            )),
+        }
+    }
+}
+
+#[cfg(target_vendor = "apple")]
+mod platform {
+    pub fn raise() -> Result<&'static str, String> {
+        // CoreAudio already runs its callbacks on time-constrained threads, and this makes sure
+        // the scheduler treats them as interactive on top of that.
+        // SAFETY: this only changes the calling thread's own QoS class.
+        let err = unsafe {
+            libc::pthread_set_qos_class_self_np(libc::qos_class_t::QOS_CLASS_USER_INTERACTIVE, 0)
+        };
+        match err {
+            0 => Ok("the user-interactive QoS class"),
+            err => Err(format!(
+                "pthread_set_qos_class_self_np failed: {}",
+                std::io::Error::from_raw_os_error(err)
+            )),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+mod platform {
+    pub fn raise() -> Result<&'static str, String> {
+        Err("raising thread priority isn't supported on this platform yet".to_owned())
+    }
+}
@@ -0,0 +1,285 @@
+//! Measures the sample rate each input really delivers at, going by the wall clock, since a
+//! device that claims 48 kHz can run a few tens of ppm fast or slow and slowly drift away from
+//! anything recorded alongside it.
+//!
+//! The control thread notes how many frames an input has delivered every so often, and the rate is
+//! the slope of a least-squares line through those counts. Start-up is ignored, and counts that
+//! stray from the line, like while a device stalls and then catches up, are left out of the fit.
+//! If they keep straying, frames were lost or the device changed pace, so the fit starts over.
+//!
+//! The rate is only reported. Nothing feeds it back into the resampler, whose ratio is fixed.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long after its first frames an input is left to settle before it's measured.
+const WARM_UP: Duration = Duration::from_secs(5);
+/// How long an input has to be measured for before its rate is worth reporting.
+const MIN_SPAN: Duration = Duration::from_secs(20);
+/// How long counts can stray from the line before the fit starts over.
+const RESTART_AFTER: Duration = Duration::from_secs(1);
+/// The least a count can stray from the line by, in seconds of audio, before it's left out.
+const MIN_TOLERANCE_SECS: f64 = 0.01;
+/// How far from its nominal rate an input can run before it's warned about.
+pub const WARNING_PPM: f64 = 50.0;
+
+/// A running estimate of one input's sample rate.
+#[derive(Debug)]
+pub struct RateEstimator {
+    nominal: f64,
+    /// When the first frames arrived.
+    first: Option<Instant>,
+    /// The frame count at the last observation.
+    last_frames: u64,
+    /// The most frames delivered between two observations during the warm-up, which is about
+    /// how far a count can stray from the line just from how the device cuts up its buffers.
+    step: u64,
+    fit: Option<Fit>,
+    /// Since when counts have strayed from the line.
+    straying_since: Option<Instant>,
+}
+
+/// A least-squares line through frame counts, measured from its first.
+#[derive(Debug)]
+struct Fit {
+    origin: (Instant, u64),
+    points: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+    /// The time of the latest point, in seconds from the origin.
+    span: f64,
+}
+
+impl Fit {
+    fn new(now: Instant, frames: u64) -> Self {
+        let mut fit = Fit {
+            origin: (now, frames),
+            points: 0.0,
+            sum_x: 0.0,
+            sum_y: 0.0,
+            sum_xx: 0.0,
+            sum_xy: 0.0,
+            span: 0.0,
+        };
+        fit.add(0.0, 0.0);
+        fit
+    }
+
+    fn add(&mut self, x: f64, y: f64) {
+        self.points += 1.0;
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_xx += x * x;
+        self.sum_xy += x * y;
+        self.span = self.span.max(x);
+    }
+
+    /// Frames per second, once there are points far enough apart.
+    fn slope(&self) -> Option<f64> {
+        let denominator = self.points * self.sum_xx - self.sum_x * self.sum_x;
+        (self.span >= 1.0 && denominator > 0.0)
+            .then(|| (self.points * self.sum_xy - self.sum_x * self.sum_y) / denominator)
+    }
+}
+
+impl RateEstimator {
+    pub fn new(nominal: u32) -> Self {
+        RateEstimator {
+            nominal: nominal as f64,
+            first: None,
+            last_frames: 0,
+            step: 0,
+            fit: None,
+            straying_since: None,
+        }
+    }
+
+    /// Notes that the input had delivered `frames` frames in all by `now`.
+    pub fn observe(&mut self, now: Instant, frames: u64) {
+        if frames == 0 {
+            return;
+        }
+        let first = *self.first.get_or_insert(now);
+        let step = frames.saturating_sub(self.last_frames);
+        self.last_frames = frames;
+        if now.duration_since(first) < WARM_UP {
+            self.step = self.step.max(step);
+            return;
+        }
+        let Some(fit) = &mut self.fit else {
+            self.fit = Some(Fit::new(now, frames));
+            return;
+        };
+
+        let x = now.duration_since(fit.origin.0).as_secs_f64();
+        let y = frames as f64 - fit.origin.1 as f64;
+        let predicted = fit.slope().unwrap_or(self.nominal) * x;
+        let tolerance = (self.step as f64).max(self.nominal * MIN_TOLERANCE_SECS);
+        if (y - predicted).abs() <= tolerance {
+            fit.add(x, y);
+            self.straying_since = None;
+        } else if now.duration_since(*self.straying_since.get_or_insert(now)) >= RESTART_AFTER {
+            self.fit = Some(Fit::new(now, frames));
+            self.straying_since = None;
+        }
+    }
+
+    /// The measured rate, once the input has been measured for long enough.
+    pub fn rate(&self) -> Option<Rate> {
+        let fit = self.fit.as_ref()?;
+        if fit.span < MIN_SPAN.as_secs_f64() {
+            return None;
+        }
+        Some(Rate {
+            hz: fit.slope()?,
+            nominal: self.nominal,
+        })
+    }
+}
+
+/// A measured sample rate, next to the one the device claims.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub hz: f64,
+    pub nominal: f64,
+}
+
+impl Rate {
+    /// How far off nominal the rate is, in parts per million.
+    pub fn ppm(&self) -> f64 {
+        (self.hz / self.nominal - 1.0) * 1e6
+    }
+}
+
+impl fmt::Display for Rate {
+    /// Like `47,996.2 Hz, -79 ppm`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tenths = (self.hz * 10.0).round() as u64;
+        let whole = (tenths / 10).to_string();
+        let mut grouped = String::new();
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i).is_multiple_of(3) {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        write!(f, "{}.{} Hz, {:+.0} ppm", grouped, tenths % 10, self.ppm())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+
+    const CLAIMED: u32 = 48_000;
+    const REAL: f64 = 47_996.2;
+
+    /// How many frames an input running at `hz` has delivered `t` seconds in, 512 at a time.
+    fn buffers(hz: f64, t: f64) -> u64 {
+        (t * hz) as u64 / 512 * 512
+    }
+
+    /// Polls `estimator` every 100 ms or so, a few ms early or late each time, over `seconds`
+    /// after `start`, with `delivered` giving how many frames the input had delivered by then.
+    fn poll(
+        estimator: &mut RateEstimator,
+        start: Instant,
+        seconds: Range<u32>,
+        delivered: impl Fn(f64) -> u64,
+    ) {
+        for tick in seconds.start * 10..seconds.end * 10 {
+            let jitter = (tick * 7_919 % 11) as f64 - 5.0;
+            let t = tick as f64 / 10.0 + jitter / 1_000.0;
+            estimator.observe(start + Duration::from_secs_f64(t.max(0.0)), delivered(t));
+        }
+    }
+
+    fn assert_close(estimator: &RateEstimator) {
+        let rate = estimator.rate().expect("measured for long enough");
+        let real_ppm = (REAL / CLAIMED as f64 - 1.0) * 1e6;
+        assert!((rate.ppm() - real_ppm).abs() < 5.0, "{}", rate);
+        assert_eq!(rate.nominal, CLAIMED as f64);
+    }
+
+    #[test]
+    fn measures_a_steady_input_once_it_has_settled() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(CLAIMED);
+        poll(&mut estimator, start, 0..24, |t| buffers(REAL, t));
+        assert_eq!(estimator.rate(), None);
+        poll(&mut estimator, start, 24..60, |t| buffers(REAL, t));
+        assert_close(&estimator);
+    }
+
+    #[test]
+    fn ignores_how_an_input_starts_up() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(CLAIMED);
+        // Nothing for half a second, then twice the rate while it fills up, then steady.
+        poll(&mut estimator, start, 0..60, |t| match t {
+            t if t < 0.5 => 0,
+            t if t < 3.0 => buffers(2.0 * REAL, t - 0.5),
+            t => buffers(2.0 * REAL, 2.5) + buffers(REAL, t - 3.0),
+        });
+        assert_close(&estimator);
+    }
+
+    #[test]
+    fn rides_out_a_stall_that_catches_up() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(CLAIMED);
+        poll(&mut estimator, start, 0..60, |t| {
+            if (30.0..30.5).contains(&t) {
+                buffers(REAL, 30.0)
+            } else {
+                buffers(REAL, t)
+            }
+        });
+        assert_close(&estimator);
+    }
+
+    #[test]
+    fn starts_over_once_frames_are_lost() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(CLAIMED);
+        // Half a second's worth never arrives.
+        let delivered = |t: f64| {
+            if t < 30.0 {
+                buffers(REAL, t)
+            } else {
+                buffers(REAL, t) - 24_064
+            }
+        };
+        poll(&mut estimator, start, 0..30, delivered);
+        assert!(estimator.rate().is_some());
+        poll(&mut estimator, start, 30..40, delivered);
+        assert_eq!(estimator.rate(), None);
+        poll(&mut estimator, start, 40..60, delivered);
+        assert_close(&estimator);
+    }
+
+    #[test]
+    fn waits_for_the_first_frames() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(CLAIMED);
+        poll(&mut estimator, start, 0..30, |_| 0);
+        poll(&mut estimator, start, 30..50, |t| buffers(REAL, t - 30.0));
+        assert_eq!(estimator.rate(), None);
+        poll(&mut estimator, start, 50..90, |t| buffers(REAL, t - 30.0));
+        assert_close(&estimator);
+    }
+
+    #[test]
+    fn shows_the_rate_grouped_with_its_ppm() {
+        let rate = |hz| Rate {
+            hz,
+            nominal: 48_000.0,
+        };
+        assert_eq!(rate(47_996.2).to_string(), "47,996.2 Hz, -79 ppm");
+        assert_eq!(rate(48_000.0).to_string(), "48,000.0 Hz, +0 ppm");
+        assert_eq!(rate(192_004.96).to_string(), "192,005.0 Hz, +3000103 ppm");
+    }
+}
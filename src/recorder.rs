@@ -0,0 +1,1831 @@
+//! Records taps on the pipeline to WAV files from a dedicated writer thread.
+//!
+//! The audio callbacks only ever push into a [`RecordTap`]'s ring buffer, and the writer thread
+//! drains every tap into its file, so no file I/O happens on the audio threads.
+//!
+//! A track can be written to a mirror as well, like a copy on an external drive. If writing to
+//! either file fails, the writer thread gives up on that one and carries on with the other, and
+//! the failure is kept for [`Recorder::failures`] rather than stopping the recording.
+//!
+//! A tap can also note changes to an input's fader as the audio thread applies them, which the
+//! writer thread adds to the marker sidecar at the frame of the file they happened at.
+//!
+//! A track can have a ceiling, which a [`Limiter`] of its own keeps it under on the writer
+//! thread. The limiter's delay is taken back out, so the file still lines up with the others.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc, Arc, Mutex,
+};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+
+use crate::chain::OVERRIDE_FADE_MS;
+use crate::control::parse_duration;
+use crate::error::{parse_error, ParseError};
+use crate::limiter::Limiter;
+use crate::memory::Allocations;
+use crate::resample::{Quality, Resampler};
+use crate::wav::{self, WavWriter};
+
+/// How often the writer thread drains the taps.
+const WRITE_INTERVAL: Duration = Duration::from_millis(20);
+/// How often the writer thread fills in the headers unless told otherwise, so a killed process
+/// leaves valid files.
+const HEADER_INTERVAL: Duration = Duration::from_secs(1);
+/// How much audio each tap can hold before the writer thread gets to it.
+const TAP_BUFFER_MS: usize = 2_000;
+/// How many samples the writer thread moves from a tap to its file at once.
+const STAGING_SAMPLES: usize = 8_192;
+/// How many fader changes a tap can hold before the writer thread gets to them.
+const FADER_CHANGES: usize = 256;
+/// The most memory the replay buffer is allowed, which is over an hour of 48 kHz stereo.
+const MAX_REPLAY_BYTES: usize = 1 << 30;
+
+/// A file to record into.
+#[derive(Clone, Debug)]
+pub struct TrackSpec {
+    pub path: PathBuf,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Frames of silence written before anything from the tap, to line the track up with others
+    /// whose taps come later in the chain.
+    pub leading_silence_frames: usize,
+    pub sync: SyncPolicy,
+    /// Another file to write the same audio to.
+    pub mirror: Option<MirrorSpec>,
+    /// The input whose fader changes are noted in the sidecar, through [`RecordTap::fader`].
+    pub fader: Option<String>,
+    /// Limits the file and its mirror to this many dBTP.
+    pub ceiling: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorSpec {
+    pub path: PathBuf,
+    pub sync: SyncPolicy,
+}
+
+/// How often a file's header is filled in and what's been written handed to the OS, and
+/// whether to wait for it to reach the disk each time, which is slow on some drives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncPolicy {
+    pub interval: Duration,
+    pub fsync: bool,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy {
+            interval: HEADER_INTERVAL,
+            fsync: false,
+        }
+    }
+}
+
+impl SyncPolicy {
+    /// Parses an interval, `fsync`, or both like `5s,fsync`.
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        let mut policy = SyncPolicy::default();
+        for part in value.split(',') {
+            match part.trim() {
+                "fsync" => policy.fsync = true,
+                interval => {
+                    policy.interval = parse_duration(interval)
+                        .map_err(|err| err.context("expected an interval or `fsync`"))?;
+                    if policy.interval.is_zero() {
+                        return Err(parse_error!("the interval to sync at can't be 0"));
+                    }
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Where markers go, on top of the recordings they point into.
+#[derive(Clone, Debug)]
+pub struct MarkerOptions {
+    /// A JSON array of every marker, with the offset into each file it applies to.
+    pub sidecar: PathBuf,
+    /// Empties the sidecar, rather than going on from markers made before a rebuild.
+    pub new_session: bool,
+    /// Also embeds markers in the WAV files as cue points.
+    pub cues: bool,
+}
+
+/// A rolling history of the output, which `clip` writes to a file after the fact.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplaySpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub duration: Duration,
+}
+
+impl ReplaySpec {
+    fn samples(&self) -> usize {
+        (self.duration.as_secs_f64() * self.sample_rate as f64).round() as usize
+            * self.channels as usize
+    }
+}
+
+/// The audio thread's end of a track.
+pub struct RecordTap {
+    producer: HeapProducer<f32>,
+    /// How many samples have made it into the ring buffer, which is where the file will be up to
+    /// once the writer thread catches up.
+    queued: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+    channels: u16,
+    /// Only there if the track's spec names a fader.
+    faders: Option<HeapProducer<FaderChange>>,
+}
+
+/// A fader reaching for a new linear gain, from a frame of the tap on.
+#[derive(Clone, Copy, Debug)]
+struct FaderChange {
+    frame: u64,
+    gain: f32,
+}
+
+impl RecordTap {
+    /// Queues samples for the writer thread without blocking, dropping whatever doesn't fit.
+    pub fn write(&mut self, samples: &[f32]) {
+        let pushed = self.producer.push_slice(samples);
+        self.queued.fetch_add(pushed as u64, Ordering::Relaxed);
+        if pushed < samples.len() {
+            self.dropped
+                .fetch_add((samples.len() - pushed) as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Notes that the fader starts fading to linear `gain` from the next sample written, without
+    /// blocking. A change that doesn't fit is dropped.
+    pub fn fader(&mut self, gain: f32) {
+        if let Some(faders) = &mut self.faders {
+            let frame = self.queued.load(Ordering::Relaxed) / self.channels as u64;
+            let _ = faders.push(FaderChange { frame, gain });
+        }
+    }
+}
+
+struct Track {
+    spec: TrackSpec,
+    consumer: HeapConsumer<f32>,
+    dropped: Arc<AtomicU64>,
+    /// Samples dropped by the taps that fed the file before this one, if it was carried on.
+    earlier_dropped: u64,
+    /// The file and its mirror, if it has one. Empty once the track has been aborted.
+    sinks: Vec<Sink>,
+    /// Whether the first sample from the tap has arrived, and its time been noted in the sidecar.
+    started: bool,
+    failures: Arc<Mutex<Vec<String>>>,
+    faders: Option<HeapConsumer<FaderChange>>,
+    /// The tap's rate, which is the file's unless it's being resampled to it.
+    tap_rate: u32,
+    /// The frame of the file that the tap's first frame goes at.
+    base: u64,
+    resampling: Option<Resampling>,
+    limiter: Option<Limiter>,
+    /// Samples still to drop from the limiter's output, which start with its delay.
+    delayed: usize,
+}
+
+/// Converts a tap carried on into a file of another rate, on the writer thread.
+struct Resampling {
+    resampler: Resampler,
+    output: Vec<f32>,
+    /// Samples still to drop from the output, which start with the filter's delay.
+    delayed: usize,
+    /// Frames in, and frames out once the delay is dropped, to tell how many the file is owed.
+    frames_in: u64,
+    frames_out: u64,
+}
+
+struct Sink {
+    path: PathBuf,
+    /// `None` once writing to the file has failed.
+    writer: Option<WavWriter>,
+    sync: SyncPolicy,
+    last_sync: Instant,
+}
+
+/// What to do with a recording carried on into a rebuilt pipeline whose stream for it no longer
+/// has the file's sample rate or channel count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatChange {
+    /// Finishes the file and goes on in a new one, numbered like a new segment's.
+    Split,
+    /// Resamples to the file's rate, or splits if that can't be done, like when the channel count
+    /// changed.
+    Resample,
+    /// Finishes the file, and records nothing more to it.
+    Abort,
+}
+
+impl FormatChange {
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "split" => Ok(FormatChange::Split),
+            "resample" => Ok(FormatChange::Resample),
+            "abort" => Ok(FormatChange::Abort),
+            _ => Err(parse_error!(
+                "expected `split`, `resample` or `abort`, got `{}`",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FormatChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FormatChange::Split => "split",
+            FormatChange::Resample => "resample",
+            FormatChange::Abort => "abort",
+        })
+    }
+}
+
+/// Owns the writer thread, which finishes every file when this is dropped.
+pub struct Recorder {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    requests: mpsc::Sender<Request>,
+    /// The replay buffer's format, if there is one.
+    replay: Option<ReplaySpec>,
+    /// Where every track is up to, to place markers with.
+    positions: Vec<Position>,
+    failures: Arc<Mutex<Vec<String>>>,
+    /// Every track carried on into a stream of another format, and what was done about it.
+    format_changes: Vec<String>,
+}
+
+/// Where a track's file is up to, as far as the control thread can tell.
+struct Position {
+    path: PathBuf,
+    channels: u16,
+    file_rate: u32,
+    tap_rate: u32,
+    /// The frame of the file that the tap's first frame goes at.
+    base: u64,
+    queued: Arc<AtomicU64>,
+    /// Whether nothing more is recorded to the file.
+    aborted: bool,
+}
+
+impl Position {
+    /// The frame of the file that the tap's next frame will go at.
+    fn frame(&self) -> u64 {
+        let frames = self.queued.load(Ordering::Relaxed) / self.channels as u64;
+        self.base + frames * self.file_rate as u64 / self.tap_rate as u64
+    }
+}
+
+/// Something for the writer thread to do, on top of writing the tracks.
+enum Request {
+    Marker(Marker),
+    /// Writes the last of the replay buffer to a file of its own, or all of it if `None`.
+    Clip(Option<Duration>),
+    /// Swaps every track's tap, and the replay buffer's, for those of a rebuilt pipeline.
+    Continue(Vec<Continuation>, Option<ReplayTap>),
+}
+
+struct Marker {
+    label: String,
+    at: SystemTime,
+    /// The frame in each file that the marker points at.
+    frames: Vec<u64>,
+}
+
+/// A track's new tap, and what to do with its file.
+struct Continuation {
+    spec: TrackSpec,
+    consumer: HeapConsumer<f32>,
+    dropped: Arc<AtomicU64>,
+    faders: Option<HeapConsumer<FaderChange>>,
+    change: Change,
+}
+
+enum Change {
+    /// Carries on in the same file, with the tap's first frame at `base`.
+    Continue {
+        base: u64,
+    },
+    /// Carries on in the same file, resampled to its rate.
+    Resample {
+        base: u64,
+        resampler: Resampler,
+    },
+    /// Finishes the file and carries on in new ones, with a limiter of their own.
+    Split {
+        sinks: Vec<Sink>,
+        limiter: Option<Box<Limiter>>,
+    },
+    Abort,
+    /// Was aborted before, and stays that way.
+    Aborted,
+}
+
+struct ReplayTap {
+    spec: ReplaySpec,
+    consumer: HeapConsumer<f32>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    /// Creates every file and starts the writer thread, returning a tap per track in the same
+    /// order as `specs`, and a tap for the replay buffer if there is one. Everything the taps and
+    /// the writer thread need is allocated here, and registered in `allocations`.
+    pub fn start(
+        specs: Vec<TrackSpec>,
+        marker_options: MarkerOptions,
+        replay: Option<ReplaySpec>,
+        allocations: &mut Allocations,
+    ) -> Result<(Self, Vec<RecordTap>, Option<RecordTap>), RecorderError> {
+        let (replay_spec, replay, replay_tap) = match replay {
+            Some(spec) => {
+                let (tap, replay) = replay_tap(spec, allocations)?;
+                (Some(spec), Some(Replay::new(replay)), Some(tap))
+            }
+            None => (None, None, None),
+        };
+
+        let sidecar = open_sidecar(&marker_options.sidecar, marker_options.new_session).map_err(
+            |source| RecorderError::Open {
+                path: marker_options.sidecar.clone(),
+                source,
+            },
+        )?;
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let mut tracks = Vec::with_capacity(specs.len());
+        let mut taps = Vec::with_capacity(specs.len());
+        let mut positions = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let sinks = open_sinks(&spec, allocations)?;
+            let (tap, consumer, dropped, faders) = track_tap(&spec, allocations);
+            let limiter = limiter(&spec, allocations);
+            let delayed = limiter.as_ref().map_or(0, |limiter| {
+                limiter.latency_frames() * spec.channels as usize
+            });
+
+            positions.push(Position {
+                path: spec.path.clone(),
+                channels: spec.channels,
+                file_rate: spec.sample_rate,
+                tap_rate: spec.sample_rate,
+                base: spec.leading_silence_frames as u64,
+                queued: Arc::clone(&tap.queued),
+                aborted: false,
+            });
+            taps.push(tap);
+            tracks.push(Track {
+                tap_rate: spec.sample_rate,
+                base: spec.leading_silence_frames as u64,
+                spec,
+                consumer,
+                dropped,
+                earlier_dropped: 0,
+                sinks,
+                started: false,
+                failures: Arc::clone(&failures),
+                faders,
+                resampling: None,
+                limiter,
+                delayed,
+            });
+        }
+
+        let staging = vec![0.0; STAGING_SAMPLES];
+        allocations.register_samples("recording staging", STAGING_SAMPLES);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (requests, request_receiver) = mpsc::channel();
+        let mut sidecar = Sidecar {
+            path: marker_options.sidecar,
+            file: sidecar,
+            cues: marker_options.cues,
+        };
+        let thread = std::thread::Builder::new()
+            .name("recorder".to_owned())
+            .spawn({
+                let stop = Arc::clone(&stop);
+                move || {
+                    run_writer(
+                        tracks,
+                        replay,
+                        staging,
+                        &stop,
+                        &request_receiver,
+                        &mut sidecar,
+                    )
+                }
+            })
+            .map_err(RecorderError::Thread)?;
+
+        Ok((
+            Recorder {
+                stop,
+                thread: Some(thread),
+                requests,
+                replay: replay_spec,
+                positions,
+                failures,
+                format_changes: Vec::new(),
+            },
+            taps,
+            replay_tap,
+        ))
+    }
+
+    /// Carries every track on into the taps of a rebuilt pipeline, given in `specs` in the same
+    /// order as the tracks were, rather than starting new files. The files are padded with
+    /// silence to the longest of them first, so everything that goes on from here still lines up.
+    ///
+    /// A track whose new stream has another rate or channel count from its file has `on_change`
+    /// done to it, which is noted in [`Recorder::format_changes`]. A split's new files are made
+    /// at the paths in `specs`.
+    pub fn continue_with(
+        &mut self,
+        specs: Vec<TrackSpec>,
+        replay: Option<ReplaySpec>,
+        on_change: FormatChange,
+        quality: Quality,
+        allocations: &mut Allocations,
+    ) -> Result<(Vec<RecordTap>, Option<RecordTap>), RecorderError> {
+        if specs.len() != self.positions.len() || replay.is_some() != self.replay.is_some() {
+            return Err(RecorderError::Mismatch {
+                recordings: self.positions.len(),
+                specs: specs.len(),
+            });
+        }
+        // Everything carried on in the same file goes on from the same frame.
+        let offset = self
+            .positions
+            .iter()
+            .filter(|position| !position.aborted)
+            .map(Position::frame)
+            .max()
+            .unwrap_or(0);
+
+        let mut continuations = Vec::with_capacity(specs.len());
+        let mut taps = Vec::with_capacity(specs.len());
+        for (spec, position) in specs.into_iter().zip(&mut self.positions) {
+            let (tap, consumer, dropped, faders) = track_tap(&spec, allocations);
+            let was = format!(
+                "{} at {} Hz with {} channels",
+                position.path.display(),
+                position.file_rate,
+                position.channels
+            );
+            let now = format!("{} Hz with {} channels", spec.sample_rate, spec.channels);
+            let resampler = (on_change == FormatChange::Resample
+                && spec.channels == position.channels)
+                .then(|| {
+                    Resampler::new(
+                        spec.sample_rate,
+                        position.file_rate,
+                        spec.channels as usize,
+                        quality,
+                    )
+                })
+                .flatten();
+
+            let (change, note) = if position.aborted {
+                (Change::Aborted, None)
+            } else if spec.channels == position.channels && spec.sample_rate == position.file_rate {
+                // The leading silence lines the track up with the others, from the new offset.
+                let base = offset + spec.leading_silence_frames as u64;
+                (Change::Continue { base }, None)
+            } else if let Some(resampler) = resampler {
+                allocations.register(
+                    format!("resampler for {}", position.path.display()),
+                    resampler.memory_bytes(),
+                );
+                let leading = spec.leading_silence_frames as u64 * position.file_rate as u64
+                    / spec.sample_rate as u64;
+                let note = format!(
+                    "{} now gets {}, so it's resampled to the file's rate",
+                    was, now
+                );
+                let change = Change::Resample {
+                    base: offset + leading,
+                    resampler,
+                };
+                (change, Some(note))
+            } else if on_change == FormatChange::Abort {
+                let note = format!("{} now gets {}, so that recording has stopped", was, now);
+                self.failures.lock().unwrap().push(note.clone());
+                (Change::Abort, Some(note))
+            } else {
+                let why = match on_change {
+                    FormatChange::Resample if spec.channels != position.channels => {
+                        ", as resampling can't change the channel count"
+                    }
+                    FormatChange::Resample => {
+                        ", as the resampler can't convert between those rates"
+                    }
+                    _ => "",
+                };
+                let note = format!(
+                    "{} now gets {}, so it goes on in {}{}",
+                    was,
+                    now,
+                    spec.path.display(),
+                    why
+                );
+                let change = Change::Split {
+                    sinks: open_sinks(&spec, allocations)?,
+                    limiter: limiter(&spec, allocations).map(Box::new),
+                };
+                (change, Some(note))
+            };
+            if let Some(note) = note {
+                log::warn!("Recording {}.", note);
+                self.format_changes.push(note);
+            }
+
+            match &change {
+                Change::Continue { base } | Change::Resample { base, .. } => {
+                    position.base = *base;
+                    position.tap_rate = spec.sample_rate;
+                }
+                Change::Split { .. } => {
+                    position.path = spec.path.clone();
+                    position.file_rate = spec.sample_rate;
+                    position.tap_rate = spec.sample_rate;
+                    position.channels = spec.channels;
+                    position.base = spec.leading_silence_frames as u64;
+                }
+                Change::Abort | Change::Aborted => position.aborted = true,
+            }
+            position.queued = Arc::clone(&tap.queued);
+            taps.push(tap);
+            continuations.push(Continuation {
+                spec,
+                consumer,
+                dropped,
+                faders,
+                change,
+            });
+        }
+
+        let (replay, replay_tap) = match replay {
+            Some(spec) => {
+                let (tap, replay) = replay_tap(spec, allocations)?;
+                (Some(replay), Some(tap))
+            }
+            None => (None, None),
+        };
+        self.replay = replay.as_ref().map(|replay| replay.spec);
+        // The writer thread only goes away once this is dropped.
+        let _ = self.requests.send(Request::Continue(continuations, replay));
+        Ok((taps, replay_tap))
+    }
+
+    /// Marks the current position in every file with `label`, returning whether there are any
+    /// files.
+    ///
+    /// The position is however much the taps have queued right now, which the writer thread may
+    /// not have written yet, so it owns the marker list and adds the marker once it has.
+    pub fn marker(&self, label: &str) -> bool {
+        if !self.has_tracks() {
+            return false;
+        }
+        let frames = self.positions.iter().map(Position::frame).collect();
+        // The writer thread only goes away once this is dropped.
+        let _ = self.requests.send(Request::Marker(Marker {
+            label: label.to_owned(),
+            at: SystemTime::now(),
+            frames,
+        }));
+        true
+    }
+
+    /// Writes the last `duration` of the replay buffer, or all of it, to a timestamped file,
+    /// returning whether there is a replay buffer.
+    pub fn clip(&self, duration: Option<Duration>) -> bool {
+        if self.replay.is_some() {
+            let _ = self.requests.send(Request::Clip(duration));
+        }
+        self.replay.is_some()
+    }
+
+    /// Every file that writing to has failed, and so been given up on, as a sentence saying what
+    /// went wrong and what's still being recorded.
+    pub fn failures(&self) -> Vec<String> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    /// Every time a track was carried on into a stream of another format, as a sentence saying
+    /// what was done about it.
+    pub fn format_changes(&self) -> &[String] {
+        &self.format_changes
+    }
+
+    /// Whether there are any files being recorded to, rather than only a replay buffer.
+    pub fn has_tracks(&self) -> bool {
+        self.positions.iter().any(|position| !position.aborted)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn tap(channels: u16, sample_rate: u32) -> (RecordTap, HeapConsumer<f32>, Arc<AtomicU64>) {
+    let capacity = sample_rate as usize * channels as usize * TAP_BUFFER_MS / 1_000;
+    let (producer, consumer) = HeapRb::<f32>::new(capacity).split();
+    let dropped = Arc::new(AtomicU64::new(0));
+    let tap = RecordTap {
+        producer,
+        queued: Arc::new(AtomicU64::new(0)),
+        dropped: Arc::clone(&dropped),
+        channels,
+        faders: None,
+    };
+    (tap, consumer, dropped)
+}
+
+/// A tap for `spec`, and the writer thread's ends of it.
+fn track_tap(
+    spec: &TrackSpec,
+    allocations: &mut Allocations,
+) -> (
+    RecordTap,
+    HeapConsumer<f32>,
+    Arc<AtomicU64>,
+    Option<HeapConsumer<FaderChange>>,
+) {
+    let (mut tap, consumer, dropped) = tap(spec.channels, spec.sample_rate);
+    allocations.register_samples(
+        format!("recording queue for {}", spec.path.display()),
+        tap.producer.capacity(),
+    );
+    let faders = spec.fader.is_some().then(|| {
+        allocations.register(
+            format!("fader changes for {}", spec.path.display()),
+            FADER_CHANGES * std::mem::size_of::<FaderChange>(),
+        );
+        let (producer, consumer) = HeapRb::new(FADER_CHANGES).split();
+        tap.faders = Some(producer);
+        consumer
+    });
+    (tap, consumer, dropped, faders)
+}
+
+/// Creates `spec`'s file and its mirror.
+fn open_sinks(spec: &TrackSpec, allocations: &mut Allocations) -> Result<Vec<Sink>, RecorderError> {
+    let mirror = spec.mirror.iter().map(|mirror| (&mirror.path, mirror.sync));
+    std::iter::once((&spec.path, spec.sync))
+        .chain(mirror)
+        .map(|(path, sync)| {
+            let writer =
+                WavWriter::create(path, spec.channels, spec.sample_rate).map_err(|source| {
+                    RecorderError::Create {
+                        path: path.clone(),
+                        source,
+                    }
+                })?;
+            log::info!("Recording to {}.", path.display());
+            allocations.register(
+                format!("write buffer for {}", path.display()),
+                wav::BUFFER_BYTES,
+            );
+            Ok(Sink {
+                path: path.clone(),
+                writer: Some(writer),
+                sync,
+                last_sync: Instant::now(),
+            })
+        })
+        .collect()
+}
+
+fn limiter(spec: &TrackSpec, allocations: &mut Allocations) -> Option<Limiter> {
+    spec.ceiling.map(|ceiling| {
+        let limiter = Limiter::new(spec.channels as usize, spec.sample_rate, ceiling);
+        allocations.register_samples(
+            format!("limiter for {}", spec.path.display()),
+            limiter.latency_frames() * spec.channels as usize,
+        );
+        limiter
+    })
+}
+
+/// A tap for the replay buffer, refusing one that's empty or too big, and the writer thread's
+/// end of it.
+fn replay_tap(
+    spec: ReplaySpec,
+    allocations: &mut Allocations,
+) -> Result<(RecordTap, ReplayTap), RecorderError> {
+    let bytes = spec.samples() * std::mem::size_of::<f32>();
+    if bytes > MAX_REPLAY_BYTES {
+        return Err(RecorderError::ReplayTooBig { spec, bytes });
+    }
+    if spec.samples() == 0 {
+        return Err(RecorderError::EmptyReplay);
+    }
+    let (tap, consumer, dropped) = tap(spec.channels, spec.sample_rate);
+    allocations.register_samples("replay buffer", spec.samples());
+    allocations.register_samples("queue for the replay buffer", tap.producer.capacity());
+    log::info!(
+        "Keeping the last {} s of the output for `clip`, in {:.1} MB.",
+        spec.duration.as_secs_f32(),
+        bytes as f64 / 1e6
+    );
+    Ok((
+        tap,
+        ReplayTap {
+            spec,
+            consumer,
+            dropped,
+        },
+    ))
+}
+
+fn run_writer(
+    mut tracks: Vec<Track>,
+    mut replay: Option<Replay>,
+    mut staging: Vec<f32>,
+    stop: &AtomicBool,
+    requests: &mpsc::Receiver<Request>,
+    sidecar: &mut Sidecar,
+) {
+    for track in &mut tracks {
+        pad(track, &mut staging);
+    }
+
+    loop {
+        // Check before draining, so everything pushed before the stop is still written.
+        let stopping = stop.load(Ordering::Relaxed);
+
+        note_starts(&mut tracks, sidecar);
+        drain(&mut tracks, replay.as_mut(), &mut staging);
+        note_faders(&mut tracks, sidecar);
+
+        if stopping {
+            break;
+        }
+        let now = Instant::now();
+        for track in &mut tracks {
+            report(track, |sink| {
+                if now.duration_since(sink.last_sync) < sink.sync.interval {
+                    return Ok(());
+                }
+                sink.last_sync = now;
+                let writer = sink.writer.as_mut().unwrap();
+                writer.update_header()?;
+                if sink.sync.fsync {
+                    writer.sync()?;
+                }
+                Ok(())
+            });
+        }
+        // Waiting on requests doubles as the pause between drains.
+        if let Ok(request) = requests.recv_timeout(WRITE_INTERVAL) {
+            handle(request, &mut tracks, &mut replay, sidecar, &mut staging);
+        }
+    }
+
+    // Markers made while stopping still point at the end of the files.
+    while let Ok(request) = requests.try_recv() {
+        handle(request, &mut tracks, &mut replay, sidecar, &mut staging);
+    }
+    // Taps swapped in by a continuation may have had audio queued on them since.
+    note_starts(&mut tracks, sidecar);
+    drain(&mut tracks, replay.as_mut(), &mut staging);
+    note_faders(&mut tracks, sidecar);
+
+    if let Some(replay) = &replay {
+        let dropped = replay.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!(
+                "The replay buffer missed {} samples the recorder couldn't keep up with.",
+                dropped
+            );
+        }
+    }
+
+    for mut track in tracks {
+        flush(&mut track, &mut staging);
+        let dropped = track.earlier_dropped + track.dropped.load(Ordering::Relaxed);
+        finish(track.sinks, dropped);
+    }
+}
+
+/// Finishes every one of `sinks`, saying how it went and how many samples it's missing.
+fn finish(sinks: Vec<Sink>, dropped: u64) {
+    for sink in sinks {
+        let path = sink.path;
+        match sink.writer.map(WavWriter::finalize) {
+            Some(Ok(())) if dropped > 0 => log::warn!(
+                "Finished {}, missing {} samples the recorder couldn't keep up with.",
+                path.display(),
+                dropped
+            ),
+            Some(Ok(())) => log::info!("Finished {}.", path.display()),
+            Some(Err(err)) => log::warn!("couldn't finish {}: {}", path.display(), err),
+            None => log::warn!("{} was given up on and is incomplete.", path.display()),
+        }
+    }
+}
+
+fn handle(
+    request: Request,
+    tracks: &mut [Track],
+    replay: &mut Option<Replay>,
+    sidecar: &mut Sidecar,
+    staging: &mut [f32],
+) {
+    match request {
+        Request::Marker(marker) => {
+            if let Err(err) = sidecar.add(&marker, tracks) {
+                log::warn!("couldn't add to {}: {}", sidecar.path.display(), err);
+            }
+        }
+        Request::Clip(duration) => {
+            // Only the recorder makes clip requests, and only with a replay buffer.
+            let Some(replay) = replay else { return };
+            // Include everything up to the moment the clip was asked for.
+            while let len @ 1.. = replay.consumer.pop_slice(staging) {
+                replay.push(&staging[..len]);
+            }
+            if let Err(err) = replay.clip(Path::new(""), duration) {
+                log::warn!("couldn't write the clip: {}", err);
+            }
+        }
+        Request::Continue(continuations, replay_tap) => {
+            // The old pipeline is gone, so this is the last of what its taps queued.
+            note_starts(tracks, sidecar);
+            drain(tracks, replay.as_mut(), staging);
+            note_faders(tracks, sidecar);
+            for (track, continuation) in tracks.iter_mut().zip(continuations) {
+                continue_track(track, continuation, staging);
+            }
+            *replay = match (replay.take(), replay_tap) {
+                (Some(mut replay), Some(tap))
+                    if replay.spec.channels == tap.spec.channels
+                        && replay.spec.sample_rate == tap.spec.sample_rate =>
+                {
+                    replay.spec = tap.spec;
+                    replay.consumer = tap.consumer;
+                    replay.dropped = tap.dropped;
+                    // Kept for the new duration, which is the same unless reconfigured.
+                    if replay.history.len() != tap.spec.samples() {
+                        replay.history = vec![0.0; tap.spec.samples()];
+                        replay.next = 0;
+                        replay.len = 0;
+                    }
+                    Some(replay)
+                }
+                // What's in the history can't be clipped together with audio of another format.
+                (_, Some(tap)) => Some(Replay::new(tap)),
+                (_, None) => None,
+            };
+        }
+    }
+}
+
+/// Switches `track` over to its new tap, doing to its file what `continuation` says.
+fn continue_track(track: &mut Track, continuation: Continuation, staging: &mut [f32]) {
+    let Continuation {
+        spec,
+        consumer,
+        dropped,
+        faders,
+        change,
+    } = continuation;
+    flush(track, staging);
+    track.earlier_dropped += track.dropped.load(Ordering::Relaxed);
+    track.consumer = consumer;
+    track.dropped = dropped;
+    track.faders = faders;
+    track.resampling = None;
+    track.started = false;
+    match change {
+        Change::Continue { base } => {
+            track.base = base;
+            track.tap_rate = spec.sample_rate;
+            track.spec.leading_silence_frames = spec.leading_silence_frames;
+            track.spec.fader = spec.fader;
+            pad(track, staging);
+        }
+        Change::Resample { base, resampler } => {
+            let channels = track.spec.channels as usize;
+            let frames = whole_frames(staging.len(), track.spec.channels) / channels;
+            track.base = base;
+            track.tap_rate = spec.sample_rate;
+            track.spec.leading_silence_frames = spec.leading_silence_frames;
+            track.spec.fader = spec.fader;
+            track.resampling = Some(Resampling {
+                output: vec![0.0; resampler.max_output_frames(frames) * channels],
+                delayed: resampler.latency_frames() * channels,
+                resampler,
+                frames_in: 0,
+                frames_out: 0,
+            });
+            pad(track, staging);
+        }
+        Change::Split { sinks, limiter } => {
+            let finished = std::mem::replace(&mut track.sinks, sinks);
+            finish(finished, track.earlier_dropped);
+            track.earlier_dropped = 0;
+            track.tap_rate = spec.sample_rate;
+            track.base = spec.leading_silence_frames as u64;
+            track.delayed = limiter.as_ref().map_or(0, |limiter| {
+                limiter.latency_frames() * spec.channels as usize
+            });
+            track.limiter = limiter.map(|limiter| *limiter);
+            track.spec = spec;
+            pad(track, staging);
+        }
+        Change::Abort => {
+            finish(std::mem::take(&mut track.sinks), track.earlier_dropped);
+            track.limiter = None;
+        }
+        Change::Aborted => {}
+    }
+}
+
+/// Writes silence to every file of `track` until it reaches the frame its tap's audio goes at.
+fn pad(track: &mut Track, staging: &mut [f32]) {
+    let Some(written) = track
+        .live_sinks()
+        .map(|sink| sink.writer.as_ref().unwrap().frames_written())
+        .next()
+    else {
+        return;
+    };
+    let mut remaining = track.base.saturating_sub(written) as usize * track.spec.channels as usize;
+    let chunk = whole_frames(staging.len(), track.spec.channels);
+    staging[..chunk].fill(0.0);
+    while remaining > 0 {
+        let len = chunk.min(remaining);
+        report(track, |sink| {
+            sink.writer.as_mut().unwrap().write(&staging[..len])
+        });
+        remaining -= len;
+    }
+}
+
+/// Notes in the sidecar when the first sample from each tap was captured, as far as that can be
+/// told from how much has built up since.
+fn note_starts(tracks: &mut [Track], sidecar: &mut Sidecar) {
+    let now = SystemTime::now();
+    for track in tracks {
+        if track.started || track.consumer.is_empty() {
+            continue;
+        }
+        track.started = true;
+        let frames = track.consumer.len() / track.spec.channels as usize;
+        let at = now - Duration::from_secs_f64(frames as f64 / track.tap_rate as f64);
+        if let Err(err) = sidecar.add_start(track, at) {
+            log::warn!("couldn't add to {}: {}", sidecar.path.display(), err);
+        }
+    }
+}
+
+/// Adds every fader change the taps have queued to the sidecar.
+fn note_faders(tracks: &mut [Track], sidecar: &mut Sidecar) {
+    for track in tracks {
+        while let Some(change) = track.faders.as_mut().and_then(|faders| faders.pop()) {
+            if let Err(err) = sidecar.add_fader(track, change) {
+                log::warn!("couldn't add to {}: {}", sidecar.path.display(), err);
+            }
+        }
+    }
+}
+
+fn drain(tracks: &mut [Track], replay: Option<&mut Replay>, staging: &mut [f32]) {
+    for track in tracks {
+        // Whole frames at a time, for the resampler and the limiter.
+        let chunk = whole_frames(staging.len(), track.spec.channels);
+        loop {
+            let len = track.consumer.pop_slice(&mut staging[..chunk]);
+            if len == 0 {
+                break;
+            }
+            write_track(track, &mut staging[..len]);
+        }
+    }
+    if let Some(replay) = replay {
+        while let len @ 1.. = replay.consumer.pop_slice(staging) {
+            replay.push(&staging[..len]);
+        }
+    }
+}
+
+/// Writes `samples` from `track`'s tap to its files, through its resampler and limiter.
+fn write_track(track: &mut Track, samples: &mut [f32]) {
+    if track.sinks.is_empty() {
+        return;
+    }
+    let Some(mut resampling) = track.resampling.take() else {
+        // Checked on every write, as a file of another format would play at the wrong speed.
+        debug_assert_eq!(track.tap_rate, track.spec.sample_rate);
+        write_limited(track, samples);
+        return;
+    };
+    let channels = track.spec.channels as usize;
+    let len = resampling
+        .resampler
+        .process(samples, &mut resampling.output);
+    resampling.frames_in += (samples.len() / channels) as u64;
+    let skipped = resampling.delayed.min(len);
+    resampling.delayed -= skipped;
+    resampling.frames_out += ((len - skipped) / channels) as u64;
+    write_limited(track, &mut resampling.output[skipped..len]);
+    track.resampling = Some(resampling);
+}
+
+/// Writes `samples` to every file of `track`, through its limiter if it has one.
+fn write_limited(track: &mut Track, samples: &mut [f32]) {
+    let samples = match &mut track.limiter {
+        Some(limiter) => {
+            limiter.process(samples);
+            let skipped = track.delayed.min(samples.len());
+            track.delayed -= skipped;
+            &samples[skipped..]
+        }
+        None => samples,
+    };
+    if !samples.is_empty() {
+        report(track, |sink| sink.writer.as_mut().unwrap().write(samples));
+    }
+}
+
+/// Pushes what's left in the track's resampler and limiter out with silence, so its files end
+/// where its tap did, and leaves both ready to go on.
+fn flush(track: &mut Track, staging: &mut [f32]) {
+    let channels = track.spec.channels as usize;
+    let chunk = whole_frames(staging.len(), track.spec.channels);
+    if let Some(mut resampling) = track.resampling.take() {
+        let owed = resampling.frames_in * track.spec.sample_rate as u64 / track.tap_rate as u64;
+        let mut remaining = owed.saturating_sub(resampling.frames_out) as usize * channels;
+        staging[..chunk].fill(0.0);
+        while remaining > 0 {
+            let len = resampling
+                .resampler
+                .process(&staging[..chunk], &mut resampling.output);
+            let skipped = resampling.delayed.min(len);
+            resampling.delayed -= skipped;
+            let len = (len - skipped).min(remaining);
+            write_limited(track, &mut resampling.output[skipped..skipped + len]);
+            remaining -= len;
+        }
+        resampling.resampler.reset();
+        resampling.delayed = resampling.resampler.latency_frames() * channels;
+        resampling.frames_in = 0;
+        resampling.frames_out = 0;
+        track.resampling = Some(resampling);
+    }
+    if let Some(limiter) = &track.limiter {
+        let latency = limiter.latency_frames() * channels;
+        let mut remaining = latency;
+        while remaining > 0 {
+            let len = chunk.min(remaining);
+            staging[..len].fill(0.0);
+            write_limited(track, &mut staging[..len]);
+            remaining -= len;
+        }
+        // The silence it was flushed with comes out first, and is dropped like its delay was.
+        track.delayed = latency;
+    }
+}
+
+/// The most of `samples` samples that make up whole frames of `channels` channels.
+fn whole_frames(samples: usize, channels: u16) -> usize {
+    samples / channels as usize * channels as usize
+}
+
+/// The writer thread's end of the replay buffer.
+struct Replay {
+    spec: ReplaySpec,
+    consumer: HeapConsumer<f32>,
+    dropped: Arc<AtomicU64>,
+    /// A ring of the most recent samples, allocated once up front.
+    history: Vec<f32>,
+    /// Where the next sample goes.
+    next: usize,
+    /// How many samples of `history` have been filled in.
+    len: usize,
+}
+
+impl Replay {
+    fn new(tap: ReplayTap) -> Self {
+        Replay {
+            history: vec![0.0; tap.spec.samples()],
+            spec: tap.spec,
+            consumer: tap.consumer,
+            dropped: tap.dropped,
+            next: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, mut samples: &[f32]) {
+        while !samples.is_empty() {
+            let len = samples.len().min(self.history.len() - self.next);
+            self.history[self.next..self.next + len].copy_from_slice(&samples[..len]);
+            self.next = (self.next + len) % self.history.len();
+            self.len = (self.len + len).min(self.history.len());
+            samples = &samples[len..];
+        }
+    }
+
+    /// Writes the clip into `dir`, the working directory if it's empty.
+    fn clip(&self, dir: &Path, duration: Option<Duration>) -> std::io::Result<()> {
+        let channels = self.spec.channels as usize;
+        let wanted = match duration {
+            Some(duration) => {
+                (duration.as_secs_f64() * self.spec.sample_rate as f64).round() as usize * channels
+            }
+            None => self.len,
+        };
+        let len = wanted.min(self.len);
+        let start = (self.next + self.history.len() - len) % self.history.len();
+
+        let unix_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // Clips made within the same second get numbered rather than overwriting each other.
+        let path = (1..)
+            .map(|n| match n {
+                1 => dir.join(format!("clip-{}.wav", unix_time)),
+                n => dir.join(format!("clip-{}-{}.wav", unix_time, n)),
+            })
+            .find(|path| !path.exists())
+            .unwrap();
+        let mut writer = WavWriter::create(&path, self.spec.channels, self.spec.sample_rate)?;
+        if start + len <= self.history.len() {
+            writer.write(&self.history[start..start + len])?;
+        } else {
+            writer.write(&self.history[start..])?;
+            writer.write(&self.history[..start + len - self.history.len()])?;
+        }
+        writer.finalize()?;
+        log::info!(
+            "Clipped the last {:.1} s of the output to {}.",
+            (len / channels) as f64 / self.spec.sample_rate as f64,
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+/// The marker file, which is kept a valid JSON array after every marker.
+struct Sidecar {
+    path: PathBuf,
+    file: File,
+    cues: bool,
+}
+
+impl Sidecar {
+    fn add(&mut self, marker: &Marker, tracks: &mut [Track]) -> std::io::Result<()> {
+        let unix_time = marker
+            .at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let files = tracks
+            .iter()
+            .zip(&marker.frames)
+            .flat_map(|(track, frame)| {
+                track.live_sinks().map(move |sink| {
+                    format!(
+                        "{{\"file\": {}, \"frame\": {}}}",
+                        json_string(&sink.path.to_string_lossy()),
+                        frame
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.append(&format!(
+            "  {{\"label\": {}, \"unix_time\": {:.3}, \"files\": [{}]}}",
+            json_string(&marker.label),
+            unix_time,
+            files
+        ))?;
+
+        if self.cues {
+            for (track, frame) in tracks.iter_mut().zip(&marker.frames) {
+                for writer in track
+                    .sinks
+                    .iter_mut()
+                    .filter_map(|sink| sink.writer.as_mut())
+                {
+                    writer.add_cue(*frame, &marker.label);
+                }
+            }
+        }
+        log::info!("Marker \"{}\" added.", marker.label);
+        Ok(())
+    }
+
+    /// Adds an entry for when the first real sample from `track`'s tap was captured, pointing just
+    /// past its leading silence.
+    fn add_start(&mut self, track: &Track, at: SystemTime) -> std::io::Result<()> {
+        let unix_time = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let files = track
+            .live_sinks()
+            .map(|sink| {
+                format!(
+                    "{{\"file\": {}, \"frame\": {}}}",
+                    json_string(&sink.path.to_string_lossy()),
+                    track.base
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.append(&format!(
+            "  {{\"start\": true, \"unix_time\": {:.3}, \"files\": [{}]}}",
+            unix_time, files
+        ))
+    }
+
+    /// Adds an entry for `track`'s fader fading to a new gain, over [`OVERRIDE_FADE_MS`].
+    fn add_fader(&mut self, track: &Track, change: FaderChange) -> std::io::Result<()> {
+        let frame =
+            track.base + change.frame * track.spec.sample_rate as u64 / track.tap_rate as u64;
+        let files = track
+            .live_sinks()
+            .map(|sink| {
+                format!(
+                    "{{\"file\": {}, \"frame\": {}}}",
+                    json_string(&sink.path.to_string_lossy()),
+                    frame
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Silence has no level in dB, and JSON no infinity.
+        let gain_db = match change.gain {
+            gain if gain > 0.0 => format!("{:.2}", 20.0 * gain.log10()),
+            _ => "null".to_owned(),
+        };
+        self.append(&format!(
+            "  {{\"fader\": {}, \"gain\": {}, \"gain_db\": {}, \"fade_ms\": {}, \"files\": [{}]}}",
+            json_string(track.spec.fader.as_deref().unwrap_or_default()),
+            change.gain,
+            gain_db,
+            OVERRIDE_FADE_MS,
+            files
+        ))
+    }
+
+    fn append(&mut self, entry: &str) -> std::io::Result<()> {
+        // Replace the closing bracket, unless this is the first entry.
+        let len = self.file.seek(SeekFrom::End(0))?;
+        if len > 0 {
+            self.file.seek(SeekFrom::End(-(CLOSING.len() as i64)))?;
+            write!(self.file, ",\n{}{}", entry, CLOSING)?;
+        } else {
+            self.file.set_len(0)?;
+            self.file.seek(SeekFrom::Start(0))?;
+            write!(self.file, "[\n{}{}", entry, CLOSING)?;
+        }
+        self.file.flush()
+    }
+}
+
+const CLOSING: &str = "\n]\n";
+
+fn open_sidecar(path: &Path, new_session: bool) -> std::io::Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(new_session)
+        .open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    if !contents.is_empty() && !contents.ends_with(CLOSING) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "doesn't look like a marker file",
+        ));
+    }
+    Ok(file)
+}
+
+/// `value` as a JSON string, quotes included.
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+impl Track {
+    /// The sinks that are still being written to.
+    fn live_sinks(&self) -> impl Iterator<Item = &Sink> {
+        self.sinks.iter().filter(|sink| sink.writer.is_some())
+    }
+}
+
+/// Runs `write` on every sink still being written to, giving up on any it fails for.
+fn report(track: &mut Track, mut write: impl FnMut(&mut Sink) -> std::io::Result<()>) {
+    for i in 0..track.sinks.len() {
+        if track.sinks[i].writer.is_none() {
+            continue;
+        }
+        let Err(err) = write(&mut track.sinks[i]) else {
+            continue;
+        };
+        let sink = &mut track.sinks[i];
+        let seconds = sink.writer.take().map_or(0.0, |writer| {
+            writer.frames_written() as f64 / track.spec.sample_rate as f64
+        });
+        let mut failure = format!(
+            "couldn't write to {} after {:.1}s of audio: {}",
+            sink.path.display(),
+            seconds,
+            err
+        );
+        let survivors = track
+            .live_sinks()
+            .map(|sink| sink.path.display().to_string())
+            .collect::<Vec<_>>();
+        if survivors.is_empty() {
+            failure.push_str(", so that recording has stopped");
+        } else {
+            failure.push_str(&format!(
+                ", carrying on with {} alone",
+                survivors.join(", ")
+            ));
+        }
+        log::warn!("{}", failure);
+        track.failures.lock().unwrap().push(failure);
+    }
+}
+
+/// Why recording couldn't start, or carry on into a rebuilt pipeline.
+#[derive(Debug)]
+pub enum RecorderError {
+    Create {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The marker sidecar couldn't be opened.
+    Open {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The writer thread couldn't be started.
+    Thread(io::Error),
+    /// The replay buffer would take `bytes`, more than is allowed.
+    ReplayTooBig {
+        spec: ReplaySpec,
+        bytes: usize,
+    },
+    EmptyReplay,
+    /// The rebuilt pipeline has `specs` tracks, or has a replay buffer or not, unlike the
+    /// recorder's `recordings`.
+    Mismatch {
+        recordings: usize,
+        specs: usize,
+    },
+}
+
+impl fmt::Display for RecorderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecorderError::Create { path, .. } => write!(f, "couldn't create {}", path.display()),
+            RecorderError::Open { path, .. } => write!(f, "couldn't open {}", path.display()),
+            RecorderError::Thread(_) => f.write_str("couldn't start the recorder's thread"),
+            RecorderError::ReplayTooBig { spec, bytes } => write!(
+                f,
+                "a {} s replay buffer of {} channels at {} Hz would take {:.0} MB, more than the \
+                 {:.0} MB allowed",
+                spec.duration.as_secs_f32(),
+                spec.channels,
+                spec.sample_rate,
+                *bytes as f64 / 1e6,
+                MAX_REPLAY_BYTES as f64 / 1e6
+            ),
+            RecorderError::EmptyReplay => f.write_str("the replay buffer can't be empty"),
+            RecorderError::Mismatch { recordings, specs } => {
+                write!(f, "can't carry {} recordings on into {}", recordings, specs)
+            }
+        }
+    }
+}
+
+impl Error for RecorderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RecorderError::Create { source, .. }
+            | RecorderError::Open { source, .. }
+            | RecorderError::Thread(source) => Some(source),
+            RecorderError::ReplayTooBig { .. }
+            | RecorderError::EmptyReplay
+            | RecorderError::Mismatch { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(channels: u16, sample_rate: u32, duration: Duration) -> Replay {
+        let spec = ReplaySpec {
+            channels,
+            sample_rate,
+            duration,
+        };
+        let (_, consumer, dropped) = tap(channels, sample_rate);
+        Replay {
+            spec,
+            consumer,
+            dropped,
+            history: vec![0.0; spec.samples()],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Every clip in `dir`, shortest first.
+    fn clips(dir: &Path) -> Vec<Vec<f32>> {
+        let mut clips = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| wav::read_samples(&entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        clips.sort_by_key(Vec::len);
+        clips
+    }
+
+    #[test]
+    fn clips_the_last_of_the_history_as_it_wraps() {
+        let dir = std::env::temp_dir().join(format!("loopback-clip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // 2 s of stereo at 100 Hz, pushed in uneven pieces until it's wrapped round.
+        let mut replay = replay(2, 100, Duration::from_secs(2));
+        let samples = (0..700).map(|i| i as f32).collect::<Vec<_>>();
+        for piece in samples.chunks(130) {
+            replay.push(piece);
+        }
+        replay.clip(&dir, None).unwrap();
+        replay.clip(&dir, Some(Duration::from_secs(1))).unwrap();
+        replay.clip(&dir, Some(Duration::from_secs(60))).unwrap();
+        let clips = clips(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Made within the same second, but numbered rather than overwriting each other.
+        assert_eq!(clips.len(), 3);
+        assert_eq!(clips[0], samples[500..]);
+        assert_eq!(clips[1], samples[300..]);
+        assert_eq!(clips[2], samples[300..]);
+    }
+
+    #[test]
+    fn clips_only_what_has_been_kept_so_far() {
+        let dir = std::env::temp_dir().join(format!("loopback-short-clip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut replay = replay(1, 100, Duration::from_secs(2));
+        replay.push(&[0.25; 50]);
+        replay.clip(&dir, None).unwrap();
+        let clips = clips(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(clips, [vec![0.25; 50]]);
+    }
+
+    #[test]
+    fn refuses_replay_buffers_that_are_empty_or_too_big() {
+        let start = |duration| {
+            Recorder::start(
+                Vec::new(),
+                MarkerOptions {
+                    sidecar: PathBuf::from("unused.json"),
+                    new_session: false,
+                    cues: false,
+                },
+                Some(ReplaySpec {
+                    channels: 2,
+                    sample_rate: 48_000,
+                    duration,
+                }),
+                &mut Allocations::default(),
+            )
+            .map(|_| ())
+        };
+        // Both are refused before anything is created.
+        assert!(start(Duration::ZERO).is_err());
+        assert!(start(Duration::from_secs(24 * 60 * 60)).is_err());
+    }
+
+    /// A recorder writing mono tracks at `sample_rate` into `dir`, one per name.
+    fn recorder(dir: &Path, names: &[&str], sample_rate: u32) -> (Recorder, Vec<RecordTap>) {
+        let (recorder, taps, _) = Recorder::start(
+            names
+                .iter()
+                .map(|name| spec(dir, name, 1, sample_rate))
+                .collect(),
+            MarkerOptions {
+                sidecar: dir.join("markers.json"),
+                new_session: true,
+                cues: false,
+            },
+            None,
+            &mut Allocations::default(),
+        )
+        .unwrap();
+        (recorder, taps)
+    }
+
+    fn spec(dir: &Path, name: &str, channels: u16, sample_rate: u32) -> TrackSpec {
+        TrackSpec {
+            path: dir.join(format!("{}.wav", name)),
+            channels,
+            sample_rate,
+            leading_silence_frames: 0,
+            sync: SyncPolicy::default(),
+            mirror: None,
+            fader: None,
+            ceiling: None,
+        }
+    }
+
+    /// The channel count and sample rate in a WAV file's header.
+    fn format(path: &Path) -> (u16, u32) {
+        let bytes = std::fs::read(path).unwrap();
+        (
+            u16::from_le_bytes(bytes[22..24].try_into().unwrap()),
+            u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+        )
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loopback-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn carries_on_in_the_same_files_lined_up_when_the_format_matches() {
+        let dir = temp_dir("continue");
+        let (mut recorder, mut taps) = recorder(&dir, &["a", "b"], 48_000);
+        taps[0].write(&[0.5; 100]);
+        taps[1].write(&[0.25; 60]);
+        let specs = vec![spec(&dir, "a-2", 1, 48_000), spec(&dir, "b-2", 1, 48_000)];
+        let (mut taps, _) = recorder
+            .continue_with(
+                specs,
+                None,
+                FormatChange::Split,
+                Quality::default(),
+                &mut Allocations::default(),
+            )
+            .unwrap();
+        taps[0].write(&[-0.5; 50]);
+        taps[1].write(&[-0.25; 50]);
+        assert!(recorder.format_changes().is_empty());
+        drop(recorder);
+
+        let a = wav::read_samples(&dir.join("a.wav")).unwrap();
+        let b = wav::read_samples(&dir.join("b.wav")).unwrap();
+        let split = dir.join("a-2.wav").exists() || dir.join("b-2.wav").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!split);
+        assert_eq!(a, [vec![0.5; 100], vec![-0.5; 50]].concat());
+        // Padded to where the other file had got to.
+        assert_eq!(b, [vec![0.25; 60], vec![0.0; 40], vec![-0.25; 50]].concat());
+    }
+
+    #[test]
+    fn splits_a_recording_whose_rate_changes() {
+        let dir = temp_dir("split");
+        let (mut recorder, mut taps) = recorder(&dir, &["a"], 48_000);
+        taps[0].write(&[0.5; 100]);
+        let (mut taps, _) = recorder
+            .continue_with(
+                vec![spec(&dir, "a-2", 1, 44_100)],
+                None,
+                FormatChange::Split,
+                Quality::default(),
+                &mut Allocations::default(),
+            )
+            .unwrap();
+        taps[0].write(&[0.25; 50]);
+        let changes = recorder.format_changes().to_vec();
+        drop(recorder);
+
+        let first = (
+            wav::read_samples(&dir.join("a.wav")).unwrap(),
+            format(&dir.join("a.wav")),
+        );
+        let second = (
+            wav::read_samples(&dir.join("a-2.wav")).unwrap(),
+            format(&dir.join("a-2.wav")),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, (vec![0.5; 100], (1, 48_000)));
+        assert_eq!(second, (vec![0.25; 50], (1, 44_100)));
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].contains("now gets 44100 Hz with 1 channels, so it goes on in"));
+    }
+
+    #[test]
+    fn resamples_a_recording_whose_rate_changes_into_the_same_file() {
+        let dir = temp_dir("resample");
+        let (mut recorder, mut taps) = recorder(&dir, &["a"], 48_000);
+        taps[0].write(&[0.5; 4_800]);
+        let (mut taps, _) = recorder
+            .continue_with(
+                vec![spec(&dir, "a-2", 1, 44_100)],
+                None,
+                FormatChange::Resample,
+                Quality::default(),
+                &mut Allocations::default(),
+            )
+            .unwrap();
+        taps[0].write(&[0.25; 4_410]);
+        let changes = recorder.format_changes().to_vec();
+        drop(recorder);
+
+        let samples = wav::read_samples(&dir.join("a.wav")).unwrap();
+        let header = format(&dir.join("a.wav"));
+        let split = dir.join("a-2.wav").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!split);
+        assert_eq!(header, (1, 48_000));
+        // A tenth of a second at 44.1 kHz is a tenth of a second at 48 kHz too.
+        assert_eq!(samples.len(), 9_600);
+        assert_eq!(samples[..4_800], [0.5; 4_800]);
+        // Away from the edges, where the filter rings against the silence either side.
+        assert!(samples[5_000..9_400]
+            .iter()
+            .all(|sample| (sample - 0.25).abs() < 1e-3));
+        assert!(changes[0].contains("so it's resampled to the file's rate"));
+    }
+
+    #[test]
+    fn splits_instead_of_resampling_when_the_channels_change() {
+        let dir = temp_dir("resample-channels");
+        let (mut recorder, mut taps) = recorder(&dir, &["a"], 48_000);
+        taps[0].write(&[0.5; 100]);
+        let (mut taps, _) = recorder
+            .continue_with(
+                vec![spec(&dir, "a-2", 2, 48_000)],
+                None,
+                FormatChange::Resample,
+                Quality::default(),
+                &mut Allocations::default(),
+            )
+            .unwrap();
+        taps[0].write(&[0.25; 100]);
+        let changes = recorder.format_changes().to_vec();
+        drop(recorder);
+
+        let first = wav::read_samples(&dir.join("a.wav")).unwrap();
+        let second = format(&dir.join("a-2.wav"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, [0.5; 100]);
+        assert_eq!(second, (2, 48_000));
+        assert!(changes[0].ends_with("as resampling can't change the channel count"));
+    }
+
+    #[test]
+    fn aborts_a_recording_whose_rate_changes() {
+        let dir = temp_dir("abort");
+        let (mut recorder, mut taps) = recorder(&dir, &["a"], 48_000);
+        taps[0].write(&[0.5; 100]);
+        let (mut taps, _) = recorder
+            .continue_with(
+                vec![spec(&dir, "a-2", 1, 44_100)],
+                None,
+                FormatChange::Abort,
+                Quality::default(),
+                &mut Allocations::default(),
+            )
+            .unwrap();
+        taps[0].write(&[0.25; 100]);
+        assert!(!recorder.has_tracks());
+        assert!(!recorder.marker("after"));
+        let failures = recorder.failures();
+        drop(recorder);
+
+        let samples = wav::read_samples(&dir.join("a.wav")).unwrap();
+        let split = dir.join("a-2.wav").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!split);
+        assert_eq!(samples, [0.5; 100]);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].ends_with("so that recording has stopped"));
+    }
+
+    /// A mono recorder writing `file` and mirroring it to `mirror`.
+    fn mirrored(dir: &Path, file: &Path, mirror: &Path) -> (Recorder, RecordTap) {
+        let (recorder, mut taps, _) = Recorder::start(
+            vec![TrackSpec {
+                path: file.to_owned(),
+                mirror: Some(MirrorSpec {
+                    path: mirror.to_owned(),
+                    sync: SyncPolicy::default(),
+                }),
+                ..spec(dir, "unused", 1, 48_000)
+            }],
+            MarkerOptions {
+                sidecar: dir.join("markers.json"),
+                new_session: true,
+                cues: false,
+            },
+            None,
+            &mut Allocations::default(),
+        )
+        .unwrap();
+        (recorder, taps.remove(0))
+    }
+
+    /// A second of a rising ramp, written a tenth of a second at a time, so a file that skips or
+    /// repeats any of it doesn't match.
+    fn write_ramp(tap: &mut RecordTap) -> Vec<f32> {
+        let ramp = (0..48_000).map(|i| i as f32 / 48_000.0).collect::<Vec<_>>();
+        for chunk in ramp.chunks(4_800) {
+            tap.write(chunk);
+            std::thread::sleep(WRITE_INTERVAL);
+        }
+        ramp
+    }
+
+    // `/dev/full` takes the header, which is only buffered, and then fails every write with
+    // "no space left", like a drive that fills up partway through.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn carries_on_with_the_mirror_when_the_file_fails_partway() {
+        let dir = temp_dir("mirror-survives");
+        let mirror = dir.join("mirror.wav");
+        let (recorder, mut tap) = mirrored(&dir, Path::new("/dev/full"), &mirror);
+        let ramp = write_ramp(&mut tap);
+        assert!(recorder.has_tracks());
+        let failures = recorder.failures();
+        drop(recorder);
+
+        let samples = wav::read_samples(&mirror).unwrap();
+        let format = format(&mirror);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        assert!(failures[0].starts_with("couldn't write to /dev/full after "));
+        assert!(failures[0].ends_with(&format!(", carrying on with {} alone", mirror.display())));
+        assert_eq!(format, (1, 48_000));
+        assert_eq!(samples, ramp);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn carries_on_with_the_file_when_the_mirror_fails_partway() {
+        let dir = temp_dir("file-survives");
+        let file = dir.join("file.wav");
+        let (recorder, mut tap) = mirrored(&dir, &file, Path::new("/dev/full"));
+        let ramp = write_ramp(&mut tap);
+        let failures = recorder.failures();
+        drop(recorder);
+
+        let samples = wav::read_samples(&file).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        assert!(failures[0].ends_with(&format!(", carrying on with {} alone", file.display())));
+        assert_eq!(samples, ramp);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stops_the_recording_once_both_files_have_failed() {
+        let dir = temp_dir("both-fail");
+        let (recorder, mut tap) = mirrored(&dir, Path::new("/dev/full"), Path::new("/dev/full"));
+        write_ramp(&mut tap);
+        let failures = recorder.failures();
+        drop(recorder);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(failures.len(), 2, "{:?}", failures);
+        assert!(failures[0].contains("carrying on with /dev/full alone"));
+        assert!(failures[1].ends_with("so that recording has stopped"));
+    }
+}
@@ -0,0 +1,451 @@
+//! Band-limited resampling between the 44.1 kHz and 48 kHz families of sample rates, for capture
+//! devices that can only run at the rate the rest can't.
+//!
+//! Each output frame is a windowed-sinc interpolation of the input frames around it. The ratio
+//! between the rates is fixed, so there are only as many distinct positions between two input
+//! frames as the reduced ratio's numerator, and the filter for each is worked out when the
+//! resampler is built. Running it is then a dot product per channel per output frame, which never
+//! allocates.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::chain::CpuUsage;
+use crate::error::{parse_error, ParseError};
+
+/// How well the resampler keeps frequencies above the lower rate's Nyquist from aliasing, which
+/// costs CPU in proportion to the filter's length.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// About 60 dB down in the stopband, with the passband flat to 17 kHz.
+    Fast,
+    /// About 90 dB down, flat to 18 kHz.
+    #[default]
+    Balanced,
+    /// About 120 dB down, flat to 20 kHz.
+    High,
+}
+
+impl Quality {
+    pub fn parse(value: &str) -> Result<Self, ParseError> {
+        Ok(match value {
+            "fast" => Quality::Fast,
+            "balanced" => Quality::Balanced,
+            "high" => Quality::High,
+            other => {
+                return Err(parse_error!(
+                    "expected `fast`, `balanced` or `high` as the quality, got `{}`",
+                    other
+                ))
+            }
+        })
+    }
+
+    /// The filter's length in frames at the lower rate, its Kaiser window's beta, and where its
+    /// cutoff is as a fraction of the lower Nyquist. Each cutoff puts the end of the transition
+    /// band right at the Nyquist, for the attenuation the beta gives over that length.
+    fn filter(self) -> (usize, f64, f64) {
+        match self {
+            Quality::Fast => (32, 5.65, 0.887),
+            Quality::Balanced => (64, 8.96, 0.911),
+            Quality::High => (192, 12.27, 0.959),
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Quality::Fast => "fast",
+            Quality::Balanced => "balanced",
+            Quality::High => "high",
+        })
+    }
+}
+
+/// Whether one rate is of the 44.1 kHz family and the other of the 48 kHz family, which is all
+/// the resampler converts between.
+pub fn supported(from: u32, to: u32) -> bool {
+    let family = |rate: u32| match (rate.is_multiple_of(11_025), rate.is_multiple_of(8_000)) {
+        (true, false) => Some(44_100),
+        (false, true) => Some(48_000),
+        _ => None,
+    };
+    matches!((family(from), family(to)), (Some(a), Some(b)) if a != b)
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The zeroth-order modified Bessel function of the first kind, which the Kaiser window is made
+/// of.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-17 {
+            break;
+        }
+    }
+    sum
+}
+
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    quality: Quality,
+    channels: usize,
+    /// The filter's length, in input frames.
+    taps: usize,
+    /// The reduced ratio, with `to / from` equal to `phases / step`.
+    phases: u64,
+    step: u64,
+    /// `taps` coefficients for each phase, oldest input first.
+    table: Vec<f32>,
+    /// The last `taps` input frames of each channel, twice over so that any `taps` of them in a
+    /// row can be read without wrapping.
+    history: Vec<f32>,
+    /// How many input frames have arrived, counting the half a filter of silence it starts with,
+    /// which turns waiting on the frames after each output into a fixed delay.
+    received: u64,
+    /// Where the next output frame falls, in input frames times `phases`.
+    position: u64,
+    cpu: Arc<CpuUsage>,
+}
+
+impl Resampler {
+    /// `None` unless [`supported`] says the rates can be converted between.
+    pub fn new(from: u32, to: u32, channels: usize, quality: Quality) -> Option<Self> {
+        if !supported(from, to) || channels == 0 {
+            return None;
+        }
+        let divisor = gcd(from as u64, to as u64);
+        let phases = to as u64 / divisor;
+        let step = from as u64 / divisor;
+
+        let (length, beta, rolloff) = quality.filter();
+        // Downsampling needs the same filter stretched over more input frames.
+        let stretch = (from as f64 / to as f64).max(1.0);
+        let half = ((length / 2) as f64 * stretch).ceil() as usize;
+        let taps = 2 * half;
+        // The cutoff as a fraction of the input's Nyquist.
+        let cutoff = rolloff * (to as f64 / from as f64).min(1.0);
+
+        let window_scale = bessel_i0(beta);
+        let mut table = Vec::with_capacity(phases as usize * taps);
+        for phase in 0..phases {
+            let start = table.len();
+            for k in 0..taps {
+                // How far the input frame is from where the output falls, in input frames.
+                let distance = phase as f64 / phases as f64 + (half - 1) as f64 - k as f64;
+                let x = distance * cutoff;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                };
+                let edge = distance / half as f64;
+                let window = bessel_i0(beta * (1.0 - edge * edge).max(0.0).sqrt()) / window_scale;
+                table.push((cutoff * sinc * window) as f32);
+            }
+            // Leave every phase with exactly unity gain at DC, so nothing ripples in level.
+            let sum = table[start..].iter().map(|&c| c as f64).sum::<f64>();
+            for coefficient in &mut table[start..] {
+                *coefficient = (*coefficient as f64 / sum) as f32;
+            }
+        }
+
+        Some(Resampler {
+            from,
+            to,
+            quality,
+            channels,
+            taps,
+            phases,
+            step,
+            table,
+            history: vec![0.0; channels * 2 * taps],
+            received: half as u64,
+            position: 0,
+            cpu: Arc::new(CpuUsage::default()),
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The delay the filter adds, in output frames.
+    pub fn latency_frames(&self) -> usize {
+        (self.taps as u64 / 2 * self.phases).div_ceil(self.step) as usize
+    }
+
+    /// The most output frames [`Resampler::process`] can give for `input_frames`.
+    pub fn max_output_frames(&self, input_frames: usize) -> usize {
+        (input_frames as u64 * self.phases).div_ceil(self.step) as usize + 1
+    }
+
+    /// The most input frames whose output is sure to fit in `output_frames`.
+    pub fn max_input_frames(&self, output_frames: usize) -> usize {
+        (output_frames.saturating_sub(2) as u64 * self.step / self.phases) as usize
+    }
+
+    /// The bytes the filter table and the history take up.
+    pub fn memory_bytes(&self) -> usize {
+        (self.table.len() + self.history.len()) * std::mem::size_of::<f32>()
+    }
+
+    /// A handle on the time spent resampling, which stays valid after the resampler has been
+    /// moved into an audio callback.
+    pub fn cpu_usage(&self) -> Arc<CpuUsage> {
+        Arc::clone(&self.cpu)
+    }
+
+    /// Resamples interleaved `input` into `output`, returning how many samples it wrote, which
+    /// is at most [`Resampler::max_output_frames`] frames' worth.
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) -> usize {
+        let start = std::time::Instant::now();
+        let half = self.taps as u64 / 2;
+        let mut written = 0;
+        for frame in input.chunks_exact(self.channels) {
+            let slot = (self.received % self.taps as u64) as usize;
+            for (channel, &sample) in frame.iter().enumerate() {
+                let history = &mut self.history[channel * 2 * self.taps..];
+                history[slot] = sample;
+                history[slot + self.taps] = sample;
+            }
+            self.received += 1;
+
+            // Every output frame whose last input frame has now arrived.
+            while self.position / self.phases + half < self.received {
+                let phase = (self.position % self.phases) as usize;
+                let coefficients = &self.table[phase * self.taps..][..self.taps];
+                let oldest = (self.received % self.taps as u64) as usize;
+                for channel in 0..self.channels {
+                    let history = &self.history[channel * 2 * self.taps + oldest..][..self.taps];
+                    output[written] = history
+                        .iter()
+                        .zip(coefficients)
+                        .map(|(sample, coefficient)| sample * coefficient)
+                        .sum();
+                    written += 1;
+                }
+                self.position += self.step;
+            }
+        }
+        self.cpu
+            .record(start.elapsed().as_nanos() as u64, input.len() as u64);
+        written
+    }
+
+    /// Forgets every input frame so far, starting over from the half a filter of silence it was
+    /// built with.
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.received = self.taps as u64 / 2;
+        self.position = 0;
+    }
+
+    /// Like `44100 → 48000 Hz, high quality, 192 taps over 160 phases`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} → {} Hz, {} quality, {} taps over {} phases",
+            self.from, self.to, self.quality, self.taps, self.phases
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [Quality; 3] = [Quality::Fast, Quality::Balanced, Quality::High];
+
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                (std::f64::consts::TAU * frequency * frame as f64 / rate as f64).sin() as f32 * 0.5
+            })
+            .collect()
+    }
+
+    /// Resamples all of mono `input` a callback's worth at a time.
+    fn resample(resampler: &mut Resampler, input: &[f32], chunk: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        let mut buffer = vec![0.0; resampler.max_output_frames(chunk)];
+        for chunk in input.chunks(chunk) {
+            let written = resampler.process(chunk, &mut buffer);
+            output.extend_from_slice(&buffer[..written]);
+        }
+        output
+    }
+
+    /// The amplitude of `frequency` in `samples`, through a Blackman-Harris window so that
+    /// nothing far from it leaks in.
+    fn amplitude(samples: &[f32], frequency: f64, rate: u32) -> f64 {
+        let n = samples.len() as f64;
+        let (mut re, mut im, mut weight) = (0.0, 0.0, 0.0);
+        for (i, &sample) in samples.iter().enumerate() {
+            let t = std::f64::consts::TAU * i as f64 / n;
+            let window =
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos();
+            let phase = std::f64::consts::TAU * frequency * i as f64 / rate as f64;
+            re += sample as f64 * window * phase.cos();
+            im -= sample as f64 * window * phase.sin();
+            weight += window;
+        }
+        2.0 * (re * re + im * im).sqrt() / weight
+    }
+
+    fn db(ratio: f64) -> f64 {
+        20.0 * ratio.log10()
+    }
+
+    /// The tone's level after resampling, relative to before, once the filter has filled.
+    fn gain(from: u32, to: u32, quality: Quality, frequency: f64) -> f64 {
+        let mut resampler = Resampler::new(from, to, 1, quality).unwrap();
+        let output = resample(&mut resampler, &sine(frequency, from, from as usize), 512);
+        let steady = &output[resampler.latency_frames() + 1_000..][..16_384];
+        db(amplitude(steady, frequency, to) / 0.5)
+    }
+
+    #[test]
+    fn only_converts_between_the_families() {
+        assert!(supported(44_100, 48_000));
+        assert!(supported(96_000, 88_200));
+        assert!(supported(22_050, 16_000));
+        assert!(!supported(48_000, 96_000));
+        assert!(!supported(44_100, 44_100));
+        // Neither family, or both.
+        assert!(!supported(48_000, 37_000));
+        assert!(!supported(352_800, 44_100));
+        assert!(Resampler::new(48_000, 32_000, 2, Quality::High).is_none());
+        assert!(Resampler::new(44_100, 48_000, 0, Quality::High).is_none());
+    }
+
+    #[test]
+    fn gives_as_many_frames_as_the_ratio_says_however_it_is_fed() {
+        for (from, to) in [(44_100, 48_000), (48_000, 44_100), (88_200, 48_000)] {
+            let input = sine(1_000.0, from, from as usize * 2);
+            let mut whole = Resampler::new(from, to, 1, Quality::Balanced).unwrap();
+            let mut buffer = vec![0.0; whole.max_output_frames(input.len())];
+            let written = whole.process(&input, &mut buffer);
+            assert!(
+                (written as i64 - 2 * to as i64).abs() <= 1,
+                "{} → {}: {}",
+                from,
+                to,
+                written
+            );
+
+            // In odd-sized callbacks, which the output must match exactly.
+            let mut chunked = Resampler::new(from, to, 1, Quality::Balanced).unwrap();
+            assert_eq!(resample(&mut chunked, &input, 333), buffer[..written]);
+            assert!(whole.max_input_frames(1_000) <= 1_000 * from as usize / to as usize);
+        }
+    }
+
+    #[test]
+    fn delays_by_the_latency_it_reports() {
+        for quality in QUALITIES {
+            let mut resampler = Resampler::new(44_100, 48_000, 2, quality).unwrap();
+            let mut input = vec![0.0; 2 * 4_410];
+            input[0] = 1.0;
+            input[1] = -1.0;
+            let mut output = vec![0.0; 2 * resampler.max_output_frames(4_410)];
+            let written = resampler.process(&input, &mut output);
+            let peak = output[..written]
+                .chunks(2)
+                .enumerate()
+                .max_by(|a, b| a.1[0].abs().total_cmp(&b.1[0].abs()))
+                .map(|(frame, samples)| {
+                    assert_eq!(samples[0], -samples[1]);
+                    frame
+                })
+                .unwrap();
+            let latency = resampler.latency_frames();
+            assert!(
+                peak.abs_diff(latency) <= 1,
+                "{}: {} vs {}",
+                quality,
+                peak,
+                latency
+            );
+
+            // And starts over from silence once reset.
+            resampler.reset();
+            let written = resampler.process(&vec![0.0; 2 * 4_410], &mut output);
+            assert!(output[..written].iter().all(|&sample| sample == 0.0));
+        }
+    }
+
+    #[test]
+    fn keeps_the_passband_flat() {
+        // As flat as each quality says it is, going either way between the families.
+        for (quality, flat_to) in [
+            (Quality::Fast, 17_000.0),
+            (Quality::Balanced, 18_000.0),
+            (Quality::High, 20_000.0),
+        ] {
+            for (from, to) in [(44_100, 48_000), (48_000, 44_100)] {
+                for frequency in [100.0, 1_000.0, 10_000.0, flat_to] {
+                    let gain = gain(from, to, quality, frequency);
+                    assert!(
+                        gain.abs() < 0.1,
+                        "{} {} → {} at {} Hz: {:.3} dB",
+                        quality,
+                        from,
+                        to,
+                        frequency,
+                        gain
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_a_20_khz_tone_from_aliasing_at_high_quality() {
+        // Upsampling, a 20 kHz tone's first image is at 24.1 kHz, which a 48 kHz stream folds
+        // back to 23.9 kHz.
+        let mut resampler = Resampler::new(44_100, 48_000, 1, Quality::High).unwrap();
+        let output = resample(&mut resampler, &sine(20_000.0, 44_100, 44_100), 512);
+        let steady = &output[resampler.latency_frames() + 1_000..][..32_768];
+        let tone = amplitude(steady, 20_000.0, 48_000);
+        let image = amplitude(steady, 23_900.0, 48_000);
+        assert!(db(image / tone) < -100.0, "{:.1} dB", db(image / tone));
+
+        // Downsampling, 23 kHz is above the new Nyquist and would fold back to 21.1 kHz.
+        let mut resampler = Resampler::new(48_000, 44_100, 1, Quality::High).unwrap();
+        let output = resample(&mut resampler, &sine(23_000.0, 48_000, 48_000), 512);
+        let steady = &output[resampler.latency_frames() + 1_000..][..32_768];
+        let alias = amplitude(steady, 21_100.0, 44_100);
+        assert!(db(alias / 0.5) < -100.0, "{:.1} dB", db(alias / 0.5));
+        // While a 20 kHz tone comes through whole.
+        assert!(gain(48_000, 44_100, Quality::High, 20_000.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn describes_its_filter_and_costs_more_the_higher_the_quality() {
+        let resamplers =
+            QUALITIES.map(|quality| Resampler::new(44_100, 48_000, 2, quality).unwrap());
+        assert_eq!(
+            resamplers[2].describe(),
+            "44100 → 48000 Hz, high quality, 192 taps over 160 phases"
+        );
+        for pair in resamplers.windows(2) {
+            assert!(pair[0].memory_bytes() < pair[1].memory_bytes());
+            assert!(pair[0].latency_frames() < pair[1].latency_frames());
+        }
+        for quality in ["fast", "balanced", "high"] {
+            assert_eq!(Quality::parse(quality).unwrap().to_string(), quality);
+        }
+        assert!(Quality::parse("best").is_err());
+    }
+}
@@ -0,0 +1,420 @@
+//! A small Freeverb-style reverb, sent from an input and mixed back in on top of it.
+//!
+//! Every channel runs its own network of eight feedback combs into four allpasses, all fed the
+//! same mono sum of the input, with odd channels' delays offset a little so stereo comes out wide.
+//! The delay lines are allocated up front for the sample rate, so the memory and the work per
+//! frame are fixed once the stage is built.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::chain::{CpuUsage, ProcessStage, StageKind};
+use crate::pipeline::ms_to_frames;
+
+/// Freeverb's comb and allpass delays, in frames at 44.1 kHz.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+/// How much longer every delay is on odd channels.
+const STEREO_SPREAD: usize = 23;
+const TUNING_RATE: f32 = 44_100.0;
+
+const ROOM_SIZE: f32 = 0.84;
+const DAMPING: f32 = 0.2;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+/// Scales the input down so eight combs summed don't clip.
+const INPUT_GAIN: f32 = 0.015;
+/// Scales the wet level so 1 is about as loud as the dry signal.
+const WET_SCALE: f32 = 3.0;
+/// How long the send takes to fade in or out.
+const FADE_MS: f32 = 50.0;
+
+/// The send's level and whether it's on, which the control thread can change at any time.
+#[derive(Debug)]
+pub struct ReverbSend {
+    enabled: AtomicBool,
+    /// From 0 to 1, as f32 bits.
+    wet: AtomicU32,
+}
+
+impl ReverbSend {
+    pub fn new(wet: f32) -> Self {
+        ReverbSend {
+            enabled: AtomicBool::new(false),
+            wet: AtomicU32::new(wet.to_bits()),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_wet(&self, wet: f32) {
+        self.wet.store(wet.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn wet(&self) -> f32 {
+        f32::from_bits(self.wet.load(Ordering::Relaxed))
+    }
+
+    /// The wet level the stage fades towards.
+    fn target(&self) -> f32 {
+        if self.enabled() {
+            self.wet()
+        } else {
+            0.0
+        }
+    }
+}
+
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter = output * (1.0 - DAMPING) + self.filter * DAMPING;
+        self.buffer[self.index] = input + self.filter * ROOM_SIZE;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * ALLPASS_FEEDBACK;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+struct Network {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Network {
+    fn new(sample_rate: u32, spread: usize) -> Self {
+        let scale = |frames: usize| {
+            (((frames + spread) as f32 * sample_rate as f32 / TUNING_RATE).round() as usize).max(1)
+        };
+        Network {
+            combs: COMB_TUNING
+                .iter()
+                .map(|&frames| Comb {
+                    buffer: vec![0.0; scale(frames)],
+                    index: 0,
+                    filter: 0.0,
+                })
+                .collect(),
+            allpasses: ALLPASS_TUNING
+                .iter()
+                .map(|&frames| Allpass {
+                    buffer: vec![0.0; scale(frames)],
+                    index: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let mut output = self.combs.iter_mut().map(|comb| comb.process(input)).sum();
+        for allpass in &mut self.allpasses {
+            output = allpass.process(output);
+        }
+        output
+    }
+
+    fn clear(&mut self) {
+        for comb in &mut self.combs {
+            comb.buffer.fill(0.0);
+            comb.filter = 0.0;
+        }
+        for allpass in &mut self.allpasses {
+            allpass.buffer.fill(0.0);
+        }
+    }
+
+    fn samples(&self) -> usize {
+        let combs = self.combs.iter().map(|comb| comb.buffer.len());
+        let allpasses = self.allpasses.iter().map(|allpass| allpass.buffer.len());
+        combs.chain(allpasses).sum()
+    }
+}
+
+pub struct Reverb {
+    send: Arc<ReverbSend>,
+    channels: Vec<Network>,
+    /// Where the fade towards the send's target has got to.
+    wet: f32,
+    fade_step: f32,
+    /// Whether the networks have been played into since they were last cleared.
+    ringing: bool,
+    cpu: Arc<CpuUsage>,
+}
+
+impl Reverb {
+    pub fn new(channels: usize, sample_rate: u32, send: Arc<ReverbSend>) -> Self {
+        Reverb {
+            wet: send.target(),
+            send,
+            channels: (0..channels)
+                .map(|channel| Network::new(sample_rate, (channel % 2) * STEREO_SPREAD))
+                .collect(),
+            fade_step: 1.0 / ms_to_frames(FADE_MS, sample_rate).max(1) as f32,
+            ringing: false,
+            cpu: Arc::new(CpuUsage::default()),
+        }
+    }
+
+    /// The comb and allpass taps run for every frame, which is all the work the reverb does.
+    pub fn taps_per_frame(&self) -> usize {
+        self.channels.len() * (COMB_TUNING.len() + ALLPASS_TUNING.len())
+    }
+
+    /// A handle on the time spent processing, which stays valid after the reverb has been moved
+    /// into an audio callback.
+    pub fn cpu_usage(&self) -> Arc<CpuUsage> {
+        Arc::clone(&self.cpu)
+    }
+
+    /// Fades the wet level one frame further, returning it. Once it's faded out, whatever is left
+    /// ringing in the networks is cleared, so turning the send back on doesn't replay it.
+    fn next_wet(&mut self, target: f32) -> f32 {
+        if self.wet < target {
+            self.wet = (self.wet + self.fade_step).min(target);
+        } else if self.wet > target {
+            self.wet = (self.wet - self.fade_step).max(target);
+        }
+        if self.wet == 0.0 && self.ringing {
+            self.channels.iter_mut().for_each(Network::clear);
+            self.ringing = false;
+        }
+        self.wet
+    }
+
+    /// Whether the send is off and has finished fading out, so the audio passes untouched.
+    fn bypassed(&self, target: f32) -> bool {
+        target == 0.0 && self.wet == 0.0 && !self.ringing
+    }
+}
+
+impl ProcessStage for Reverb {
+    fn kind(&self) -> StageKind {
+        StageKind::Reverb
+    }
+
+    fn process(&mut self, frames: &mut [f32], channels: usize) {
+        let target = self.send.target();
+        if frames.is_empty() || self.bypassed(target) {
+            return;
+        }
+        let start = std::time::Instant::now();
+
+        for frame in frames.chunks_exact_mut(channels) {
+            let wet = self.next_wet(target) * WET_SCALE;
+            if wet == 0.0 {
+                continue;
+            }
+            self.ringing = true;
+            let input = frame.iter().sum::<f32>() * INPUT_GAIN;
+            for (sample, network) in frame.iter_mut().zip(&mut self.channels) {
+                *sample += network.process(input) * wet;
+            }
+        }
+
+        self.cpu
+            .record(start.elapsed().as_nanos() as u64, frames.len() as u64);
+    }
+
+    fn process_planar(&mut self, planes: &mut [f32], frames: usize) {
+        let target = self.send.target();
+        if frames == 0 || self.bypassed(target) {
+            return;
+        }
+        let start = std::time::Instant::now();
+
+        for frame in 0..frames {
+            let wet = self.next_wet(target) * WET_SCALE;
+            if wet == 0.0 {
+                continue;
+            }
+            self.ringing = true;
+            let input = (0..self.channels.len())
+                .map(|channel| planes[channel * frames + frame])
+                .sum::<f32>()
+                * INPUT_GAIN;
+            for (channel, network) in self.channels.iter_mut().enumerate() {
+                planes[channel * frames + frame] += network.process(input) * wet;
+            }
+        }
+
+        self.cpu
+            .record(start.elapsed().as_nanos() as u64, planes.len() as u64);
+    }
+
+    fn reset(&mut self) {
+        self.channels.iter_mut().for_each(Network::clear);
+        self.ringing = false;
+        self.wet = self.send.target();
+    }
+
+    /// The bytes the delay lines take up, which is all the reverb ever allocates.
+    fn memory_bytes(&self) -> usize {
+        let samples = self.channels.iter().map(Network::samples).sum::<usize>();
+        samples * std::mem::size_of::<f32>()
+    }
+
+    fn describe(&self) -> String {
+        format!("reverb ({} KB)", self.memory_bytes().div_ceil(1024))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// A stereo reverb with the send on at `wet`, already faded in.
+    fn reverb(wet: f32) -> Reverb {
+        let send = Arc::new(ReverbSend::new(wet));
+        send.set_enabled(true);
+        let mut reverb = Reverb::new(2, RATE, send);
+        reverb.reset();
+        reverb
+    }
+
+    fn energy(frames: &[f32]) -> f32 {
+        frames.iter().map(|sample| sample * sample).sum()
+    }
+
+    #[test]
+    fn impulse_rings_out_in_a_decaying_tail() {
+        let mut reverb = reverb(0.5);
+        let mut frames = vec![0.0; 2 * RATE as usize * 2];
+        frames[0] = 1.0;
+        frames[1] = 1.0;
+        reverb.process(&mut frames, 2);
+
+        // Every tenth of a second after the first holds less than the one before.
+        let windows = frames
+            .chunks(RATE as usize / 10 * 2)
+            .map(energy)
+            .collect::<Vec<_>>();
+        assert!(windows[1] > 0.0);
+        for pair in windows[1..].windows(2) {
+            assert!(pair[1] < pair[0], "{:?}", windows);
+        }
+        assert!(windows.last().unwrap() * 100.0 < windows[1]);
+    }
+
+    #[test]
+    fn no_wet_is_bit_identical_to_bypass() {
+        let input = (0..4800)
+            .map(|i| (i as f32 * 0.37).sin() * 0.8)
+            .collect::<Vec<_>>();
+        for mut reverb in [
+            reverb(0.0),
+            Reverb::new(2, RATE, Arc::new(ReverbSend::new(0.5))),
+        ] {
+            let mut frames = input.clone();
+            reverb.process(&mut frames, 2);
+            assert!(frames
+                .iter()
+                .zip(&input)
+                .all(|(out, dry)| out.to_bits() == dry.to_bits()));
+
+            let mut planes = input.clone();
+            reverb.process_planar(&mut planes, input.len() / 2);
+            assert!(planes
+                .iter()
+                .zip(&input)
+                .all(|(out, dry)| out.to_bits() == dry.to_bits()));
+        }
+    }
+
+    #[test]
+    fn reset_stage_is_silent_for_silent_input() {
+        let mut reverb = reverb(0.5);
+        let mut frames = vec![0.5; 4800];
+        reverb.process(&mut frames, 2);
+
+        let mut ringing = vec![0.0; 4800];
+        reverb.process(&mut ringing, 2);
+        assert!(energy(&ringing) > 0.0);
+
+        reverb.reset();
+        let mut silence = vec![0.0; 4800];
+        reverb.process(&mut silence, 2);
+        assert!(silence.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn fades_the_send_in_and_out_without_replaying_its_tail() {
+        let send = Arc::new(ReverbSend::new(0.5));
+        let mut reverb = Reverb::new(2, RATE, Arc::clone(&send));
+        let fade_frames = ms_to_frames(FADE_MS, RATE);
+
+        send.set_enabled(true);
+        let mut frames = vec![0.5; 2 * fade_frames / 4];
+        reverb.process(&mut frames, 2);
+        // A quarter of the way through fading the whole way in, which is half the way to 0.5.
+        assert!((reverb.wet - 0.25).abs() < 0.001, "{}", reverb.wet);
+        let mut frames = vec![0.5; 2 * fade_frames];
+        reverb.process(&mut frames, 2);
+        assert_eq!(reverb.wet, 0.5);
+
+        // Still ringing once it's off, until it has faded out.
+        send.set_enabled(false);
+        let mut fading = vec![0.0; 2 * fade_frames / 4];
+        reverb.process(&mut fading, 2);
+        assert!(energy(&fading) > 0.0);
+        let mut rest = vec![0.0; 2 * fade_frames];
+        reverb.process(&mut rest, 2);
+        assert_eq!(reverb.wet, 0.0);
+
+        send.set_enabled(true);
+        let mut silence = vec![0.0; 4800];
+        reverb.process(&mut silence, 2);
+        assert!(silence.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn sizes_its_work_and_memory_by_the_rate_alone() {
+        let mut reverb = reverb(0.5);
+        assert_eq!(reverb.taps_per_frame(), 2 * 12);
+        let bytes = reverb.memory_bytes();
+        // About Freeverb's delays, scaled from 44.1 kHz, for each channel.
+        let tuned = (COMB_TUNING.iter().sum::<usize>() + ALLPASS_TUNING.iter().sum::<usize>())
+            as f32
+            * RATE as f32
+            / TUNING_RATE;
+        let per_channel = bytes as f32 / 2.0 / 4.0;
+        assert!((per_channel / tuned - 1.0).abs() < 0.02, "{}", per_channel);
+
+        let mut frames = vec![0.5; 2 * RATE as usize];
+        reverb.process(&mut frames, 2);
+        assert_eq!(reverb.memory_bytes(), bytes);
+        assert_eq!(
+            reverb.describe(),
+            format!("reverb ({} KB)", bytes.div_ceil(1024))
+        );
+
+        let double = Reverb::new(2, 2 * RATE, Arc::new(ReverbSend::new(0.5)));
+        let ratio = double.memory_bytes() as f32 / bytes as f32;
+        assert!((ratio - 2.0).abs() < 0.01, "{}", ratio);
+    }
+}
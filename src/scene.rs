@@ -0,0 +1,921 @@
+//! Scenes: named sets of input levels and mutes, duck-hold and reverb settings, switched between
+//! with `scene <name>`.
+//!
+//! A scene is written as its name, then `:` and its settings separated by commas, like
+//! `gameplay: Game Capture=0dB, Microphone=-6dB, Music=mute, duck=off, reverb=0.2`. An input
+//! given a level plays at that level, one given `mute` is muted and keeps its level, and anything
+//! a scene doesn't mention is left how it is.
+//!
+//! Switching ramps every input's gain from where it is to where the scene puts it over the
+//! transition, on the control thread, by moving the same [`OverrideGain`]s the other commands do,
+//! so the input callbacks smooth each step. The reverb's wet level ramps the same way, while
+//! duck-hold fades by itself.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::control::{DuckHold, DuckHoldCommand, MasterControl, ReverbCommand, ReverbControl};
+use crate::pipeline::OverrideGain;
+
+/// How quiet an input is taken to be while a ramp passes through silence, in dB.
+const SILENT_DB: f32 = -120.0;
+
+/// What a scene does to one input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputSetting {
+    /// Plays it at this level, in dB.
+    Level(f32),
+    Muted,
+}
+
+/// What a scene does to the reverb.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReverbSetting {
+    On,
+    Off,
+    /// Turns it on at this wet level, from 0 to 1.
+    Wet(f32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scene {
+    name: String,
+    inputs: Vec<(String, InputSetting)>,
+    duck: Option<bool>,
+    reverb: Option<ReverbSetting>,
+}
+
+impl Scene {
+    /// Parses a scene written like `gameplay: Game Capture=0dB, Music=mute, duck=off`.
+    pub fn parse(definition: &str) -> Result<Self, SceneError> {
+        let invalid = |reason: String| SceneError::Invalid {
+            definition: definition.trim().to_owned(),
+            reason,
+        };
+        let (name, settings) = definition
+            .split_once(':')
+            .ok_or_else(|| invalid("expected its name, then `:` and its settings".to_owned()))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) || name.contains('"') {
+            return Err(invalid(
+                "its name has to be one word, to be switched to with `scene <name>`".to_owned(),
+            ));
+        }
+        if name == "list" {
+            return Err(invalid(
+                "`scene list` lists the scenes, so none can be called `list`".to_owned(),
+            ));
+        }
+        let mut scene = Scene {
+            name: name.to_owned(),
+            inputs: Vec::new(),
+            duck: None,
+            reverb: None,
+        };
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            // Input names can have `=` in them, but levels can't.
+            let (key, value) = setting
+                .rsplit_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "expected `<input>=<level>`, `<input>=mute`, `duck=on|off` or \
+                         `reverb=on|off|<wet>`, got `{}`",
+                        setting
+                    ))
+                })?;
+            match key {
+                "duck" => {
+                    scene.duck = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => {
+                            return Err(invalid(format!(
+                                "`duck` expects `on` or `off`, got `{}`",
+                                value
+                            )))
+                        }
+                    })
+                }
+                "reverb" => {
+                    scene.reverb = Some(match value {
+                        "on" => ReverbSetting::On,
+                        "off" => ReverbSetting::Off,
+                        wet => ReverbSetting::Wet(
+                            wet.parse()
+                                .ok()
+                                .filter(|wet| (0.0..=1.0).contains(wet))
+                                .ok_or_else(|| {
+                                    invalid(format!(
+                                        "`reverb` expects `on`, `off` or a wet level from 0 to \
+                                         1, got `{}`",
+                                        wet
+                                    ))
+                                })?,
+                        ),
+                    })
+                }
+                "" => return Err(invalid(format!("`{}` doesn't name an input", setting))),
+                input => {
+                    let setting = match value {
+                        "mute" => InputSetting::Muted,
+                        level => InputSetting::Level(parse_level(level).ok_or_else(|| {
+                            invalid(format!(
+                                "expected a level up to {:+.0}dB like `-6dB`, or `mute`, for \
+                                 \"{}\", got `{}`",
+                                MasterControl::MAX_DB,
+                                input,
+                                level
+                            ))
+                        })?),
+                    };
+                    // The last setting for an input wins.
+                    scene.inputs.retain(|(name, _)| name != input);
+                    scene.inputs.push((input.to_owned(), setting));
+                }
+            }
+        }
+        if scene.inputs.is_empty() && scene.duck.is_none() && scene.reverb.is_none() {
+            return Err(invalid("it doesn't set anything".to_owned()));
+        }
+        Ok(scene)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether switching to it changes the reverb, which needs an input to have one.
+    pub fn sets_reverb(&self) -> bool {
+        self.reverb.is_some()
+    }
+}
+
+/// Parses levels like `-6dB`, `+3 dB` or `0`.
+fn parse_level(value: &str) -> Option<f32> {
+    value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value)
+        .trim()
+        .parse()
+        .ok()
+        .filter(|db: &f32| db.is_finite() && *db <= MasterControl::MAX_DB)
+}
+
+/// Reads a file of scenes, one to a line, written the same as [`Scene::parse`] takes them. Blank
+/// lines and ones starting with `#` are skipped.
+pub fn load(path: &Path) -> Result<Vec<Scene>, SceneError> {
+    let text = std::fs::read_to_string(path).map_err(|source| SceneError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line, definition)| {
+            Scene::parse(definition).map_err(|source| SceneError::At {
+                path: path.to_owned(),
+                line,
+                source: Box::new(source),
+            })
+        })
+        .collect()
+}
+
+/// The scenes there are, which one is current, and the switch to it while that's under way.
+pub struct Scenes {
+    scenes: Vec<Scene>,
+    transition: Duration,
+    current: Option<usize>,
+    /// The level and mute the scenes have left each input they've set at, for a rebuilt pipeline
+    /// to be set up the same.
+    left: Vec<(String, f32, bool)>,
+    switch: Option<Switch>,
+}
+
+struct Switch {
+    started: Instant,
+    duration: Duration,
+    inputs: Vec<InputRamp>,
+    reverb: Option<ReverbRamp>,
+}
+
+/// An input's gain on its way from one linear gain to another, and the level and mute it's left
+/// at once there.
+struct InputRamp {
+    name: String,
+    gain: Arc<OverrideGain>,
+    from: f32,
+    to: f32,
+    level_db: f32,
+    muted: bool,
+}
+
+/// The reverb's wet level on its way from one level to another, counting off as 0, and the state
+/// it's left in once there.
+struct ReverbRamp {
+    from: f32,
+    to: f32,
+    wet: f32,
+    enabled: bool,
+}
+
+impl Scenes {
+    /// Each switch takes `transition` unless it says otherwise. Fails if two scenes have the same
+    /// name.
+    pub fn new(scenes: Vec<Scene>, transition: Duration) -> Result<Self, SceneError> {
+        for (index, scene) in scenes.iter().enumerate() {
+            if scenes[..index].iter().any(|other| other.name == scene.name) {
+                return Err(SceneError::Duplicate(scene.name.clone()));
+            }
+        }
+        Ok(Scenes {
+            scenes,
+            transition,
+            current: None,
+            left: Vec::new(),
+            switch: None,
+        })
+    }
+
+    /// Starts switching to the scene called `name` over `transition`, or the default transition,
+    /// from wherever everything is, even partway through another switch. `gains` are every
+    /// input's, by name.
+    ///
+    /// Returns the inputs the scene sets that aren't there, which are skipped. Fails without
+    /// changing anything if there's no such scene, or if it sets the reverb and no input has one.
+    pub fn switch(
+        &mut self,
+        name: &str,
+        transition: Option<Duration>,
+        gains: &[(String, Arc<OverrideGain>)],
+        duck_hold: &mut DuckHold,
+        reverb: &mut ReverbControl,
+        now: Instant,
+    ) -> Result<Vec<String>, SceneError> {
+        let index = self
+            .scenes
+            .iter()
+            .position(|scene| scene.name == name)
+            .ok_or_else(|| SceneError::Unknown {
+                name: name.to_owned(),
+                known: self.names(),
+            })?;
+        let scene = &self.scenes[index];
+        if scene.reverb.is_some() && reverb.is_empty() {
+            return Err(SceneError::NoReverb(scene.name.clone()));
+        }
+
+        let previous = self.switch.take();
+        let mut missing = Vec::new();
+        let mut inputs = Vec::new();
+        for (input, setting) in &scene.inputs {
+            let Some((_, gain)) = gains.iter().find(|(name, _)| name == input) else {
+                missing.push(input.clone());
+                continue;
+            };
+            let (level_db, muted) = match *setting {
+                InputSetting::Level(db) => (db, false),
+                // The level it's left at, not wherever a ramp has got it to.
+                InputSetting::Muted => match self.left.iter().find(|(name, _, _)| name == input) {
+                    Some(&(_, level_db, _)) => (level_db, true),
+                    None => (gain.level_db(), true),
+                },
+            };
+            self.left.retain(|(name, _, _)| name != input);
+            self.left.push((input.clone(), level_db, muted));
+            inputs.push(InputRamp {
+                name: input.clone(),
+                gain: Arc::clone(gain),
+                from: linear_gain(gain),
+                to: if muted { 0.0 } else { db_to_gain(level_db) },
+                level_db,
+                muted,
+            });
+        }
+
+        if let Some(on) = scene.duck {
+            duck_hold.apply(
+                if on {
+                    DuckHoldCommand::On
+                } else {
+                    DuckHoldCommand::Off
+                },
+                now,
+            );
+        }
+
+        let resting_wet = previous
+            .as_ref()
+            .and_then(|switch| switch.reverb.as_ref())
+            .map_or(reverb.wet(), |ramp| ramp.wet);
+        let reverb_ramp = scene.reverb.map(|setting| {
+            let (enabled, wet) = match setting {
+                ReverbSetting::On => (true, resting_wet),
+                ReverbSetting::Off => (false, resting_wet),
+                ReverbSetting::Wet(wet) => (true, wet),
+            };
+            ReverbRamp {
+                from: audible_wet(reverb),
+                to: if enabled { wet } else { 0.0 },
+                wet,
+                enabled,
+            }
+        });
+
+        // Whatever the last switch hadn't got to yet carries on from where it is.
+        let mut carried_reverb = None;
+        if let Some(previous) = previous {
+            for ramp in previous.inputs {
+                if !inputs.iter().any(|input| input.name == ramp.name) {
+                    inputs.push(InputRamp {
+                        from: linear_gain(&ramp.gain),
+                        ..ramp
+                    });
+                }
+            }
+            carried_reverb = previous.reverb.map(|ramp| ReverbRamp {
+                from: audible_wet(reverb),
+                ..ramp
+            });
+        }
+        let reverb_ramp = reverb_ramp.or(carried_reverb);
+        if let Some(ramp) = &reverb_ramp {
+            // The sends only check there are some, which has been.
+            let _ = reverb.apply(ReverbCommand::Wet(ramp.from));
+            let _ = reverb.apply(ReverbCommand::On);
+        }
+
+        self.current = Some(index);
+        self.switch = Some(Switch {
+            started: now,
+            duration: transition.unwrap_or(self.transition),
+            inputs,
+            reverb: reverb_ramp,
+        });
+        self.tick(now, reverb);
+        Ok(missing)
+    }
+
+    /// Moves a switch along, returning whether it just finished.
+    pub fn tick(&mut self, now: Instant, reverb: &mut ReverbControl) -> bool {
+        let Some(switch) = &self.switch else {
+            return false;
+        };
+        let elapsed = now.saturating_duration_since(switch.started);
+        if elapsed >= switch.duration {
+            self.finish(reverb);
+            return true;
+        }
+        let progress = elapsed.as_secs_f32() / switch.duration.as_secs_f32();
+        for ramp in &switch.inputs {
+            let gain = ramp.from + (ramp.to - ramp.from) * progress;
+            ramp.gain.set_muted(false);
+            ramp.gain.set_level_db(gain_to_db(gain));
+        }
+        if let Some(ramp) = &switch.reverb {
+            let _ = reverb.apply(ReverbCommand::Wet(
+                ramp.from + (ramp.to - ramp.from) * progress,
+            ));
+        }
+        false
+    }
+
+    /// Puts everything where the switch under way is taking it.
+    fn finish(&mut self, reverb: &mut ReverbControl) {
+        let Some(switch) = self.switch.take() else {
+            return;
+        };
+        for ramp in &switch.inputs {
+            ramp.gain.set_level_db(ramp.level_db);
+            ramp.gain.set_muted(ramp.muted);
+        }
+        if let Some(ramp) = &switch.reverb {
+            let _ = reverb.apply(ReverbCommand::Wet(ramp.wet));
+            if !ramp.enabled {
+                let _ = reverb.apply(ReverbCommand::Off);
+            }
+        }
+    }
+
+    /// Sets the inputs of a rebuilt pipeline up as the scenes left them, finishing any switch
+    /// under way. Call it once `reverb` has been attached too.
+    pub fn attach(&mut self, gains: &[(String, Arc<OverrideGain>)], reverb: &mut ReverbControl) {
+        self.finish(reverb);
+        for (input, level_db, muted) in &self.left {
+            if let Some((_, gain)) = gains.iter().find(|(name, _)| name == input) {
+                gain.set_level_db(*level_db);
+                gain.set_muted(*muted);
+            }
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.scenes.iter().map(|scene| scene.name.clone()).collect()
+    }
+
+    /// Every scene, with the current one marked.
+    pub fn list(&self) -> String {
+        if self.scenes.is_empty() {
+            return "no scenes: define some with `--scene` or `--scenes`".to_owned();
+        }
+        let scenes = self
+            .scenes
+            .iter()
+            .enumerate()
+            .map(|(index, scene)| {
+                if Some(index) == self.current {
+                    format!("{} (current)", scene.name)
+                } else {
+                    scene.name.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        format!("scenes: {}", scenes.join(", "))
+    }
+
+    /// `None` when there are no scenes.
+    pub fn status(&self, now: Instant) -> Option<String> {
+        if self.scenes.is_empty() {
+            return None;
+        }
+        let Some(current) = self.current else {
+            return Some("scene: none switched to yet".to_owned());
+        };
+        let name = &self.scenes[current].name;
+        Some(match &self.switch {
+            Some(switch) => format!(
+                "scene: switching to {}, {:.1}s to go",
+                name,
+                (switch
+                    .duration
+                    .saturating_sub(now.saturating_duration_since(switch.started)))
+                .as_secs_f32()
+            ),
+            None => format!("scene: {}", name),
+        })
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    (20.0 * gain.log10()).max(SILENT_DB)
+}
+
+/// The linear gain a scene has an input at, leaving the override to duck-hold.
+fn linear_gain(gain: &OverrideGain) -> f32 {
+    if gain.muted() {
+        0.0
+    } else {
+        db_to_gain(gain.level_db())
+    }
+}
+
+/// How loud the reverb is, counting off as 0.
+fn audible_wet(reverb: &ReverbControl) -> f32 {
+    if reverb.enabled() {
+        reverb.wet()
+    } else {
+        0.0
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Invalid {
+        definition: String,
+        reason: String,
+    },
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A scene in a file that couldn't be parsed, counting lines from 1.
+    At {
+        path: PathBuf,
+        line: usize,
+        source: Box<SceneError>,
+    },
+    Duplicate(String),
+    Unknown {
+        name: String,
+        known: Vec<String>,
+    },
+    /// Switching to the scene would set the reverb, but no input has one.
+    NoReverb(String),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Invalid { definition, reason } => {
+                write!(f, "`{}` isn't a scene: {}", definition, reason)
+            }
+            SceneError::Read { path, .. } => write!(f, "couldn't read {}", path.display()),
+            SceneError::At { path, line, .. } => {
+                write!(f, "on line {} of {}", line, path.display())
+            }
+            SceneError::Duplicate(name) => write!(f, "there are two scenes called \"{}\"", name),
+            SceneError::Unknown { name, known } if known.is_empty() => write!(
+                f,
+                "there's no scene called \"{}\", or any other: define some with `--scene` or \
+                 `--scenes`",
+                name
+            ),
+            SceneError::Unknown { name, known } => write!(
+                f,
+                "there's no scene called \"{}\": the scenes are {}",
+                name,
+                known.join(", ")
+            ),
+            SceneError::NoReverb(name) => write!(
+                f,
+                "scene \"{}\" sets the reverb, but no input has one: add one with `--reverb`",
+                name
+            ),
+        }
+    }
+}
+
+impl Error for SceneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SceneError::Read { source, .. } => Some(source),
+            SceneError::At { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverb::ReverbSend;
+
+    const TRANSITION: Duration = Duration::from_secs(1);
+
+    struct Fixture {
+        gains: Vec<(String, Arc<OverrideGain>)>,
+        duck_hold: DuckHold,
+        sends: Vec<(String, Arc<ReverbSend>)>,
+        reverb: ReverbControl,
+        scenes: Scenes,
+    }
+
+    fn fixture() -> Fixture {
+        let gains = ["Microphone", "Game", "Music"]
+            .into_iter()
+            .map(|name| (name.to_owned(), Arc::new(OverrideGain::new())))
+            .collect::<Vec<_>>();
+        let sends = vec![("Microphone".to_owned(), Arc::new(ReverbSend::new(0.3)))];
+        let scenes = [
+            "gameplay: Game=0dB, Microphone=-6dB, Music=mute, duck=off, reverb=off",
+            "chatting: Game=-20dB, Microphone=+3dB, Music=-12dB, reverb=0.5",
+        ]
+        .into_iter()
+        .map(|definition| Scene::parse(definition).unwrap())
+        .collect();
+        Fixture {
+            duck_hold: DuckHold::new("Game", Arc::clone(&gains[1].1)),
+            reverb: ReverbControl::new(&sends, 0.3),
+            gains,
+            sends,
+            scenes: Scenes::new(scenes, TRANSITION).unwrap(),
+        }
+    }
+
+    impl Fixture {
+        fn switch(&mut self, name: &str, now: Instant) -> Result<Vec<String>, SceneError> {
+            self.scenes.switch(
+                name,
+                None,
+                &self.gains,
+                &mut self.duck_hold,
+                &mut self.reverb,
+                now,
+            )
+        }
+
+        fn gain(&self, name: &str) -> &OverrideGain {
+            &self
+                .gains
+                .iter()
+                .find(|(input, _)| input == name)
+                .unwrap()
+                .1
+        }
+    }
+
+    #[test]
+    fn parses_scenes() {
+        let scene = Scene::parse(" chatting : Game Capture = -20 dB, Mic=+3dB, Mic=mute, duck=on ")
+            .unwrap();
+        assert_eq!(scene.name(), "chatting");
+        assert_eq!(
+            scene.inputs,
+            [
+                ("Game Capture".to_owned(), InputSetting::Level(-20.0)),
+                ("Mic".to_owned(), InputSetting::Muted),
+            ]
+        );
+        assert_eq!(scene.duck, Some(true));
+        assert!(!scene.sets_reverb());
+        assert_eq!(
+            Scene::parse("fx: reverb=0.25").unwrap().reverb,
+            Some(ReverbSetting::Wet(0.25))
+        );
+
+        for (definition, reason) in [
+            ("Game=0dB", "expected its name"),
+            ("two words: Game=0dB", "one word"),
+            ("list: Game=0dB", "none can be called `list`"),
+            ("quiet:", "doesn't set anything"),
+            ("quiet: Game", "expected `<input>=<level>`"),
+            ("quiet: Game=loud", "for \"Game\", got `loud`"),
+            ("quiet: Game=+20dB", "up to +12dB"),
+            ("quiet: duck=maybe", "`duck` expects"),
+            ("quiet: reverb=2", "wet level from 0 to 1"),
+        ] {
+            let err = Scene::parse(definition).unwrap_err().to_string();
+            assert!(err.contains(reason), "{}: {}", definition, err);
+        }
+    }
+
+    #[test]
+    fn loads_scenes_from_a_file() {
+        let path = std::env::temp_dir().join(format!("scenes-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Scenes for the stream\n\ngameplay: Game=0dB, Music=mute\nchatting: Game=-20dB\n",
+        )
+        .unwrap();
+        let scenes = load(&path).unwrap();
+        assert_eq!(
+            scenes.iter().map(Scene::name).collect::<Vec<_>>(),
+            ["gameplay", "chatting"]
+        );
+
+        std::fs::write(&path, "gameplay: Game=0dB\n\nchatting Game=-20dB\n").unwrap();
+        let err = load(&path).unwrap_err();
+        assert!(err.to_string().starts_with("on line 3 of"), "{}", err);
+        assert!(err
+            .source()
+            .unwrap()
+            .to_string()
+            .contains("`chatting Game=-20dB`"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_two_scenes_with_the_same_name() {
+        let scenes = vec![
+            Scene::parse("quiet: Game=-20dB").unwrap(),
+            Scene::parse("quiet: Music=mute").unwrap(),
+        ];
+        assert!(matches!(
+            Scenes::new(scenes, TRANSITION),
+            Err(SceneError::Duplicate(name)) if name == "quiet"
+        ));
+    }
+
+    #[test]
+    fn switches_every_setting_the_scene_has() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        fixture.duck_hold.apply(DuckHoldCommand::On, now);
+        fixture.reverb.apply(ReverbCommand::On).unwrap();
+
+        assert_eq!(
+            fixture.switch("gameplay", now).unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(fixture.scenes.tick(now + TRANSITION, &mut fixture.reverb));
+        assert_eq!(fixture.gain("Game").level_db(), 0.0);
+        assert!(!fixture.gain("Game").muted());
+        assert_eq!(fixture.gain("Microphone").level_db(), -6.0);
+        assert!(fixture.gain("Music").muted());
+        // Duck-hold is released, which lets the game through again.
+        assert_eq!(fixture.gain("Game").target(), 1.0);
+        assert!(!fixture.reverb.enabled());
+        assert_eq!(fixture.reverb.wet(), 0.3);
+        assert_eq!(
+            fixture.scenes.list(),
+            "scenes: gameplay (current), chatting"
+        );
+        assert_eq!(
+            fixture.scenes.status(now + TRANSITION).unwrap(),
+            "scene: gameplay"
+        );
+
+        fixture.switch("chatting", now + TRANSITION).unwrap();
+        assert!(fixture
+            .scenes
+            .tick(now + TRANSITION * 2, &mut fixture.reverb));
+        assert_eq!(fixture.gain("Game").level_db(), -20.0);
+        assert_eq!(fixture.gain("Microphone").level_db(), 3.0);
+        assert_eq!(fixture.gain("Music").level_db(), -12.0);
+        assert!(!fixture.gain("Music").muted());
+        assert!(fixture.reverb.enabled());
+        assert_eq!(fixture.reverb.wet(), 0.5);
+        assert!(fixture.sends[0].1.enabled());
+        assert_eq!(
+            fixture.scenes.list(),
+            "scenes: gameplay, chatting (current)"
+        );
+    }
+
+    #[test]
+    fn ramps_the_gains_over_the_transition() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        fixture.switch("gameplay", now).unwrap();
+        fixture.scenes.tick(now + TRANSITION, &mut fixture.reverb);
+
+        fixture.switch("chatting", now).unwrap();
+        // Nothing has moved yet, and the reverb is on, but silent, to fade in.
+        assert!((fixture.gain("Microphone").level_db() + 6.0).abs() < 1e-4);
+        assert_eq!(fixture.gain("Music").level_db(), SILENT_DB);
+        assert!(!fixture.gain("Music").muted());
+        assert!(fixture.reverb.enabled());
+        assert_eq!(fixture.reverb.wet(), 0.0);
+
+        // Halfway, each linear gain is halfway to where it's going.
+        assert!(!fixture
+            .scenes
+            .tick(now + TRANSITION / 2, &mut fixture.reverb));
+        let halfway = |from: f32, to: f32| gain_to_db((db_to_gain(from) + db_to_gain(to)) / 2.0);
+        let game = fixture.gain("Game").level_db();
+        assert!((game - halfway(0.0, -20.0)).abs() < 1e-3, "{}", game);
+        let microphone = fixture.gain("Microphone").level_db();
+        assert!(
+            (microphone - halfway(-6.0, 3.0)).abs() < 1e-3,
+            "{}",
+            microphone
+        );
+        let music = db_to_gain(fixture.gain("Music").level_db());
+        assert!((music - db_to_gain(-12.0) / 2.0).abs() < 1e-4, "{}", music);
+        assert!((fixture.reverb.wet() - 0.25).abs() < 1e-6);
+        assert!(fixture
+            .scenes
+            .status(now + TRANSITION / 2)
+            .unwrap()
+            .starts_with("scene: switching to chatting, 0.5s to go"));
+
+        assert!(fixture.scenes.tick(now + TRANSITION, &mut fixture.reverb));
+        assert_eq!(fixture.gain("Game").level_db(), -20.0);
+        assert!(!fixture
+            .scenes
+            .tick(now + TRANSITION * 2, &mut fixture.reverb));
+    }
+
+    #[test]
+    fn fades_an_input_out_to_mute_it_and_keeps_its_level() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        fixture.gain("Music").set_level_db(-3.0);
+        fixture.switch("gameplay", now).unwrap();
+        fixture
+            .scenes
+            .tick(now + TRANSITION / 2, &mut fixture.reverb);
+        assert!(!fixture.gain("Music").muted());
+        let music = db_to_gain(fixture.gain("Music").level_db());
+        assert!((music - db_to_gain(-3.0) / 2.0).abs() < 1e-4, "{}", music);
+
+        fixture.scenes.tick(now + TRANSITION, &mut fixture.reverb);
+        assert!(fixture.gain("Music").muted());
+        assert_eq!(fixture.gain("Music").level_db(), -3.0);
+    }
+
+    #[test]
+    fn switching_partway_carries_on_from_where_everything_is() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        fixture.scenes = Scenes::new(
+            vec![
+                Scene::parse("loud: Game=0dB, Music=0dB").unwrap(),
+                Scene::parse("quiet: Game=-40dB").unwrap(),
+            ],
+            TRANSITION,
+        )
+        .unwrap();
+        fixture.gain("Game").set_level_db(-40.0);
+        fixture.gain("Music").set_muted(true);
+        fixture.switch("loud", now).unwrap();
+        fixture
+            .scenes
+            .tick(now + TRANSITION / 2, &mut fixture.reverb);
+        let game = fixture.gain("Game").level_db();
+
+        fixture.switch("quiet", now + TRANSITION / 2).unwrap();
+        assert!((fixture.gain("Game").level_db() - game).abs() < 1e-3);
+        // Music isn't in the new scene, so it carries on to where the last one was taking it.
+        fixture
+            .scenes
+            .tick(now + TRANSITION * 3 / 2, &mut fixture.reverb);
+        assert_eq!(fixture.gain("Game").level_db(), -40.0);
+        assert_eq!(fixture.gain("Music").level_db(), 0.0);
+        assert!(!fixture.gain("Music").muted());
+    }
+
+    #[test]
+    fn switches_straight_away_without_a_transition() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        fixture
+            .scenes
+            .switch(
+                "chatting",
+                Some(Duration::ZERO),
+                &fixture.gains,
+                &mut fixture.duck_hold,
+                &mut fixture.reverb,
+                now,
+            )
+            .unwrap();
+        assert_eq!(fixture.gain("Game").level_db(), -20.0);
+        assert_eq!(fixture.reverb.wet(), 0.5);
+        assert_eq!(fixture.scenes.status(now).unwrap(), "scene: chatting");
+    }
+
+    #[test]
+    fn refuses_a_scene_that_isnt_defined() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        let err = fixture.switch("cutscene", now).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "there's no scene called \"cutscene\": the scenes are gameplay, chatting"
+        );
+        assert_eq!(
+            fixture.scenes.status(now).unwrap(),
+            "scene: none switched to yet"
+        );
+        for (_, gain) in &fixture.gains {
+            assert_eq!((gain.level_db(), gain.muted()), (0.0, false));
+        }
+
+        let mut empty = Scenes::new(Vec::new(), TRANSITION).unwrap();
+        let err = empty
+            .switch(
+                "gameplay",
+                None,
+                &fixture.gains,
+                &mut fixture.duck_hold,
+                &mut fixture.reverb,
+                now,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("or any other"), "{}", err);
+        assert_eq!(empty.status(now), None);
+    }
+
+    #[test]
+    fn refuses_a_scene_that_sets_the_reverb_without_one() {
+        let mut fixture = fixture();
+        fixture.reverb = ReverbControl::new(&[], 0.3);
+        let err = fixture.switch("chatting", Instant::now()).unwrap_err();
+        assert!(matches!(err, SceneError::NoReverb(name) if name == "chatting"));
+        assert_eq!(fixture.gain("Game").level_db(), 0.0);
+    }
+
+    #[test]
+    fn skips_inputs_that_arent_there() {
+        let mut fixture = fixture();
+        fixture.gains.retain(|(name, _)| name != "Music");
+        let missing = fixture.switch("gameplay", Instant::now()).unwrap();
+        assert_eq!(missing, ["Music"]);
+    }
+
+    #[test]
+    fn sets_a_rebuilt_pipeline_up_as_the_scenes_left_it() {
+        let mut fixture = fixture();
+        let now = Instant::now();
+        fixture.switch("gameplay", now).unwrap();
+        fixture
+            .scenes
+            .tick(now + TRANSITION / 2, &mut fixture.reverb);
+
+        let rebuilt = fixture
+            .gains
+            .iter()
+            .map(|(name, _)| (name.clone(), Arc::new(OverrideGain::new())))
+            .collect::<Vec<_>>();
+        let sends = vec![("Microphone".to_owned(), Arc::new(ReverbSend::new(0.3)))];
+        fixture.reverb.attach(&sends);
+        fixture.scenes.attach(&rebuilt, &mut fixture.reverb);
+        let gain = |name: &str| &rebuilt.iter().find(|(input, _)| input == name).unwrap().1;
+        assert_eq!(gain("Microphone").level_db(), -6.0);
+        assert!(gain("Music").muted());
+        assert!(!sends[0].1.enabled());
+        assert_eq!(fixture.scenes.status(now).unwrap(), "scene: gameplay");
+    }
+}
@@ -0,0 +1,196 @@
+//! An append-only record of what happened during a run, for looking back on long sessions.
+//!
+//! Events come from the control thread, never from the audio callbacks, and each one is a line
+//! with a UTC timestamp, optionally mirrored as a line of JSON in a file of its own.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::recorder::json_string;
+
+/// Where events go, which is nowhere unless a file was given.
+#[derive(Default)]
+pub struct SessionLog {
+    text: Option<(PathBuf, File)>,
+    json: Option<(PathBuf, File)>,
+}
+
+impl SessionLog {
+    /// Opens either file for appending, so a restarted run adds to the same log.
+    pub fn open(text: Option<&Path>, json: Option<&Path>) -> Result<Self, OpenError> {
+        let open = |path: &Path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|file| (path.to_owned(), file))
+                .map_err(|source| OpenError {
+                    path: path.to_owned(),
+                    source,
+                })
+        };
+        Ok(SessionLog {
+            text: text.map(open).transpose()?,
+            json: json.map(open).transpose()?,
+        })
+    }
+
+    /// Appends an event of the given `kind`, like `marker`, described by `detail`.
+    pub fn event(&mut self, kind: &str, detail: &str) {
+        self.event_at(SystemTime::now(), kind, detail);
+    }
+
+    pub fn event_at(&mut self, at: SystemTime, kind: &str, detail: &str) {
+        let unix_time = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let timestamp = utc_timestamp(unix_time);
+        if let Some((path, file)) = &mut self.text {
+            if let Err(err) = writeln!(file, "{} {} {}", timestamp, kind, detail) {
+                log::warn!("couldn't write to {}: {}", path.display(), err);
+            }
+        }
+        if let Some((path, file)) = &mut self.json {
+            let line = format!(
+                "{{\"time\": \"{}\", \"unix_time\": {:.3}, \"event\": {}, \"detail\": {}}}",
+                timestamp,
+                unix_time,
+                json_string(kind),
+                json_string(detail)
+            );
+            if let Err(err) = writeln!(file, "{}", line) {
+                log::warn!("couldn't write to {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// A log file that couldn't be opened.
+#[derive(Debug)]
+pub struct OpenError {
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "couldn't open {}", self.path.display())
+    }
+}
+
+impl Error for OpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Formats seconds since the Unix epoch like `2024-05-01T18:30:00.250Z`.
+fn utc_timestamp(unix_time: f64) -> String {
+    let millis = (unix_time * 1_000.0) as i64;
+    let (days, millis_of_day) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    // Civil from days, after Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        millis_of_day / 3_600_000,
+        millis_of_day / 60_000 % 60,
+        millis_of_day / 1_000 % 60,
+        millis_of_day % 1_000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("loopback-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        assert_eq!(utc_timestamp(0.0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(utc_timestamp(1_714_588_200.25), "2024-05-01T18:30:00.250Z");
+        assert_eq!(utc_timestamp(1_709_164_800.0), "2024-02-29T00:00:00.000Z");
+        assert_eq!(utc_timestamp(951_868_799.999), "2000-02-29T23:59:59.999Z");
+        assert_eq!(utc_timestamp(-1.0), "1969-12-31T23:59:59.000Z");
+    }
+
+    #[test]
+    fn writes_events_in_order_as_text_and_json() {
+        let (text, json) = (temp_path("session.log"), temp_path("session.jsonl"));
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_588_200);
+        {
+            let mut log = SessionLog::open(Some(&text), Some(&json)).unwrap();
+            log.event_at(start, "recording", "started out.wav");
+            log.event_at(start + Duration::from_millis(1_500), "marker", "take \"2\"");
+            log.event_at(
+                start + Duration::from_secs(60),
+                "summary",
+                "{\"underruns\":0}",
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(&text).unwrap(),
+            "2024-05-01T18:30:00.000Z recording started out.wav\n\
+             2024-05-01T18:30:01.500Z marker take \"2\"\n\
+             2024-05-01T18:31:00.000Z summary {\"underruns\":0}\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&json).unwrap(),
+            "{\"time\": \"2024-05-01T18:30:00.000Z\", \"unix_time\": 1714588200.000, \"event\": \
+             \"recording\", \"detail\": \"started out.wav\"}\n\
+             {\"time\": \"2024-05-01T18:30:01.500Z\", \"unix_time\": 1714588201.500, \"event\": \
+             \"marker\", \"detail\": \"take \\\"2\\\"\"}\n\
+             {\"time\": \"2024-05-01T18:31:00.000Z\", \"unix_time\": 1714588260.000, \"event\": \
+             \"summary\", \"detail\": \"{\\\"underruns\\\":0}\"}\n"
+        );
+        std::fs::remove_file(text).unwrap();
+        std::fs::remove_file(json).unwrap();
+    }
+
+    #[test]
+    fn appends_to_a_log_from_an_earlier_run() {
+        let path = temp_path("session-append.log");
+        let at = SystemTime::UNIX_EPOCH;
+        SessionLog::open(Some(&path), None)
+            .unwrap()
+            .event_at(at, "started", "first run");
+        SessionLog::open(Some(&path), None)
+            .unwrap()
+            .event_at(at, "started", "second run");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "1970-01-01T00:00:00.000Z started first run\n\
+             1970-01-01T00:00:00.000Z started second run\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn logs_nowhere_without_a_file() {
+        SessionLog::default().event("marker", "dropped");
+    }
+}
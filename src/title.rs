@@ -0,0 +1,212 @@
+//! A compact status in the terminal's title, so a glance at the window switcher shows that audio
+//! is flowing.
+//!
+//! The control thread updates it a few times a second from what the callbacks already measure.
+//! Each input is shown with `●` if anything above silence has come in on it within the last
+//! second and `○` if not, followed by `⬤REC` while recording and the output's short-term
+//! loudness, over the last 3 seconds, like `Microphone● Game○ ⬤REC -18 LUFS`.
+
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::health::SILENCE_THRESHOLD;
+
+/// How often the title is updated.
+pub const INTERVAL: Duration = Duration::from_millis(250);
+/// How long an input still counts as having signal after its last peak above silence.
+const PRESENCE_WINDOW: Duration = Duration::from_secs(1);
+
+/// What the title shows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TitleStatus {
+    /// Every input by name, and whether it has had signal within the last second.
+    pub inputs: Vec<(String, bool)>,
+    pub recording: bool,
+    /// The output's short-term loudness, in LUFS, or `None` if it has been silent.
+    pub loudness: Option<f32>,
+}
+
+impl fmt::Display for TitleStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, signal) in &self.inputs {
+            write!(f, "{}{} ", name, if *signal { '●' } else { '○' })?;
+        }
+        if self.recording {
+            f.write_str("⬤REC ")?;
+        }
+        match self.loudness {
+            Some(loudness) => write!(f, "{:.0} LUFS", loudness),
+            None => f.write_str("silent"),
+        }
+    }
+}
+
+/// Keeps the terminal's title up to date, and clears it again once dropped.
+pub struct TerminalTitle {
+    /// When each input last had a peak above silence, by name.
+    last_signal: Vec<(String, Instant)>,
+    /// The title as last written, which isn't written again until it changes.
+    shown: String,
+}
+
+impl TerminalTitle {
+    /// `None` unless stdout is a terminal that can be expected to show a title.
+    pub fn new() -> Option<Self> {
+        if !io::stdout().is_terminal() || !supports_title(std::env::var("TERM").ok().as_deref()) {
+            return None;
+        }
+        Some(TerminalTitle {
+            last_signal: Vec::new(),
+            shown: String::new(),
+        })
+    }
+
+    /// Shows `inputs`, by name with each one's peak since the last update, whether anything is
+    /// being recorded, and the output's short-term loudness.
+    pub fn update<'a>(
+        &mut self,
+        now: Instant,
+        inputs: impl IntoIterator<Item = (&'a str, f32)>,
+        recording: bool,
+        loudness: Option<f32>,
+    ) {
+        let status = self.status(now, inputs, recording, loudness).to_string();
+        if status != self.shown {
+            set_title(&status);
+            self.shown = status;
+        }
+    }
+
+    /// What [`TerminalTitle::update`] shows, noting which inputs have signal now.
+    fn status<'a>(
+        &mut self,
+        now: Instant,
+        inputs: impl IntoIterator<Item = (&'a str, f32)>,
+        recording: bool,
+        loudness: Option<f32>,
+    ) -> TitleStatus {
+        let inputs = inputs
+            .into_iter()
+            .map(|(name, peak)| {
+                if peak >= SILENCE_THRESHOLD {
+                    match self.last_signal.iter_mut().find(|(input, _)| input == name) {
+                        Some((_, at)) => *at = now,
+                        None => self.last_signal.push((name.to_owned(), now)),
+                    }
+                }
+                let signal = self
+                    .last_signal
+                    .iter()
+                    .any(|(input, at)| input == name && now.duration_since(*at) < PRESENCE_WINDOW);
+                (name.to_owned(), signal)
+            })
+            .collect();
+        TitleStatus {
+            inputs,
+            recording,
+            loudness,
+        }
+    }
+}
+
+impl Drop for TerminalTitle {
+    fn drop(&mut self) {
+        if !self.shown.is_empty() {
+            set_title("");
+        }
+    }
+}
+
+/// Whether a terminal described by `term`, the `TERM` variable, handles the title escape rather
+/// than printing it. Windows terminals don't set it, but all handle the escape.
+fn supports_title(term: Option<&str>) -> bool {
+    match term {
+        None => cfg!(windows),
+        Some(term) => !term.is_empty() && term != "dumb",
+    }
+}
+
+/// Sets the title with the OSC 0 escape, which xterm and everything modelled on it understand.
+fn set_title(title: &str) {
+    let mut stdout = io::stdout().lock();
+    // A title that can't be written is no reason to stop.
+    let _ = write!(stdout, "\x1b]0;{}\x07", title.replace(['\x1b', '\x07'], ""));
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title() -> TerminalTitle {
+        TerminalTitle {
+            last_signal: Vec::new(),
+            shown: String::new(),
+        }
+    }
+
+    #[test]
+    fn shows_each_input_the_recording_and_the_level() {
+        let status = |inputs: &[(&str, bool)], recording, loudness| {
+            TitleStatus {
+                inputs: inputs
+                    .iter()
+                    .map(|&(name, signal)| (name.to_owned(), signal))
+                    .collect(),
+                recording,
+                loudness,
+            }
+            .to_string()
+        };
+        assert_eq!(
+            status(&[("Microphone", true), ("Game", false)], true, Some(-18.3)),
+            "Microphone● Game○ ⬤REC -18 LUFS"
+        );
+        assert_eq!(status(&[("Mic", false)], false, None), "Mic○ silent");
+        assert_eq!(status(&[("Mic", true)], false, Some(0.0)), "Mic● 0 LUFS");
+        assert_eq!(status(&[], false, None), "silent");
+        assert_eq!(status(&[], true, Some(-40.6)), "⬤REC -41 LUFS");
+    }
+
+    #[test]
+    fn keeps_an_input_lit_for_a_second_after_its_last_signal() {
+        let mut title = title();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let lit = |status: TitleStatus| {
+            status
+                .inputs
+                .into_iter()
+                .map(|(_, signal)| signal)
+                .collect::<Vec<_>>()
+        };
+
+        let status = title.status(at(0), [("Mic", 0.5), ("Game", 0.0)], false, Some(-20.0));
+        assert_eq!(status.loudness, Some(-20.0));
+        assert_eq!(lit(status), [true, false]);
+        let quiet = [("Mic", 0.0), ("Game", 0.0)];
+        assert_eq!(
+            lit(title.status(at(750), quiet, false, None)),
+            [true, false]
+        );
+        assert_eq!(
+            lit(title.status(at(1_000), quiet, false, None)),
+            [false, false]
+        );
+        // Anything under silence doesn't count as signal.
+        let hum = [("Mic", SILENCE_THRESHOLD / 2.0), ("Game", 0.2)];
+        let status = title.status(at(1_250), hum, true, None);
+        assert_eq!(status.loudness, None);
+        assert_eq!(lit(status), [false, true]);
+    }
+
+    #[test]
+    fn leaves_terminals_that_cant_show_a_title_alone() {
+        assert!(supports_title(Some("xterm-256color")));
+        assert!(supports_title(Some("screen")));
+        assert!(!supports_title(Some("dumb")));
+        assert!(!supports_title(Some("")));
+        assert_eq!(supports_title(None), cfg!(windows));
+    }
+}
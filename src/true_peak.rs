@@ -0,0 +1,106 @@
+//! Estimates the true peak of a signal, including the peaks between samples that only show up
+//! once it's converted back to analogue or resampled.
+//!
+//! The signal is oversampled 4x with a short windowed-sinc filter, split into one phase per
+//! oversampled position so only the taps that matter are computed.
+
+use std::f32::consts::PI;
+
+const OVERSAMPLING: usize = 4;
+/// Taps per phase, which is enough to catch inter-sample peaks to within a fraction of a dB.
+pub const PHASE_TAPS: usize = 12;
+
+/// Measures the true peak of interleaved audio, keeping a short history per channel.
+pub struct TruePeakMeter {
+    phases: [[f32; PHASE_TAPS]; OVERSAMPLING],
+    /// The most recent samples of each channel, newest first.
+    history: Vec<[f32; PHASE_TAPS]>,
+}
+
+impl TruePeakMeter {
+    pub fn new(channels: usize) -> Self {
+        let taps = OVERSAMPLING * PHASE_TAPS;
+        let centre = (taps - 1) as f32 / 2.0;
+        let mut phases = [[0.0; PHASE_TAPS]; OVERSAMPLING];
+        for (phase, coefficients) in phases.iter_mut().enumerate() {
+            for (k, coefficient) in coefficients.iter_mut().enumerate() {
+                let i = phase + OVERSAMPLING * k;
+                let x = (i as f32 - centre) / OVERSAMPLING as f32;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let hann = 0.5 - 0.5 * (2.0 * PI * (i as f32 + 0.5) / taps as f32).cos();
+                *coefficient = sinc * hann;
+            }
+            // Each phase passes DC through at unity, so a steady signal reads as itself.
+            let sum = coefficients.iter().sum::<f32>();
+            coefficients.iter_mut().for_each(|c| *c /= sum);
+        }
+        TruePeakMeter {
+            phases,
+            history: vec![[0.0; PHASE_TAPS]; channels],
+        }
+    }
+
+    /// Returns the highest absolute value of `samples`, with `channels` channels to a frame, and
+    /// of the signal between them.
+    pub fn process(&mut self, samples: &[f32], channels: usize) -> f32 {
+        let mut peak = 0.0f32;
+        for frame in samples.chunks_exact(channels) {
+            for (&sample, history) in frame.iter().zip(&mut self.history) {
+                history.copy_within(..PHASE_TAPS - 1, 1);
+                history[0] = sample;
+                peak = peak.max(sample.abs());
+                for phase in &self.phases {
+                    let estimate = phase
+                        .iter()
+                        .zip(history.iter())
+                        .map(|(c, x)| c * x)
+                        .sum::<f32>();
+                    peak = peak.max(estimate.abs());
+                }
+            }
+        }
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_steady_signal_as_itself() {
+        let mut meter = TruePeakMeter::new(2);
+        // The step up from silence rings a little, like it would on the way to analogue.
+        assert!(meter.process(&[0.5; 200], 2) > 0.5);
+        let peak = meter.process(&[0.5; 200], 2);
+        assert!((peak - 0.5).abs() < 1e-6, "{}", peak);
+    }
+
+    #[test]
+    fn finds_the_peaks_between_samples() {
+        // A full-scale sine at a quarter of the rate, sampled 45 degrees off its peaks, so every
+        // sample is 3 dB below them.
+        let samples = (0..1_000)
+            .map(|i| (PI / 2.0 * i as f32 + PI / 4.0).sin())
+            .collect::<Vec<_>>();
+        let mut meter = TruePeakMeter::new(1);
+        meter.process(&samples[..100], 1);
+        assert!(samples.iter().all(|sample| sample.abs() < 0.71));
+        let peak = meter.process(&samples[100..], 1);
+        assert!((peak - 1.0).abs() < 0.03, "{}", peak);
+    }
+
+    #[test]
+    fn forgets_a_peak_once_it_leaves_the_history() {
+        let mut meter = TruePeakMeter::new(2);
+        assert!(meter.process(&[0.0, 0.9, 0.0, -0.9], 2) >= 0.9);
+        // Still ringing out of the filter while the peak is in the history.
+        assert!(meter.process(&[0.0; 2], 2) > 0.0);
+        meter.process(&[0.0; PHASE_TAPS * 2], 2);
+        assert_eq!(meter.process(&[0.0; 20], 2), 0.0);
+    }
+}
@@ -0,0 +1,305 @@
+//! Checks that the pipeline passes audio through untouched when nothing is set to change it.
+//!
+//! A noise-like reference is played through a few configurations of the pipeline on fake devices,
+//! so this runs anywhere, and what reaches both the output and its recording is compared against
+//! the reference bit for bit, past the silence the ring buffers are prefilled with.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backend::fake::{FakeProvider, Signal};
+use crate::backend::StreamConfig;
+use crate::error::PipelineError;
+use crate::pipeline::{ms_to_frames, InputConfig, Pipeline, PipelineConfig};
+use crate::recorder::SyncPolicy;
+use crate::resample::Quality;
+use crate::wav;
+
+const SAMPLE_RATE: u32 = 48_000;
+const CHANNELS: u16 = 2;
+const PERIOD_FRAMES: u32 = 480;
+const LATENCY_MS: f32 = 50.0;
+/// How much of the reference is played through each case.
+const REFERENCE_FRAMES: usize = SAMPLE_RATE as usize;
+
+/// One way of running the pipeline that shouldn't change the audio.
+struct Case {
+    name: &'static str,
+    planar: bool,
+    /// Mixes the reference with a silent second input, rather than on its own.
+    silent_input: bool,
+}
+
+const CASES: [Case; 3] = [
+    Case {
+        name: "interleaved",
+        planar: false,
+        silent_input: false,
+    },
+    Case {
+        name: "planar",
+        planar: true,
+        silent_input: false,
+    },
+    Case {
+        name: "mixed with a silent input",
+        planar: false,
+        silent_input: true,
+    },
+];
+
+/// How one case went.
+#[derive(Clone, Debug)]
+pub struct CaseReport {
+    pub case: &'static str,
+    /// How many samples were compared, in the output and in the recording each.
+    pub samples: usize,
+    pub mismatch: Option<Mismatch>,
+}
+
+/// The first sample that came out different from the reference.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// `output` or `recording`.
+    pub sink: &'static str,
+    /// Which sample of the reference, counting interleaved samples from 0.
+    pub index: usize,
+    pub expected: f32,
+    /// Or `None` if the sink stopped short of this sample.
+    pub actual: Option<f32>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.index / CHANNELS as usize;
+        let channel = self.index % CHANNELS as usize + 1;
+        match self.actual {
+            Some(actual) => write!(
+                f,
+                "the {} has {:e} ({:#010x}) rather than {:e} ({:#010x}) at frame {}, channel {}",
+                self.sink,
+                actual,
+                actual.to_bits(),
+                self.expected,
+                self.expected.to_bits(),
+                frame,
+                channel
+            ),
+            None => write!(
+                f,
+                "the {} ends before frame {}, channel {}",
+                self.sink, frame, channel
+            ),
+        }
+    }
+}
+
+/// Runs every case, with the recordings going to `dir` and removed again afterwards.
+pub fn verify_passthrough(dir: &Path) -> Result<Vec<CaseReport>, PipelineError> {
+    let reference = reference();
+    CASES
+        .iter()
+        .map(|case| run_case(case, &reference, dir))
+        .collect()
+}
+
+/// Pseudo-random samples, so that any offset or reordering shows, which are never negative zero
+/// since mixing with silence would turn that into positive zero.
+fn reference() -> Arc<[f32]> {
+    let mut state = 0x2545_f491_u32;
+    (0..REFERENCE_FRAMES * CHANNELS as usize)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+        })
+        .collect()
+}
+
+fn run_case(case: &Case, reference: &Arc<[f32]>, dir: &Path) -> Result<CaseReport, PipelineError> {
+    let config = StreamConfig {
+        channels: CHANNELS,
+        sample_rate: cpal::SampleRate(SAMPLE_RATE),
+        buffer_size: cpal::BufferSize::Fixed(PERIOD_FRAMES),
+    };
+    let provider = FakeProvider::new();
+    provider.add_input(
+        "Reference",
+        config.clone(),
+        Signal::Samples(Arc::clone(reference)),
+    );
+    let mut inputs = vec![InputConfig::new("Reference")];
+    if case.silent_input {
+        provider.add_input("Silence", config.clone(), Signal::Silence);
+        inputs.push(InputConfig::new("Silence"));
+    }
+    provider.add_output("Output", config);
+
+    let recording = dir.join(format!("loopback-verify-{}.wav", std::process::id()));
+    let sidecar = dir.join(format!("loopback-verify-{}.json", std::process::id()));
+    let pipeline_config = PipelineConfig {
+        inputs,
+        output: "Output".to_owned(),
+        latency_ms: LATENCY_MS,
+        ringbuf_ms: None,
+        denoise_mix: 1.0,
+        segment: 0,
+        cue_markers: false,
+        negotiate: false,
+        output_channels: None,
+        adaptive_latency: None,
+        replay_buffer: None,
+        planar: case.planar,
+        record_output: Some(recording.clone()),
+        record_mirror: None,
+        record_sync: SyncPolicy::default(),
+        record_ceiling: None,
+        record_continue: None,
+        stems_pre_fader: false,
+        rt_priority: false,
+        preroll: Duration::ZERO,
+        marker_sidecar: sidecar.clone(),
+        record_dir: dir.to_owned(),
+        strict_routing: false,
+        resample_quality: Quality::default(),
+    };
+
+    let result = play_through(&provider, &pipeline_config, &recording);
+    let _ = std::fs::remove_file(&recording);
+    let _ = std::fs::remove_file(&sidecar);
+    let (output, recorded, skipped) = result?;
+
+    // Everything comes out after the silence the ring buffers were prefilled with, less whatever
+    // was skipped to line the reference up with the other input.
+    let offset = ms_to_frames(LATENCY_MS, SAMPLE_RATE) * CHANNELS as usize;
+    let start = skipped * CHANNELS as usize;
+    let mismatch = [("output", &output), ("recording", &recorded)]
+        .into_iter()
+        .find_map(|(sink, samples)| {
+            first_mismatch(
+                sink,
+                reference,
+                start,
+                &samples[offset.min(samples.len())..],
+            )
+        });
+    Ok(CaseReport {
+        case: case.name,
+        samples: reference.len() - start,
+        mismatch,
+    })
+}
+
+/// Plays the whole reference through, returning what the output was given, what was recorded,
+/// and how many frames of the reference were skipped when starting.
+fn play_through(
+    provider: &FakeProvider,
+    config: &PipelineConfig,
+    recording: &Path,
+) -> Result<(Vec<f32>, Vec<f32>, usize), PipelineError> {
+    let mut pipeline = Pipeline::build(provider, config)?;
+    pipeline.start_inputs()?;
+    provider.advance(1);
+    pipeline.start_output()?;
+    let skipped = pipeline.start_offsets()[0].1;
+    let frames = REFERENCE_FRAMES + ms_to_frames(LATENCY_MS, SAMPLE_RATE);
+    provider.advance(frames.div_ceil(PERIOD_FRAMES as usize) as u64 + 1);
+    let output = provider.take_output("Output");
+    // The recording is only complete once the pipeline has finished it.
+    drop(pipeline);
+    let recorded = wav::read_samples(recording).map_err(|source| PipelineError::Recording {
+        source: source.into(),
+    })?;
+    Ok((output, recorded, skipped))
+}
+
+/// Compares `samples` against the reference from sample `start` on.
+fn first_mismatch(
+    sink: &'static str,
+    reference: &[f32],
+    start: usize,
+    samples: &[f32],
+) -> Option<Mismatch> {
+    reference
+        .iter()
+        .enumerate()
+        .skip(start)
+        .find_map(|(index, &expected)| {
+            let actual = samples.get(index - start).copied();
+            (actual.map(f32::to_bits) != Some(expected.to_bits())).then_some(Mismatch {
+                sink,
+                index,
+                expected,
+                actual,
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_the_reference_through_untouched_in_every_case() {
+        let dir = std::env::temp_dir();
+        let reports = verify_passthrough(&dir).unwrap();
+        assert_eq!(reports.len(), CASES.len());
+        for report in reports {
+            assert!(
+                report.mismatch.is_none(),
+                "{}: {}",
+                report.case,
+                report.mismatch.unwrap()
+            );
+            assert!(report.samples > REFERENCE_FRAMES * CHANNELS as usize * 9 / 10);
+        }
+        let leftover = dir.join(format!("loopback-verify-{}.wav", std::process::id()));
+        assert!(!leftover.exists());
+    }
+
+    #[test]
+    fn finds_the_first_sample_that_differs_at_all() {
+        let reference = [0.5, -0.25, 0.0, 1.0];
+        assert!(first_mismatch("output", &reference, 0, &reference).is_none());
+        // Lined up from `start`, so the sink starts with the reference's third sample.
+        assert!(first_mismatch("output", &reference, 2, &[0.0, 1.0]).is_none());
+
+        // A sign on a zero is a different bit pattern, so it counts.
+        let mismatch =
+            first_mismatch("recording", &reference, 0, &[0.5, -0.25, -0.0, 1.0]).unwrap();
+        assert_eq!(
+            (mismatch.sink, mismatch.index, mismatch.actual),
+            ("recording", 2, Some(-0.0))
+        );
+        assert_eq!(
+            mismatch.to_string(),
+            "the recording has -0e0 (0x80000000) rather than 0e0 (0x00000000) at frame 1, channel 1"
+        );
+
+        // Off by one sample, as if the start hadn't been skipped.
+        let mismatch = first_mismatch("output", &reference, 1, &reference).unwrap();
+        assert_eq!(mismatch.index, 1);
+
+        let mismatch = first_mismatch("output", &reference, 0, &reference[..3]).unwrap();
+        assert_eq!(mismatch.actual, None);
+        assert_eq!(
+            mismatch.to_string(),
+            "the output ends before frame 1, channel 2"
+        );
+    }
+
+    #[test]
+    fn makes_a_reference_with_no_negative_zeros_or_repeats() {
+        let reference = reference();
+        assert_eq!(reference.len(), REFERENCE_FRAMES * CHANNELS as usize);
+        assert!(reference
+            .iter()
+            .all(|&sample| (-1.0..1.0).contains(&sample)));
+        assert!(reference
+            .iter()
+            .all(|sample| sample.to_bits() != (-0.0f32).to_bits()));
+        // Any offset shows, since no stretch of it repeats another nearby.
+        assert!(reference.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+}
@@ -0,0 +1,318 @@
+//! Writes 32-bit float WAV files.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const FORMAT_IEEE_FLOAT: u16 = 3;
+const BYTES_PER_SAMPLE: u32 = 4;
+
+/// Offsets of the size fields that can only be filled in once the length is known.
+const RIFF_SIZE_OFFSET: u64 = 4;
+const FACT_FRAMES_OFFSET: u64 = 46;
+const DATA_SIZE_OFFSET: u64 = 54;
+const HEADER_LEN: u32 = 58;
+/// How much is written at once, which each writer sets aside when it's created.
+pub const BUFFER_BYTES: usize = 8 * 1024;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    samples_written: u64,
+    /// Frame offsets and labels, written as cue points after the data when finalized.
+    cues: Vec<(u32, String)>,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::with_capacity(BUFFER_BYTES, File::create(path)?);
+
+        let block_align = channels as u32 * BYTES_PER_SAMPLE;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(HEADER_LEN - 8).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&18u32.to_le_bytes())?;
+        file.write_all(&FORMAT_IEEE_FLOAT.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align).to_le_bytes())?;
+        file.write_all(&(block_align as u16).to_le_bytes())?;
+        file.write_all(&((BYTES_PER_SAMPLE * 8) as u16).to_le_bytes())?;
+        // No extension to the format.
+        file.write_all(&0u16.to_le_bytes())?;
+
+        // Required for anything that isn't integer PCM.
+        file.write_all(b"fact")?;
+        file.write_all(&4u32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            file,
+            channels,
+            samples_written: 0,
+            cues: Vec::new(),
+        })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples_written += samples.len() as u64;
+        Ok(())
+    }
+
+    pub fn frames_written(&self) -> u64 {
+        self.samples_written / self.channels as u64
+    }
+
+    /// Fills in the header for everything written so far, so that the file is valid even if the
+    /// process dies before [`WavWriter::finalize`] is called.
+    pub fn update_header(&mut self) -> io::Result<()> {
+        let data_len = (self.samples_written * BYTES_PER_SAMPLE as u64).min(u32::MAX as u64 - 64);
+        let frames = self.frames_written().min(u32::MAX as u64) as u32;
+
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        file.write_all(&(data_len as u32 + HEADER_LEN - 8).to_le_bytes())?;
+        file.seek(SeekFrom::Start(FACT_FRAMES_OFFSET))?;
+        file.write_all(&frames.to_le_bytes())?;
+        file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        file.write_all(&(data_len as u32).to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Waits for everything written so far, header included, to reach the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    /// Adds a labelled cue point at `frame`, which DAWs show as a marker.
+    pub fn add_cue(&mut self, frame: u64, label: &str) {
+        let frame = frame.min(u32::MAX as u64) as u32;
+        self.cues.push((frame, label.to_owned()));
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        self.update_header()?;
+        if !self.cues.is_empty() {
+            self.write_cues()?;
+            self.file.flush()?;
+            let file = self.file.get_mut();
+            let len = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+            file.write_all(&((len - 8).min(u32::MAX as u64) as u32).to_le_bytes())?;
+        }
+        self.file.get_ref().sync_all()
+    }
+
+    /// Writes a `cue ` chunk with every cue point, followed by a `LIST` chunk labelling them.
+    fn write_cues(&mut self) -> io::Result<()> {
+        self.file.write_all(b"cue ")?;
+        self.file
+            .write_all(&(4 + 24 * self.cues.len() as u32).to_le_bytes())?;
+        self.file
+            .write_all(&(self.cues.len() as u32).to_le_bytes())?;
+        for (id, (frame, _)) in (1u32..).zip(&self.cues) {
+            self.file.write_all(&id.to_le_bytes())?;
+            self.file.write_all(&frame.to_le_bytes())?;
+            self.file.write_all(b"data")?;
+            // Chunk and block starts, which are zero for a single data chunk.
+            self.file.write_all(&0u32.to_le_bytes())?;
+            self.file.write_all(&0u32.to_le_bytes())?;
+            self.file.write_all(&frame.to_le_bytes())?;
+        }
+
+        // Every label is NUL terminated and padded to an even length.
+        let label_len = |label: &str| (label.len() as u32 + 1 + 1) & !1;
+        let list_len = 4 + self
+            .cues
+            .iter()
+            .map(|(_, label)| 12 + label_len(label))
+            .sum::<u32>();
+        self.file.write_all(b"LIST")?;
+        self.file.write_all(&list_len.to_le_bytes())?;
+        self.file.write_all(b"adtl")?;
+        for (id, (_, label)) in (1u32..).zip(&self.cues) {
+            self.file.write_all(b"labl")?;
+            self.file
+                .write_all(&(4 + label.len() as u32 + 1).to_le_bytes())?;
+            self.file.write_all(&id.to_le_bytes())?;
+            self.file.write_all(label.as_bytes())?;
+            let padding = label_len(label) - label.len() as u32;
+            self.file.write_all(&[0, 0][..padding as usize])?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads back the samples of a file written by [`WavWriter`], which always has the same header.
+pub fn read_samples(path: &Path) -> io::Result<Vec<f32>> {
+    let bytes = std::fs::read(path)?;
+    let data = bytes
+        .get(DATA_SIZE_OFFSET as usize..HEADER_LEN as usize)
+        .filter(|_| bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WAVE")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a file this writes"))?;
+    let data_len = u32::from_le_bytes(data.try_into().unwrap()) as usize;
+    let data = bytes
+        .get(HEADER_LEN as usize..HEADER_LEN as usize + data_len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "the data is cut short"))?;
+    Ok(data
+        .chunks_exact(BYTES_PER_SAMPLE as usize)
+        .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("loopback-wav-{}-{}.wav", name, std::process::id()))
+    }
+
+    /// Every chunk of a RIFF file after its header, by id, padded ones included.
+    fn chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut rest = &bytes[12..];
+        while rest.len() >= 8 {
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            chunks.push((&rest[..4], &rest[8..8 + len]));
+            rest = &rest[(8 + len + len % 2).min(rest.len())..];
+        }
+        chunks
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_float_samples_that_read_back() {
+        let path = temp_path("samples");
+        let samples = (0..960)
+            .map(|i| (i as f32 * 0.01).sin())
+            .collect::<Vec<_>>();
+        let mut writer = WavWriter::create(&path, 2, 48_000).unwrap();
+        writer.write(&samples).unwrap();
+        assert_eq!(writer.frames_written(), 480);
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let read = read_samples(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, samples);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        let chunks = chunks(&bytes);
+        let ids = chunks.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, [&b"fmt "[..], b"fact", b"data"]);
+        let format = chunks[0].1;
+        assert_eq!(
+            u16::from_le_bytes([format[0], format[1]]),
+            FORMAT_IEEE_FLOAT
+        );
+        assert_eq!(u16::from_le_bytes([format[2], format[3]]), 2);
+        assert_eq!(u32_at(format, 4), 48_000);
+        assert_eq!(u32_at(format, 8), 48_000 * 8);
+        assert_eq!(u32_at(chunks[1].1, 0), 480);
+    }
+
+    #[test]
+    fn is_readable_before_it_is_finished() {
+        let path = temp_path("unfinished");
+        let mut writer = WavWriter::create(&path, 1, 44_100).unwrap();
+        writer.write(&[0.25; 100]).unwrap();
+        writer.update_header().unwrap();
+        let first = read_samples(&path).unwrap();
+        writer.write(&[-0.5; 50]).unwrap();
+        writer.update_header().unwrap();
+        let second = read_samples(&path).unwrap();
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(first, [0.25; 100]);
+        assert_eq!(second.len(), 150);
+        assert_eq!(second[100..], [-0.5; 50]);
+    }
+
+    #[test]
+    fn labels_cue_points_after_the_data() {
+        let path = temp_path("cues");
+        let mut writer = WavWriter::create(&path, 1, 48_000).unwrap();
+        writer.write(&[0.5; 1_000]).unwrap();
+        // Labels of odd and even lengths, which pad differently.
+        writer.add_cue(0, "start");
+        writer.add_cue(750, "laugh");
+        writer.add_cue(999, "outro!");
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let samples = read_samples(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples, [0.5; 1_000]);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+
+        let chunks = chunks(&bytes);
+        let (id, cue) = chunks[3];
+        assert_eq!(id, b"cue ");
+        assert_eq!(u32_at(cue, 0), 3);
+        let points = cue[4..]
+            .chunks_exact(24)
+            .map(|point| {
+                assert_eq!(&point[8..12], b"data");
+                assert_eq!(u32_at(point, 4), u32_at(point, 20));
+                (u32_at(point, 0), u32_at(point, 4))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(points, [(1, 0), (2, 750), (3, 999)]);
+
+        let (id, list) = chunks[4];
+        assert_eq!(id, b"LIST");
+        assert_eq!(&list[..4], b"adtl");
+        let mut labels = Vec::new();
+        let mut rest = &list[4..];
+        while !rest.is_empty() {
+            assert_eq!(&rest[..4], b"labl");
+            let len = u32_at(rest, 4) as usize;
+            let text = &rest[12..8 + len];
+            assert_eq!(text.last(), Some(&0));
+            labels.push((
+                u32_at(rest, 8),
+                String::from_utf8(text[..text.len() - 1].to_vec()).unwrap(),
+            ));
+            rest = &rest[8 + len + len % 2..];
+        }
+        assert_eq!(
+            labels,
+            [
+                (1, "start".to_owned()),
+                (2, "laugh".to_owned()),
+                (3, "outro!".to_owned())
+            ]
+        );
+        assert_eq!(chunks.len(), 5);
+    }
+
+    #[test]
+    fn refuses_files_it_did_not_write() {
+        let path = temp_path("foreign");
+        std::fs::write(&path, b"ID3\x04 definitely an mp3").unwrap();
+        let foreign = read_samples(&path).unwrap_err().kind();
+        let mut writer = WavWriter::create(&path, 1, 48_000).unwrap();
+        writer.write(&[0.5; 100]).unwrap();
+        writer.finalize().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        let cut = read_samples(&path).unwrap_err().kind();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(foreign, io::ErrorKind::InvalidData);
+        assert_eq!(cut, io::ErrorKind::UnexpectedEof);
+    }
+}